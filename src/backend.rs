@@ -14,6 +14,176 @@ fn compile_valtype(ty: Ty) -> cranelift::Type {
   }
 }
 
+// REPRESENTATION OF ENVIRONMENTS AND ARRAYS
+//
+// An environment holds its values in order, each at an offset aligned to its
+// size. An empty environment is a null pointer. An environment that may
+// outlive the code that makes it, because it may be returned or passed
+// around a back edge to a later run of that code, is a block from `malloc`,
+// and those are never freed. Any other environment is a stack slot, which
+// each run of the code that makes it reuses.
+//
+// An array that is indexed is laid out in memory once, with each element laid
+// out like an environment and padded to a multiple of its largest value. An
+// array of immediates is a read-only data object, which equal arrays share.
+// Any other array gets a stack slot, and is stored to it just after the last
+// of its values is defined, which dominates every use of the array. Indexing
+// is then a load of each value of the element, after the bounds check that
+// lowering emits.
+
+fn env_layout(tys: &[cranelift::Type]) -> (Vec<i32>, i64) {
+  let mut offsets = Vec::new();
+  let mut n = 0;
+  for &ty in tys.iter() {
+    let k = ty.bytes() as i32;
+    n = (n + k - 1) / k * k;
+    offsets.push(n);
+    n += k;
+  }
+  (offsets, n as i64)
+}
+
+// The offsets of the values of an element and the distance between elements.
+
+fn array_layout(tys: &[cranelift::Type]) -> (Vec<i32>, i64) {
+  let (offsets, n) = env_layout(tys);
+  let k = tys.iter().map(|ty| ty.bytes() as i64).max().unwrap_or(1);
+  (offsets, (n + k - 1) / k * k)
+}
+
+// The number of variables that an instruction defines.
+
+fn num_outputs(program: bytecode::Program<'_>, inst: bytecode::Inst<'_>) -> usize {
+  match inst {
+    bytecode::Inst::Block(tys) | bytecode::Inst::EnvUnpack(_, tys) | bytecode::Inst::Index(_, _, tys) => {
+      tys.len()
+    }
+    bytecode::Inst::FunCall(f, _) => {
+      program.functions[f.0 as usize].signature.outputs.len()
+    }
+    bytecode::Inst::FunCallIndirect(_, signature, _) => {
+      signature.outputs.len()
+    }
+    bytecode::Inst::EnvPack(_)
+    | bytecode::Inst::FunAddr(_)
+    | bytecode::Inst::ImmBool(_)
+    | bytecode::Inst::ImmI6(_)
+    | bytecode::Inst::ImmI64(_)
+    | bytecode::Inst::Op11(_, _)
+    | bytecode::Inst::Op21(_, _, _) => {
+      1
+    }
+    bytecode::Inst::FunTailCall
+    | bytecode::Inst::FunTailCallIndirect
+    | bytecode::Inst::Goto(_, _)
+    | bytecode::Inst::If(_, _, _)
+    | bytecode::Inst::Ret(_)
+    | bytecode::Inst::Switch(_, _, _)
+    | bytecode::Inst::Trap => {
+      0
+    }
+  }
+}
+
+// The bytes in memory of each variable that an immediate defines.
+
+fn immediates(program: bytecode::Program<'_>, func: bytecode::Function<'_>) -> Vec<Option<Box<[u8]>>> {
+  let mut out = vec![None; func.signature.inputs.len()];
+
+  for &inst in func.code.iter() {
+    match inst {
+      bytecode::Inst::ImmBool(imm) => out.push(Some(Box::from([u8::from(imm)]))),
+      bytecode::Inst::ImmI6(imm) => out.push(Some(Box::from([u8::from(imm)]))),
+      bytecode::Inst::ImmI64(imm) => out.push(Some(Box::from(imm.to_le_bytes()))),
+      _ => out.resize(out.len() + num_outputs(program, inst), None),
+    }
+  }
+
+  out
+}
+
+// Whether each variable escapes, which is when its value, or a value made
+// from it, may be returned or passed along a back edge. A call's outputs may
+// be made from any of its inputs.
+
+fn escapes(program: bytecode::Program<'_>, func: bytecode::Function<'_>) -> Vec<bool> {
+  // The instruction and output that define each variable, the instruction of
+  // each block, and the gotos to each block.
+
+  let mut defs = vec![None; func.signature.inputs.len()];
+  let mut blocks = Vec::new();
+  let mut gotos = Vec::new();
+  let mut roots = Vec::new();
+
+  for (k, &inst) in func.code.iter().enumerate() {
+    match inst {
+      bytecode::Inst::Block(_) => {
+        blocks.push(k);
+      }
+      bytecode::Inst::Goto(a, xs) => {
+        let a = a.0 as usize;
+        if gotos.len() < a + 1 { gotos.resize(a + 1, Vec::new()) }
+        gotos[a].push(xs);
+        if a < blocks.len() { roots.extend_from_slice(xs) }
+      }
+      bytecode::Inst::Ret(xs) => {
+        roots.extend_from_slice(xs);
+      }
+      _ => {
+      }
+    }
+    for i in 0 .. num_outputs(program, inst) { defs.push(Some((k, i))) }
+  }
+
+  let mut out = vec![false; defs.len()];
+
+  while let Some(x) = roots.pop() {
+    let x = usize::from(x);
+    if out[x] { continue }
+    out[x] = true;
+    let Some((k, i)) = defs[x] else { continue };
+    match func.code[k] {
+      bytecode::Inst::Block(_) => {
+        let a = blocks.binary_search(&k).unwrap();
+        for xs in gotos.get(a).into_iter().flatten() { roots.push(xs[i]) }
+      }
+      bytecode::Inst::EnvPack(xs) | bytecode::Inst::FunCall(_, xs) => {
+        roots.extend_from_slice(xs);
+      }
+      bytecode::Inst::EnvUnpack(y, _) | bytecode::Inst::Op11(_, y) => {
+        roots.push(y);
+      }
+      bytecode::Inst::FunCallIndirect(y, _, xs) | bytecode::Inst::Index(y, xs, _) => {
+        roots.push(y);
+        roots.extend_from_slice(xs);
+      }
+      bytecode::Inst::Op21(_, y, z) => {
+        roots.push(y);
+        roots.push(z);
+      }
+      _ => {
+      }
+    }
+  }
+
+  out
+}
+
+// The contents of an array of immediates, or `None` if any value isn't one.
+
+fn array_bytes(xs: &[bytecode::VarId], tys: &[cranelift::Type], imms: &[Option<Box<[u8]>>]) -> Option<Box<[u8]>> {
+  let (offsets, stride) = array_layout(tys);
+  let mut out = vec![0; xs.len() / tys.len() * stride as usize];
+
+  for (j, &x) in xs.iter().enumerate() {
+    let bytes = imms[usize::from(x)].as_ref()?;
+    let k = (j / tys.len()) * stride as usize + offsets[j % tys.len()] as usize;
+    out[k .. k + bytes.len()].copy_from_slice(bytes);
+  }
+
+  Some(out.into_boxed_slice())
+}
+
 pub fn compile<'a>(program: bytecode::Program<'a>) -> Box<[u8]> {
   const CALL_CONV: cranelift::CallConv = cranelift::CallConv::AppleAarch64;
 
@@ -51,6 +221,7 @@ pub fn compile<'a>(program: bytecode::Program<'a>) -> Box<[u8]> {
   let mut fbc = cranelift::FunctionBuilderContext::new();
 
   let mut func_ids = Vec::new();
+  let mut tables = HashMap::new();
 
   // declare functions
 
@@ -79,6 +250,37 @@ pub fn compile<'a>(program: bytecode::Program<'a>) -> Box<[u8]> {
     ctx.clear()
   }
 
+  // `malloc` is only imported by a program with an environment that escapes.
+  // Lowering reserves the name.
+
+  let escaping = map_slice(program.functions, |&func| escapes(program, func));
+
+  let needs_malloc =
+    program.functions.iter().zip(escaping.iter()).any(|(func, escaping)| {
+      let mut n = func.signature.inputs.len();
+      func.code.iter().any(|&inst| {
+        let is_escaping = matches!(inst, bytecode::Inst::EnvPack(xs) if ! xs.is_empty() && escaping[n]);
+        n += num_outputs(program, inst);
+        is_escaping
+      })
+    });
+
+  let malloc_id =
+    if needs_malloc {
+      let mut malloc_signature = cranelift::Signature::new(CALL_CONV);
+      malloc_signature.params.push(cranelift::AbiParam::new(cranelift::I64));
+      malloc_signature.returns.push(cranelift::AbiParam::new(cranelift::I64));
+      Some(
+        object_module.declare_function(
+          "malloc",
+          cranelift::Linkage::Import,
+          &malloc_signature
+        ).unwrap()
+      )
+    } else {
+      None
+    };
+
   // define functions
 
   for (func_idx, &func) in program.functions.iter().enumerate() {
@@ -99,6 +301,30 @@ pub fn compile<'a>(program: bytecode::Program<'a>) -> Box<[u8]> {
     let mut blocks = Vec::new();
     let mut num_blocks = 0;
 
+    // The stack slot of each indexed array that isn't all immediates, and the
+    // arrays to store after each variable.
+
+    let imms = immediates(program, func);
+    let mut slots = HashMap::new();
+    let mut stores: HashMap<usize, Vec<_>> = HashMap::new();
+
+    for &inst in func.code.iter() {
+      let bytecode::Inst::Index(_, xs, tys) = inst else { continue };
+      if xs.is_empty() || xs.iter().all(|&x| imms[usize::from(x)].is_some()) { continue }
+      let key: Box<[u16]> = xs.iter().map(|x| x.0).collect();
+      if slots.contains_key(&key) { continue }
+      let tys = map_slice(tys, |&ty| compile_valtype(ty));
+      let (offsets, stride) = array_layout(&tys);
+      let size = xs.len() / tys.len() * stride as usize;
+      let slot =
+        fb.create_sized_stack_slot(
+          cranelift::StackSlotData::new(cranelift::StackSlotKind::ExplicitSlot, size as u32)
+        );
+      let last = xs.iter().map(|&x| usize::from(x)).max().unwrap();
+      stores.entry(last).or_default().push((slot, xs, tys.len(), offsets, stride));
+      let _ = slots.insert(key, slot);
+    }
+
     let entry = fb.create_block();
     fb.append_block_params_for_function_params(entry);
     fb.switch_to_block(entry);
@@ -107,7 +333,22 @@ pub fn compile<'a>(program: bytecode::Program<'a>) -> Box<[u8]> {
       vars.push(fb.block_params(entry)[i]);
     }
 
+    let mut num_stored = 0;
+
     for &inst in func.code.iter() {
+      // Stores the arrays whose last values are now defined.
+
+      for v in num_stored .. vars.len() {
+        for &(slot, xs, w, ref offsets, stride) in stores.get(&v).into_iter().flatten() {
+          for (j, &x) in xs.iter().enumerate() {
+            let offset = (j / w) as i64 * stride + offsets[j % w] as i64;
+            let _: _ = fb.ins().stack_store(vars[usize::from(x)], slot, offset as i32);
+          }
+        }
+      }
+
+      num_stored = vars.len();
+
       match inst {
         bytecode::Inst::Block(tys) => {
           num_blocks += 1;
@@ -119,6 +360,61 @@ pub fn compile<'a>(program: bytecode::Program<'a>) -> Box<[u8]> {
             vars.push(fb.append_block_param(block, compile_valtype(ty)));
           }
         }
+        bytecode::Inst::EnvPack(xs) => {
+          let tys = map_slice(xs, |&x| fb.func.dfg.value_type(vars[usize::from(x)]));
+          let (offsets, n) = env_layout(&tys);
+          if n == 0 {
+            vars.push(fb.ins().iconst(cranelift::I64, 0));
+          } else {
+            let p =
+              if escaping[func_idx][vars.len()] {
+                let f = object_module.declare_func_in_func(malloc_id.unwrap(), fb.func);
+                let n = fb.ins().iconst(cranelift::I64, n);
+                let call = fb.ins().call(f, &[n]);
+                fb.inst_results(call)[0]
+              } else {
+                let slot =
+                  fb.create_sized_stack_slot(
+                    cranelift::StackSlotData::new(cranelift::StackSlotKind::ExplicitSlot, n as u32)
+                  );
+                fb.ins().stack_addr(cranelift::I64, slot, 0)
+              };
+            for (&x, &k) in xs.iter().zip(offsets.iter()) {
+              let _: _ = fb.ins().store(cranelift::MemFlags::trusted(), vars[usize::from(x)], p, k);
+            }
+            vars.push(p);
+          }
+        }
+        bytecode::Inst::EnvUnpack(x, tys) => {
+          let p = vars[usize::from(x)];
+          let tys = map_slice(tys, |&ty| compile_valtype(ty));
+          let (offsets, _) = env_layout(&tys);
+          for (&ty, &k) in tys.iter().zip(offsets.iter()) {
+            vars.push(fb.ins().load(ty, cranelift::MemFlags::trusted(), p, k));
+          }
+        }
+        bytecode::Inst::FunAddr(f) => {
+          let f = object_module.declare_func_in_func(func_ids[f.0 as usize], fb.func);
+          vars.push(fb.ins().func_addr(cranelift::I64, f));
+        }
+        bytecode::Inst::FunCall(f, xs) => {
+          let f = object_module.declare_func_in_func(func_ids[f.0 as usize], fb.func);
+          let call = fb.ins().call(f, &map_slice(xs, |&x| vars[usize::from(x)]));
+          vars.extend_from_slice(fb.inst_results(call));
+        }
+        bytecode::Inst::FunCallIndirect(f, signature, xs) => {
+          let mut sig = cranelift::Signature::new(CALL_CONV);
+          for &ty in signature.inputs.iter() {
+            sig.params.push(cranelift::AbiParam::new(compile_valtype(ty)));
+          }
+          for &ty in signature.outputs.iter() {
+            sig.returns.push(cranelift::AbiParam::new(compile_valtype(ty)));
+          }
+          let sig = fb.import_signature(sig);
+          let f = vars[usize::from(f)];
+          let call = fb.ins().call_indirect(sig, f, &map_slice(xs, |&x| vars[usize::from(x)]));
+          vars.extend_from_slice(fb.inst_results(call));
+        }
         bytecode::Inst::Goto(a, xs) => {
           let a = a.0 as usize;
           while blocks.len() < a + 1 { blocks.push(fb.create_block()); }
@@ -145,9 +441,42 @@ pub fn compile<'a>(program: bytecode::Program<'a>) -> Box<[u8]> {
           vars.push(u)
         }
         bytecode::Inst::ImmI64(imm) => {
-          let u = fb.ins().iconst(cranelift::I8, imm as i64);
+          let u = fb.ins().iconst(cranelift::I64, imm as i64);
           vars.push(u)
         }
+        bytecode::Inst::Index(i, xs, tys) => {
+          let tys = map_slice(tys, |&ty| compile_valtype(ty));
+          let (offsets, stride) = array_layout(&tys);
+          let p =
+            if xs.is_empty() {
+              // Every index is out of bounds, so this is never reached.
+              fb.ins().iconst(cranelift::I64, 0)
+            } else if let Some(bytes) = array_bytes(xs, &tys, &imms) {
+              let table =
+                match tables.get(&bytes) {
+                  Some(&table) => table,
+                  None => {
+                    let table = object_module.declare_anonymous_data(false, false).unwrap();
+                    let mut data = cranelift::DataContext::new();
+                    data.define(bytes.clone());
+                    data.set_align(tys.iter().map(|ty| ty.bytes() as u64).max().unwrap());
+                    object_module.define_data(table, &data).unwrap();
+                    let _ = tables.insert(bytes, table);
+                    table
+                  }
+                };
+              let table = object_module.declare_data_in_func(table, fb.func);
+              fb.ins().global_value(cranelift::I64, table)
+            } else {
+              let key: Box<[u16]> = xs.iter().map(|x| x.0).collect();
+              fb.ins().stack_addr(cranelift::I64, slots[&key], 0)
+            };
+          let i = fb.ins().imul_imm(vars[usize::from(i)], stride);
+          let p = fb.ins().iadd(p, i);
+          for (&ty, &k) in tys.iter().zip(offsets.iter()) {
+            vars.push(fb.ins().load(ty, cranelift::MemFlags::trusted(), p, k));
+          }
+        }
         bytecode::Inst::Op11(tag, x) => {
           let x = vars[usize::from(x)];
           let u =
@@ -194,6 +523,24 @@ pub fn compile<'a>(program: bytecode::Program<'a>) -> Box<[u8]> {
                 fb.ins().icmp(cranelift::IntCC::NotEqual, x, y),
               Op21::BoolOr =>
                 fb.ins().bor(x, y),
+              Op21::I128Add =>
+                fb.ins().iadd(x, y),
+              Op21::I128IsEq =>
+                fb.ins().icmp(cranelift::IntCC::Equal, x, y),
+              Op21::I128IsGeS =>
+                fb.ins().icmp(cranelift::IntCC::SignedGreaterThanOrEqual, x, y),
+              Op21::I128IsGtS =>
+                fb.ins().icmp(cranelift::IntCC::SignedGreaterThan, x, y),
+              Op21::I128IsLeS =>
+                fb.ins().icmp(cranelift::IntCC::SignedLessThanOrEqual, x, y),
+              Op21::I128IsLtS =>
+                fb.ins().icmp(cranelift::IntCC::SignedLessThan, x, y),
+              Op21::I128IsNeq =>
+                fb.ins().icmp(cranelift::IntCC::NotEqual, x, y),
+              Op21::I128Mul =>
+                fb.ins().imul(x, y),
+              Op21::I128Sub =>
+                fb.ins().isub(x, y),
               Op21::I64Add =>
                 fb.ins().iadd(x, y),
               Op21::I64BitAnd =>
@@ -202,6 +549,8 @@ pub fn compile<'a>(program: bytecode::Program<'a>) -> Box<[u8]> {
                 fb.ins().bor(x, y),
               Op21::I64BitXor =>
                 fb.ins().bxor(x, y),
+              Op21::I64DivS =>
+                fb.ins().sdiv(x, y),
               Op21::I64IsEq =>
                 fb.ins().icmp(cranelift::IntCC::Equal, x, y),
               Op21::I64IsGeS =>
@@ -258,6 +607,21 @@ pub fn compile<'a>(program: bytecode::Program<'a>) -> Box<[u8]> {
                 fb.ins().ushr(x, y),
               Op21::I64Sub =>
                 fb.ins().isub(x, y),
+              Op21::I6IsEq | Op21::I6IsGe | Op21::I6IsGt | Op21::I6IsLe | Op21::I6IsLt | Op21::I6IsNeq => {
+                // clear the unspecified MSBs of both i6s
+                let x = fb.ins().band_imm(x, 0x3f);
+                let y = fb.ins().band_imm(y, 0x3f);
+                let cc =
+                  match tag {
+                    Op21::I6IsEq => cranelift::IntCC::Equal,
+                    Op21::I6IsGe => cranelift::IntCC::UnsignedGreaterThanOrEqual,
+                    Op21::I6IsGt => cranelift::IntCC::UnsignedGreaterThan,
+                    Op21::I6IsLe => cranelift::IntCC::UnsignedLessThanOrEqual,
+                    Op21::I6IsLt => cranelift::IntCC::UnsignedLessThan,
+                    _ => cranelift::IntCC::NotEqual,
+                  };
+                fb.ins().icmp(cc, x, y)
+              }
             };
           vars.push(u);
         }
@@ -277,6 +641,24 @@ pub fn compile<'a>(program: bytecode::Program<'a>) -> Box<[u8]> {
         bytecode::Inst::Ret(xs) => {
           let _: _ = fb.ins().return_(&map_slice(xs, |&x| vars[usize::from(x)]));
         }
+        bytecode::Inst::Switch(x, cases, c) => {
+          let mut n = c.0 as usize + 1;
+          for &(_, a) in cases.iter() { n = max(n, a.0 as usize + 1); }
+          while blocks.len() < n { blocks.push(fb.create_block()); }
+          let mut x = vars[usize::from(x)];
+          if fb.func.dfg.value_type(x) == cranelift::I8 {
+            // clear the unspecified MSBs of an i6, a no-op on a bool
+            x = fb.ins().band_imm(x, 0x3f);
+          }
+          let mut switch = cranelift::Switch::new();
+          for &(v, a) in cases.iter() {
+            switch.set_entry(v as u128, blocks[a.0 as usize]);
+          }
+          switch.emit(&mut fb, x, blocks[c.0 as usize]);
+        }
+        bytecode::Inst::Trap => {
+          let _: _ = fb.ins().trap(cranelift::TrapCode::HeapOutOfBounds);
+        }
         _ => unimplemented!()
       }
     }
@@ -284,10 +666,6 @@ pub fn compile<'a>(program: bytecode::Program<'a>) -> Box<[u8]> {
     fb.seal_all_blocks();
     fb.finalize();
 
-    let mut s = String::new();
-    cranelift::codegen::write::write_function(&mut s, &ctx.func).unwrap();
-    std::io::stdout().write_all(s.as_bytes()).unwrap();
-
     let func_id = func_ids[func_idx];
 
     let _: cranelift::ModuleCompiledFunction =
@@ -1,5 +1,28 @@
 use adelie;
+use std::process::ExitCode;
 
-fn main() {
-  adelie::go()
+fn main() -> ExitCode {
+  let args: Vec<String> = std::env::args().skip(1).collect();
+
+  match args.first().map(|x| x.as_str()) {
+    Some("build") => {
+      if adelie::build(&args[1 ..]) { ExitCode::SUCCESS } else { ExitCode::FAILURE }
+    }
+    Some("doc") => {
+      if adelie::doc(&args[1 ..]) { ExitCode::SUCCESS } else { ExitCode::FAILURE }
+    }
+    Some("dump") => {
+      if adelie::dump(&args[1 ..]) { ExitCode::SUCCESS } else { ExitCode::FAILURE }
+    }
+    Some("fmt") => {
+      if adelie::fmt(&args[1 ..]) { ExitCode::SUCCESS } else { ExitCode::FAILURE }
+    }
+    Some("highlight") => {
+      if adelie::highlight(&args[1 ..]) { ExitCode::SUCCESS } else { ExitCode::FAILURE }
+    }
+    _ => {
+      eprintln!("usage: adelie build [-A|-W|-D LINT]... FILE | adelie doc [--html] FILE | adelie dump FILE | adelie fmt [--check] FILE... | adelie highlight [--html] FILE");
+      ExitCode::FAILURE
+    }
+  }
 }
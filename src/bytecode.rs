@@ -18,6 +18,10 @@ pub struct Function<'a> {
   pub code: &'a [Inst<'a>],
 }
 
+#[derive(Clone, Copy, Eq, PartialEq)]
+#[repr(transparent)]
+pub struct FunId(pub u32);
+
 #[derive(Clone, Copy, Eq, PartialEq)]
 #[repr(transparent)]
 pub struct VarId(pub u16);
@@ -29,8 +33,18 @@ pub struct BlockId(pub u16);
 #[derive(Clone, Copy)]
 pub enum Inst<'a> {
   Block(&'a [Ty]),
-  FunCall,
-  FunCallIndirect,
+  // Stores the values in a new environment for a closure, and defines an i64
+  // that points to it.
+  EnvPack(&'a [VarId]),
+  // Loads the values of the given types from an environment.
+  EnvUnpack(VarId, &'a [Ty]),
+  // Defines an i64 that points to the code of a function of the program.
+  FunAddr(FunId),
+  // Calls a function of the program by its index, and defines a variable for
+  // each of its outputs, in order.
+  FunCall(FunId, &'a [VarId]),
+  // Calls the code that an i64 points to, which has the given signature.
+  FunCallIndirect(VarId, Signature<'a>, &'a [VarId]),
   FunTailCall,
   FunTailCallIndirect,
   Goto(BlockId, &'a [VarId]),
@@ -38,9 +52,15 @@ pub enum Inst<'a> {
   ImmBool(bool),
   ImmI6(u6),
   ImmI64(u64),
+  // Defines the values of an element of an array, which holds the variables
+  // in order, given the index of the element, which must be in bounds, and
+  // the types of the values of each element.
+  Index(VarId, &'a [VarId], &'a [Ty]),
   Op11(Op11, VarId),
   Op21(Op21, VarId, VarId),
   Ret(&'a [VarId]),
+  Switch(VarId, &'a [(u64, BlockId)], BlockId),
+  Trap,
 }
 
 impl From<VarId> for usize {
@@ -91,10 +111,20 @@ pub(crate) static TYPE_OP_21: &[([Ty; 2], [Ty; 1])] = &[
   /* BoolEq       */ ([Bool, Bool], [Bool]),
   /* BoolNeq      */ ([Bool, Bool], [Bool]),
   /* BoolOr       */ ([Bool, Bool], [Bool]),
+  /* I128Add      */ ([I128, I128], [I128]),
+  /* I128IsEq     */ ([I128, I128], [Bool]),
+  /* I128IsGeS    */ ([I128, I128], [Bool]),
+  /* I128IsGtS    */ ([I128, I128], [Bool]),
+  /* I128IsLeS    */ ([I128, I128], [Bool]),
+  /* I128IsLtS    */ ([I128, I128], [Bool]),
+  /* I128IsNeq    */ ([I128, I128], [Bool]),
+  /* I128Mul      */ ([I128, I128], [I128]),
+  /* I128Sub      */ ([I128, I128], [I128]),
   /* I64Add       */ ([I64, I64], [I64]),
   /* I64BitAnd    */ ([I64, I64], [I64]),
   /* I64BitOr     */ ([I64, I64], [I64]),
   /* I64BitXor    */ ([I64, I64], [I64]),
+  /* I64DivS      */ ([I64, I64], [I64]),
   /* I64IsEq      */ ([I64, I64], [Bool]),
   /* I64IsGeS     */ ([I64, I64], [Bool]),
   /* I64IsGeU     */ ([I64, I64], [Bool]),
@@ -120,4 +150,10 @@ pub(crate) static TYPE_OP_21: &[([Ty; 2], [Ty; 1])] = &[
   /* I64ShrS      */ ([I64, I6], [I64]),
   /* I64ShrU      */ ([I64, I6], [I64]),
   /* I64Sub       */ ([I64, I64], [I64]),
+  /* I6IsEq       */ ([I6, I6], [Bool]),
+  /* I6IsGe       */ ([I6, I6], [Bool]),
+  /* I6IsGt       */ ([I6, I6], [Bool]),
+  /* I6IsLe       */ ([I6, I6], [Bool]),
+  /* I6IsLt       */ ([I6, I6], [Bool]),
+  /* I6IsNeq      */ ([I6, I6], [Bool]),
 ];
@@ -2,7 +2,13 @@ use crate::prelude::*;
 
 #[derive(Clone, Copy)]
 pub enum AstItem<'a> {
+  ConstDef(&'a AstConstDef<'a>),
   FunDef(&'a AstFunDef<'a>),
+  Import(&'a AstImport<'a>),
+  MacroDef(&'a AstMacroDef<'a>),
+  Module(&'a AstModule<'a>),
+  StaticAssert(&'a AstStaticAssert<'a>),
+  StructDef(&'a AstStructDef<'a>),
 }
 
 #[derive(Clone, Copy)]
@@ -15,13 +21,47 @@ pub enum AstStmt<'a> {
 
 #[derive(Clone, Copy)]
 pub enum AstExpr<'a> {
+  Array(&'a AstArray<'a>),
+  ArrayRepeat(&'a AstArrayRepeat<'a>),
+  Bool(&'a AstBool),
+  Closure(&'a AstClosure<'a>),
+  Field(&'a AstField<'a>),
   FunCall(&'a AstFunCall<'a>),
   If(&'a AstIf<'a>),
+  Index(&'a AstIndex<'a>),
+  Lambda(&'a AstLambda<'a>),
   Loop(&'a AstLoop<'a>),
+  Match(&'a AstMatch<'a>),
+  MethodCall(&'a AstMethodCall<'a>),
   Number(&'a AstNumber<'a>),
   OpCall1(&'a AstOpCall<'a, 1>),
   OpCall2(&'a AstOpCall<'a, 2>),
-  Symbol(&'a AstSymbol<'a>),
+  Pipeline(&'a AstPipeline<'a>),
+  Record(&'a AstRecord<'a>),
+  Symbol(&'a AstSymbol),
+  Tuple(&'a AstTuple<'a>),
+  Unquote(&'a AstSymbol),
+}
+
+#[derive(Clone, Copy)]
+pub enum AstPat<'a> {
+  Symbol(&'a AstSymbol),
+  Tuple(&'a AstTuplePat<'a>),
+}
+
+#[derive(Clone, Copy)]
+pub enum AstType<'a> {
+  Array(&'a AstArrayType<'a>),
+  Symbol(&'a AstSymbol),
+  Tuple(&'a AstTupleType<'a>),
+}
+
+#[derive(Clone, Copy)]
+pub enum AstMatchPat<'a> {
+  Bool(&'a AstBool),
+  Number(&'a AstNumber<'a>),
+  Range(&'a AstRange<'a>),
+  Wildcard,
 }
 
 #[derive(Clone, Copy)]
@@ -43,189 +83,550 @@ pub enum AstOp {
   LE,
 }
 
+// `const x = e` names the value of `e`, which is computed when the program is
+// compiled.
+
+#[derive(Clone, Copy)]
+pub struct AstConstDef<'a> {
+  pub is_pub: bool,
+  pub name: AstSymbol,
+  pub ty: Option<AstType<'a>>,
+  pub value: AstExpr<'a>,
+  pub id: NodeId,
+}
+
 #[derive(Clone, Copy)]
 pub struct AstFunDef<'a> {
-  pub name: AstSymbol<'a>,
-  pub params: &'a [AstSymbol<'a>],
+  pub is_pub: bool,
+  pub name: AstSymbol,
+  pub params: &'a [AstParam<'a>],
+  pub result: Option<AstType<'a>>,
+  pub body: &'a [AstStmt<'a>],
+  pub id: NodeId,
+}
+
+// `import a.b` makes module `a.b` visible as `b`.
+
+#[derive(Clone, Copy)]
+pub struct AstImport<'a>(pub &'a [AstSymbol], pub NodeId);
+
+// `macro m(x) ... end` is a template that `m(e)` expands into, with `$x`
+// replaced by `e`.
+
+#[derive(Clone, Copy)]
+pub struct AstMacroDef<'a> {
+  pub name: AstSymbol,
+  pub params: &'a [AstSymbol],
   pub body: &'a [AstStmt<'a>],
+  pub span: Span,
+  pub id: NodeId,
 }
 
 #[derive(Clone, Copy)]
-pub struct AstBreak<'a>(pub &'a [AstExpr<'a>]);
+pub struct AstModule<'a> {
+  pub name: AstSymbol,
+  pub items: &'a [AstItem<'a>],
+  pub id: NodeId,
+}
+
+// `static_assert(e)` fails compilation unless `e` is true.
 
 #[derive(Clone, Copy)]
-pub struct AstExprSeq<'a>(pub &'a [AstExpr<'a>]);
+pub struct AstStaticAssert<'a>(pub AstExpr<'a>, pub NodeId);
 
 #[derive(Clone, Copy)]
-pub struct AstLet<'a>(pub &'a [AstSymbol<'a>], pub &'a [AstExpr<'a>]);
+pub struct AstStructDef<'a> {
+  pub is_pub: bool,
+  pub name: AstSymbol,
+  pub fields: &'a [AstStructField<'a>],
+  pub id: NodeId,
+}
 
 #[derive(Clone, Copy)]
-pub struct AstReturn<'a>(pub &'a [AstExpr<'a>]);
+pub struct AstStructField<'a> {
+  pub name: AstSymbol,
+  pub ty: AstType<'a>,
+}
 
 #[derive(Clone, Copy)]
-pub struct AstFunCall<'a>(pub AstExpr<'a>, pub &'a [AstExpr<'a>]);
+pub struct AstParam<'a> {
+  pub pat: AstPat<'a>,
+  pub ty: Option<AstType<'a>>,
+}
 
 #[derive(Clone, Copy)]
-pub struct AstIf<'a>(pub AstExpr<'a>, pub &'a [AstStmt<'a>], pub &'a [AstStmt<'a>]);
+pub struct AstBreak<'a>(pub &'a [AstExpr<'a>], pub NodeId);
 
 #[derive(Clone, Copy)]
-pub struct AstLoop<'a>(pub &'a [AstStmt<'a>]);
+pub struct AstExprSeq<'a>(pub &'a [AstExpr<'a>], pub NodeId);
 
 #[derive(Clone, Copy)]
-pub struct AstNumber<'a>(pub &'a str);
+pub struct AstLet<'a>(pub &'a [AstPat<'a>], pub &'a [AstExpr<'a>], pub NodeId);
 
 #[derive(Clone, Copy)]
-pub struct AstOpCall<'a, const N: usize>(pub AstOp, pub [AstExpr<'a>; N]);
+pub struct AstReturn<'a>(pub &'a [AstExpr<'a>], pub NodeId);
 
 #[derive(Clone, Copy)]
-pub struct AstSymbol<'a>(pub &'a str);
+pub struct AstArray<'a>(pub &'a [AstExpr<'a>], pub NodeId);
 
-fn sexp_list<T>(list: &[T]) -> Sexp
-where
-  T: ToSexp
-{
-  let mut a = Vec::new();
-  for x in list.iter() {
-    a.push(x.to_sexp())
-  }
-  Sexp::List(a.into_boxed_slice())
+// `[x; n]`, an array of `n` copies of `x`.
+
+#[derive(Clone, Copy)]
+pub struct AstArrayRepeat<'a>(pub AstExpr<'a>, pub AstNumber<'a>, pub NodeId);
+
+#[derive(Clone, Copy)]
+pub struct AstArrayType<'a>(pub AstType<'a>, pub AstNumber<'a>);
+
+#[derive(Clone, Copy)]
+pub struct AstBool(pub bool, pub Span, pub NodeId);
+
+// A closure after closure conversion. The code is a lifted function item
+// whose leading parameters receive the environment, so a call `f(x)` of a
+// closure `f` is a call of the code with the environment values prepended to
+// the arguments.
+
+#[derive(Clone, Copy)]
+pub struct AstClosure<'a>(pub AstSymbol, pub &'a [AstExpr<'a>], pub NodeId);
+
+#[derive(Clone, Copy)]
+pub struct AstField<'a>(pub AstExpr<'a>, pub AstSymbol, pub NodeId);
+
+#[derive(Clone, Copy)]
+pub struct AstFunCall<'a>(pub AstExpr<'a>, pub &'a [AstExpr<'a>], pub NodeId);
+
+#[derive(Clone, Copy)]
+pub struct AstIf<'a>(pub AstExpr<'a>, pub &'a [AstStmt<'a>], pub &'a [AstStmt<'a>], pub NodeId);
+
+// Indexing is bounds checked, and an index that is out of bounds traps.
+
+#[derive(Clone, Copy)]
+pub struct AstIndex<'a>(pub AstExpr<'a>, pub AstExpr<'a>, pub NodeId);
+
+#[derive(Clone, Copy)]
+pub struct AstLambda<'a> {
+  pub params: &'a [AstParam<'a>],
+  pub result: Option<AstType<'a>>,
+  pub body: &'a [AstStmt<'a>],
+  pub id: NodeId,
 }
 
-fn sexp_head_and_body<T>(head: Sexp, body: &[T]) -> Sexp
-where
-  T: ToSexp
-{
-  let mut a = Vec::new();
-  a.push(head);
-  for x in body.iter() {
-    a.push(x.to_sexp())
-  }
-  Sexp::List(a.into_boxed_slice())
+#[derive(Clone, Copy)]
+pub struct AstLoop<'a>(pub &'a [AstStmt<'a>], pub NodeId);
+
+#[derive(Clone, Copy)]
+pub struct AstMatch<'a> {
+  pub expr: AstExpr<'a>,
+  pub arms: &'a [AstMatchArm<'a>],
+  pub span: Span,
+  pub id: NodeId,
 }
 
-impl<'a> ToSexp for AstItem<'a> {
-  fn to_sexp(&self) -> Sexp {
-    match self {
-      Self::FunDef(x) => x.to_sexp(),
+// An arm matches if any of its patterns do.
+
+#[derive(Clone, Copy)]
+pub struct AstMatchArm<'a> {
+  pub pats: &'a [AstMatchPat<'a>],
+  pub body: &'a [AstStmt<'a>],
+  pub span: Span,
+}
+
+// `x.f(y)`, which is sugar for `f(x, y)` unless `x` names a module.
+
+#[derive(Clone, Copy)]
+pub struct AstMethodCall<'a>(pub AstExpr<'a>, pub AstSymbol, pub &'a [AstExpr<'a>], pub NodeId);
+
+#[derive(Clone, Copy)]
+pub struct AstNumber<'a>(pub &'a str, pub Span, pub NodeId);
+
+#[derive(Clone, Copy)]
+pub struct AstOpCall<'a, const N: usize>(pub AstOp, pub [AstExpr<'a>; N], pub NodeId);
+
+// `x |> f(y)`
+
+#[derive(Clone, Copy)]
+pub struct AstPipeline<'a>(pub AstExpr<'a>, pub AstExpr<'a>, pub NodeId);
+
+// `{ x = 1, y = 2 }`, whose struct is the one with exactly those fields.
+
+#[derive(Clone, Copy)]
+pub struct AstRecord<'a>(pub &'a [(AstSymbol, AstExpr<'a>)], pub NodeId);
+
+// An inclusive range of values, `lo .. hi`.
+
+#[derive(Clone, Copy)]
+pub struct AstRange<'a>(pub AstNumber<'a>, pub AstNumber<'a>);
+
+#[derive(Clone, Copy, Debug)]
+pub struct AstSymbol(pub Symbol, pub Span, pub NodeId);
+
+#[derive(Clone, Copy)]
+pub struct AstTuple<'a>(pub &'a [AstExpr<'a>], pub NodeId);
+
+#[derive(Clone, Copy)]
+pub struct AstTuplePat<'a>(pub &'a [AstPat<'a>]);
+
+#[derive(Clone, Copy)]
+pub struct AstTupleType<'a>(pub &'a [AstType<'a>]);
+
+// WRITING SEXPS
+//
+// A visitor that builds the sexp of each node on a stack of unfinished lists.
+
+struct SexpBuilder {
+  stack: Vec<Vec<Sexp>>,
+}
+
+impl SexpBuilder {
+  fn build(f: impl FnOnce(&mut Self)) -> Sexp {
+    let mut b = Self { stack: vec![Vec::new()] };
+    f(&mut b);
+    let mut a = b.stack.pop().unwrap();
+    a.pop().unwrap()
+  }
+
+  fn atom(&mut self, a: &str) {
+    self.stack.last_mut().unwrap().push(Sexp::from_atom(a));
+  }
+
+  fn open(&mut self, head: &str) {
+    self.stack.push(vec![Sexp::from_atom(head)]);
+  }
+
+  fn open_list(&mut self) {
+    self.stack.push(Vec::new());
+  }
+
+  fn close(&mut self) {
+    let a = self.stack.pop().unwrap();
+    self.stack.last_mut().unwrap().push(Sexp::List(a.into_boxed_slice()));
+  }
+
+  fn open_pub(&mut self, is_pub: bool, head: &str) {
+    if is_pub { self.open("pub") }
+    self.open(head);
+  }
+
+  fn close_pub(&mut self, is_pub: bool) {
+    self.close();
+    if is_pub { self.close() }
+  }
+
+  // The parameters, result type, and body of `fundef` and `fun` forms.
+
+  fn params_and_result<'a>(&mut self, params: &'a [AstParam<'a>], result: &'a Option<AstType<'a>>, body: &'a [AstStmt<'a>]) {
+    self.open_list();
+    for x in params.iter() { self.visit_param(x) }
+    self.close();
+    if let Some(ty) = result {
+      self.open(":");
+      self.visit_type(ty);
+      self.close();
     }
+    for x in body.iter() { self.visit_stmt(x) }
   }
 }
 
-impl<'a> ToSexp for AstStmt<'a> {
-  fn to_sexp(&self) -> Sexp {
-    match self {
-      Self::Break(x) => x.to_sexp(),
-      Self::ExprSeq(x) => x.to_sexp(),
-      Self::Let(x) => x.to_sexp(),
-      Self::Return(x) => x.to_sexp(),
+impl<'a> Visitor<'a> for SexpBuilder {
+  fn visit_const_def(&mut self, x: &'a AstConstDef<'a>) {
+    self.open_pub(x.is_pub, "const");
+    self.visit_symbol(&x.name);
+    if let Some(ty) = &x.ty {
+      self.open(":");
+      self.visit_type(ty);
+      self.close();
     }
+    self.visit_expr(&x.value);
+    self.close_pub(x.is_pub);
   }
-}
 
-impl<'a> ToSexp for AstExpr<'a> {
-  fn to_sexp(&self) -> Sexp {
-    match self {
-      Self::FunCall(x) => x.to_sexp(),
-      Self::If(x) => x.to_sexp(),
-      Self::Loop(x) => x.to_sexp(),
-      Self::Number(x) => x.to_sexp(),
-      Self::OpCall1(x) => x.to_sexp(),
-      Self::OpCall2(x) => x.to_sexp(),
-      Self::Symbol(x) => x.to_sexp(),
+  fn visit_fun_def(&mut self, x: &'a AstFunDef<'a>) {
+    self.open_pub(x.is_pub, "fundef");
+    self.visit_symbol(&x.name);
+    self.params_and_result(x.params, &x.result, x.body);
+    self.close_pub(x.is_pub);
+  }
+
+  fn visit_import(&mut self, x: &'a AstImport<'a>) {
+    self.open("import");
+    walk_import(self, x);
+    self.close();
+  }
+
+  fn visit_macro_def(&mut self, x: &'a AstMacroDef<'a>) {
+    self.open("macro");
+    self.visit_symbol(&x.name);
+    self.open_list();
+    for y in x.params.iter() { self.visit_symbol(y) }
+    self.close();
+    for y in x.body.iter() { self.visit_stmt(y) }
+    self.close();
+  }
+
+  fn visit_module(&mut self, x: &'a AstModule<'a>) {
+    self.open("module");
+    walk_module(self, x);
+    self.close();
+  }
+
+  fn visit_static_assert(&mut self, x: &'a AstStaticAssert<'a>) {
+    self.open("static_assert");
+    walk_static_assert(self, x);
+    self.close();
+  }
+
+  fn visit_struct_def(&mut self, x: &'a AstStructDef<'a>) {
+    self.open_pub(x.is_pub, "struct");
+    walk_struct_def(self, x);
+    self.close_pub(x.is_pub);
+  }
+
+  fn visit_struct_field(&mut self, x: &'a AstStructField<'a>) {
+    self.open(":");
+    walk_struct_field(self, x);
+    self.close();
+  }
+
+  fn visit_param(&mut self, x: &'a AstParam<'a>) {
+    match x.ty {
+      None => self.visit_pat(&x.pat),
+      Some(_) => {
+        self.open(":");
+        walk_param(self, x);
+        self.close();
+      }
     }
   }
-}
 
-impl<'a> ToSexp for AstFunDef<'a> {
-  fn to_sexp(&self) -> Sexp {
-    let mut a = Vec::new();
-    a.push(Sexp::from_atom("fundef"));
-    a.push(self.name.to_sexp());
-    a.push(sexp_list(self.params));
-    for stmt in self.body.iter() {
-      a.push(stmt.to_sexp())
+  fn visit_break(&mut self, x: &'a AstBreak<'a>) {
+    self.open("break");
+    walk_break(self, x);
+    self.close();
+  }
+
+  fn visit_expr_seq(&mut self, x: &'a AstExprSeq<'a>) {
+    self.open("exprseq");
+    walk_expr_seq(self, x);
+    self.close();
+  }
+
+  fn visit_let(&mut self, x: &'a AstLet<'a>) {
+    self.open("let");
+    for y in x.0.iter() { self.visit_pat(y) }
+    self.atom("=");
+    for y in x.1.iter() { self.visit_expr(y) }
+    self.close();
+  }
+
+  fn visit_return(&mut self, x: &'a AstReturn<'a>) {
+    self.open("return");
+    walk_return(self, x);
+    self.close();
+  }
+
+  fn visit_array(&mut self, x: &'a AstArray<'a>) {
+    self.open("array");
+    walk_array(self, x);
+    self.close();
+  }
+
+  fn visit_array_repeat(&mut self, x: &'a AstArrayRepeat<'a>) {
+    self.open("repeat");
+    walk_array_repeat(self, x);
+    self.close();
+  }
+
+  fn visit_bool(&mut self, x: &'a AstBool) {
+    self.atom(if x.0 { "true" } else { "false" });
+  }
+
+  fn visit_closure(&mut self, x: &'a AstClosure<'a>) {
+    self.open("closure");
+    walk_closure(self, x);
+    self.close();
+  }
+
+  fn visit_field(&mut self, x: &'a AstField<'a>) {
+    self.open(".");
+    walk_field(self, x);
+    self.close();
+  }
+
+  fn visit_fun_call(&mut self, x: &'a AstFunCall<'a>) {
+    self.open("call");
+    walk_fun_call(self, x);
+    self.close();
+  }
+
+  fn visit_if(&mut self, x: &'a AstIf<'a>) {
+    self.open("if");
+    self.visit_expr(&x.0);
+    self.open("then");
+    for y in x.1.iter() { self.visit_stmt(y) }
+    self.close();
+    self.open("else");
+    for y in x.2.iter() { self.visit_stmt(y) }
+    self.close();
+    self.close();
+  }
+
+  fn visit_index(&mut self, x: &'a AstIndex<'a>) {
+    self.open("index");
+    walk_index(self, x);
+    self.close();
+  }
+
+  fn visit_lambda(&mut self, x: &'a AstLambda<'a>) {
+    self.open("fun");
+    self.params_and_result(x.params, &x.result, x.body);
+    self.close();
+  }
+
+  fn visit_loop(&mut self, x: &'a AstLoop<'a>) {
+    self.open("loop");
+    walk_loop(self, x);
+    self.close();
+  }
+
+  fn visit_match(&mut self, x: &'a AstMatch<'a>) {
+    self.open("match");
+    walk_match(self, x);
+    self.close();
+  }
+
+  fn visit_match_arm(&mut self, x: &'a AstMatchArm<'a>) {
+    self.open("case");
+    self.open_list();
+    for y in x.pats.iter() { self.visit_match_pat(y) }
+    self.close();
+    for y in x.body.iter() { self.visit_stmt(y) }
+    self.close();
+  }
+
+  fn visit_method_call(&mut self, x: &'a AstMethodCall<'a>) {
+    self.open("method");
+    walk_method_call(self, x);
+    self.close();
+  }
+
+  fn visit_number(&mut self, x: &'a AstNumber<'a>) {
+    self.atom(x.0);
+  }
+
+  fn visit_op_call1(&mut self, x: &'a AstOpCall<'a, 1>) {
+    self.open(x.0.to_name());
+    walk_op_call(self, x);
+    self.close();
+  }
+
+  fn visit_op_call2(&mut self, x: &'a AstOpCall<'a, 2>) {
+    self.open(x.0.to_name());
+    walk_op_call(self, x);
+    self.close();
+  }
+
+  fn visit_pipeline(&mut self, x: &'a AstPipeline<'a>) {
+    self.open("|>");
+    walk_pipeline(self, x);
+    self.close();
+  }
+
+  fn visit_record(&mut self, x: &'a AstRecord<'a>) {
+    self.open("record");
+    for (y, z) in x.0.iter() {
+      self.open("=");
+      self.visit_symbol(y);
+      self.visit_expr(z);
+      self.close();
     }
-    Sexp::List(a.into_boxed_slice())
+    self.close();
   }
-}
 
-impl<'a> ToSexp for AstBreak<'a> {
-  fn to_sexp(&self) -> Sexp {
-    sexp_head_and_body(Sexp::from_atom("break"), self.0)
+  fn visit_symbol(&mut self, x: &'a AstSymbol) {
+    self.atom(x.0.as_str());
   }
-}
 
-impl<'a> ToSexp for AstLet<'a> {
-  fn to_sexp(&self) -> Sexp {
-    let mut a = Vec::new();
-    a.push(Sexp::from_atom("let"));
-    for x in self.0.iter() { a.push(x.to_sexp()) }
-    a.push(Sexp::from_atom("="));
-    for x in self.1.iter() { a.push(x.to_sexp()) }
-    Sexp::List(a.into_boxed_slice())
+  fn visit_tuple(&mut self, x: &'a AstTuple<'a>) {
+    self.open("tuple");
+    walk_tuple(self, x);
+    self.close();
   }
-}
 
-impl<'a> ToSexp for AstReturn<'a> {
-  fn to_sexp(&self) -> Sexp {
-    sexp_head_and_body(Sexp::from_atom("return"), self.0)
+  fn visit_unquote(&mut self, x: &'a AstSymbol) {
+    self.open("unquote");
+    self.visit_symbol(x);
+    self.close();
   }
-}
 
-impl<'a> ToSexp for AstExprSeq<'a> {
-  fn to_sexp(&self) -> Sexp {
-    sexp_head_and_body(Sexp::from_atom("exprseq"), self.0)
+  fn visit_tuple_pat(&mut self, x: &'a AstTuplePat<'a>) {
+    self.open("tuple");
+    walk_tuple_pat(self, x);
+    self.close();
+  }
+
+  fn visit_array_type(&mut self, x: &'a AstArrayType<'a>) {
+    self.open("array");
+    walk_array_type(self, x);
+    self.close();
+  }
+
+  fn visit_tuple_type(&mut self, x: &'a AstTupleType<'a>) {
+    self.open("tuple");
+    walk_tuple_type(self, x);
+    self.close();
+  }
+
+  fn visit_match_pat(&mut self, x: &'a AstMatchPat<'a>) {
+    match x {
+      AstMatchPat::Wildcard => self.atom("_"),
+      _ => walk_match_pat(self, x),
+    }
+  }
+
+  fn visit_range(&mut self, x: &'a AstRange<'a>) {
+    self.open("..");
+    walk_range(self, x);
+    self.close();
   }
 }
 
-impl<'a> ToSexp for AstFunCall<'a> {
+impl<'a> ToSexp for AstItem<'a> {
   fn to_sexp(&self) -> Sexp {
-    sexp_head_and_body(self.0.to_sexp(), self.1)
+    SexpBuilder::build(|b| b.visit_item(self))
   }
 }
 
-impl<'a> ToSexp for AstIf<'a> {
+impl<'a> ToSexp for AstStmt<'a> {
   fn to_sexp(&self) -> Sexp {
-    Sexp::List(
-      Box::new([
-        Sexp::from_atom("if"),
-        self.0.to_sexp(),
-        sexp_head_and_body(Sexp::from_atom("then"), self.1),
-        sexp_head_and_body(Sexp::from_atom("else"), self.2),
-      ])
-    )
+    SexpBuilder::build(|b| b.visit_stmt(self))
   }
 }
 
-impl<'a> ToSexp for AstLoop<'a> {
+impl<'a> ToSexp for AstExpr<'a> {
   fn to_sexp(&self) -> Sexp {
-    let mut a = Vec::new();
-    a.push(Sexp::from_atom("loop"));
-    for x in self.0.iter() { a.push(x.to_sexp()) }
-    Sexp::List(a.into_boxed_slice())
+    SexpBuilder::build(|b| b.visit_expr(self))
   }
 }
 
-impl<'a> ToSexp for AstNumber<'a> {
+impl<'a> ToSexp for AstPat<'a> {
   fn to_sexp(&self) -> Sexp {
-    Sexp::from_atom(self.0)
+    SexpBuilder::build(|b| b.visit_pat(self))
   }
 }
 
-impl<'a, const N: usize> ToSexp for AstOpCall<'a, N> {
+impl<'a> ToSexp for AstType<'a> {
   fn to_sexp(&self) -> Sexp {
-    sexp_head_and_body(Sexp::from_atom(self.0.to_name()), &self.1)
+    SexpBuilder::build(|b| b.visit_type(self))
   }
 }
 
-impl<'a> ToSexp for AstSymbol<'a> {
+impl<'a> ToSexp for AstMatchPat<'a> {
   fn to_sexp(&self) -> Sexp {
-    Sexp::from_atom(self.0)
+    SexpBuilder::build(|b| b.visit_match_pat(self))
   }
 }
 
 impl AstOp {
-  fn to_name(self) -> &'static str {
+  pub fn to_name(self) -> &'static str {
     match self {
       Self::Add => "+",
       Self::And => "&",
@@ -244,4 +645,678 @@ impl AstOp {
       Self::LE => "<=",
     }
   }
+
+  fn from_name(name: &str) -> Option<Self> {
+    let op =
+      match name {
+        "+" => Self::Add,
+        "&" => Self::And,
+        "/" => Self::Div,
+        "*" => Self::Mul,
+        "-/neg" => Self::Neg,
+        "!" => Self::Not,
+        "|" => Self::Or,
+        "-" => Self::Sub,
+        "^" => Self::Xor,
+        "==" => Self::EQ,
+        "!=" => Self::NE,
+        ">" => Self::GT,
+        ">=" => Self::GE,
+        "<" => Self::LT,
+        "<=" => Self::LE,
+        _ => return None,
+      };
+    Some(op)
+  }
+}
+
+// READING SEXPS
+//
+// Rebuilds the AST from the sexps that `ToSexp` makes. Spans aren't in the
+// sexps, so nodes that carry one get an empty span.
+
+fn bad_form<T>(x: &Sexp) -> Result<T, SexpError> {
+  Err(SexpError::Form(x.to_string()))
+}
+
+fn is_number(a: &str) -> bool {
+  let a = a.strip_prefix(|c| c == '+' || c == '-').unwrap_or(a);
+  a.starts_with(|c: char| c.is_ascii_digit())
+}
+
+fn from_sexp_seq<'a, T>(xs: &[Sexp], aa: &mut Allocator<'a>) -> Result<&'a [T], SexpError>
+where
+  T: FromSexp<'a> + Copy
+{
+  let mut a = Vec::new();
+  for x in xs.iter() { a.push(T::from_sexp(x, aa)?) }
+  Ok(aa.copy_slice(a.as_slice()))
+}
+
+fn from_sexp_list<'a, T>(x: &Sexp, aa: &mut Allocator<'a>) -> Result<&'a [T], SexpError>
+where
+  T: FromSexp<'a> + Copy
+{
+  match x.as_list() {
+    Some(xs) => from_sexp_seq(xs, aa),
+    None => bad_form(x),
+  }
+}
+
+// The parameters, result type, and body of `fundef` and `fun` forms.
+
+type ParamsAndResult<'a> = (&'a [AstParam<'a>], Option<AstType<'a>>, &'a [AstStmt<'a>]);
+
+fn from_sexp_params_and_result<'a>(x: &Sexp, xs: &[Sexp], aa: &mut Allocator<'a>) -> Result<ParamsAndResult<'a>, SexpError> {
+  let (params, rest) =
+    match xs.split_first() {
+      Some((y, rest)) => (from_sexp_list(y, aa)?, rest),
+      None => return bad_form(x),
+    };
+  let (result, rest) =
+    match rest.split_first() {
+      Some((y, body)) if matches!(y.as_form(), Some((":", [_]))) => {
+        (Some(AstType::from_sexp(&y.as_list().unwrap()[1], aa)?), body)
+      }
+      _ => {
+        (None, rest)
+      }
+    };
+  let body = from_sexp_seq(rest, aa)?;
+  Ok((params, result, body))
+}
+
+impl<'a> FromSexp<'a> for AstSymbol {
+  fn from_sexp(x: &Sexp, _: &mut Allocator<'a>) -> Result<Self, SexpError> {
+    match x.as_atom() {
+      Some(a) => Ok(AstSymbol(Symbol::intern(a), Span { file: 0, start: 0, stop: 0 }, NodeId::fresh())),
+      None => bad_form(x),
+    }
+  }
+}
+
+impl<'a> FromSexp<'a> for AstNumber<'a> {
+  fn from_sexp(x: &Sexp, aa: &mut Allocator<'a>) -> Result<Self, SexpError> {
+    match x.as_atom() {
+      Some(a) if is_number(a) => Ok(AstNumber(aa.copy_str(a), Span { file: 0, start: 0, stop: 0 }, NodeId::fresh())),
+      _ => bad_form(x),
+    }
+  }
+}
+
+impl<'a> FromSexp<'a> for AstItem<'a> {
+  fn from_sexp(x: &Sexp, aa: &mut Allocator<'a>) -> Result<Self, SexpError> {
+    let item =
+      match x.as_form() {
+        Some(("const", [name, ty, value])) if matches!(ty.as_form(), Some((":", [_]))) => {
+          let name = AstSymbol::from_sexp(name, aa)?;
+          let ty = Some(AstType::from_sexp(&ty.as_list().unwrap()[1], aa)?);
+          let value = AstExpr::from_sexp(value, aa)?;
+          AstItem::ConstDef(aa.alloc().init(AstConstDef { is_pub: false, name, ty, value, id: NodeId::fresh() }))
+        }
+        Some(("const", [name, value])) => {
+          let name = AstSymbol::from_sexp(name, aa)?;
+          let value = AstExpr::from_sexp(value, aa)?;
+          AstItem::ConstDef(aa.alloc().init(AstConstDef { is_pub: false, name, ty: None, value, id: NodeId::fresh() }))
+        }
+        Some(("fundef", [name, rest @ ..])) => {
+          let name = AstSymbol::from_sexp(name, aa)?;
+          let (params, result, body) = from_sexp_params_and_result(x, rest, aa)?;
+          AstItem::FunDef(aa.alloc().init(AstFunDef { is_pub: false, name, params, result, body, id: NodeId::fresh() }))
+        }
+        Some(("import", path)) => {
+          AstItem::Import(aa.alloc().init(AstImport(from_sexp_seq(path, aa)?, NodeId::fresh())))
+        }
+        Some(("macro", [name, params, body @ ..])) => {
+          let name = AstSymbol::from_sexp(name, aa)?;
+          let params = from_sexp_list(params, aa)?;
+          let body = from_sexp_seq(body, aa)?;
+          let span = Span { file: 0, start: 0, stop: 0 };
+          AstItem::MacroDef(aa.alloc().init(AstMacroDef { name, params, body, span, id: NodeId::fresh() }))
+        }
+        Some(("module", [name, items @ ..])) => {
+          let name = AstSymbol::from_sexp(name, aa)?;
+          let items = from_sexp_seq(items, aa)?;
+          AstItem::Module(aa.alloc().init(AstModule { name, items, id: NodeId::fresh() }))
+        }
+        Some(("pub", [y])) => {
+          match AstItem::from_sexp(y, aa)? {
+            AstItem::ConstDef(z) => AstItem::ConstDef(aa.alloc().init(AstConstDef { is_pub: true, ..*z })),
+            AstItem::FunDef(z) => AstItem::FunDef(aa.alloc().init(AstFunDef { is_pub: true, ..*z })),
+            AstItem::StructDef(z) => AstItem::StructDef(aa.alloc().init(AstStructDef { is_pub: true, ..*z })),
+            _ => return bad_form(x),
+          }
+        }
+        Some(("static_assert", [y])) => {
+          AstItem::StaticAssert(aa.alloc().init(AstStaticAssert(AstExpr::from_sexp(y, aa)?, NodeId::fresh())))
+        }
+        Some(("struct", [name, fields @ ..])) => {
+          let name = AstSymbol::from_sexp(name, aa)?;
+          let mut a = Vec::new();
+          for y in fields.iter() {
+            match y.as_form() {
+              Some((":", [f, ty])) => {
+                let f = AstSymbol::from_sexp(f, aa)?;
+                let ty = AstType::from_sexp(ty, aa)?;
+                a.push(AstStructField { name: f, ty })
+              }
+              _ => return bad_form(y),
+            }
+          }
+          let fields = aa.copy_slice(a.as_slice());
+          AstItem::StructDef(aa.alloc().init(AstStructDef { is_pub: false, name, fields, id: NodeId::fresh() }))
+        }
+        _ => {
+          return bad_form(x);
+        }
+      };
+    Ok(item)
+  }
+}
+
+impl<'a> FromSexp<'a> for AstStmt<'a> {
+  fn from_sexp(x: &Sexp, aa: &mut Allocator<'a>) -> Result<Self, SexpError> {
+    let stmt =
+      match x.as_form() {
+        Some(("break", y)) => {
+          AstStmt::Break(aa.alloc().init(AstBreak(from_sexp_seq(y, aa)?, NodeId::fresh())))
+        }
+        Some(("exprseq", y)) => {
+          AstStmt::ExprSeq(aa.alloc().init(AstExprSeq(from_sexp_seq(y, aa)?, NodeId::fresh())))
+        }
+        Some(("let", y)) => {
+          let i =
+            match y.iter().position(|z| z.as_atom() == Some("=")) {
+              Some(i) => i,
+              None => return bad_form(x),
+            };
+          let pats = from_sexp_seq(&y[.. i], aa)?;
+          let exprs = from_sexp_seq(&y[i + 1 ..], aa)?;
+          AstStmt::Let(aa.alloc().init(AstLet(pats, exprs, NodeId::fresh())))
+        }
+        Some(("return", y)) => {
+          AstStmt::Return(aa.alloc().init(AstReturn(from_sexp_seq(y, aa)?, NodeId::fresh())))
+        }
+        _ => {
+          return bad_form(x);
+        }
+      };
+    Ok(stmt)
+  }
+}
+
+impl<'a> FromSexp<'a> for AstExpr<'a> {
+  fn from_sexp(x: &Sexp, aa: &mut Allocator<'a>) -> Result<Self, SexpError> {
+    if let Some(a) = x.as_atom() {
+      let e =
+        match a {
+          "false" | "true" => AstExpr::Bool(aa.alloc().init(AstBool(a == "true", Span { file: 0, start: 0, stop: 0 }, NodeId::fresh()))),
+          _ if is_number(a) => AstExpr::Number(aa.alloc().init(AstNumber::from_sexp(x, aa)?)),
+          _ => AstExpr::Symbol(aa.alloc().init(AstSymbol::from_sexp(x, aa)?)),
+        };
+      return Ok(e);
+    }
+
+    let e =
+      match x.as_form() {
+        Some(("array", y)) => {
+          AstExpr::Array(aa.alloc().init(AstArray(from_sexp_seq(y, aa)?, NodeId::fresh())))
+        }
+        Some(("call", [f, y @ ..])) => {
+          let f = AstExpr::from_sexp(f, aa)?;
+          let y = from_sexp_seq(y, aa)?;
+          AstExpr::FunCall(aa.alloc().init(AstFunCall(f, y, NodeId::fresh())))
+        }
+        Some(("closure", [f, y @ ..])) => {
+          let f = AstSymbol::from_sexp(f, aa)?;
+          let y = from_sexp_seq(y, aa)?;
+          AstExpr::Closure(aa.alloc().init(AstClosure(f, y, NodeId::fresh())))
+        }
+        Some((".", [y, f])) => {
+          let y = AstExpr::from_sexp(y, aa)?;
+          let f = AstSymbol::from_sexp(f, aa)?;
+          AstExpr::Field(aa.alloc().init(AstField(y, f, NodeId::fresh())))
+        }
+        Some(("fun", y)) => {
+          let (params, result, body) = from_sexp_params_and_result(x, y, aa)?;
+          AstExpr::Lambda(aa.alloc().init(AstLambda { params, result, body, id: NodeId::fresh() }))
+        }
+        Some(("if", [p, y, z])) => {
+          let p = AstExpr::from_sexp(p, aa)?;
+          let y =
+            match y.as_form() {
+              Some(("then", y)) => from_sexp_seq(y, aa)?,
+              _ => return bad_form(x),
+            };
+          let z =
+            match z.as_form() {
+              Some(("else", z)) => from_sexp_seq(z, aa)?,
+              _ => return bad_form(x),
+            };
+          AstExpr::If(aa.alloc().init(AstIf(p, y, z, NodeId::fresh())))
+        }
+        Some(("index", [y, z])) => {
+          let y = AstExpr::from_sexp(y, aa)?;
+          let z = AstExpr::from_sexp(z, aa)?;
+          AstExpr::Index(aa.alloc().init(AstIndex(y, z, NodeId::fresh())))
+        }
+        Some(("loop", y)) => {
+          AstExpr::Loop(aa.alloc().init(AstLoop(from_sexp_seq(y, aa)?, NodeId::fresh())))
+        }
+        Some(("match", [y, arms @ ..])) => {
+          let y = AstExpr::from_sexp(y, aa)?;
+          let span = Span { file: 0, start: 0, stop: 0 };
+          let mut a = Vec::new();
+          for arm in arms.iter() {
+            match arm.as_form() {
+              Some(("case", [pats, body @ ..])) => {
+                let pats = from_sexp_list(pats, aa)?;
+                let body = from_sexp_seq(body, aa)?;
+                a.push(AstMatchArm { pats, body, span })
+              }
+              _ => return bad_form(arm),
+            }
+          }
+          let arms = aa.copy_slice(a.as_slice());
+          AstExpr::Match(aa.alloc().init(AstMatch { expr: y, arms, span, id: NodeId::fresh() }))
+        }
+        Some(("method", [y, f, z @ ..])) => {
+          let y = AstExpr::from_sexp(y, aa)?;
+          let f = AstSymbol::from_sexp(f, aa)?;
+          let z = from_sexp_seq(z, aa)?;
+          AstExpr::MethodCall(aa.alloc().init(AstMethodCall(y, f, z, NodeId::fresh())))
+        }
+        Some(("record", y)) => {
+          let mut a = Vec::new();
+          for z in y.iter() {
+            match z.as_form() {
+              Some(("=", [f, e])) => {
+                let f = AstSymbol::from_sexp(f, aa)?;
+                let e = AstExpr::from_sexp(e, aa)?;
+                a.push((f, e))
+              }
+              _ => return bad_form(z),
+            }
+          }
+          AstExpr::Record(aa.alloc().init(AstRecord(aa.copy_slice(a.as_slice()), NodeId::fresh())))
+        }
+        Some(("repeat", [y, n])) => {
+          let y = AstExpr::from_sexp(y, aa)?;
+          let n = AstNumber::from_sexp(n, aa)?;
+          AstExpr::ArrayRepeat(aa.alloc().init(AstArrayRepeat(y, n, NodeId::fresh())))
+        }
+        Some(("tuple", y)) => {
+          AstExpr::Tuple(aa.alloc().init(AstTuple(from_sexp_seq(y, aa)?, NodeId::fresh())))
+        }
+        Some(("unquote", [y])) => {
+          AstExpr::Unquote(aa.alloc().init(AstSymbol::from_sexp(y, aa)?))
+        }
+        Some(("|>", [y, z])) => {
+          let y = AstExpr::from_sexp(y, aa)?;
+          let z = AstExpr::from_sexp(z, aa)?;
+          AstExpr::Pipeline(aa.alloc().init(AstPipeline(y, z, NodeId::fresh())))
+        }
+        Some((op, [y])) if matches!(AstOp::from_name(op), Some(AstOp::Neg | AstOp::Not)) => {
+          let op = AstOp::from_name(op).unwrap();
+          let y = AstExpr::from_sexp(y, aa)?;
+          AstExpr::OpCall1(aa.alloc().init(AstOpCall(op, [y], NodeId::fresh())))
+        }
+        Some((op, [y, z])) if matches!(AstOp::from_name(op), Some(op) if ! matches!(op, AstOp::Neg | AstOp::Not)) => {
+          let op = AstOp::from_name(op).unwrap();
+          let y = AstExpr::from_sexp(y, aa)?;
+          let z = AstExpr::from_sexp(z, aa)?;
+          AstExpr::OpCall2(aa.alloc().init(AstOpCall(op, [y, z], NodeId::fresh())))
+        }
+        _ => {
+          return bad_form(x);
+        }
+      };
+    Ok(e)
+  }
+}
+
+impl<'a> FromSexp<'a> for AstParam<'a> {
+  fn from_sexp(x: &Sexp, aa: &mut Allocator<'a>) -> Result<Self, SexpError> {
+    match x.as_form() {
+      Some((":", [pat, ty])) => {
+        let pat = AstPat::from_sexp(pat, aa)?;
+        let ty = AstType::from_sexp(ty, aa)?;
+        Ok(AstParam { pat, ty: Some(ty) })
+      }
+      _ => {
+        let pat = AstPat::from_sexp(x, aa)?;
+        Ok(AstParam { pat, ty: None })
+      }
+    }
+  }
+}
+
+impl<'a> FromSexp<'a> for AstPat<'a> {
+  fn from_sexp(x: &Sexp, aa: &mut Allocator<'a>) -> Result<Self, SexpError> {
+    match x.as_form() {
+      Some(("tuple", y)) => {
+        let y = from_sexp_seq(y, aa)?;
+        Ok(AstPat::Tuple(aa.alloc().init(AstTuplePat(y))))
+      }
+      _ => {
+        Ok(AstPat::Symbol(aa.alloc().init(AstSymbol::from_sexp(x, aa)?)))
+      }
+    }
+  }
+}
+
+impl<'a> FromSexp<'a> for AstType<'a> {
+  fn from_sexp(x: &Sexp, aa: &mut Allocator<'a>) -> Result<Self, SexpError> {
+    match x.as_form() {
+      Some(("array", [y, n])) => {
+        let y = AstType::from_sexp(y, aa)?;
+        let n = AstNumber::from_sexp(n, aa)?;
+        Ok(AstType::Array(aa.alloc().init(AstArrayType(y, n))))
+      }
+      Some(("tuple", y)) => {
+        let y = from_sexp_seq(y, aa)?;
+        Ok(AstType::Tuple(aa.alloc().init(AstTupleType(y))))
+      }
+      _ => {
+        Ok(AstType::Symbol(aa.alloc().init(AstSymbol::from_sexp(x, aa)?)))
+      }
+    }
+  }
+}
+
+impl<'a> FromSexp<'a> for AstMatchPat<'a> {
+  fn from_sexp(x: &Sexp, aa: &mut Allocator<'a>) -> Result<Self, SexpError> {
+    match (x.as_atom(), x.as_form()) {
+      (Some("_"), _) => {
+        Ok(AstMatchPat::Wildcard)
+      }
+      (Some(a @ ("false" | "true")), _) => {
+        Ok(AstMatchPat::Bool(aa.alloc().init(AstBool(a == "true", Span { file: 0, start: 0, stop: 0 }, NodeId::fresh()))))
+      }
+      (Some(_), _) => {
+        Ok(AstMatchPat::Number(aa.alloc().init(AstNumber::from_sexp(x, aa)?)))
+      }
+      (_, Some(("..", [y, z]))) => {
+        let y = AstNumber::from_sexp(y, aa)?;
+        let z = AstNumber::from_sexp(z, aa)?;
+        Ok(AstMatchPat::Range(aa.alloc().init(AstRange(y, z))))
+      }
+      _ => {
+        bad_form(x)
+      }
+    }
+  }
+}
+
+impl<'a> AstMethodCall<'a> {
+  pub fn desugar(&self, aa: &mut Allocator<'a>) -> AstFunCall<'a> {
+    let mut a = Vec::new();
+    a.push(self.0);
+    a.extend_from_slice(self.2);
+    let f = AstExpr::Symbol(aa.alloc().init(self.1));
+    AstFunCall(f, aa.copy_slice(a.as_slice()), NodeId::fresh())
+  }
+}
+
+impl<'a> AstPipeline<'a> {
+  // `x |> f(y)` is `f(x, y)` and `x |> f` is `f(x)`. The piped value goes first
+  // in the written arguments, so `x |> m.f(y)` is `m.f(x, y)`.
+
+  pub fn desugar(&self, aa: &mut Allocator<'a>) -> AstExpr<'a> {
+    let mut a = Vec::new();
+    a.push(self.0);
+    match self.1 {
+      AstExpr::FunCall(y) => {
+        a.extend_from_slice(y.1);
+        AstExpr::FunCall(aa.alloc().init(AstFunCall(y.0, aa.copy_slice(a.as_slice()), NodeId::fresh())))
+      }
+      AstExpr::MethodCall(y) => {
+        a.extend_from_slice(y.2);
+        AstExpr::MethodCall(aa.alloc().init(AstMethodCall(y.0, y.1, aa.copy_slice(a.as_slice()), NodeId::fresh())))
+      }
+      f => {
+        AstExpr::FunCall(aa.alloc().init(AstFunCall(f, aa.copy_slice(a.as_slice()), NodeId::fresh())))
+      }
+    }
+  }
+}
+
+// Nodes don't record where their keywords and punctuation are, so the span
+// of an expression is the one that covers the names and literals in it. A
+// `match` knows its own span.
+
+struct SpanFinder(Option<Span>);
+
+impl SpanFinder {
+  fn add(&mut self, x: Span) {
+    self.0 = Some(match self.0 { None => x, Some(y) => y.join(x) });
+  }
+}
+
+impl<'a> Visitor<'a> for SpanFinder {
+  fn visit_bool(&mut self, x: &'a AstBool) { self.add(x.1) }
+  fn visit_match(&mut self, x: &'a AstMatch<'a>) { self.add(x.span) }
+  fn visit_number(&mut self, x: &'a AstNumber<'a>) { self.add(x.1) }
+  fn visit_symbol(&mut self, x: &'a AstSymbol) { self.add(x.1) }
+}
+
+impl SpanFinder {
+  fn span(self) -> Span {
+    self.0.unwrap_or(Span { file: 0, start: 0, stop: 0 })
+  }
+}
+
+impl<'a> AstStmt<'a> {
+  pub fn span(&self) -> Span {
+    let mut s = SpanFinder(None);
+    s.visit_stmt(self);
+    s.span()
+  }
+}
+
+impl<'a> AstExpr<'a> {
+  pub fn span(&self) -> Span {
+    let mut s = SpanFinder(None);
+    s.visit_expr(self);
+    s.span()
+  }
+}
+
+impl<'a> AstPat<'a> {
+  pub fn span(&self) -> Span {
+    let mut s = SpanFinder(None);
+    s.visit_pat(self);
+    s.span()
+  }
+}
+
+// The ID that a node was given when it was made. An unquoted symbol is
+// numbered as the symbol.
+
+impl<'a> AstItem<'a> {
+  pub fn id(&self) -> NodeId {
+    match self {
+      AstItem::ConstDef(x) => x.id,
+      AstItem::FunDef(x) => x.id,
+      AstItem::Import(x) => x.1,
+      AstItem::MacroDef(x) => x.id,
+      AstItem::Module(x) => x.id,
+      AstItem::StaticAssert(x) => x.1,
+      AstItem::StructDef(x) => x.id,
+    }
+  }
+}
+
+impl<'a> AstStmt<'a> {
+  pub fn id(&self) -> NodeId {
+    match self {
+      AstStmt::Break(x) => x.1,
+      AstStmt::ExprSeq(x) => x.1,
+      AstStmt::Let(x) => x.2,
+      AstStmt::Return(x) => x.1,
+    }
+  }
+}
+
+impl<'a> AstExpr<'a> {
+  pub fn id(&self) -> NodeId {
+    match self {
+      AstExpr::Array(x) => x.1,
+      AstExpr::ArrayRepeat(x) => x.2,
+      AstExpr::Bool(x) => x.2,
+      AstExpr::Closure(x) => x.2,
+      AstExpr::Field(x) => x.2,
+      AstExpr::FunCall(x) => x.2,
+      AstExpr::If(x) => x.3,
+      AstExpr::Index(x) => x.2,
+      AstExpr::Lambda(x) => x.id,
+      AstExpr::Loop(x) => x.1,
+      AstExpr::Match(x) => x.id,
+      AstExpr::MethodCall(x) => x.3,
+      AstExpr::Number(x) => x.2,
+      AstExpr::OpCall1(x) => x.2,
+      AstExpr::OpCall2(x) => x.2,
+      AstExpr::Pipeline(x) => x.2,
+      AstExpr::Record(x) => x.1,
+      AstExpr::Symbol(x) => x.2,
+      AstExpr::Tuple(x) => x.1,
+      AstExpr::Unquote(x) => x.2,
+    }
+  }
+}
+
+impl<'a> AstNumber<'a> {
+  pub fn value(self) -> Option<i128> {
+    let s: String = self.0.chars().filter(|&c| c != '_').collect();
+    s.parse::<i128>().ok()
+  }
+}
+
+// Tuples, arrays, and structs have no runtime representation of their own.
+// Such a value is flattened into its scalar components, which are what get
+// passed through block parameters, function inputs and outputs, and `Ret`.
+
+impl<'a> AstPat<'a> {
+  pub fn flatten(self, out: &mut Vec<AstSymbol>) {
+    match self {
+      Self::Symbol(x) => {
+        out.push(*x)
+      }
+      Self::Tuple(x) => {
+        for &p in x.0.iter() { p.flatten(out) }
+      }
+    }
+  }
+}
+
+impl<'a> AstType<'a> {
+  pub fn flatten(self, structs: &StructTable<'a>, out: &mut Vec<Ty>) -> Result<(), AstSymbol> {
+    match self {
+      Self::Array(x) => {
+        let n = x.1.value().unwrap();
+        for _ in 0 .. n { x.0.flatten(structs, out)? }
+      }
+      Self::Symbol(x) => {
+        if let Some(t) = Ty::from_name(x.0.as_str()) {
+          out.push(t)
+        } else if let Some(y) = structs.get(x.0) {
+          for field in y.fields.iter() { field.ty.flatten(structs, out)? }
+        } else {
+          return Err(*x)
+        }
+      }
+      Self::Tuple(x) => {
+        for &t in x.0.iter() { t.flatten(structs, out)? }
+      }
+    }
+    Ok(())
+  }
+}
+
+impl<'a> AstFunDef<'a> {
+  // Returns `None` unless every parameter and the result are annotated with
+  // known types.
+
+  pub fn signature<'b>(&self, structs: &StructTable<'a>, aa: &mut Allocator<'b>) -> Option<bytecode::Signature<'b>> {
+    let mut inputs = Vec::new();
+    for param in self.params.iter() {
+      param.ty?.flatten(structs, &mut inputs).ok()?;
+    }
+    let mut outputs = Vec::new();
+    self.result?.flatten(structs, &mut outputs).ok()?;
+    let inputs = aa.copy_slice(inputs.as_slice());
+    let outputs = aa.copy_slice(outputs.as_slice());
+    Some(bytecode::Signature { inputs, outputs })
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  const SOURCE: &[u8] = b"\
+import math.bits
+pub struct P { x: i64, y: i64 }
+const N: i64 = 4
+static_assert(N > 0)
+macro unless(c, body) if ! $c then $body else 0 end end
+fun main(x, y: i64): [i64; 4]
+  let a, b = x + 1, y * 2
+  let t = (a,)
+  let f = fun (q) q + x end
+  loop break end
+  let r = match x case 0, 1 then true case 2..10 then false case _ then !(x == 3) end
+  let h = x |> g(1) |> math.gcd(2) |> k
+  a.b.c(1, 2)[3].d
+  { px = 1, py = (2 - 3) - (4 - 5) }
+  [1, 2, 3][0] + [0; 4][1] * -(a - b)
+end
+module m
+  fun g(a) a end
+end
+";
+
+  // Prints `x` as a sexp in the given way, reads it back, and checks that the
+  // rebuilt item prints the same.
+
+  fn round_trip(print: impl Fn(&Sexp) -> String) {
+    let mut arena = Arena::new();
+    let aa = arena.allocator_mut();
+    let items = Parser::new(SOURCE).parse_file(aa).unwrap();
+    assert!(! items.is_empty());
+
+    for x in items.iter() {
+      let s = x.to_sexp();
+      let y = Sexp::read(&print(&s)).unwrap();
+      let z = AstItem::from_sexp(&y, aa).unwrap();
+      assert_eq!(z.to_sexp().to_string(), s.to_string());
+    }
+  }
+
+  #[test]
+  fn round_trip_compact() {
+    round_trip(|x| x.to_string());
+  }
+
+  #[test]
+  fn round_trip_pretty() {
+    round_trip(|x| x.pretty(40));
+  }
+
+  #[test]
+  fn quoted_symbol() {
+    let mut arena = Arena::new();
+    let aa = arena.allocator_mut();
+    let s = Sexp::List(Box::new([Sexp::from_atom("call"), Sexp::from_atom("a (b) \"c\"")]));
+    let x = AstExpr::from_sexp(&Sexp::read(&s.to_string()).unwrap(), aa).unwrap();
+    assert_eq!(x.to_sexp().to_string(), s.to_string());
+  }
+
+  #[test]
+  fn bad_form() {
+    let mut arena = Arena::new();
+    let aa = arena.allocator_mut();
+    let x = Sexp::read("(fundef)").unwrap();
+    assert!(matches!(AstItem::from_sexp(&x, aa), Err(SexpError::Form(_))));
+  }
 }
@@ -0,0 +1,245 @@
+use crate::prelude::*;
+
+// CAPTURE ANALYSIS
+//
+// The free variables of a lambda are the symbols that it uses but doesn't
+// bind. Some of them name local variables of an enclosing function, and those
+// are the captures. The rest refer to items.
+
+pub fn free_vars<'a>(x: &'a AstLambda<'a>) -> Vec<AstSymbol> {
+  let mut s = FreeVars { bound: Vec::new(), free: Vec::new() };
+  s.visit_lambda(x);
+  s.free
+}
+
+struct FreeVars {
+  bound: Vec<Symbol>,
+  free: Vec<AstSymbol>,
+}
+
+impl<'a> FreeVars {
+  fn bind(&mut self, pat: AstPat<'a>) {
+    let mut a = Vec::new();
+    pat.flatten(&mut a);
+    for x in a.iter() { self.bound.push(x.0) }
+  }
+}
+
+impl<'a> Visitor<'a> for FreeVars {
+  fn visit_block(&mut self, x: &'a [AstStmt<'a>]) {
+    let n = self.bound.len();
+    walk_block(self, x);
+    self.bound.truncate(n);
+  }
+
+  fn visit_let(&mut self, x: &'a AstLet<'a>) {
+    for y in x.1.iter() { self.visit_expr(y) }
+    for &pat in x.0.iter() { self.bind(pat) }
+  }
+
+  fn visit_closure(&mut self, x: &'a AstClosure<'a>) {
+    for y in x.1.iter() { self.visit_expr(y) }
+  }
+
+  fn visit_field(&mut self, x: &'a AstField<'a>) {
+    self.visit_expr(&x.0);
+  }
+
+  fn visit_lambda(&mut self, x: &'a AstLambda<'a>) {
+    let n = self.bound.len();
+    for param in x.params.iter() { self.bind(param.pat) }
+    self.visit_block(x.body);
+    self.bound.truncate(n);
+  }
+
+  fn visit_record(&mut self, x: &'a AstRecord<'a>) {
+    for (_, y) in x.0.iter() { self.visit_expr(y) }
+  }
+
+  fn visit_symbol(&mut self, x: &'a AstSymbol) {
+    if ! self.bound.contains(&x.0) && ! self.free.iter().any(|y| y.0 == x.0) {
+      self.free.push(*x)
+    }
+  }
+
+  fn visit_unquote(&mut self, _: &'a AstSymbol) {
+  }
+}
+
+// CLOSURE CONVERSION
+//
+// Every lambda is lifted to a function item that takes its captures as extra
+// leading parameters, and is replaced by an `AstClosure` that pairs the lifted
+// function with the captured values. The lifted items are named after the
+// item that they came from, e.g. `foo$fun0`, which can't collide with a
+// symbol from the source, and are put just before it, in the same module.
+//
+// This runs on the items that `load_program` produces, before name
+// resolution. A symbol that ends up in more than one place is copied with a
+// fresh ID. Lowering then gives each closure an environment that holds the
+// captured values, and passes it to the lifted function in place of them.
+
+pub fn convert_closures<'a>(items: &[AstItem<'a>], aa: &mut Allocator<'a>) -> Vec<AstItem<'a>> {
+  let mut out = Vec::new();
+
+  for &item in items.iter() {
+    match item {
+      AstItem::FunDef(x) => {
+        let mut s = Converter { name: x.name, count: 0, locals: Vec::new(), lifted: Vec::new(), aa };
+        for param in x.params.iter() { s.bind(param.pat) }
+        let body = s.fold_block(x.body);
+        out.extend(s.lifted);
+        let x = AstFunDef { is_pub: x.is_pub, name: x.name, params: x.params, result: x.result, body, id: NodeId::fresh() };
+        out.push(AstItem::FunDef(aa.alloc().init(x)));
+      }
+      AstItem::Module(x) => {
+        let y = convert_closures(x.items, aa);
+        let y = AstModule { name: x.name, items: aa.copy_slice(y.as_slice()), id: NodeId::fresh() };
+        out.push(AstItem::Module(aa.alloc().init(y)));
+      }
+      AstItem::ConstDef(_) | AstItem::Import(_) | AstItem::MacroDef(_) | AstItem::StaticAssert(_) | AstItem::StructDef(_) => {
+        out.push(item);
+      }
+    }
+  }
+
+  out
+}
+
+struct Converter<'s, 'a> {
+  name: AstSymbol,
+  count: usize,
+  locals: Vec<Symbol>,
+  lifted: Vec<AstItem<'a>>,
+  aa: &'s mut Allocator<'a>,
+}
+
+impl<'s, 'a> Converter<'s, 'a> {
+  fn lambda(&mut self, x: &'a AstLambda<'a>) -> AstExpr<'a> {
+    let mut captures = free_vars(x);
+    captures.retain(|y| self.locals.contains(&y.0));
+
+    // The body of the lambda can only see its own parameters and captures, so
+    // nested lambdas capture from those.
+
+    let outer = mem::take(&mut self.locals);
+    let mut params = Vec::new();
+    for &y in captures.iter() {
+      self.locals.push(y.0);
+      let y = AstSymbol(y.0, y.1, NodeId::fresh());
+      params.push(AstParam { pat: AstPat::Symbol(self.aa.alloc().init(y)), ty: None });
+    }
+    for &param in x.params.iter() {
+      self.bind(param.pat);
+      params.push(param);
+    }
+    let body = self.fold_block(x.body);
+    self.locals = outer;
+
+    let name = AstSymbol(Symbol::intern(&format!("{}$fun{}", self.name.0, self.count)), self.name.1, NodeId::fresh());
+    self.count += 1;
+    let params = self.aa.copy_slice(params.as_slice());
+    let fundef = AstFunDef { is_pub: false, name, params, result: x.result, body, id: NodeId::fresh() };
+    self.lifted.push(AstItem::FunDef(self.aa.alloc().init(fundef)));
+
+    let mut env = Vec::new();
+    for &y in captures.iter() {
+      env.push(AstExpr::Symbol(self.aa.alloc().init(AstSymbol(y.0, y.1, NodeId::fresh()))));
+    }
+    let env = self.aa.copy_slice(env.as_slice());
+    let name = AstSymbol(name.0, name.1, NodeId::fresh());
+    AstExpr::Closure(self.aa.alloc().init(AstClosure(name, env, NodeId::fresh())))
+  }
+}
+
+impl<'s, 'a> Folder<'a, 'a> for Converter<'s, 'a> {
+  fn allocator(&mut self) -> &mut Allocator<'a> {
+    self.aa
+  }
+
+  fn fold_block(&mut self, x: &'a [AstStmt<'a>]) -> &'a [AstStmt<'a>] {
+    fold_scoped_block(self, x)
+  }
+
+  fn fold_let(&mut self, x: &'a AstLet<'a>) -> AstLet<'a> {
+    fold_scoped_let(self, x)
+  }
+
+  fn fold_expr(&mut self, x: &'a AstExpr<'a>) -> AstExpr<'a> {
+    match x {
+      AstExpr::Lambda(x) => self.lambda(x),
+      _ => rebuild_expr(self, x),
+    }
+  }
+}
+
+impl<'s, 'a> ScopedFolder<'a, 'a> for Converter<'s, 'a> {
+  fn locals(&mut self) -> &mut Vec<Symbol> {
+    &mut self.locals
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  // The free variables of the lambda that is the body of the first function.
+
+  fn free(src: &str) -> Vec<String> {
+    let mut arena = Arena::new();
+    let aa = arena.allocator_mut();
+    let items = Parser::new(src.as_bytes()).parse_file(aa).unwrap();
+    let AstItem::FunDef(f) = items[0] else { panic!() };
+    let [AstStmt::ExprSeq(AstExprSeq([AstExpr::Lambda(x)], _))] = f.body else { panic!() };
+    free_vars(x).iter().map(|y| y.0.to_string()).collect()
+  }
+
+  fn convert(src: &str) -> Vec<String> {
+    let mut arena = Arena::new();
+    let aa = arena.allocator_mut();
+    let items = Parser::new(src.as_bytes()).parse_file(aa).unwrap();
+    convert_closures(items, aa).iter().map(|x| x.to_sexp().to_string()).collect()
+  }
+
+  #[test]
+  fn free_variables() {
+    assert_eq!(free("fun f() fun (q) q + k end end"), ["k"]);
+    assert_eq!(free("fun f() fun (q) let k = q k + g(q) end end"), ["g"]);
+    assert_eq!(free("fun f() fun (q) let k = k + q k end end"), ["k"]);
+    assert_eq!(free("fun f() fun (q) fun (r) r + q + k end end end"), ["k"]);
+    assert_eq!(free("fun f() fun (q) q.k + { k = 1 }.k end end"), Vec::<String>::new());
+  }
+
+  #[test]
+  fn shadowing() {
+    assert_eq!(convert("fun h(k) fun (k) k end end"), [
+      "(fundef h$fun0 (k) (exprseq k))",
+      "(fundef h (k) (exprseq (closure h$fun0)))",
+    ]);
+    assert_eq!(convert("fun h(k) fun (q) let k = q k end end"), [
+      "(fundef h$fun0 (q) (let k = q) (exprseq k))",
+      "(fundef h (k) (exprseq (closure h$fun0)))",
+    ]);
+  }
+
+  // An inner lambda captures through the outer one, which captures what the
+  // inner one needs from the function.
+
+  #[test]
+  fn nested() {
+    assert_eq!(convert("fun f(k) let g = fun (q) fun (r) r + q + k end end g(1)(2) end"), [
+      "(fundef f$fun0 (q k r) (exprseq (+ (+ r q) k)))",
+      "(fundef f$fun1 (k q) (exprseq (closure f$fun0 q k)))",
+      "(fundef f (k) (let g = (closure f$fun1 k)) (exprseq (call (call g 1) 2)))",
+    ]);
+  }
+
+  #[test]
+  fn lifted_names() {
+    assert_eq!(convert("fun f() fun (x) x end end module m fun f() fun (x) x end end fun g() [fun (x) x end, fun (y) y end] end end"), [
+      "(fundef f$fun0 (x) (exprseq x))",
+      "(fundef f () (exprseq (closure f$fun0)))",
+      "(module m (fundef f$fun0 (x) (exprseq x)) (fundef f () (exprseq (closure f$fun0))) (fundef g$fun0 (x) (exprseq x)) (fundef g$fun1 (y) (exprseq y)) (fundef g () (exprseq (array (closure g$fun0) (closure g$fun1)))))",
+    ]);
+  }
+}
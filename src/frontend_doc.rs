@@ -0,0 +1,304 @@
+use crate::prelude::*;
+
+// DOCUMENTATION
+//
+// Generates a reference for the functions of a program that are declared
+// `pub`. Each is listed with its signature and its doc comment, which is the
+// run of comments on the lines just before it, with the `#` and one space
+// after it removed.
+//
+// Signatures come from type checking, so a function without annotations is
+// documented with the types that were inferred for it.
+//
+// A name in backticks in a doc comment that is the name of a documented
+// function becomes a link to it. A name is looked up in the module of the
+// function that mentions it first, and then as a global name, so `gcd` and
+// `math.gcd` both refer to `math.gcd` from within `math`. Each function lists
+// the functions whose doc comments mention it.
+
+pub struct DocItem {
+  pub name: String,
+  pub signature: String,
+  pub doc: String,
+  // The documented functions that its doc comment mentions, and those whose
+  // doc comments mention it, by index.
+  pub mentions: Vec<usize>,
+  pub mentioned_by: Vec<usize>,
+}
+
+pub fn document_program<'a>(items: &'a [AstItem<'a>], names: &Resolution, typing: &Typing, sources: &[ParsedUnit]) -> Vec<DocItem> {
+  let mut docs = Vec::new();
+
+  collect(items, names, typing, sources, &mut docs);
+
+  for i in 0 .. docs.len() {
+    let mut mentions = Vec::new();
+    for line in docs[i].doc.lines() {
+      for x in segments(&docs, i, line).iter() {
+        if let &Segment::Code(_, Some(j)) = x {
+          if j != i && ! mentions.contains(&j) { mentions.push(j) }
+        }
+      }
+    }
+    for &j in mentions.iter() {
+      docs[j].mentioned_by.push(i);
+    }
+    docs[i].mentions = mentions;
+  }
+
+  docs
+}
+
+fn collect<'a>(items: &'a [AstItem<'a>], names: &Resolution, typing: &Typing, sources: &[ParsedUnit], out: &mut Vec<DocItem>) {
+  for item in items.iter() {
+    match item {
+      AstItem::FunDef(x) => {
+        if ! x.is_pub { continue; }
+        let id = names.binding_of(&x.name).unwrap();
+        let doc =
+          match sources.get(x.name.1.file as usize) {
+            Some(y) => doc_comment(y.source(), x.name.1.start as usize),
+            None => String::new(),
+          };
+        out.push(DocItem {
+          name: x.name.0.to_string(),
+          signature: signature(x, typing.binding_type(id)),
+          doc,
+          mentions: Vec::new(),
+          mentioned_by: Vec::new(),
+        });
+      }
+      AstItem::Module(x) => {
+        collect(x.items, names, typing, sources, out);
+      }
+      AstItem::ConstDef(_) | AstItem::Import(_) | AstItem::MacroDef(_) | AstItem::StaticAssert(_) | AstItem::StructDef(_) => {
+      }
+    }
+  }
+}
+
+// The module path of a global name, which is empty for the root module.
+
+fn module_of(name: &str) -> &str {
+  name.rsplit_once('.').map(|x| x.0).unwrap_or("")
+}
+
+// The comments on the lines just before the line that `pos` is on.
+
+fn doc_comment(text: &[u8], pos: usize) -> String {
+  let mut a = Vec::new();
+  let Some(mut stop) = text[.. pos].iter().rposition(|&c| c == b'\n') else { return String::new() };
+
+  loop {
+    let start = text[.. stop].iter().rposition(|&c| c == b'\n').map(|i| i + 1).unwrap_or(0);
+    let line = String::from_utf8_lossy(&text[start .. stop]);
+    let Some(line) = line.trim().strip_prefix('#') else { break };
+    a.push(line.strip_prefix(' ').unwrap_or(line).to_string());
+    if start == 0 { break; }
+    stop = start - 1;
+  }
+
+  a.reverse();
+  a.join("\n")
+}
+
+fn signature(x: &AstFunDef<'_>, t: &Type) -> String {
+  let Type::Fun(params, result) = t else { unreachable!() };
+  let a: Vec<String> = x.params.iter().zip(params.iter()).map(|(p, t)| format!("{}: {}", pat(p.pat), t)).collect();
+  format!("fun {}({}): {}", x.name.0, a.join(", "), result)
+}
+
+fn pat(x: AstPat<'_>) -> String {
+  match x {
+    AstPat::Symbol(y) => y.0.to_string(),
+    AstPat::Tuple(y) => {
+      let a: Vec<String> = y.0.iter().map(|z| pat(*z)).collect();
+      if a.len() == 1 { format!("({},)", a[0]) } else { format!("({})", a.join(", ")) }
+    }
+  }
+}
+
+// MENTIONS
+
+enum Segment<'s> {
+  Text(&'s str),
+  // Text in backticks, and the documented function that it names, if any.
+  Code(&'s str, Option<usize>),
+}
+
+fn lookup(docs: &[DocItem], from: &str, name: &str) -> Option<usize> {
+  let module = module_of(from);
+  let local = format!("{}.{}", module, name);
+  let find = |x: &str| docs.iter().position(|y| y.name == x);
+  if module.is_empty() { find(name) } else { find(&local).or_else(|| find(name)) }
+}
+
+// Splits a line of the doc comment of `docs[i]` into text and code. A
+// backtick without a partner is just text.
+
+fn segments<'s>(docs: &[DocItem], i: usize, line: &'s str) -> Vec<Segment<'s>> {
+  let mut out = Vec::new();
+  let mut rest = line;
+
+  while let Some(j) = rest.find('`') {
+    let Some(k) = rest[j + 1 ..].find('`') else { break };
+    let code = &rest[j + 1 .. j + 1 + k];
+    if j != 0 { out.push(Segment::Text(&rest[.. j])) }
+    out.push(Segment::Code(code, lookup(docs, &docs[i].name, code)));
+    rest = &rest[j + 2 + k ..];
+  }
+
+  if ! rest.is_empty() { out.push(Segment::Text(rest)) }
+
+  out
+}
+
+// MARKDOWN
+
+pub fn docs_to_markdown(title: &str, docs: &[DocItem]) -> String {
+  let mut out = format!("# {}\n", title);
+
+  for (i, x) in docs.iter().enumerate() {
+    out.push_str(&format!("\n<a id=\"{}\"></a>\n\n## `{}`\n\n```\n{}\n```\n", x.name, x.name, x.signature));
+
+    if ! x.doc.is_empty() {
+      out.push('\n');
+      for line in x.doc.lines() {
+        for y in segments(docs, i, line).iter() {
+          match *y {
+            Segment::Text(s) => out.push_str(s),
+            Segment::Code(s, None) => out.push_str(&format!("`{}`", s)),
+            Segment::Code(s, Some(j)) => out.push_str(&format!("[`{}`](#{})", s, docs[j].name)),
+          }
+        }
+        out.push('\n');
+      }
+    }
+
+    if ! x.mentioned_by.is_empty() {
+      let a: Vec<String> = x.mentioned_by.iter().map(|&j| format!("[`{}`](#{})", docs[j].name, docs[j].name)).collect();
+      out.push_str(&format!("\nMentioned by: {}\n", a.join(", ")));
+    }
+  }
+
+  out
+}
+
+// HTML
+//
+// A standalone page. Paragraphs of a doc comment are separated by blank
+// lines.
+
+pub(crate) fn escape_html(s: &str) -> String {
+  let mut out = String::new();
+  for c in s.chars() {
+    match c {
+      '&' => out.push_str("&amp;"),
+      '<' => out.push_str("&lt;"),
+      '>' => out.push_str("&gt;"),
+      '"' => out.push_str("&quot;"),
+      _ => out.push(c),
+    }
+  }
+  out
+}
+
+fn html_link(docs: &[DocItem], j: usize, text: &str) -> String {
+  format!("<a href=\"#{}\"><code>{}</code></a>", escape_html(&docs[j].name), escape_html(text))
+}
+
+pub fn docs_to_html(title: &str, docs: &[DocItem]) -> String {
+  let mut out = String::new();
+
+  out.push_str("<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n");
+  out.push_str(&format!("<title>{}</title>\n</head>\n<body>\n<h1>{}</h1>\n", escape_html(title), escape_html(title)));
+
+  for (i, x) in docs.iter().enumerate() {
+    out.push_str(&format!("<section id=\"{}\">\n", escape_html(&x.name)));
+    out.push_str(&format!("<h2><code>{}</code></h2>\n", escape_html(&x.name)));
+    out.push_str(&format!("<pre><code>{}</code></pre>\n", escape_html(&x.signature)));
+
+    for para in x.doc.split("\n\n").filter(|y| ! y.trim().is_empty()) {
+      let mut a = Vec::new();
+      for line in para.lines() {
+        let mut s = String::new();
+        for y in segments(docs, i, line).iter() {
+          match *y {
+            Segment::Text(t) => s.push_str(&escape_html(t)),
+            Segment::Code(t, None) => s.push_str(&format!("<code>{}</code>", escape_html(t))),
+            Segment::Code(t, Some(j)) => s.push_str(&html_link(docs, j, t)),
+          }
+        }
+        a.push(s);
+      }
+      out.push_str(&format!("<p>{}</p>\n", a.join("\n")));
+    }
+
+    if ! x.mentioned_by.is_empty() {
+      let a: Vec<String> = x.mentioned_by.iter().map(|&j| html_link(docs, j, &docs[j].name)).collect();
+      out.push_str(&format!("<p>Mentioned by: {}</p>\n", a.join(", ")));
+    }
+
+    out.push_str("</section>\n");
+  }
+
+  out.push_str("</body>\n</html>\n");
+  out
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use std::fs;
+
+  // Writes the files under a fresh root directory and documents the program
+  // whose root module is the first of them.
+
+  fn document(name: &str, files: &[(&str, &str)]) -> Vec<DocItem> {
+    let root = std::env::temp_dir().join(format!("adelie-doc-{}-{}", name, std::process::id()));
+    for (path, src) in files.iter() {
+      let path = root.join(path);
+      fs::create_dir_all(path.parent().unwrap()).unwrap();
+      fs::write(path, src).unwrap();
+    }
+    let mut arena = Arena::new();
+    let aa = arena.allocator_mut();
+    let mut units = Vec::new();
+    let items = load_program_sources(&root, &root.join(files[0].0), &mut units, aa);
+    fs::remove_dir_all(&root).unwrap();
+    let items = aa.copy_slice(items.unwrap().as_slice());
+    let structs = StructTable::new(items).unwrap();
+    let names = resolve_names(items).unwrap();
+    let typing = check_types(items, &names, &structs).unwrap();
+    document_program(items, &names, &typing, &units)
+  }
+
+  #[test]
+  fn doc_comments() {
+    let docs = document("comments", &[
+      ("main.adelie", "import math\n# Not a doc comment.\n\n# Adds one.\n#  Indented.\npub fun inc(x) x + 1 end\nfun hidden() 0 end\n"),
+      ("math.adelie", "module inner\n  # In an inline module.\n  pub fun id(x: i64): i64 x end\nend\n# The greatest common divisor.\npub fun gcd(a: i64, b: i64): i64 a end\n"),
+    ]);
+    let x = docs.iter().map(|y| (y.name.as_str(), y.signature.as_str(), y.doc.as_str())).collect::<Vec<_>>();
+    assert_eq!(x, [
+      ("inc", "fun inc(x: i64): i64", "Adds one.\n Indented."),
+      ("math.gcd", "fun math.gcd(a: i64, b: i64): i64", "The greatest common divisor."),
+      ("math.inner.id", "fun math.inner.id(x: i64): i64", "In an inline module."),
+    ]);
+  }
+
+  #[test]
+  fn mentions() {
+    let docs = document("mentions", &[
+      ("main.adelie", "import math\n# Uses `math.gcd`, `lcm`, and `x`.\npub fun lcm(a: i64, b: i64): i64 a * b / math.gcd(a, b) end\n"),
+      ("math.adelie", "# See `gcd2` and `lcm`, not `math`.\npub fun gcd(a: i64, b: i64): i64 gcd2(a, b) end\n# Like `gcd`.\npub fun gcd2(a: i64, b: i64): i64 a end\n"),
+    ]);
+    let x = docs.iter().map(|y| (y.name.as_str(), y.mentions.clone(), y.mentioned_by.clone())).collect::<Vec<_>>();
+    assert_eq!(x, [
+      ("lcm", vec![1], vec![1]),
+      ("math.gcd", vec![2, 0], vec![0, 2]),
+      ("math.gcd2", vec![1], vec![1]),
+    ]);
+    assert!(docs_to_markdown("t", &docs).contains("`math.gcd2`"));
+  }
+}
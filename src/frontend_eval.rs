@@ -0,0 +1,511 @@
+use crate::prelude::*;
+
+// COMPILE-TIME EVALUATION
+//
+// Computes the value of every `const` item and checks every `static_assert` by
+// interpreting the tree. Operators and intrinsics are evaluated by
+// `Op11::eval` and `Op21::eval`, so a constant has exactly the value that the
+// same code computes at run time. Function items can be called, so a constant
+// can be computed by any function that only does what is listed below.
+//
+// A value is the list of its scalars, flattened as in lowering, and a constant
+// has to be a single scalar. Lambdas and other values of function type can't
+// be evaluated, and an index that is out of bounds or an operation that traps,
+// such as a division by zero, where generated code would trap, is an error.
+//
+// A constant is evaluated when it is first used, so constants can refer to
+// ones that are defined after them, but not to themselves. Each evaluation
+// has a budget of steps and a limit on the depth of calls, so that one that
+// doesn't terminate is reported rather than hanging the compiler.
+//
+// This runs after type checking, on the items that `load_program` produces.
+
+#[derive(Debug)]
+pub enum EvalError {
+  AssertFailed(Span),
+  // A constant whose value depends on itself.
+  Cycle(AstSymbol),
+  IndexOutOfBounds(Span),
+  NotConstant(String, Span),
+  NotScalar(AstSymbol),
+  OutOfFuel(Span),
+  TooDeep(Span),
+  Trap(Span),
+}
+
+impl EvalError {
+  pub fn span(&self) -> Span {
+    match self {
+      Self::AssertFailed(x) | Self::IndexOutOfBounds(x) | Self::OutOfFuel(x) | Self::TooDeep(x) | Self::Trap(x) => *x,
+      Self::Cycle(x) | Self::NotScalar(x) => x.1,
+      Self::NotConstant(_, x) => *x,
+    }
+  }
+}
+
+const FUEL: u64 = 1 << 20;
+
+const CALL_LIMIT: usize = 256;
+
+pub struct ConstValues {
+  values: HashMap<BindingId, i128>,
+}
+
+impl ConstValues {
+  pub fn get(&self, x: BindingId) -> Option<i128> {
+    self.values.get(&x).copied()
+  }
+}
+
+pub fn eval_constants<'a>(items: &'a [AstItem<'a>], names: &Resolution, typing: &Typing, structs: &StructTable<'a>) -> Result<ConstValues, Vec<EvalError>> {
+  let mut s =
+    Evaluator {
+      names,
+      typing,
+      structs,
+      funs: HashMap::new(),
+      consts: HashMap::new(),
+      values: HashMap::new(),
+      pending: Vec::new(),
+      locals: HashMap::new(),
+      fuel: FUEL,
+      depth: 0,
+      errors: Vec::new(),
+    };
+
+  s.declare(items);
+  s.items(items);
+
+  if ! s.errors.is_empty() { return Err(s.errors); }
+
+  let values = s.values.into_iter().map(|(id, x)| (id, x.unwrap())).collect();
+
+  Ok(ConstValues { values })
+}
+
+// What stops the evaluation of an expression before it has a value.
+
+enum Flow {
+  Break(Vec<i128>),
+  Return(Vec<i128>),
+  Error(EvalError),
+  // Evaluating a constant that this depends on failed, and that was reported
+  // there.
+  Failed,
+}
+
+struct Evaluator<'s, 'a> {
+  names: &'s Resolution,
+  typing: &'s Typing,
+  structs: &'s StructTable<'a>,
+  funs: HashMap<BindingId, &'a AstFunDef<'a>>,
+  consts: HashMap<BindingId, &'a AstConstDef<'a>>,
+  // `None` for a constant whose evaluation failed.
+  values: HashMap<BindingId, Option<i128>>,
+  // The constants being evaluated, innermost last.
+  pending: Vec<BindingId>,
+  locals: HashMap<BindingId, Box<[i128]>>,
+  fuel: u64,
+  depth: usize,
+  errors: Vec<EvalError>,
+}
+
+// ITEMS
+
+impl<'s, 'a> Evaluator<'s, 'a> {
+  fn declare(&mut self, items: &'a [AstItem<'a>]) {
+    for item in items.iter() {
+      match item {
+        AstItem::ConstDef(x) => {
+          let _ = self.consts.insert(self.names.binding_of(&x.name).unwrap(), x);
+        }
+        AstItem::FunDef(x) => {
+          let _ = self.funs.insert(self.names.binding_of(&x.name).unwrap(), x);
+        }
+        AstItem::Module(x) => {
+          self.declare(x.items);
+        }
+        AstItem::Import(_) | AstItem::MacroDef(_) | AstItem::StaticAssert(_) | AstItem::StructDef(_) => {
+        }
+      }
+    }
+  }
+
+  fn items(&mut self, items: &'a [AstItem<'a>]) {
+    for item in items.iter() {
+      match item {
+        AstItem::ConstDef(x) => {
+          let _ = self.constant(self.names.binding_of(&x.name).unwrap());
+        }
+        AstItem::Module(x) => {
+          self.items(x.items);
+        }
+        AstItem::StaticAssert(x) => {
+          self.fuel = FUEL;
+          match self.top(&x.0) {
+            Ok(xs) => if xs[0] == 0 { self.errors.push(EvalError::AssertFailed(x.0.span())) },
+            Err(Flow::Error(e)) => self.errors.push(e),
+            Err(_) => {}
+          }
+        }
+        AstItem::FunDef(_) | AstItem::Import(_) | AstItem::MacroDef(_) | AstItem::StructDef(_) => {
+        }
+      }
+    }
+  }
+
+  // The value of a constant, which is evaluated the first time that it is
+  // needed, with a budget of its own.
+
+  fn constant(&mut self, id: BindingId) -> Result<i128, Flow> {
+    if let Some(&x) = self.values.get(&id) { return x.ok_or(Flow::Failed); }
+
+    let x = self.consts[&id];
+
+    if self.pending.contains(&id) { return Err(Flow::Error(EvalError::Cycle(x.name))); }
+
+    if ! matches!(self.typing.binding_type(id), Type::Scalar(_)) {
+      self.errors.push(EvalError::NotScalar(x.name));
+      let _ = self.values.insert(id, None);
+      return Err(Flow::Failed);
+    }
+
+    self.pending.push(id);
+    let fuel = mem::replace(&mut self.fuel, FUEL);
+    let r = self.top(&x.value);
+    self.fuel = fuel;
+    let _ = self.pending.pop();
+
+    let value =
+      match r {
+        Ok(xs) => Some(xs[0]),
+        Err(Flow::Error(e)) => { self.errors.push(e); None }
+        Err(_) => None,
+      };
+
+    let _ = self.values.insert(id, value);
+    value.ok_or(Flow::Failed)
+  }
+
+  // Evaluates the expression of an item. A `return` in it gives the item its
+  // value, as type checking expects.
+
+  fn top(&mut self, x: &'a AstExpr<'a>) -> Result<Vec<i128>, Flow> {
+    let locals = mem::take(&mut self.locals);
+    let r =
+      match self.expr(x) {
+        Ok(xs) | Err(Flow::Return(xs)) => Ok(xs),
+        Err(e) => Err(e),
+      };
+    self.locals = locals;
+    r
+  }
+
+  fn call(&mut self, f: &'a AstFunDef<'a>, args: Vec<i128>, span: Span) -> Result<Vec<i128>, Flow> {
+    if self.depth == CALL_LIMIT { return Err(Flow::Error(EvalError::TooDeep(span))); }
+
+    let locals = mem::take(&mut self.locals);
+    self.depth += 1;
+    let mut xs = args.as_slice();
+    for param in f.params.iter() { self.bind(&param.pat, &mut xs) }
+    let r =
+      match self.stmt_seq(f.body) {
+        Ok(xs) | Err(Flow::Return(xs)) => Ok(xs),
+        Err(e) => Err(e),
+      };
+    self.depth -= 1;
+    self.locals = locals;
+    r
+  }
+
+  // The number of scalars in a value of a type.
+
+  fn width(&self, t: &Type) -> usize {
+    match t {
+      Type::Array(x, n) => self.width(x) * *n as usize,
+      Type::Fun(_, _) => 0,
+      Type::Scalar(_) => 1,
+      Type::Struct(x) => {
+        let mut out = Vec::new();
+        for field in self.structs.get(*x).unwrap().fields.iter() {
+          field.ty.flatten(self.structs, &mut out).unwrap();
+        }
+        out.len()
+      }
+      Type::Tuple(xs) => xs.iter().map(|x| self.width(x)).sum(),
+    }
+  }
+
+  // Binds the names in a pattern to the leading values of `xs`, and advances
+  // `xs` past them.
+
+  fn bind(&mut self, pat: &'a AstPat<'a>, xs: &mut &[i128]) {
+    match pat {
+      AstPat::Symbol(x) => {
+        let id = self.names.binding_of(x).unwrap();
+        let (a, b) = xs.split_at(self.width(self.typing.binding_type(id)));
+        let _ = self.locals.insert(id, a.into());
+        *xs = b;
+      }
+      AstPat::Tuple(x) => {
+        for y in x.0.iter() { self.bind(y, xs) }
+      }
+    }
+  }
+}
+
+// STATEMENTS AND EXPRESSIONS
+
+fn not_constant(what: &str, span: Span) -> Flow {
+  Flow::Error(EvalError::NotConstant(what.to_string(), span))
+}
+
+fn eval21(op: Op21, x: i128, y: i128, span: Span) -> Result<Vec<i128>, Flow> {
+  if op.traps(x, y) { return Err(Flow::Error(EvalError::Trap(span))); }
+  Ok(vec![op.eval(x, y)])
+}
+
+impl<'s, 'a> Evaluator<'s, 'a> {
+  fn step(&mut self, span: Span) -> Result<(), Flow> {
+    if self.fuel == 0 { return Err(Flow::Error(EvalError::OutOfFuel(span))); }
+    self.fuel -= 1;
+    Ok(())
+  }
+
+  fn stmt_seq(&mut self, body: &'a [AstStmt<'a>]) -> Result<Vec<i128>, Flow> {
+    let mut out = Vec::new();
+    for stmt in body.iter() {
+      out.clear();
+      match stmt {
+        AstStmt::Break(x) => {
+          return Err(Flow::Break(self.exprs(x.0)?));
+        }
+        AstStmt::ExprSeq(x) => {
+          out = self.exprs(x.0)?;
+        }
+        AstStmt::Let(x) => {
+          let xs = self.exprs(x.1)?;
+          let mut xs = xs.as_slice();
+          for y in x.0.iter() { self.bind(y, &mut xs) }
+        }
+        AstStmt::Return(x) => {
+          return Err(Flow::Return(self.exprs(x.0)?));
+        }
+      }
+    }
+    Ok(out)
+  }
+
+  fn exprs(&mut self, xs: &'a [AstExpr<'a>]) -> Result<Vec<i128>, Flow> {
+    let mut out = Vec::new();
+    for x in xs.iter() { out.extend(self.expr(x)?) }
+    Ok(out)
+  }
+
+  fn struct_of(&self, x: &'a AstExpr<'a>) -> &'a AstStructDef<'a> {
+    let Some(Type::Struct(name)) = self.typing.type_of(x) else { unreachable!() };
+    self.structs.get(*name).unwrap()
+  }
+
+  fn expr(&mut self, x: &'a AstExpr<'a>) -> Result<Vec<i128>, Flow> {
+    self.step(x.span())?;
+
+    match x {
+      AstExpr::Array(y) => {
+        self.exprs(y.0)
+      }
+      AstExpr::ArrayRepeat(y) => {
+        let xs = self.expr(&y.0)?;
+        let n = y.1.value().unwrap() as usize;
+        Ok(xs.repeat(n))
+      }
+      AstExpr::Bool(y) => {
+        Ok(vec![y.0 as i128])
+      }
+      AstExpr::Closure(_) | AstExpr::Lambda(_) => {
+        Err(not_constant("lambda", x.span()))
+      }
+      AstExpr::MethodCall(_) | AstExpr::Pipeline(_) | AstExpr::Unquote(_) => {
+        unreachable!()
+      }
+      AstExpr::Field(y) => {
+        let xs = self.expr(&y.0)?;
+        let def = self.struct_of(&y.0);
+        let slots = self.structs.field_slots(def, y.1.0).unwrap();
+        Ok(xs[slots].to_vec())
+      }
+      AstExpr::FunCall(y) => {
+        if let Some(n) = self.typing.len_of(x) {
+          return Ok(vec![n as i128]);
+        }
+        if let Some(op) = self.typing.op_of(x) {
+          let xs = self.exprs(y.1)?;
+          return
+            match op {
+              TypedOp::Op11(op) => Ok(vec![op.eval(xs[0])]),
+              TypedOp::Op21(op) => eval21(op, xs[0], xs[1], x.span()),
+            };
+        }
+        let f =
+          match &y.0 {
+            AstExpr::Symbol(f) => self.names.binding_of(f).and_then(|id| self.funs.get(&id).copied()),
+            _ => None,
+          };
+        let Some(f) = f else { return Err(not_constant("call of a function value", x.span())) };
+        let xs = self.exprs(y.1)?;
+        self.call(f, xs, x.span())
+      }
+      AstExpr::If(y) => {
+        let c = self.expr(&y.0)?[0];
+        self.stmt_seq(if c != 0 { y.1 } else { y.2 })
+      }
+      AstExpr::Index(y) => {
+        let xs = self.expr(&y.0)?;
+        let i = self.expr(&y.1)?[0];
+        let Some(&Type::Array(_, n)) = self.typing.type_of(&y.0) else { unreachable!() };
+        if i < 0 || i >= n as i128 { return Err(Flow::Error(EvalError::IndexOutOfBounds(y.1.span()))); }
+        let w = xs.len() / n as usize;
+        let i = i as usize;
+        Ok(xs[i * w .. (i + 1) * w].to_vec())
+      }
+      AstExpr::Loop(y) => {
+        loop {
+          self.step(x.span())?;
+          match self.stmt_seq(y.0) {
+            Ok(_) => {}
+            Err(Flow::Break(xs)) => return Ok(xs),
+            Err(e) => return Err(e),
+          }
+        }
+      }
+      AstExpr::Match(y) => {
+        let s = self.expr(&y.expr)?[0];
+        let table = self.typing.match_table(y).unwrap();
+        let c = table.cases.iter().find(|c| c.lo <= s && s <= c.hi).unwrap();
+        self.stmt_seq(y.arms[c.arm].body)
+      }
+      AstExpr::Number(y) => {
+        Ok(vec![y.value().unwrap()])
+      }
+      AstExpr::OpCall1(y) => {
+        let a = self.expr(&y.1[0])?[0];
+        let Some(TypedOp::Op11(op)) = self.typing.op_of(x) else { unreachable!() };
+        Ok(vec![op.eval(a)])
+      }
+      AstExpr::OpCall2(y) => {
+        let a = self.expr(&y.1[0])?[0];
+        let b = self.expr(&y.1[1])?[0];
+        let Some(TypedOp::Op21(op)) = self.typing.op_of(x) else { unreachable!() };
+        eval21(op, a, b, x.span())
+      }
+      AstExpr::Record(y) => {
+        // Fields are evaluated in the order written and laid out in the order
+        // declared.
+        let mut fields = Vec::new();
+        for (name, z) in y.0.iter() { fields.push((name.0, self.expr(z)?)) }
+        let def = self.struct_of(x);
+        let mut out = Vec::new();
+        for field in def.fields.iter() {
+          out.extend_from_slice(&fields.iter().find(|z| z.0 == field.name.0).unwrap().1);
+        }
+        Ok(out)
+      }
+      AstExpr::Symbol(y) => {
+        let id = self.names.binding_of(y).unwrap();
+        if let Some(xs) = self.locals.get(&id) { return Ok(xs.to_vec()); }
+        if self.consts.contains_key(&id) { return Ok(vec![self.constant(id)?]); }
+        Err(not_constant("function value", y.1))
+      }
+      AstExpr::Tuple(y) => {
+        self.exprs(y.0)
+      }
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  // The value of each constant, in the order defined.
+
+  fn eval(src: &str) -> Result<Vec<(String, i128)>, Vec<EvalError>> {
+    let mut arena = Arena::new();
+    let aa = arena.allocator_mut();
+    let items = Parser::new(src.as_bytes()).parse_file(aa).unwrap();
+    let structs = StructTable::new(items).unwrap();
+    let names = resolve_names(items).unwrap();
+    let typing = check_types(items, &names, &structs).unwrap();
+    let consts = eval_constants(items, &names, &typing, &structs)?;
+    let mut out = Vec::new();
+    for (i, x) in names.bindings.iter().enumerate() {
+      if let Some(n) = consts.get(BindingId(i as u32)) { out.push((x.name.0.to_string(), n)) }
+    }
+    Ok(out)
+  }
+
+  fn at(src: &str, s: &str) -> u32 {
+    src.find(s).unwrap() as u32
+  }
+
+  #[test]
+  fn constants() {
+    let src = "
+      const a: i64 = b * 2
+      const b: i64 = fact(5) + len([1, 2, 3])
+      const c: i64 = 9223372036854775807 + 1
+      const d: bool = min_u(-1, 2) == 2
+      fun fact(n: i64): i64 if n == 0 then 1 else n * fact(n - 1) end end
+    ";
+    assert_eq!(eval(src).unwrap(), [
+      ("a".to_string(), 246),
+      ("b".to_string(), 123),
+      ("c".to_string(), i64::MIN as i128),
+      ("d".to_string(), 1),
+    ]);
+  }
+
+  #[test]
+  fn static_assert() {
+    let src = "const k: i64 = 2 static_assert(k == 2) static_assert(k > 2)";
+    let e = eval(src).unwrap_err();
+    assert!(matches!(&e[..], [EvalError::AssertFailed(x)] if x.start == at(src, "k > 2")));
+  }
+
+  #[test]
+  fn out_of_fuel() {
+    let src = "fun spin(n: i64): i64 loop if n < 0 then break n else 0 end end end const k: i64 = spin(1)";
+    let e = eval(src).unwrap_err();
+    assert!(matches!(&e[..], [EvalError::OutOfFuel(_)]));
+
+  }
+
+  #[test]
+  fn cycle() {
+    let src = "const a: i64 = b + 1 const b: i64 = a";
+    let e = eval(src).unwrap_err();
+    assert!(matches!(&e[..], [EvalError::Cycle(x)] if x.0.as_str() == "a"));
+  }
+
+  #[test]
+  fn trap() {
+    let src = "fun f(n: i64): i64 10 / n end const k: i64 = f(0)";
+    let e = eval(src).unwrap_err();
+    assert!(matches!(&e[..], [EvalError::Trap(x)] if x.start == at(src, "10")));
+
+    let src = "fun f(n: i64): i64 [1, 2][n] end const k: i64 = f(2)";
+    let e = eval(src).unwrap_err();
+    assert!(matches!(&e[..], [EvalError::IndexOutOfBounds(x)] if x.start == at(src, "n]")));
+  }
+
+  #[test]
+  fn not_constant() {
+    let src = "fun f(n: i64): i64 let g = fun (x: i64): i64 x end g(n) end const k: i64 = f(1)";
+    let e = eval(src).unwrap_err();
+    assert!(matches!(&e[..], [EvalError::NotConstant(x, y)] if x == "lambda" && y.start > at(src, "fun (") && y.stop < at(src, "g(n)")));
+
+    let src = "fun f(n: i64): i64 n end fun h(g): i64 g(1) end const k: i64 = h(f)";
+    let e = eval(src).unwrap_err();
+    assert!(matches!(&e[..], [EvalError::NotConstant(x, _)] if x == "function value"));
+  }
+}
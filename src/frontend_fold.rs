@@ -0,0 +1,256 @@
+use crate::prelude::*;
+
+// CONSTANT FOLDING
+//
+// Replaces operator calls and intrinsic calls on literals, calls of `len`,
+// and uses of constants, by their values, and simplifies `x + 0`, `0 + x`,
+// `x - 0`, `x * 1`, `1 * x`, `!!x`, and `--x` to `x`. An `if` on a literal
+// condition is replaced by the branch that it takes, if that branch is a
+// single expression. The value of each constant item is replaced by the value
+// that `eval_constants` computed for it.
+//
+// Which operation an operator performs, and so where it wraps around,
+// depends on the types of its operands, so this runs after type checking. The
+// result is a new tree, which has to be resolved and checked again.
+//
+// An operation that fails whatever its other operand is, such as a division
+// by a literal zero, is an error, as is a shift or rotation by a literal
+// amount that isn't less than 64. That is, whether the operation is written
+// with an operator or as a call of its intrinsic.
+
+#[derive(Debug)]
+pub enum FoldError {
+  DivByZero(Span),
+  ShiftRange(Span),
+}
+
+impl FoldError {
+  pub fn span(&self) -> Span {
+    match self {
+      Self::DivByZero(x) | Self::ShiftRange(x) => *x,
+    }
+  }
+}
+
+pub fn fold_constants<'a, 'b>(items: &'a [AstItem<'a>], names: &Resolution, typing: &Typing, consts: &ConstValues, aa: &mut Allocator<'b>) -> Result<Vec<AstItem<'b>>, Vec<FoldError>> {
+  let mut f = ConstFolder { names, typing, consts, aa, errors: Vec::new() };
+  let out = items.iter().map(|x| f.fold_item(x)).collect();
+  if ! f.errors.is_empty() { return Err(f.errors); }
+  Ok(out)
+}
+
+struct ConstFolder<'s, 'b> {
+  names: &'s Resolution,
+  typing: &'s Typing,
+  consts: &'s ConstValues,
+  aa: &'s mut Allocator<'b>,
+  errors: Vec<FoldError>,
+}
+
+fn literal(x: &AstExpr<'_>) -> Option<i128> {
+  match x {
+    AstExpr::Bool(y) => Some(y.0 as i128),
+    AstExpr::Number(y) => y.value(),
+    _ => None,
+  }
+}
+
+impl<'s, 'b> ConstFolder<'s, 'b> {
+  fn check_operand(&mut self, op: Op21, y: &AstExpr<'b>) {
+    match (op, literal(y)) {
+      (Op21::I64DivS, Some(0)) => {
+        self.errors.push(FoldError::DivByZero(y.span()))
+      }
+      (Op21::I64Rol | Op21::I64Ror | Op21::I64Shl | Op21::I64ShrS | Op21::I64ShrU, Some(n)) if ! (0 .. 64).contains(&n) => {
+        self.errors.push(FoldError::ShiftRange(y.span()))
+      }
+      _ => {}
+    }
+  }
+
+  fn constant(&mut self, t: Ty, n: i128, span: Span) -> AstExpr<'b> {
+    if t == Ty::Bool {
+      AstExpr::Bool(self.aa.alloc().init(AstBool(n != 0, span, NodeId::fresh())))
+    } else {
+      let s = self.aa.copy_str(&n.to_string());
+      AstExpr::Number(self.aa.alloc().init(AstNumber(s, span, NodeId::fresh())))
+    }
+  }
+
+  fn op_call1<'a>(&mut self, x: &'a AstExpr<'a>, y: &'a AstOpCall<'a, 1>) -> AstExpr<'b> {
+    let a = self.fold_expr(&y.1[0]);
+
+    if let Some(TypedOp::Op11(op)) = self.typing.op_of(x) {
+      if let Some(m) = literal(&a) {
+        return self.constant(op.types().1[0], op.eval(m), x.span());
+      }
+    }
+
+    match (y.0, a) {
+      (AstOp::Neg, AstExpr::OpCall1(z)) if matches!(z.0, AstOp::Neg) => return z.1[0],
+      (AstOp::Not, AstExpr::OpCall1(z)) if matches!(z.0, AstOp::Not) => return z.1[0],
+      _ => {}
+    }
+
+    AstExpr::OpCall1(self.aa.alloc().init(AstOpCall(y.0, [a], NodeId::fresh())))
+  }
+
+  fn op_call2<'a>(&mut self, x: &'a AstExpr<'a>, y: &'a AstOpCall<'a, 2>) -> AstExpr<'b> {
+    let a = self.fold_expr(&y.1[0]);
+    let b = self.fold_expr(&y.1[1]);
+
+    if let Some(TypedOp::Op21(op)) = self.typing.op_of(x) {
+      self.check_operand(op, &b);
+      // An operation that traps is left for run time.
+      if let (Some(m), Some(n)) = (literal(&a), literal(&b)) {
+        if ! op.traps(m, n) { return self.constant(op.types().1[0], op.eval(m, n), x.span()); }
+      }
+    }
+
+    match (y.0, literal(&a), literal(&b)) {
+      (AstOp::Add, Some(0), _) | (AstOp::Mul, Some(1), _) => return b,
+      (AstOp::Add | AstOp::Sub, _, Some(0)) | (AstOp::Mul, _, Some(1)) => return a,
+      _ => {}
+    }
+
+    AstExpr::OpCall2(self.aa.alloc().init(AstOpCall(y.0, [a, b], NodeId::fresh())))
+  }
+
+  fn fun_call<'a>(&mut self, x: &'a AstExpr<'a>, y: &'a AstFunCall<'a>) -> AstExpr<'b> {
+    if let Some(n) = self.typing.len_of(x) {
+      return self.constant(Ty::I64, n as i128, x.span());
+    }
+
+    let f = self.fold_expr(&y.0);
+    let args: Vec<AstExpr<'b>> = y.1.iter().map(|z| self.fold_expr(z)).collect();
+
+    if let Some(op) = self.typing.op_of(x) {
+      if let TypedOp::Op21(op) = op { self.check_operand(op, &args[1]) }
+      match (op, args.iter().map(literal).collect::<Option<Vec<_>>>().as_deref()) {
+        (TypedOp::Op11(op), Some(&[m])) => return self.constant(op.types().1[0], op.eval(m), x.span()),
+        (TypedOp::Op21(op), Some(&[m, n])) if ! op.traps(m, n) => return self.constant(op.types().1[0], op.eval(m, n), x.span()),
+        _ => {}
+      }
+    }
+
+    let args = self.aa.copy_slice(args.as_slice());
+    AstExpr::FunCall(self.aa.alloc().init(AstFunCall(f, args, NodeId::fresh())))
+  }
+}
+
+impl<'s, 'a, 'b> Folder<'a, 'b> for ConstFolder<'s, 'b> {
+  fn allocator(&mut self) -> &mut Allocator<'b> {
+    self.aa
+  }
+
+  fn fold_const_def(&mut self, x: &'a AstConstDef<'a>) -> AstConstDef<'b> {
+    let id = self.names.binding_of(&x.name).unwrap();
+    let y = rebuild_const_def(self, x);
+    match (self.typing.binding_type(id), self.consts.get(id)) {
+      (&Type::Scalar(t), Some(n)) => AstConstDef { value: self.constant(t, n, x.value.span()), ..y },
+      _ => y,
+    }
+  }
+
+  fn fold_expr(&mut self, x: &'a AstExpr<'a>) -> AstExpr<'b> {
+    match x {
+      AstExpr::FunCall(y) => {
+        self.fun_call(x, y)
+      }
+      AstExpr::If(y) => {
+        let c = self.fold_expr(&y.0);
+        let t = fold_stmts(self, y.1);
+        let e = fold_stmts(self, y.2);
+        if let AstExpr::Bool(z) = c {
+          let body = if z.0 { t } else { e };
+          if let [AstStmt::ExprSeq(&AstExprSeq(&[v], _))] = body { return v; }
+        }
+        AstExpr::If(self.aa.alloc().init(AstIf(c, t, e, NodeId::fresh())))
+      }
+      AstExpr::OpCall1(y) => {
+        self.op_call1(x, y)
+      }
+      AstExpr::OpCall2(y) => {
+        self.op_call2(x, y)
+      }
+      AstExpr::Symbol(y) => {
+        match (self.typing.type_of(x), self.names.binding_of(y).and_then(|id| self.consts.get(id))) {
+          (Some(&Type::Scalar(t)), Some(n)) => self.constant(t, n, y.1),
+          _ => rebuild_expr(self, x),
+        }
+      }
+      _ => {
+        rebuild_expr(self, x)
+      }
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn fold(src: &str) -> Result<Vec<String>, Vec<FoldError>> {
+    let mut arena = Arena::new();
+    let aa = arena.allocator_mut();
+    let items = Parser::new(src.as_bytes()).parse_file(aa).unwrap();
+    let structs = StructTable::new(items).unwrap();
+    let names = resolve_names(items).unwrap();
+    let typing = check_types(items, &names, &structs).unwrap();
+    let consts = eval_constants(items, &names, &typing, &structs).unwrap();
+    let items = fold_constants(items, &names, &typing, &consts, aa)?;
+    Ok(items.iter().map(|x| x.to_sexp().to_string()).collect())
+  }
+
+  #[test]
+  fn literals() {
+    assert_eq!(fold("fun f(): i64 9223372036854775807 + 1 end").unwrap(), ["(fundef f () (: i64) (exprseq -9223372036854775808))"]);
+    assert_eq!(fold("fun f(): i64 mul(3, 4) - -2 end").unwrap(), ["(fundef f () (: i64) (exprseq 14))"]);
+    assert_eq!(fold("fun f(): bool ! (1 < 2) end").unwrap(), ["(fundef f () (: bool) (exprseq false))"]);
+    assert_eq!(fold("const k: i64 = 2 * 3 fun f(): i64 k * k end").unwrap(), [
+      "(const k (: i64) 6)",
+      "(fundef f () (: i64) (exprseq 36))",
+    ]);
+  }
+
+  #[test]
+  fn identities() {
+    assert_eq!(fold("fun f(x: i64): i64 (x + 0) * 1 - (0 + x) * (1 * x) - 0 end").unwrap(), ["(fundef f ((: x i64)) (: i64) (exprseq (- x (* x x))))"]);
+    assert_eq!(fold("fun f(b: bool, x: i64): bool ! ! b == (- - x > 0) end").unwrap(), ["(fundef f ((: b bool) (: x i64)) (: bool) (exprseq (== b (> x 0))))"]);
+  }
+
+  #[test]
+  fn if_on_a_literal() {
+    assert_eq!(fold("fun f(x: i64): i64 if 1 < 2 then x else 0 end end").unwrap(), ["(fundef f ((: x i64)) (: i64) (exprseq x))"]);
+    assert_eq!(fold("fun f(x: i64): i64 if false then x else let y = x y end end").unwrap(), [
+      "(fundef f ((: x i64)) (: i64) (exprseq (if false (then (exprseq x)) (else (let y = x) (exprseq y)))))",
+    ]);
+  }
+
+  #[test]
+  fn errors() {
+    let src = "fun f(x: i64): i64 x / 0 + div_s(x, 0) end";
+    let e = fold(src).unwrap_err();
+    assert!(matches!(&e[..], [FoldError::DivByZero(a), FoldError::DivByZero(b)] if a.start as usize == src.find("0 +").unwrap() && b.start as usize == src.find("0)").unwrap()));
+  }
+
+  // A literal shift amount is an `i6`, which type checking already keeps
+  // less than 64, so this checks the operand directly.
+
+  #[test]
+  fn shift_range() {
+    let mut arena = Arena::new();
+    let aa = arena.allocator_mut();
+    let names = resolve_names(&[]).unwrap();
+    let structs = StructTable::new(&[]).unwrap();
+    let typing = check_types(&[], &names, &structs).unwrap();
+    let consts = eval_constants(&[], &names, &typing, &structs).unwrap();
+    let mut f = ConstFolder { names: &names, typing: &typing, consts: &consts, aa, errors: Vec::new() };
+    let span = Span { file: 0, start: 3, stop: 5 };
+    for (op, n) in [(Op21::I64Shl, "64"), (Op21::I64Rol, "-1"), (Op21::I64ShrU, "63")] {
+      let x = AstExpr::Number(f.aa.alloc().init(AstNumber(n, span, NodeId::fresh())));
+      f.check_operand(op, &x);
+    }
+    assert!(matches!(&f.errors[..], [FoldError::ShiftRange(a), FoldError::ShiftRange(b)] if *a == span && *b == span));
+  }
+}
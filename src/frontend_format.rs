@@ -0,0 +1,635 @@
+use crate::prelude::*;
+
+// FORMATTING
+//
+// The canonical layout puts one statement on each line, indents blocks by two
+// spaces, and surrounds binary operators with spaces. An argument list, or any
+// other list, that doesn't fit within `WIDTH` is broken into one element per
+// line, and so is one with an element that spans lines, like an `if`.
+//
+// Comments aren't in the AST, so they are put back by position. A comment on
+// its own line stays before the statement or item that follows it, and one
+// after a statement on the same line stays there. A comment inside a
+// statement moves to just before the next statement. At most one blank line
+// is kept between statements, and items are separated by exactly one.
+//
+// Formatting checks its own work. The result must parse to the same AST, and
+// formatting it again must not change it.
+
+pub const WIDTH: usize = 80;
+
+#[derive(Debug)]
+pub enum FormatError {
+  Changed,
+  Parse,
+  Unstable,
+}
+
+pub fn format_source(buf: &[u8]) -> Result<String, FormatError> {
+  let (out, x) = format_once(buf)?;
+
+  let (again, y) =
+    match format_once(out.as_bytes()) {
+      Ok(r) => r,
+      Err(_) => return Err(FormatError::Changed),
+    };
+
+  if x != y { return Err(FormatError::Changed); }
+  if again != out { return Err(FormatError::Unstable); }
+
+  Ok(out)
+}
+
+// Returns the formatted source and the sexp of its AST.
+
+fn format_once(buf: &[u8]) -> Result<(String, String), FormatError> {
+  let mut arena = Arena::new();
+  let aa = arena.allocator_mut();
+  let mut parser = Parser::new(buf);
+
+  let items =
+    match parser.parse_file(aa) {
+      Ok(items) => items,
+      Err(_) => return Err(FormatError::Parse),
+    };
+
+  let mut f =
+    Formatter {
+      buf,
+      comments: comments(buf),
+      next_comment: 0,
+      marks: parser.marks(),
+      next_mark: 0,
+      last_stop: None,
+      force_blank: false,
+    };
+
+  let mut out = f.items(items, 0);
+  let mut first = items.is_empty();
+  f.comments_before(u32::MAX, 0, &mut out, &mut first);
+
+  let sexp: Vec<String> = items.iter().map(|x| x.to_sexp().to_string()).collect();
+
+  Ok((out, sexp.join("\n")))
+}
+
+fn comments(buf: &[u8]) -> Vec<Span> {
+  let mut lexer = Lexer::new(buf);
+  let mut a = Vec::new();
+
+  loop {
+    match lexer.next() {
+      Token::EOF => break,
+      Token::Space => {
+        let Span { start, stop, .. } = lexer.loc();
+        let mut i = start as usize;
+        while i < stop as usize {
+          if buf[i] == b'#' {
+            let mut j = i;
+            while j < stop as usize && buf[j] != b'\n' { j += 1 }
+            a.push(Span { file: 0, start: i as u32, stop: j as u32 });
+            i = j;
+          } else {
+            i += 1;
+          }
+        }
+      }
+      _ => {
+      }
+    }
+  }
+
+  a
+}
+
+fn op_token(op: AstOp) -> &'static str {
+  match op {
+    AstOp::Add => "+",
+    AstOp::And => "&",
+    AstOp::Div => "/",
+    AstOp::Mul => "*",
+    AstOp::Neg => "-",
+    AstOp::Not => "!",
+    AstOp::Or => "|",
+    AstOp::Sub => "-",
+    AstOp::Xor => "^",
+    AstOp::EQ => "==",
+    AstOp::NE => "!=",
+    AstOp::GT => ">",
+    AstOp::GE => ">=",
+    AstOp::LT => "<",
+    AstOp::LE => "<=",
+  }
+}
+
+// Binding strength, from the loosest. Operands that bind more loosely than
+// their position allows get parentheses.
+
+const PREC_PIPELINE: u8 = 0;
+const PREC_COMPARISON: u8 = 1;
+const PREC_ADDITION: u8 = 2;
+const PREC_MULTIPLICATION: u8 = 3;
+const PREC_PREFIX: u8 = 4;
+const PREC_POSTFIX: u8 = 5;
+
+fn prec(e: AstExpr<'_>) -> u8 {
+  match e {
+    AstExpr::OpCall1(_) => PREC_PREFIX,
+    AstExpr::OpCall2(x) => {
+      match x.0 {
+        AstOp::Mul | AstOp::Div => PREC_MULTIPLICATION,
+        AstOp::EQ | AstOp::NE | AstOp::GT | AstOp::GE | AstOp::LT | AstOp::LE => PREC_COMPARISON,
+        _ => PREC_ADDITION,
+      }
+    }
+    AstExpr::Pipeline(_) => PREC_PIPELINE,
+    _ => PREC_POSTFIX,
+  }
+}
+
+// Whether an operand would lex together with a prefix operator in front of it,
+// like `-1` or `!!`.
+
+fn needs_space(op: AstOp, operand: &str) -> bool {
+  match operand.bytes().next() {
+    Some(b'0' ..= b'9') => matches!(op, AstOp::Neg),
+    Some(c) => b"!$%&*+-/<=>?@^|~".contains(&c),
+    None => false,
+  }
+}
+
+fn indentation(out: &mut String, indent: usize) {
+  for _ in 0 .. indent { out.push(' ') }
+}
+
+struct Formatter<'s> {
+  buf: &'s [u8],
+  comments: Vec<Span>,
+  next_comment: usize,
+  marks: &'s [Mark],
+  next_mark: usize,
+  last_stop: Option<u32>,
+  force_blank: bool,
+}
+
+impl<'s> Formatter<'s> {
+  fn mark(&mut self) -> Mark {
+    let x = self.marks[self.next_mark];
+    self.next_mark += 1;
+    x
+  }
+
+  fn start(&mut self) -> u32 {
+    match self.mark() {
+      Mark::Start(x) => x,
+      _ => unreachable!(),
+    }
+  }
+
+  fn stop(&mut self) {
+    match self.mark() {
+      Mark::Stop(x) => self.last_stop = Some(x),
+      _ => unreachable!(),
+    }
+  }
+
+  fn end(&mut self) -> u32 {
+    match self.mark() {
+      Mark::End(x) => x,
+      _ => unreachable!(),
+    }
+  }
+
+  fn has_else(&mut self) -> bool {
+    match self.marks.get(self.next_mark) {
+      Some(Mark::Else) => { self.next_mark += 1; true }
+      _ => false,
+    }
+  }
+
+  // Whether there is a blank line just before `pos`.
+
+  fn blank_before(&self, pos: u32) -> bool {
+    let mut n = 0;
+    for &c in self.buf[.. pos as usize].iter().rev() {
+      match c {
+        b'\n' => n += 1,
+        b' ' | b'\t' | b'\r' => {}
+        _ => break,
+      }
+    }
+    n >= 2
+  }
+
+  fn line(&mut self, out: &mut String, indent: usize, text: &str, blank: bool) {
+    if (blank || self.force_blank) && ! out.is_empty() { out.push('\n') }
+    self.force_blank = false;
+    indentation(out, indent);
+    out.push_str(text);
+    out.push('\n');
+  }
+
+  fn comments_before(&mut self, pos: u32, indent: usize, out: &mut String, first: &mut bool) {
+    while let Some(&c) = self.comments.get(self.next_comment) {
+      if c.start >= pos { break; }
+      self.next_comment += 1;
+
+      let text = String::from_utf8_lossy(&self.buf[c.start as usize .. c.stop as usize]);
+      let text = text.trim_end();
+
+      match self.last_stop {
+        Some(stop) if stop <= c.start && ! out.is_empty() && ! self.buf[stop as usize .. c.start as usize].contains(&b'\n') => {
+          let _ = out.pop();
+          out.push(' ');
+          out.push_str(text);
+          out.push('\n');
+        }
+        _ => {
+          let blank = ! *first && self.blank_before(c.start);
+          self.line(out, indent, text, blank);
+          *first = false;
+        }
+      }
+
+      self.last_stop = None;
+    }
+  }
+
+  // A list in delimiters after `head`, on one line if it fits and otherwise
+  // with one element per line. The elements must have been formatted at
+  // `indent + 2`.
+
+  fn list(&self, head: &str, open: &str, close: &str, items: &[String], indent: usize, trailing: bool) -> String {
+    let flat = items.join(", ");
+    let comma = if trailing { "," } else { "" };
+    let column =
+      match head.rfind('\n') {
+        Some(i) => head.len() - i - 1,
+        None => indent + head.len(),
+      };
+
+    if ! flat.contains('\n') && column + open.len() + flat.len() + comma.len() + close.len() <= WIDTH {
+      if items.is_empty() {
+        return format!("{}{}{}", head, open.trim_end(), close.trim_start());
+      }
+      return format!("{}{}{}{}{}", head, open, flat, comma, close);
+    }
+
+    let mut out = String::new();
+    out.push_str(head);
+    out.push_str(open.trim_end());
+    out.push('\n');
+    for (i, x) in items.iter().enumerate() {
+      indentation(&mut out, indent + 2);
+      out.push_str(x);
+      if trailing || i + 1 != items.len() { out.push(',') }
+      out.push('\n');
+    }
+    indentation(&mut out, indent);
+    out.push_str(close.trim_start());
+    out
+  }
+
+  fn items(&mut self, items: &[AstItem<'_>], indent: usize) -> String {
+    let mut out = String::new();
+    let mut first = true;
+
+    for (i, &item) in items.iter().enumerate() {
+      let start = self.start();
+      self.force_blank = i != 0;
+      self.comments_before(start, indent, &mut out, &mut first);
+      let blank = self.force_blank;
+      self.force_blank = false;
+      self.last_stop = None;
+      let x = self.item(item, indent);
+      self.stop();
+      self.line(&mut out, indent, &x, blank);
+      first = false;
+    }
+
+    out
+  }
+
+  fn item(&mut self, item: AstItem<'_>, indent: usize) -> String {
+    match item {
+      AstItem::ConstDef(x) => {
+        let is_pub = if x.is_pub { "pub " } else { "" };
+        let ty = self.result(x.ty);
+        format!("{}const {}{} = {}", is_pub, x.name.0, ty, self.expr(x.value, indent))
+      }
+      AstItem::FunDef(x) => {
+        let is_pub = if x.is_pub { "pub " } else { "" };
+        let head = format!("{}fun {}", is_pub, x.name.0);
+        let params = self.params(&head, x.params, indent);
+        let result = self.result(x.result);
+        let body = self.stmt_seq(x.body, indent + 2);
+        format!("{}{}\n{}{}end", params, result, body, " ".repeat(indent))
+      }
+      AstItem::Import(x) => {
+        let a: Vec<&str> = x.0.iter().map(|y| y.0.as_str()).collect();
+        format!("import {}", a.join("."))
+      }
+      AstItem::MacroDef(x) => {
+        let a: Vec<String> = x.params.iter().map(|y| y.0.to_string()).collect();
+        let params = self.list(&format!("macro {}", x.name.0), "(", ")", &a, indent, false);
+        let body = self.stmt_seq(x.body, indent + 2);
+        format!("{}\n{}{}end", params, body, " ".repeat(indent))
+      }
+      AstItem::Module(x) => {
+        let mut items = self.items(x.items, indent + 2);
+        let end = self.end();
+        let mut first = x.items.is_empty();
+        self.comments_before(end, indent + 2, &mut items, &mut first);
+        self.last_stop = None;
+        format!("module {}\n{}{}end", x.name.0, items, " ".repeat(indent))
+      }
+      AstItem::StaticAssert(x) => {
+        format!("static_assert({})", self.expr(x.0, indent))
+      }
+      AstItem::StructDef(x) => {
+        let a: Vec<String> = x.fields.iter().map(|y| format!("{}: {}", y.name.0, self.ty(y.ty))).collect();
+        let is_pub = if x.is_pub { "pub " } else { "" };
+        self.list(&format!("{}struct {} ", is_pub, x.name.0), "{ ", " }", &a, indent, false)
+      }
+    }
+  }
+
+  fn params(&mut self, head: &str, params: &[AstParam<'_>], indent: usize) -> String {
+    let a: Vec<String> = params.iter().map(|x| self.param(*x)).collect();
+    self.list(head, "(", ")", &a, indent, false)
+  }
+
+  fn param(&self, x: AstParam<'_>) -> String {
+    match x.ty {
+      Some(ty) => format!("{}: {}", self.pat(x.pat), self.ty(ty)),
+      None => self.pat(x.pat),
+    }
+  }
+
+  fn result(&self, x: Option<AstType<'_>>) -> String {
+    match x {
+      Some(ty) => format!(": {}", self.ty(ty)),
+      None => String::new(),
+    }
+  }
+
+  fn pat(&self, x: AstPat<'_>) -> String {
+    match x {
+      AstPat::Symbol(x) => x.0.to_string(),
+      AstPat::Tuple(x) => {
+        let a: Vec<String> = x.0.iter().map(|y| self.pat(*y)).collect();
+        if a.len() == 1 { format!("({},)", a[0]) } else { format!("({})", a.join(", ")) }
+      }
+    }
+  }
+
+  fn ty(&self, x: AstType<'_>) -> String {
+    match x {
+      AstType::Array(x) => format!("[{}; {}]", self.ty(x.0), x.1.0),
+      AstType::Symbol(x) => x.0.to_string(),
+      AstType::Tuple(x) => {
+        let a: Vec<String> = x.0.iter().map(|y| self.ty(*y)).collect();
+        if a.len() == 1 { format!("({},)", a[0]) } else { format!("({})", a.join(", ")) }
+      }
+    }
+  }
+
+  fn stmt_seq(&mut self, body: &[AstStmt<'_>], indent: usize) -> String {
+    let mut out = String::new();
+    let mut first = true;
+
+    for &stmt in body.iter() {
+      let start = self.start();
+      self.comments_before(start, indent, &mut out, &mut first);
+      self.last_stop = None;
+      let blank = ! first && self.blank_before(start);
+      let x = self.stmt(stmt, indent);
+      self.stop();
+      self.line(&mut out, indent, &x, blank);
+      first = false;
+    }
+
+    let end = self.end();
+    self.comments_before(end, indent, &mut out, &mut first);
+    self.last_stop = None;
+
+    out
+  }
+
+  fn stmt(&mut self, stmt: AstStmt<'_>, indent: usize) -> String {
+    match stmt {
+      AstStmt::Break(x) => {
+        if x.0.is_empty() { return "break".to_string(); }
+        format!("break {}", self.exprs(x.0, indent))
+      }
+      AstStmt::ExprSeq(x) => {
+        self.exprs(x.0, indent)
+      }
+      AstStmt::Let(x) => {
+        let a: Vec<String> = x.0.iter().map(|y| self.pat(*y)).collect();
+        format!("let {} = {}", a.join(", "), self.exprs(x.1, indent))
+      }
+      AstStmt::Return(x) => {
+        if x.0.is_empty() { return "return".to_string(); }
+        format!("return {}", self.exprs(x.0, indent))
+      }
+    }
+  }
+
+  fn exprs(&mut self, exprs: &[AstExpr<'_>], indent: usize) -> String {
+    let a: Vec<String> = exprs.iter().map(|x| self.expr(*x, indent)).collect();
+    a.join(", ")
+  }
+
+  fn expr_list(&mut self, exprs: &[AstExpr<'_>], indent: usize) -> Vec<String> {
+    exprs.iter().map(|x| self.expr(*x, indent + 2)).collect()
+  }
+
+  fn operand(&mut self, e: AstExpr<'_>, indent: usize, p: u8) -> String {
+    let x = self.expr(e, indent);
+    if prec(e) < p { format!("({})", x) } else { x }
+  }
+
+  fn expr(&mut self, e: AstExpr<'_>, indent: usize) -> String {
+    match e {
+      AstExpr::Array(x) => {
+        let a = self.expr_list(x.0, indent);
+        self.list("", "[", "]", &a, indent, false)
+      }
+      AstExpr::ArrayRepeat(x) => {
+        format!("[{}; {}]", self.expr(x.0, indent), x.1.0)
+      }
+      AstExpr::Bool(x) => {
+        (if x.0 { "true" } else { "false" }).to_string()
+      }
+      AstExpr::Closure(_) => {
+        // Only made by closure conversion.
+        unreachable!()
+      }
+      AstExpr::Field(x) => {
+        format!("{}.{}", self.operand(x.0, indent, PREC_POSTFIX), x.1.0)
+      }
+      AstExpr::FunCall(x) => {
+        let f = self.operand(x.0, indent, PREC_POSTFIX);
+        let a = self.expr_list(x.1, indent);
+        self.list(&f, "(", ")", &a, indent, false)
+      }
+      AstExpr::If(x) => {
+        let p = self.expr(x.0, indent);
+        let y = self.stmt_seq(x.1, indent + 2);
+        let mut out = format!("if {} then\n{}", p, y);
+        if self.has_else() {
+          let z = self.stmt_seq(x.2, indent + 2);
+          indentation(&mut out, indent);
+          out.push_str("else\n");
+          out.push_str(&z);
+        }
+        indentation(&mut out, indent);
+        out.push_str("end");
+        out
+      }
+      AstExpr::Index(x) => {
+        format!("{}[{}]", self.operand(x.0, indent, PREC_POSTFIX), self.expr(x.1, indent))
+      }
+      AstExpr::Lambda(x) => {
+        let params = self.params("fun ", x.params, indent);
+        let result = self.result(x.result);
+        let body = self.stmt_seq(x.body, indent + 2);
+        format!("{}{}\n{}{}end", params, result, body, " ".repeat(indent))
+      }
+      AstExpr::Loop(x) => {
+        let body = self.stmt_seq(x.0, indent + 2);
+        format!("loop\n{}{}end", body, " ".repeat(indent))
+      }
+      AstExpr::Match(x) => {
+        let mut out = format!("match {}\n", self.expr(x.expr, indent));
+        for arm in x.arms.iter() {
+          let a: Vec<String> = arm.pats.iter().map(|y| self.match_pat(*y)).collect();
+          let body = self.stmt_seq(arm.body, indent + 4);
+          indentation(&mut out, indent + 2);
+          out.push_str(&format!("case {} then\n{}", a.join(", "), body));
+        }
+        indentation(&mut out, indent);
+        out.push_str("end");
+        out
+      }
+      AstExpr::MethodCall(x) => {
+        let y = self.operand(x.0, indent, PREC_POSTFIX);
+        let a = self.expr_list(x.2, indent);
+        self.list(&format!("{}.{}", y, x.1.0), "(", ")", &a, indent, false)
+      }
+      AstExpr::Number(x) => {
+        x.0.to_string()
+      }
+      AstExpr::OpCall1(x) => {
+        let [a] = x.1;
+        let a = self.operand(a, indent, PREC_PREFIX);
+        let space = if needs_space(x.0, &a) { " " } else { "" };
+        format!("{}{}{}", op_token(x.0), space, a)
+      }
+      AstExpr::OpCall2(x) => {
+        let p = prec(e);
+        let [a, b] = x.1;
+        let a = self.operand(a, indent, p);
+        let b = self.operand(b, indent, p + 1);
+        format!("{} {} {}", a, op_token(x.0), b)
+      }
+      AstExpr::Pipeline(x) => {
+        let a = self.operand(x.0, indent, PREC_PIPELINE);
+        let b = self.operand(x.1, indent, PREC_POSTFIX);
+        format!("{} |> {}", a, b)
+      }
+      AstExpr::Record(x) => {
+        let a: Vec<String> = x.0.iter().map(|&(y, z)| format!("{} = {}", y.0, self.expr(z, indent + 2))).collect();
+        self.list("", "{ ", " }", &a, indent, false)
+      }
+      AstExpr::Symbol(x) => {
+        x.0.to_string()
+      }
+      AstExpr::Tuple(x) => {
+        let a = self.expr_list(x.0, indent);
+        self.list("", "(", ")", &a, indent, x.0.len() == 1)
+      }
+      AstExpr::Unquote(x) => {
+        format!("${}", x.0)
+      }
+    }
+  }
+
+  fn match_pat(&self, x: AstMatchPat<'_>) -> String {
+    match x {
+      AstMatchPat::Bool(x) => (if x.0 { "true" } else { "false" }).to_string(),
+      AstMatchPat::Number(x) => x.0.to_string(),
+      AstMatchPat::Range(x) => format!("{}..{}", x.0.0, x.1.0),
+      AstMatchPat::Wildcard => "_".to_string(),
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  // Formats `src` and checks that the result has the same AST and is a fixed
+  // point of formatting.
+
+  fn format(src: &str) -> String {
+    let out = format_source(src.as_bytes()).unwrap();
+    let (_, x) = format_once(src.as_bytes()).unwrap();
+    let (again, y) = format_once(out.as_bytes()).unwrap();
+    assert_eq!(x, y);
+    assert_eq!(again, out);
+    out
+  }
+
+  #[test]
+  fn spaces_and_indents() {
+    let src = "fun main(x,y:i64):i64\n let a,b=x + 1,y*2\n  if a==0 then b else a end\nend";
+    let out = "fun main(x, y: i64): i64\n  let a, b = x + 1, y * 2\n  if a == 0 then\n    b\n  else\n    a\n  end\nend\n";
+    assert_eq!(format(src), out);
+  }
+
+  #[test]
+  fn long_lists_break() {
+    let src = "fun f(x)\n  some_really_long_function_name(argument_number_one, argument_number_two, argument_three)\nend\n";
+    let out = "fun f(x)\n  some_really_long_function_name(\n    argument_number_one,\n    argument_number_two,\n    argument_three\n  )\nend\n";
+    assert_eq!(format(src), out);
+  }
+
+  #[test]
+  fn comments_stay_and_blank_lines_collapse() {
+    let src = "# header\nfun f(x)\n  # leading\n  g(x) # trailing\n\n\n  h(x)\nend\n# eof\n";
+    let out = "# header\nfun f(x)\n  # leading\n  g(x) # trailing\n\n  h(x)\nend\n# eof\n";
+    assert_eq!(format(src), out);
+  }
+
+  #[test]
+  fn idempotent() {
+    let src = "\
+import math.bits
+pub struct P {x:i64,y:i64}   # on struct
+macro unless(c,body) if ! $c then $body else 0 end end
+fun main(x,y:i64):i64
+  let t = (x,)
+  let f=fun(q) q+x end
+  loop break end
+  let r = match x case 0,1 then true case 2..10 then false case _ then !(x == 3) end
+  let h = x |> g(1) |> math.gcd(2) |> k
+  a.b.c(1, 2)[3].d
+  g(fun (v) v end, 2)
+  { px = 1, py = (2 - 3) - (4 - 5) }
+  [1, 2, 3][0] + [0; 4][1] * -(a - b)
+end
+module m
+  fun g(a) a end # after g
+end
+";
+    let out = format(src);
+    assert_eq!(format(&out), out);
+  }
+
+  #[test]
+  fn parse_error() {
+    assert!(matches!(format_source(b"fun f(x"), Err(FormatError::Parse)));
+  }
+}
@@ -0,0 +1,262 @@
+use crate::prelude::*;
+
+// SYNTAX HIGHLIGHTING
+//
+// Classifies the text of a source file by the tokens that the lexer finds in
+// it, so that highlighting agrees with the compiler about where every token
+// starts and stops. The lexer puts comments in space tokens, so those are
+// split again here, with a comment running from `#` to the end of its line.
+//
+// The pieces cover the whole text, in order.
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Highlight {
+  Comment,
+  Error,
+  Keyword,
+  Number,
+  Operator,
+  Punctuation,
+  Space,
+  Symbol,
+}
+
+impl Highlight {
+  pub fn of_token(token: Token) -> Self {
+    match token {
+      Token::EOF | Token::Space => Self::Space,
+      Token::Error => Self::Error,
+      Token::Number => Self::Number,
+      Token::Symbol => Self::Symbol,
+      Token::Dot | Token::DotDot | Token::DotDotDot => Self::Punctuation,
+      Token::Colon | Token::Comma | Token::LBrace | Token::LBracket | Token::LParen | Token::RBrace | Token::RBracket | Token::RParen | Token::Semi => {
+        Self::Punctuation
+      }
+      Token::Assign | Token::EQ | Token::NE | Token::GT | Token::GE | Token::LT | Token::LE | Token::Ampersand | Token::At | Token::Bang | Token::Caret | Token::Dollar | Token::Minus | Token::Percent | Token::Pipe | Token::Pipeline | Token::Plus | Token::Query | Token::Slash | Token::Star | Token::Tilde => {
+        Self::Operator
+      }
+      Token::And | Token::Break | Token::Case | Token::Const | Token::Do | Token::Elif | Token::Else | Token::End | Token::False | Token::For | Token::Fun | Token::If | Token::Import | Token::Let | Token::Loop | Token::Macro | Token::Match | Token::Module | Token::Or | Token::Pub | Token::Return | Token::StaticAssert | Token::Struct | Token::Then | Token::True | Token::While => {
+        Self::Keyword
+      }
+    }
+  }
+
+  // The name of the CSS class for the category.
+
+  pub fn to_name(self) -> &'static str {
+    match self {
+      Self::Comment => "comment",
+      Self::Error => "error",
+      Self::Keyword => "keyword",
+      Self::Number => "number",
+      Self::Operator => "operator",
+      Self::Punctuation => "punctuation",
+      Self::Space => "space",
+      Self::Symbol => "symbol",
+    }
+  }
+
+  // The SGR parameters for the category, or none for plain text.
+
+  fn ansi(self) -> Option<&'static str> {
+    match self {
+      Self::Comment => Some("90"),
+      Self::Error => Some("1;31"),
+      Self::Keyword => Some("1;35"),
+      Self::Number => Some("36"),
+      Self::Operator => Some("33"),
+      Self::Punctuation => Some("34"),
+      Self::Space | Self::Symbol => None,
+    }
+  }
+}
+
+pub fn highlight(buf: &[u8]) -> Vec<(Highlight, Span)> {
+  let mut out = Vec::new();
+  let mut lexer = Lexer::new(buf);
+
+  loop {
+    let token = lexer.next();
+    if token == Token::EOF { break; }
+    let loc = lexer.loc();
+    if token == Token::Space {
+      split_space(buf, loc, &mut out);
+    } else {
+      out.push((Highlight::of_token(token), loc));
+    }
+  }
+
+  out
+}
+
+fn split_space(buf: &[u8], loc: Span, out: &mut Vec<(Highlight, Span)>) {
+  let mut i = loc.start as usize;
+  let stop = loc.stop as usize;
+
+  while i != stop {
+    let (x, j) =
+      if buf[i] == b'#' {
+        (Highlight::Comment, buf[i .. stop].iter().position(|&c| c == b'\n').map_or(stop, |k| i + k))
+      } else {
+        (Highlight::Space, buf[i .. stop].iter().position(|&c| c == b'#').map_or(stop, |k| i + k))
+      };
+    out.push((x, Span { file: 0, start: i as u32, stop: j as u32 }));
+    i = j;
+  }
+}
+
+// Runs of the same category are joined, so that a character of several bytes
+// that the lexer rejects one byte at a time is still printed whole.
+
+fn runs(buf: &[u8]) -> Vec<(Highlight, String)> {
+  let mut out = Vec::new();
+  let mut a: Vec<(Highlight, Span)> = Vec::new();
+
+  for (x, loc) in highlight(buf) {
+    match a.last_mut() {
+      Some((y, z)) if *y == x => z.stop = loc.stop,
+      _ => a.push((x, loc)),
+    }
+  }
+
+  for (x, loc) in a {
+    out.push((x, String::from_utf8_lossy(&buf[loc.start as usize .. loc.stop as usize]).into_owned()));
+  }
+
+  out
+}
+
+// ANSI
+
+pub fn highlight_to_ansi(buf: &[u8]) -> String {
+  let mut out = String::new();
+
+  for (x, s) in runs(buf) {
+    match x.ansi() {
+      Some(sgr) => out.push_str(&format!("\x1b[{}m{}\x1b[0m", sgr, s)),
+      None => out.push_str(&s),
+    }
+  }
+
+  out
+}
+
+// HTML
+//
+// A standalone page. Each token that isn't a symbol or space is in a `span`
+// whose class is the name of its category.
+
+const STYLE: &str = "\
+.comment { color: #6a737d; font-style: italic; }
+.error { color: #cb2431; text-decoration: underline wavy; }
+.keyword { color: #a626a4; font-weight: bold; }
+.number { color: #0184bc; }
+.operator { color: #b76b01; }
+.punctuation { color: #4078f2; }
+";
+
+pub fn highlight_to_html(title: &str, buf: &[u8]) -> String {
+  let mut out = String::new();
+
+  out.push_str("<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n");
+  out.push_str(&format!("<title>{}</title>\n<style>\n{}</style>\n</head>\n<body>\n<pre><code>", escape_html(title), STYLE));
+
+  for (x, s) in runs(buf) {
+    match x {
+      Highlight::Space | Highlight::Symbol => out.push_str(&escape_html(&s)),
+      _ => out.push_str(&format!("<span class=\"{}\">{}</span>", x.to_name(), escape_html(&s))),
+    }
+  }
+
+  out.push_str("</code></pre>\n</body>\n</html>\n");
+  out
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  // The category and text of each piece, leaving out space.
+
+  fn pieces(src: &str) -> Vec<(Highlight, &str)> {
+    let x = highlight(src.as_bytes()).into_iter().filter(|y| y.0 != Highlight::Space);
+    x.map(|(y, z)| (y, &src[z.start as usize .. z.stop as usize])).collect()
+  }
+
+  #[test]
+  fn covers_the_text() {
+    let srcs: &[&[u8]] = &[
+      b"",
+      b"fun f(a) a + 1 end",
+      b"  # only a comment",
+      b"let x = 1 # trailing\n\n# next\nx",
+      b"a \xff\xfe b \xc3\xa9 ` c",
+    ];
+    for src in srcs.iter() {
+      let mut i = 0;
+      for (_, x) in highlight(src).iter() {
+        assert_eq!(x.start, i);
+        assert!(x.stop > x.start);
+        i = x.stop;
+      }
+      assert_eq!(i as usize, src.len());
+    }
+  }
+
+  #[test]
+  fn comments() {
+    let src = "x # a # b\n  # c\ny";
+    let x = highlight(src.as_bytes()).into_iter().map(|(y, z)| (y, &src[z.start as usize .. z.stop as usize])).collect::<Vec<_>>();
+    assert_eq!(x, [
+      (Highlight::Symbol, "x"),
+      (Highlight::Space, " "),
+      (Highlight::Comment, "# a # b"),
+      (Highlight::Space, "\n  "),
+      (Highlight::Comment, "# c"),
+      (Highlight::Space, "\n"),
+      (Highlight::Symbol, "y"),
+    ]);
+  }
+
+  #[test]
+  fn categories() {
+    use Highlight::*;
+    assert_eq!(pieces("pub fun f(a: i64) a.b |> g, 1_000 end"), [
+      (Keyword, "pub"),
+      (Keyword, "fun"),
+      (Symbol, "f"),
+      (Punctuation, "("),
+      (Symbol, "a"),
+      (Punctuation, ":"),
+      (Symbol, "i64"),
+      (Punctuation, ")"),
+      (Symbol, "a"),
+      (Punctuation, "."),
+      (Symbol, "b"),
+      (Operator, "|>"),
+      (Symbol, "g"),
+      (Punctuation, ","),
+      (Number, "1_000"),
+      (Keyword, "end"),
+    ]);
+    assert_eq!(pieces("if true then $x else [] end"), [
+      (Keyword, "if"),
+      (Keyword, "true"),
+      (Keyword, "then"),
+      (Operator, "$"),
+      (Symbol, "x"),
+      (Keyword, "else"),
+      (Punctuation, "["),
+      (Punctuation, "]"),
+      (Keyword, "end"),
+    ]);
+    assert_eq!(pieces("a ` b").iter().map(|x| x.0).collect::<Vec<_>>(), [Symbol, Error, Symbol]);
+  }
+
+  #[test]
+  fn html() {
+    let x = highlight_to_html("a < b", b"x < 1 # & more");
+    assert!(x.contains("<title>a &lt; b</title>"));
+    assert!(x.contains("x <span class=\"operator\">&lt;</span> <span class=\"number\">1</span> <span class=\"comment\"># &amp; more</span>"));
+  }
+}
@@ -0,0 +1,155 @@
+use crate::prelude::*;
+
+// INTRINSICS
+//
+// Every bytecode operation can be called like a function by the name of its
+// intrinsic, e.g. `popcount(x)` or `mulhi_u(a, b)`. A call of an intrinsic
+// takes and returns exactly the types of its operation, and it is lowered to
+// just that operation.
+//
+// An intrinsic name only refers to the intrinsic where nothing of that name is
+// in scope, so a function or variable of the same name hides it. The
+// operations on `i64`, which are most of them, have short names, and the
+// others are prefixed by their type.
+
+static INTRINSICS: &[(&str, TypedOp)] = &[
+  ("bool_not", TypedOp::Op11(Op11::BoolNot)),
+  ("i128_hi", TypedOp::Op11(Op11::I128HiI64)),
+  ("i128_lo", TypedOp::Op11(Op11::I128ToI64)),
+  ("abs", TypedOp::Op11(Op11::I64Abs)),
+  ("bit_not", TypedOp::Op11(Op11::I64BitNot)),
+  ("clz", TypedOp::Op11(Op11::I64Clz)),
+  ("ctz", TypedOp::Op11(Op11::I64Ctz)),
+  ("is_nonzero", TypedOp::Op11(Op11::I64IsNonZero)),
+  ("neg", TypedOp::Op11(Op11::I64Neg)),
+  ("popcount", TypedOp::Op11(Op11::I64Popcount)),
+  ("rev_bits", TypedOp::Op11(Op11::I64RevBits)),
+  ("rev_bytes", TypedOp::Op11(Op11::I64RevBytes)),
+  ("to_i6", TypedOp::Op11(Op11::I64ToI6)),
+  ("bool_and", TypedOp::Op21(Op21::BoolAnd)),
+  ("bool_eq", TypedOp::Op21(Op21::BoolEq)),
+  ("bool_neq", TypedOp::Op21(Op21::BoolNeq)),
+  ("bool_or", TypedOp::Op21(Op21::BoolOr)),
+  ("i128_add", TypedOp::Op21(Op21::I128Add)),
+  ("i128_is_eq", TypedOp::Op21(Op21::I128IsEq)),
+  ("i128_is_ge_s", TypedOp::Op21(Op21::I128IsGeS)),
+  ("i128_is_gt_s", TypedOp::Op21(Op21::I128IsGtS)),
+  ("i128_is_le_s", TypedOp::Op21(Op21::I128IsLeS)),
+  ("i128_is_lt_s", TypedOp::Op21(Op21::I128IsLtS)),
+  ("i128_is_neq", TypedOp::Op21(Op21::I128IsNeq)),
+  ("i128_mul", TypedOp::Op21(Op21::I128Mul)),
+  ("i128_sub", TypedOp::Op21(Op21::I128Sub)),
+  ("add", TypedOp::Op21(Op21::I64Add)),
+  ("bit_and", TypedOp::Op21(Op21::I64BitAnd)),
+  ("bit_or", TypedOp::Op21(Op21::I64BitOr)),
+  ("bit_xor", TypedOp::Op21(Op21::I64BitXor)),
+  ("div_s", TypedOp::Op21(Op21::I64DivS)),
+  ("is_eq", TypedOp::Op21(Op21::I64IsEq)),
+  ("is_ge_s", TypedOp::Op21(Op21::I64IsGeS)),
+  ("is_ge_u", TypedOp::Op21(Op21::I64IsGeU)),
+  ("is_gt_s", TypedOp::Op21(Op21::I64IsGtS)),
+  ("is_gt_u", TypedOp::Op21(Op21::I64IsGtU)),
+  ("is_le_s", TypedOp::Op21(Op21::I64IsLeS)),
+  ("is_le_u", TypedOp::Op21(Op21::I64IsLeU)),
+  ("is_lt_s", TypedOp::Op21(Op21::I64IsLtS)),
+  ("is_lt_u", TypedOp::Op21(Op21::I64IsLtU)),
+  ("is_neq", TypedOp::Op21(Op21::I64IsNeq)),
+  ("max_s", TypedOp::Op21(Op21::I64MaxS)),
+  ("max_u", TypedOp::Op21(Op21::I64MaxU)),
+  ("min_s", TypedOp::Op21(Op21::I64MinS)),
+  ("min_u", TypedOp::Op21(Op21::I64MinU)),
+  ("mul", TypedOp::Op21(Op21::I64Mul)),
+  ("mulfull_s", TypedOp::Op21(Op21::I64MulFullS)),
+  ("mulfull_u", TypedOp::Op21(Op21::I64MulFullU)),
+  ("mulhi_s", TypedOp::Op21(Op21::I64MulHiS)),
+  ("mulhi_u", TypedOp::Op21(Op21::I64MulHiU)),
+  ("rol", TypedOp::Op21(Op21::I64Rol)),
+  ("ror", TypedOp::Op21(Op21::I64Ror)),
+  ("shl", TypedOp::Op21(Op21::I64Shl)),
+  ("shr_s", TypedOp::Op21(Op21::I64ShrS)),
+  ("shr_u", TypedOp::Op21(Op21::I64ShrU)),
+  ("sub", TypedOp::Op21(Op21::I64Sub)),
+  ("i6_is_eq", TypedOp::Op21(Op21::I6IsEq)),
+  ("i6_is_ge", TypedOp::Op21(Op21::I6IsGe)),
+  ("i6_is_gt", TypedOp::Op21(Op21::I6IsGt)),
+  ("i6_is_le", TypedOp::Op21(Op21::I6IsLe)),
+  ("i6_is_lt", TypedOp::Op21(Op21::I6IsLt)),
+  ("i6_is_neq", TypedOp::Op21(Op21::I6IsNeq)),
+];
+
+pub fn intrinsic(name: Symbol) -> Option<TypedOp> {
+  let name = name.as_str();
+  INTRINSICS.iter().find(|x| x.0 == name).map(|x| x.1)
+}
+
+// LENGTH
+//
+// `len(a)` is the length of the array `a`. That is part of the type of `a`,
+// so `len(a)` is a constant and `a` isn't evaluated. Like an intrinsic, `len`
+// only refers to this where nothing of that name is in scope.
+
+pub fn is_len(name: Symbol) -> bool {
+  name.as_str() == "len"
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use bytecode::Inst;
+
+  // The operations that the functions of a program are lowered to.
+
+  fn ops(src: &str) -> Vec<TypedOp> {
+    let mut arena = Arena::new();
+    let aa = arena.allocator_mut();
+    let items = Parser::new(src.as_bytes()).parse_file(aa).unwrap();
+    let items = convert_closures(items, aa);
+    let items = aa.copy_slice(items.as_slice());
+    let structs = StructTable::new(items).unwrap();
+    let names = resolve_names(items).unwrap();
+    let typing = check_types(items, &names, &structs).unwrap();
+    let program = lower_program(items, &names, &typing, &structs, aa).unwrap();
+    let mut out = Vec::new();
+    for f in program.functions.iter() {
+      for x in f.code.iter() {
+        match *x {
+          Inst::Op11(op, _) => out.push(TypedOp::Op11(op)),
+          Inst::Op21(op, _, _) => out.push(TypedOp::Op21(op)),
+          _ => {}
+        }
+      }
+    }
+    out
+  }
+
+  // Each intrinsic takes the operand types of its operation and returns its
+  // result type, and is lowered to just that operation.
+
+  #[test]
+  fn types_and_lowering() {
+    for &(name, op) in INTRINSICS.iter() {
+      let (params, args, result) =
+        match op {
+          TypedOp::Op11(x) => {
+            let ([a], [r]) = x.types();
+            (format!("a: {}", a.to_name()), "a", r)
+          }
+          TypedOp::Op21(x) => {
+            let ([a, b], [r]) = x.types();
+            (format!("a: {}, b: {}", a.to_name(), b.to_name()), "a, b", r)
+          }
+        };
+      let src = format!("fun f({}): {} {}({}) end", params, result.to_name(), name, args);
+      assert_eq!(ops(&src), [op], "{}", src);
+      assert_eq!(intrinsic(Symbol::intern(name)), Some(op));
+    }
+  }
+
+  #[test]
+  fn hidden_by_names_in_scope() {
+    assert_eq!(ops("fun f(a: i64): i64 popcount(a) end"), [TypedOp::Op11(Op11::I64Popcount)]);
+    assert_eq!(ops("fun popcount(x: i64): i64 x end fun f(a: i64): i64 popcount(a) end"), []);
+    assert_eq!(ops("fun f(a: i64): i64 let abs = fun (x: i64): i64 x end abs(a) end"), []);
+    assert!(intrinsic(Symbol::intern("popcnt")).is_none());
+  }
+}
@@ -2,8 +2,11 @@ use crate::prelude::*;
 
 pub struct Lexer<'a> {
   buf: &'a [u8],
+  file: u32,
   start: usize,
   stop: usize,
+  symbol: Symbol,
+  symbols: HashMap<&'a [u8], Symbol>,
   kinds: [Kind; 256],
   jumps: [[State; Kind::VARIANT_COUNT]; 8],
 }
@@ -12,8 +15,11 @@ impl<'a> Lexer<'a> {
   pub fn new(buf: &'a [u8]) -> Self {
     Self {
       buf,
+      file: 0,
       start: 0,
       stop: 0,
+      symbol: Symbol::intern(""),
+      symbols: HashMap::new(),
       kinds: array::from_fn(|c| Kind::classify(c as u8)),
       jumps: [
         [
@@ -61,7 +67,7 @@ impl<'a> Lexer<'a> {
         [
           // Number =>
           State::Number,
-          State::Number,
+          State::TerminalNumber,
           State::TerminalNumber,
           State::TerminalNumber,
           State::TerminalNumber,
@@ -190,6 +196,7 @@ impl<'a> Lexer<'a> {
             b"-" => Token::Minus,
             b"%" => Token::Percent,
             b"|" => Token::Pipe,
+            b"|>" => Token::Pipeline,
             b"+" => Token::Plus,
             b"?" => Token::Query,
             b"/" => Token::Slash,
@@ -199,23 +206,13 @@ impl<'a> Lexer<'a> {
           }
         }
         State::Symbol | State::TerminalSymbol => {
-          match unsafe { buf.get_unchecked(i .. j) } {
-            b"and" => Token::And,
-            b"break" => Token::Break,
-            b"do" => Token::Do,
-            b"elif" => Token::Elif,
-            b"else" => Token::Else,
-            b"end" => Token::End,
-            b"for" => Token::For,
-            b"fun" => Token::Fun,
-            b"if" => Token::If,
-            b"let" => Token::Let,
-            b"loop" => Token::Loop,
-            b"or" => Token::Or,
-            b"return" => Token::Return,
-            b"then" => Token::Then,
-            b"while" => Token::While,
-            _ => Token::Symbol,
+          let x = self.intern(&buf[i .. j]);
+          match x.keyword() {
+            Some(t) => t,
+            None => {
+              self.symbol = x;
+              Token::Symbol
+            }
           }
         }
         State::TerminalPunctuation => {
@@ -250,6 +247,29 @@ impl<'a> Lexer<'a> {
   pub fn span(&self) -> &'a [u8] {
     unsafe { self.buf.get_unchecked(self.start .. self.stop) }
   }
+
+  // Interns a name, which only takes the lock of the table of symbols the
+  // first time that the name occurs in the buffer.
+
+  fn intern(&mut self, x: &'a [u8]) -> Symbol {
+    *self.symbols.entry(x).or_insert_with(|| Symbol::intern(str::from_utf8(x).unwrap()))
+  }
+
+  // The symbol of the last `Token::Symbol` returned by `next`.
+
+  pub fn symbol(&self) -> Symbol {
+    self.symbol
+  }
+
+  // Sets the file that the spans from `loc` are in.
+
+  pub fn set_file(&mut self, file: u32) {
+    self.file = file;
+  }
+
+  pub fn loc(&self) -> Span {
+    Span { file: self.file, start: self.start as u32, stop: self.stop as u32 }
+  }
 }
 
 #[derive(Clone, Copy, Eq, PartialEq, VariantCount)]
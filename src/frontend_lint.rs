@@ -0,0 +1,283 @@
+use crate::prelude::*;
+
+// LINTS
+//
+// Checks for code that is legal but probably not what was meant. Each lint has
+// a level, which decides whether what it finds is ignored, reported as a
+// warning, or reported as an error.
+//
+// This runs on the items that `load_program` produces, after name resolution.
+// A variable or parameter whose name starts with `_` is never reported as
+// unused.
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq, VariantCount)]
+pub enum Lint {
+  IdenticalBranches,
+  LoopWithoutBreak,
+  SelfComparison,
+  UnreachableCode,
+  UnusedVariable,
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Level {
+  Allow,
+  Warn,
+  Deny,
+}
+
+#[derive(Debug)]
+pub struct LintReport {
+  pub lint: Lint,
+  pub level: Level,
+  pub message: String,
+  pub span: Span,
+}
+
+impl Lint {
+  pub fn from_name(name: &str) -> Option<Self> {
+    match name {
+      "identical-branches" => Some(Self::IdenticalBranches),
+      "loop-without-break" => Some(Self::LoopWithoutBreak),
+      "self-comparison" => Some(Self::SelfComparison),
+      "unreachable-code" => Some(Self::UnreachableCode),
+      "unused-variable" => Some(Self::UnusedVariable),
+      _ => None,
+    }
+  }
+
+  pub fn to_name(self) -> &'static str {
+    match self {
+      Self::IdenticalBranches => "identical-branches",
+      Self::LoopWithoutBreak => "loop-without-break",
+      Self::SelfComparison => "self-comparison",
+      Self::UnreachableCode => "unreachable-code",
+      Self::UnusedVariable => "unused-variable",
+    }
+  }
+}
+
+// Every lint warns unless it is set otherwise.
+
+#[derive(Clone)]
+pub struct LintLevels([Level; Lint::VARIANT_COUNT]);
+
+impl Default for LintLevels {
+  fn default() -> Self {
+    Self([Level::Warn; Lint::VARIANT_COUNT])
+  }
+}
+
+impl LintLevels {
+  pub fn get(&self, lint: Lint) -> Level {
+    self.0[lint as usize]
+  }
+
+  pub fn set(&mut self, lint: Lint, level: Level) {
+    self.0[lint as usize] = level;
+  }
+}
+
+pub fn lint_program<'a>(items: &'a [AstItem<'a>], names: &Resolution, levels: &LintLevels) -> Vec<LintReport> {
+  let mut s =
+    Linter {
+      names,
+      levels,
+      used: vec![false; names.bindings.len()],
+      reports: Vec::new(),
+    };
+
+  for item in items.iter() { s.visit_item(item) }
+
+  for (i, x) in names.bindings.iter().enumerate() {
+    if s.used[i] || x.name.0.as_str().starts_with('_') { continue; }
+    match x.kind {
+      BindingKind::Const | BindingKind::Fun => {}
+      BindingKind::Let => s.report(Lint::UnusedVariable, format!("unused variable `{}`", x.name.0), x.name.1),
+      BindingKind::Param => s.report(Lint::UnusedVariable, format!("unused parameter `{}`", x.name.0), x.name.1),
+    }
+  }
+
+  s.reports.sort_by_key(|x| (x.span.file, x.span.start));
+  s.reports
+}
+
+struct Linter<'s> {
+  names: &'s Resolution,
+  levels: &'s LintLevels,
+  used: Vec<bool>,
+  reports: Vec<LintReport>,
+}
+
+impl<'s> Linter<'s> {
+  fn report(&mut self, lint: Lint, message: String, span: Span) {
+    let level = self.levels.get(lint);
+    if level == Level::Allow { return; }
+    self.reports.push(LintReport { lint, level, message, span });
+  }
+
+  // Only the first statement after a `return` or `break` is reported.
+
+  fn block(&mut self, body: &[AstStmt<'_>]) {
+    for (i, stmt) in body.iter().enumerate() {
+      if let AstStmt::Break(_) | AstStmt::Return(_) = stmt {
+        if let Some(x) = body.get(i + 1) {
+          self.report(Lint::UnreachableCode, "unreachable statement".to_string(), x.span());
+        }
+        break;
+      }
+    }
+  }
+}
+
+impl<'s, 'a> Visitor<'a> for Linter<'s> {
+  fn visit_fun_def(&mut self, x: &'a AstFunDef<'a>) {
+    self.block(x.body);
+    walk_fun_def(self, x);
+  }
+
+  fn visit_if(&mut self, x: &'a AstIf<'a>) {
+    self.block(x.1);
+    self.block(x.2);
+    walk_if(self, x);
+  }
+
+  fn visit_lambda(&mut self, x: &'a AstLambda<'a>) {
+    self.block(x.body);
+    walk_lambda(self, x);
+  }
+
+  fn visit_loop(&mut self, x: &'a AstLoop<'a>) {
+    self.block(x.0);
+    walk_loop(self, x);
+  }
+
+  fn visit_match_arm(&mut self, x: &'a AstMatchArm<'a>) {
+    self.block(x.body);
+    walk_match_arm(self, x);
+  }
+
+  // A `loop` that is a statement by itself is the only kind whose value isn't
+  // used.
+
+  fn visit_stmt(&mut self, x: &'a AstStmt<'a>) {
+    match *x {
+      AstStmt::ExprSeq(&AstExprSeq(&[AstExpr::Loop(y)], _)) => self.visit_loop(y),
+      _ => walk_stmt(self, x),
+    }
+  }
+
+  fn visit_expr(&mut self, x: &'a AstExpr<'a>) {
+    match x {
+      AstExpr::If(y) if ! y.2.is_empty() && same_stmts(y.1, y.2) => {
+        self.report(Lint::IdenticalBranches, "both branches of `if` are the same".to_string(), x.span());
+      }
+      AstExpr::Loop(y) if ! has_break(y) => {
+        self.report(Lint::LoopWithoutBreak, "the value of a `loop` without a `break` is used".to_string(), x.span());
+      }
+      AstExpr::OpCall2(y) if is_comparison(y.0) && y.1[0].to_sexp() == y.1[1].to_sexp() => {
+        self.report(Lint::SelfComparison, "comparison of a value with itself".to_string(), x.span());
+      }
+      AstExpr::Symbol(y) => {
+        if let Some(id) = self.names.binding_of(y) { self.used[id.0 as usize] = true }
+      }
+      _ => {}
+    }
+
+    walk_expr(self, x)
+  }
+}
+
+fn is_comparison(op: AstOp) -> bool {
+  matches!(op, AstOp::EQ | AstOp::NE | AstOp::GT | AstOp::GE | AstOp::LT | AstOp::LE)
+}
+
+fn same_stmts(x: &[AstStmt<'_>], y: &[AstStmt<'_>]) -> bool {
+  x.len() == y.len() && x.iter().zip(y.iter()).all(|(a, b)| a.to_sexp() == b.to_sexp())
+}
+
+// A `break` in a nested loop or lambda doesn't leave this loop.
+
+struct FindBreak(bool);
+
+impl<'a> Visitor<'a> for FindBreak {
+  fn visit_break(&mut self, _: &'a AstBreak<'a>) { self.0 = true }
+  fn visit_lambda(&mut self, _: &'a AstLambda<'a>) {}
+  fn visit_loop(&mut self, _: &'a AstLoop<'a>) {}
+}
+
+fn has_break<'a>(x: &'a AstLoop<'a>) -> bool {
+  let mut s = FindBreak(false);
+  for stmt in x.0.iter() { s.visit_stmt(stmt) }
+  s.0
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn lint(src: &str, levels: &LintLevels) -> Vec<(&'static str, Level, usize)> {
+    let mut arena = Arena::new();
+    let aa = arena.allocator_mut();
+    let items = Parser::new(src.as_bytes()).parse_file(aa).unwrap();
+    let names = resolve_names(items).unwrap();
+    lint_program(items, &names, levels).iter().map(|x| (x.lint.to_name(), x.level, x.span.start as usize)).collect()
+  }
+
+  // The names of the lints that a function reports, at the default levels.
+
+  fn names(src: &str) -> Vec<&'static str> {
+    let src = format!("fun g() 0 end {}", src);
+    lint(&src, &LintLevels::default()).iter().map(|x| x.0).collect()
+  }
+
+  #[test]
+  fn identical_branches() {
+    assert_eq!(names("fun f(c) if c then g() else g() end end"), ["identical-branches"]);
+    assert_eq!(names("fun f(c) if c then g() else 1 end end"), Vec::<&str>::new());
+  }
+
+  #[test]
+  fn loop_without_break() {
+    assert_eq!(names("fun f() let x = loop g() end x end"), ["loop-without-break"]);
+    assert_eq!(names("fun f() let x = loop loop break 1 end end x end"), ["loop-without-break"]);
+    assert_eq!(names("fun f() let x = loop break 1 end x end"), Vec::<&str>::new());
+    assert_eq!(names("fun f() loop g() end end"), Vec::<&str>::new());
+  }
+
+  #[test]
+  fn self_comparison() {
+    assert_eq!(names("fun f(a) a + 1 < a + 1 end"), ["self-comparison"]);
+    assert_eq!(names("fun f(a, b) a < b end"), Vec::<&str>::new());
+  }
+
+  #[test]
+  fn unreachable_code() {
+    assert_eq!(names("fun f() return 1 g() end"), ["unreachable-code"]);
+    assert_eq!(names("fun f() loop break 1 g() g() end end"), ["unreachable-code"]);
+    assert_eq!(names("fun f() g() return 1 end"), Vec::<&str>::new());
+  }
+
+  #[test]
+  fn unused_variable() {
+    assert_eq!(names("fun f(a, b) let x = a 1 end"), ["unused-variable", "unused-variable"]);
+    assert_eq!(names("fun f(_a) let _x = 1 2 end"), Vec::<&str>::new());
+  }
+
+  #[test]
+  fn levels() {
+    let src = "fun f(a) a == a end";
+    assert_eq!(lint(src, &LintLevels::default()), [("self-comparison", Level::Warn, 9)]);
+
+    let mut levels = LintLevels::default();
+    levels.set(Lint::SelfComparison, Level::Deny);
+    assert_eq!(lint(src, &levels), [("self-comparison", Level::Deny, 9)]);
+
+    levels.set(Lint::SelfComparison, Level::Allow);
+    assert_eq!(lint(src, &levels), []);
+
+    for lint in [Lint::IdenticalBranches, Lint::LoopWithoutBreak, Lint::SelfComparison, Lint::UnreachableCode, Lint::UnusedVariable] {
+      assert_eq!(Lint::from_name(lint.to_name()), Some(lint));
+    }
+  }
+}
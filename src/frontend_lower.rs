@@ -0,0 +1,832 @@
+use crate::prelude::*;
+
+// LOWERING
+//
+// Turns each function item into a `bytecode::Function`. This runs on the
+// items that `load_program` produces, after name resolution and type
+// checking, and uses their results to choose instructions.
+//
+// Code is built with an `SsaBuilder`. A value is the list of SSA values
+// that hold its scalars, as flattened by `AstType::flatten`, so a tuple or
+// struct is just its components. A `let` binds names to runs of those values
+// and emits no code.
+//
+// Control flow is structured, so an `if`, `loop`, or `match` each jumps to a
+// join block, and every branch defines the same SSA variables to hold its
+// value, which the join block then uses. An index checks that it is in bounds,
+// and traps if not, before the `Index` that picks out the element. A `break`
+// jumps to the join block of the innermost loop, and a `return` returns.
+//
+// A call of a function item by name is a `FunCall` of its index in the
+// program, which is its position among the function items in the order they
+// are written. Constant folding, which runs first, replaces each use of a
+// constant by its value, so constant items emit no code.
+//
+// A value of function type is two i64s, a pointer to code and a pointer to an
+// environment, and calling it passes the environment as an extra first
+// argument. A closure's environment holds its captured values, and the lifted
+// function that it calls unpacks them. A function item used as a value has no
+// environment, so it is called through a trampoline, e.g. `foo$ref`, that
+// drops the environment and calls it. Trampolines are made as needed and come
+// after the function items in the program.
+
+#[derive(Debug)]
+pub enum LowerError {
+  // Something that can't be compiled, such as a function with more variables
+  // than a `VarId` can number, or a function named `malloc`.
+  Unsupported(String, Span),
+}
+
+impl LowerError {
+  pub fn span(&self) -> Span {
+    match self {
+      Self::Unsupported(_, x) => *x,
+    }
+  }
+}
+
+// A match with at most this many values outside its largest arm becomes a
+// `Switch`. Otherwise it is a chain of comparisons. Every match on a bool or
+// an i6 fits.
+
+const SWITCH_LIMIT: usize = 64;
+
+pub fn lower_program<'a>(items: &'a [AstItem<'a>], names: &Resolution, typing: &Typing, structs: &StructTable<'a>, aa: &mut Allocator<'a>) -> Result<bytecode::Program<'a>, Vec<LowerError>> {
+  let mut s =
+    Lowerer {
+      names,
+      typing,
+      structs,
+      aa,
+      ssa: SsaBuilder::new(&[]),
+      funs: HashMap::new(),
+      closures: HashMap::new(),
+      refs: Vec::new(),
+      locals: HashMap::new(),
+      loops: Vec::new(),
+    };
+
+  s.declare(items);
+  for item in items.iter() { s.visit_item(item) }
+
+  let mut functions = Vec::new();
+  let mut errors = Vec::new();
+
+  s.items(items, &mut functions, &mut errors);
+
+  // A trampoline can't ask for another, so this sees all of them.
+
+  for i in 0 .. s.refs.len() {
+    let (id, name) = s.refs[i];
+    match s.trampoline(id, name) {
+      Ok(y) => functions.push(y),
+      Err(e) => errors.push(e),
+    }
+  }
+
+  if ! errors.is_empty() { return Err(errors); }
+
+  Ok(bytecode::Program { functions: s.aa.copy_slice(functions.as_slice()) })
+}
+
+struct Lowerer<'s, 'a> {
+  names: &'s Resolution,
+  typing: &'s Typing,
+  structs: &'s StructTable<'a>,
+  aa: &'s mut Allocator<'a>,
+  ssa: SsaBuilder,
+  funs: HashMap<BindingId, bytecode::FunId>,
+  // The number of captures of each function that a closure calls.
+  closures: HashMap<BindingId, usize>,
+  // The function of each trampoline, in order, and its name.
+  refs: Vec<(BindingId, &'a AstSymbol)>,
+  locals: HashMap<BindingId, Box<[SsaValue]>>,
+  // The join block of each enclosing loop and the variables for its value.
+  loops: Vec<(SsaBlock, Box<[SsaVar]>)>,
+}
+
+// TYPES
+
+impl<'s, 'a> Lowerer<'s, 'a> {
+  fn flatten(&self, t: &Type, out: &mut Vec<Ty>) {
+    match t {
+      Type::Array(x, n) => {
+        for _ in 0 .. *n { self.flatten(x, out) }
+      }
+      Type::Fun(_, _) => {
+        out.push(Ty::I64);
+        out.push(Ty::I64);
+      }
+      Type::Scalar(x) => {
+        out.push(*x);
+      }
+      Type::Struct(x) => {
+        for field in self.structs.get(*x).unwrap().fields.iter() {
+          field.ty.flatten(self.structs, out).unwrap();
+        }
+      }
+      Type::Tuple(xs) => {
+        for x in xs.iter() { self.flatten(x, out) }
+      }
+    }
+  }
+
+  fn tys_of(&self, x: &'a AstExpr<'a>) -> Vec<Ty> {
+    let mut out = Vec::new();
+    self.flatten(self.typing.type_of(x).unwrap(), &mut out);
+    out
+  }
+
+  fn struct_of(&self, x: &'a AstExpr<'a>) -> &'a AstStructDef<'a> {
+    let Some(Type::Struct(name)) = self.typing.type_of(x) else { unreachable!() };
+    self.structs.get(*name).unwrap()
+  }
+}
+
+// CLOSURES
+
+impl<'s, 'a> Visitor<'a> for Lowerer<'s, 'a> {
+  fn visit_closure(&mut self, x: &'a AstClosure<'a>) {
+    let id = self.names.binding_of(&x.0).unwrap();
+    let _ = self.closures.insert(id, x.1.len());
+    walk_closure(self, x);
+  }
+}
+
+// FUNCTIONS
+
+impl<'s, 'a> Lowerer<'s, 'a> {
+  fn declare(&mut self, items: &'a [AstItem<'a>]) {
+    for item in items.iter() {
+      match item {
+        AstItem::FunDef(x) => {
+          let id = self.names.binding_of(&x.name).unwrap();
+          let f = bytecode::FunId(self.funs.len() as u32);
+          let _ = self.funs.insert(id, f);
+        }
+        AstItem::Module(x) => {
+          self.declare(x.items);
+        }
+        AstItem::ConstDef(_) | AstItem::Import(_) | AstItem::MacroDef(_) | AstItem::StaticAssert(_) | AstItem::StructDef(_) => {
+        }
+      }
+    }
+  }
+
+  fn items(&mut self, items: &'a [AstItem<'a>], out: &mut Vec<bytecode::Function<'a>>, errors: &mut Vec<LowerError>) {
+    for item in items.iter() {
+      match item {
+        AstItem::FunDef(x) => {
+          match self.fun_def(x) {
+            Ok(y) => out.push(y),
+            Err(e) => errors.push(e),
+          }
+        }
+        AstItem::Module(x) => {
+          self.items(x.items, out, errors);
+        }
+        AstItem::ConstDef(_) | AstItem::Import(_) | AstItem::MacroDef(_) | AstItem::StaticAssert(_) | AstItem::StructDef(_) => {
+        }
+      }
+    }
+  }
+
+  fn fun_def(&mut self, x: &'a AstFunDef<'a>) -> Result<bytecode::Function<'a>, LowerError> {
+    // The backend may import `malloc` for environments, so a function of the
+    // root module can't take the name.
+
+    if x.name.0.as_str() == "malloc" {
+      return Err(LowerError::Unsupported("`malloc` is reserved".to_string(), x.name.1));
+    }
+
+    let id = self.names.binding_of(&x.name).unwrap();
+    let Type::Fun(params, result) = self.typing.binding_type(id) else { unreachable!() };
+
+    // The function of a closure takes its environment in place of its
+    // captures.
+
+    let k = self.closures.get(&id).copied();
+    let (captures, params) = params.split_at(k.unwrap_or(0));
+
+    let mut env = Vec::new();
+    for t in captures.iter() { self.flatten(t, &mut env) }
+    let mut inputs = Vec::new();
+    if k.is_some() { inputs.push(Ty::I64) }
+    for t in params.iter() { self.flatten(t, &mut inputs) }
+    let mut outputs = Vec::new();
+    self.flatten(result, &mut outputs);
+
+    self.ssa = SsaBuilder::new(&inputs);
+    self.locals.clear();
+    self.loops.clear();
+
+    let mut xs: Vec<_> = (0 .. inputs.len()).map(|i| self.ssa.input(i)).collect();
+    if k.is_some() {
+      let e = xs.remove(0);
+      if ! env.is_empty() {
+        let ys = self.ssa.env_unpack(e, &env);
+        let _ = xs.splice(0 .. 0, ys);
+      }
+    }
+    let mut xs = xs.as_slice();
+    for param in x.params.iter() { self.bind(&param.pat, &mut xs) }
+
+    let xs = self.stmt_seq(x.body)?;
+    if ! self.ssa.is_filled() { self.ssa.ret(&xs) }
+
+    let signature =
+      bytecode::Signature {
+        inputs: self.aa.copy_slice(inputs.as_slice()),
+        outputs: self.aa.copy_slice(outputs.as_slice()),
+      };
+
+    let code = self.finish(&x.name)?;
+
+    Ok(bytecode::Function { name: x.name.0.as_str(), signature, code })
+  }
+
+  // The trampoline that lets a function item be called as a value.
+
+  fn trampoline(&mut self, id: BindingId, name: &'a AstSymbol) -> Result<bytecode::Function<'a>, LowerError> {
+    let Type::Fun(params, result) = self.typing.binding_type(id) else { unreachable!() };
+
+    let mut inputs = vec![Ty::I64];
+    for t in params.iter() { self.flatten(t, &mut inputs) }
+    let mut outputs = Vec::new();
+    self.flatten(result, &mut outputs);
+
+    self.ssa = SsaBuilder::new(&inputs);
+    let xs: Vec<_> = (1 .. inputs.len()).map(|i| self.ssa.input(i)).collect();
+    let ys = self.ssa.fun_call(self.funs[&id], &xs, &outputs);
+    self.ssa.ret(&ys);
+
+    let signature =
+      bytecode::Signature {
+        inputs: self.aa.copy_slice(inputs.as_slice()),
+        outputs: self.aa.copy_slice(outputs.as_slice()),
+      };
+
+    let code = self.finish(name)?;
+
+    let name = Symbol::intern(&format!("{}$ref", name.0));
+
+    Ok(bytecode::Function { name: name.as_str(), signature, code })
+  }
+
+  // The code of the function being built, or an error at its name if it has
+  // too many variables or blocks to number.
+
+  fn finish(&mut self, name: &AstSymbol) -> Result<&'a [bytecode::Inst<'a>], LowerError> {
+    match mem::replace(&mut self.ssa, SsaBuilder::new(&[])).finish(self.aa) {
+      Some(code) => Ok(code),
+      None => Err(LowerError::Unsupported(format!("`{}` has too many variables or blocks", name.0), name.1)),
+    }
+  }
+
+  // The code pointer of a function item used as a value, which is that of
+  // its trampoline.
+
+  fn fun_ref(&mut self, id: BindingId, name: &'a AstSymbol) -> bytecode::FunId {
+    let i =
+      match self.refs.iter().position(|z| z.0 == id) {
+        Some(i) => i,
+        None => {
+          self.refs.push((id, name));
+          self.refs.len() - 1
+        }
+      };
+    bytecode::FunId((self.funs.len() + i) as u32)
+  }
+
+  // Binds the names in a pattern to the leading values of `xs`, and advances
+  // `xs` past them.
+
+  fn bind(&mut self, pat: &'a AstPat<'a>, xs: &mut &[SsaValue]) {
+    match pat {
+      AstPat::Symbol(x) => {
+        let id = self.names.binding_of(x).unwrap();
+        let mut tys = Vec::new();
+        self.flatten(self.typing.binding_type(id), &mut tys);
+        let (a, b) = xs.split_at(tys.len());
+        let _ = self.locals.insert(id, a.into());
+        *xs = b;
+      }
+      AstPat::Tuple(x) => {
+        for y in x.0.iter() { self.bind(y, xs) }
+      }
+    }
+  }
+}
+
+// STATEMENTS AND EXPRESSIONS
+
+impl<'s, 'a> Lowerer<'s, 'a> {
+  // The value of a block is that of its last statement if that is an
+  // expression, and otherwise the empty tuple.
+
+  fn stmt_seq(&mut self, body: &'a [AstStmt<'a>]) -> Result<Vec<SsaValue>, LowerError> {
+    let mut out = Vec::new();
+    for stmt in body.iter() {
+      out.clear();
+      match stmt {
+        AstStmt::Break(x) => {
+          let xs = self.exprs(x.0)?;
+          let (a, ys) = self.loops.last().unwrap().clone();
+          self.join(a, &ys, &xs);
+        }
+        AstStmt::ExprSeq(x) => {
+          out = self.exprs(x.0)?;
+        }
+        AstStmt::Let(x) => {
+          let xs = self.exprs(x.1)?;
+          let mut xs = xs.as_slice();
+          for y in x.0.iter() { self.bind(y, &mut xs) }
+        }
+        AstStmt::Return(x) => {
+          let xs = self.exprs(x.0)?;
+          self.ssa.ret(&xs);
+        }
+      }
+    }
+    Ok(out)
+  }
+
+  fn exprs(&mut self, xs: &'a [AstExpr<'a>]) -> Result<Vec<SsaValue>, LowerError> {
+    let mut out = Vec::new();
+    for x in xs.iter() { out.extend(self.expr(x)?) }
+    Ok(out)
+  }
+
+  fn vars(&mut self, tys: &[Ty]) -> Box<[SsaVar]> {
+    tys.iter().map(|&t| self.ssa.declare_var(t)).collect()
+  }
+
+  // Jumps to a join block with a branch's value. The value of an `if` or
+  // `match` that is unused has no variables, whatever its branches produce.
+
+  fn join(&mut self, a: SsaBlock, ys: &[SsaVar], xs: &[SsaValue]) {
+    if self.ssa.is_filled() { return; }
+    for (&y, &x) in ys.iter().zip(xs.iter()) { self.ssa.def_var(y, x) }
+    self.ssa.goto(a);
+  }
+
+  // Seals a join block once every branch has jumped to it, and starts it.
+
+  fn enter(&mut self, a: SsaBlock, ys: &[SsaVar]) -> Vec<SsaValue> {
+    self.ssa.seal_block(a);
+    self.ssa.switch_to_block(a);
+    ys.iter().map(|&y| self.ssa.use_var(y)).collect()
+  }
+
+  // Starts a block whose only predecessor has already jumped to it.
+
+  fn start(&mut self, a: SsaBlock) {
+    self.ssa.seal_block(a);
+    self.ssa.switch_to_block(a);
+  }
+
+  fn expr(&mut self, x: &'a AstExpr<'a>) -> Result<Vec<SsaValue>, LowerError> {
+    match x {
+      AstExpr::Array(y) => {
+        self.exprs(y.0)
+      }
+      AstExpr::ArrayRepeat(y) => {
+        let xs = self.expr(&y.0)?;
+        let n = y.1.value().unwrap() as usize;
+        Ok(xs.repeat(n))
+      }
+      AstExpr::Bool(y) => {
+        Ok(vec![self.ssa.imm_bool(y.0)])
+      }
+      AstExpr::Closure(y) => {
+        let f = self.funs[&self.names.binding_of(&y.0).unwrap()];
+        let xs = self.exprs(y.1)?;
+        let f = self.ssa.fun_addr(f);
+        Ok(vec![f, self.ssa.env_pack(&xs)])
+      }
+      AstExpr::Lambda(_) | AstExpr::MethodCall(_) | AstExpr::Pipeline(_) | AstExpr::Unquote(_) => {
+        // Removed by module loading and closure conversion, which come
+        // earlier.
+        unreachable!()
+      }
+      AstExpr::Field(y) => {
+        let xs = self.expr(&y.0)?;
+        let def = self.struct_of(&y.0);
+        let slots = self.structs.field_slots(def, y.1.0).unwrap();
+        Ok(xs[slots].to_vec())
+      }
+      AstExpr::FunCall(y) => {
+        if let Some(n) = self.typing.len_of(x) {
+          return Ok(vec![self.ssa.imm_i64(n)]);
+        }
+        match self.typing.op_of(x) {
+          Some(TypedOp::Op11(op)) => {
+            let a = self.expr(&y.1[0])?[0];
+            Ok(vec![self.ssa.op11(op, a)])
+          }
+          Some(TypedOp::Op21(op)) => {
+            let a = self.expr(&y.1[0])?[0];
+            let b = self.expr(&y.1[1])?[0];
+            Ok(vec![self.ssa.op21(op, a, b)])
+          }
+          None => {
+            let f =
+              match &y.0 {
+                AstExpr::Symbol(f) => self.funs.get(&self.names.binding_of(f).unwrap()).copied(),
+                _ => None,
+              };
+            let outputs = self.tys_of(x);
+            match f {
+              Some(f) => {
+                let xs = self.exprs(y.1)?;
+                Ok(self.ssa.fun_call(f, &xs, &outputs))
+              }
+              None => {
+                let f = self.expr(&y.0)?;
+                let Some(Type::Fun(params, _)) = self.typing.type_of(&y.0) else { unreachable!() };
+                let mut inputs = vec![Ty::I64];
+                for t in params.iter() { self.flatten(t, &mut inputs) }
+                let mut xs = vec![f[1]];
+                xs.extend(self.exprs(y.1)?);
+                Ok(self.ssa.fun_call_indirect(f[0], &inputs, &outputs, &xs))
+              }
+            }
+          }
+        }
+      }
+      AstExpr::If(y) => {
+        let c = self.expr(&y.0)?[0];
+        let a = self.ssa.create_block();
+        let b = self.ssa.create_block();
+        let j = self.ssa.create_block();
+        let tys = self.tys_of(x);
+        let ys = self.vars(&tys);
+        self.ssa.if_(c, a, b);
+        self.start(a);
+        let xs = self.stmt_seq(y.1)?;
+        self.join(j, &ys, &xs);
+        self.start(b);
+        let xs = self.stmt_seq(y.2)?;
+        self.join(j, &ys, &xs);
+        Ok(self.enter(j, &ys))
+      }
+      AstExpr::Index(y) => {
+        let xs = self.expr(&y.0)?;
+        let i = self.expr(&y.1)?[0];
+        let Some(&Type::Array(_, n)) = self.typing.type_of(&y.0) else { unreachable!() };
+        let tys = self.tys_of(x);
+        let n = self.ssa.imm_i64(n);
+        let p = self.ssa.op21(Op21::I64IsLtU, i, n);
+        let a = self.ssa.create_block();
+        let trap = self.ssa.create_block();
+        self.ssa.if_(p, a, trap);
+        self.start(trap);
+        self.ssa.trap();
+        self.start(a);
+        Ok(self.ssa.index(i, &xs, &tys))
+      }
+      AstExpr::Loop(y) => {
+        // The header isn't sealed until the back edge is emitted.
+        let a = self.ssa.create_block();
+        let j = self.ssa.create_block();
+        let tys = self.tys_of(x);
+        let ys = self.vars(&tys);
+        self.ssa.goto(a);
+        self.ssa.switch_to_block(a);
+        self.loops.push((j, ys.clone()));
+        let _ = self.stmt_seq(y.0)?;
+        let _ = self.loops.pop();
+        if ! self.ssa.is_filled() { self.ssa.goto(a) }
+        self.ssa.seal_block(a);
+        Ok(self.enter(j, &ys))
+      }
+      AstExpr::Match(y) => {
+        self.match_(x, y)
+      }
+      AstExpr::Number(y) => {
+        let Some(&Type::Scalar(t)) = self.typing.type_of(x) else { unreachable!() };
+        Ok(vec![self.imm(t, y.value().unwrap())])
+      }
+      AstExpr::OpCall1(y) => {
+        let a = self.expr(&y.1[0])?[0];
+        let Some(TypedOp::Op11(op)) = self.typing.op_of(x) else { unreachable!() };
+        Ok(vec![self.ssa.op11(op, a)])
+      }
+      AstExpr::OpCall2(y) => {
+        let a = self.expr(&y.1[0])?[0];
+        let b = self.expr(&y.1[1])?[0];
+        let Some(TypedOp::Op21(op)) = self.typing.op_of(x) else { unreachable!() };
+        Ok(vec![self.ssa.op21(op, a, b)])
+      }
+      AstExpr::Record(y) => {
+        // Fields are evaluated in the order written and laid out in the order
+        // declared.
+        let mut fields = Vec::new();
+        for (name, z) in y.0.iter() { fields.push((name.0, self.expr(z)?)) }
+        let def = self.struct_of(x);
+        let mut out = Vec::new();
+        for field in def.fields.iter() {
+          out.extend_from_slice(&fields.iter().find(|z| z.0 == field.name.0).unwrap().1);
+        }
+        Ok(out)
+      }
+      AstExpr::Symbol(y) => {
+        let id = self.names.binding_of(y).unwrap();
+        match self.locals.get(&id) {
+          Some(xs) => Ok(xs.to_vec()),
+          None => {
+            // Constant folding replaces each use of a constant, so this names
+            // a function item.
+            let f = self.fun_ref(id, y);
+            let f = self.ssa.fun_addr(f);
+            Ok(vec![f, self.ssa.imm_i64(0)])
+          }
+        }
+      }
+      AstExpr::Tuple(y) => {
+        self.exprs(y.0)
+      }
+    }
+  }
+
+  fn imm(&mut self, t: Ty, n: i128) -> SsaValue {
+    match t {
+      Ty::Bool => {
+        self.ssa.imm_bool(n != 0)
+      }
+      Ty::I6 => {
+        self.ssa.imm_i6(u6::from(n as u8))
+      }
+      Ty::I64 => {
+        self.ssa.imm_i64(n as u64)
+      }
+      Ty::I128 => {
+        // There is no 128-bit immediate, so this multiplies out the halves
+        // with widening multiplies: n = hi * 2^32 * 2^32 + lo.
+        let one = self.ssa.imm_i64(1);
+        let lo = self.ssa.imm_i64(n as u64);
+        let lo = self.ssa.op21(Op21::I64MulFullU, lo, one);
+        let hi = (n >> 64) as i64;
+        if hi == 0 { return lo; }
+        let hi = self.ssa.imm_i64(hi as u64);
+        let hi = self.ssa.op21(Op21::I64MulFullS, hi, one);
+        let k = self.ssa.imm_i64(1 << 32);
+        let k = self.ssa.op21(Op21::I64MulFullU, k, one);
+        let hi = self.ssa.op21(Op21::I128Mul, hi, k);
+        let hi = self.ssa.op21(Op21::I128Mul, hi, k);
+        self.ssa.op21(Op21::I128Add, hi, lo)
+      }
+    }
+  }
+
+  fn match_(&mut self, x: &'a AstExpr<'a>, y: &'a AstMatch<'a>) -> Result<Vec<SsaValue>, LowerError> {
+    let s = self.expr(&y.expr)?[0];
+    let Some(&Type::Scalar(t)) = self.typing.type_of(&y.expr) else { unreachable!() };
+    let table = self.typing.match_table(y).unwrap();
+    let arms: Vec<_> = y.arms.iter().map(|_| self.ssa.create_block()).collect();
+    let j = self.ssa.create_block();
+    let tys = self.tys_of(x);
+    let ys = self.vars(&tys);
+
+    // A `Switch` is on at most 64 bits, so a match on an i128 is always a
+    // chain of comparisons.
+
+    let switch = if t == Ty::I128 { None } else { table.to_switch(SWITCH_LIMIT) };
+
+    match switch {
+      Some(switch) => {
+        let cases: Vec<_> = switch.entries.iter().map(|&(v, k)| (v as u64, arms[k])).collect();
+        self.ssa.switch(s, &cases, arms[switch.default]);
+      }
+      None => {
+        // Only an i64 or i128 has too many values for a switch. The cases are
+        // sorted and cover every value, so each comparison rules one out.
+        let (last, cases) = table.cases.split_last().unwrap();
+        let is_le = if t == Ty::I128 { Op21::I128IsLeS } else { Op21::I64IsLeS };
+        for c in cases.iter() {
+          let hi = self.imm(t, c.hi);
+          let p = self.ssa.op21(is_le, s, hi);
+          let a = self.ssa.create_block();
+          self.ssa.if_(p, arms[c.arm], a);
+          self.start(a);
+        }
+        self.ssa.goto(arms[last.arm]);
+      }
+    }
+
+    for (arm, &a) in y.arms.iter().zip(arms.iter()) {
+      self.start(a);
+      let xs = self.stmt_seq(arm.body)?;
+      self.join(j, &ys, &xs);
+    }
+
+    Ok(self.enter(j, &ys))
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use bytecode::Inst;
+  use bytecode::VarId;
+
+  // Runs the passes that `build` runs before lowering, and lists each
+  // function as its name and signature followed by its instructions.
+
+  fn lower(src: &str) -> Result<Vec<String>, Vec<LowerError>> {
+    let mut arena = Arena::new();
+    let aa = arena.allocator_mut();
+    let items = Parser::new(src.as_bytes()).parse_file(aa).unwrap();
+    let items = convert_closures(items, aa);
+    let items = aa.copy_slice(items.as_slice());
+    let structs = StructTable::new(items).unwrap();
+    let names = resolve_names(items).unwrap();
+    let typing = check_types(items, &names, &structs).unwrap();
+    let program = lower_program(items, &names, &typing, &structs, aa)?;
+    let mut out = Vec::new();
+    for f in program.functions.iter() {
+      out.push(format!("fun {} {:?} {:?}", f.name, f.signature.inputs, f.signature.outputs));
+      out.extend(f.code.iter().map(show));
+    }
+    Ok(out)
+  }
+
+  fn show(x: &Inst<'_>) -> String {
+    let vars = |xs: &[VarId]| xs.iter().map(|x| format!(" {}", x.0)).collect::<String>();
+    match *x {
+      Inst::Block(tys) => format!("block {:?}", tys),
+      Inst::EnvPack(xs) => format!("env_pack{}", vars(xs)),
+      Inst::EnvUnpack(x, tys) => format!("env_unpack {} {:?}", x.0, tys),
+      Inst::FunAddr(f) => format!("fun_addr {}", f.0),
+      Inst::FunCall(f, xs) => format!("call {}{}", f.0, vars(xs)),
+      Inst::FunCallIndirect(f, _, xs) => format!("call_indirect {}{}", f.0, vars(xs)),
+      Inst::FunTailCall => "tail_call".to_string(),
+      Inst::FunTailCallIndirect => "tail_call_indirect".to_string(),
+      Inst::Goto(a, xs) => format!("goto {}{}", a.0, vars(xs)),
+      Inst::If(x, a, b) => format!("if {} {} {}", x.0, a.0, b.0),
+      Inst::ImmBool(x) => format!("{}", x),
+      Inst::ImmI6(x) => format!("i6 {}", u8::from(x)),
+      Inst::ImmI64(x) => format!("i64 {}", x as i64),
+      Inst::Index(i, xs, tys) => format!("index {}{} {:?}", i.0, vars(xs), tys),
+      Inst::Op11(op, x) => format!("{:?} {}", op, x.0),
+      Inst::Op21(op, x, y) => format!("{:?} {} {}", op, x.0, y.0),
+      Inst::Ret(xs) => format!("ret{}", vars(xs)),
+      Inst::Switch(x, cases, a) => format!("switch {} {:?} {}", x.0, cases.iter().map(|&(v, b)| (v, b.0)).collect::<Vec<_>>(), a.0),
+      Inst::Trap => "trap".to_string(),
+    }
+  }
+
+  #[test]
+  fn straight_line() {
+    let x = lower("fun f(a: i64, b: i64): i64 a + b end").unwrap();
+    assert_eq!(x, [
+      "fun f [I64, I64] [I64]",
+      "I64Add 0 1",
+      "ret 2",
+    ]);
+  }
+
+  #[test]
+  fn if_joins_with_a_parameter() {
+    let x = lower("fun f(c: bool): i64 if c then 1 else 2 end end").unwrap();
+    assert_eq!(x, [
+      "fun f [Bool] [I64]",
+      "if 0 0 1",
+      "block []",
+      "i64 1",
+      "goto 2 1",
+      "block []",
+      "i64 2",
+      "goto 2 2",
+      "block [I64]",
+      "ret 3",
+    ]);
+  }
+
+  #[test]
+  fn loop_break_and_return() {
+    let x = lower("fun f(n: i64): i64 loop if n < 0 then break n end return 0 end end").unwrap();
+    assert_eq!(x, [
+      "fun f [I64] [I64]",
+      "goto 0",
+      "block []",
+      "i64 0",
+      "I64IsLtS 0 1",
+      "if 2 1 3",
+      "block []",
+      "goto 2",
+      "block []",
+      "ret 0",
+      "block []",
+      "goto 4",
+      "block []",
+      "i64 0",
+      "ret 3",
+    ]);
+  }
+
+  #[test]
+  fn multi_value_let() {
+    let x = lower("fun f(n: i64): (bool, i64) let a, b = n + 1, n > 3 b, a end").unwrap();
+    assert_eq!(x, [
+      "fun f [I64] [Bool, I64]",
+      "i64 1",
+      "I64Add 0 1",
+      "i64 3",
+      "I64IsGtS 0 3",
+      "ret 4 2",
+    ]);
+  }
+
+  #[test]
+  fn match_to_switch() {
+    let x = lower("fun f(n: i64): i64 match n case 0 then 1 case 1 .. 3 then 2 case _ then 3 end end").unwrap();
+    assert_eq!(x, [
+      "fun f [I64] [I64]",
+      "switch 0 [(0, 0), (1, 1), (2, 1), (3, 1)] 2",
+      "block []",
+      "i64 1",
+      "goto 3 1",
+      "block []",
+      "i64 2",
+      "goto 3 2",
+      "block []",
+      "i64 3",
+      "goto 3 3",
+      "block [I64]",
+      "ret 4",
+    ]);
+  }
+
+  #[test]
+  fn match_to_comparisons() {
+    let x = lower("fun f(n: i64): i64 match n case 0 .. 1000000 then 1 case _ then 2 end end").unwrap();
+    assert_eq!(x, [
+      "fun f [I64] [I64]",
+      "i64 -1",
+      "I64IsLeS 0 1",
+      "if 2 3 0",
+      "block []",
+      "i64 1000000",
+      "I64IsLeS 0 3",
+      "if 4 1 2",
+      "block []",
+      "i64 1",
+      "goto 4 5",
+      "block []",
+      "goto 3",
+      "block []",
+      "i64 2",
+      "goto 4 6",
+      "block [I64]",
+      "ret 7",
+    ]);
+  }
+
+  #[test]
+  fn closure_call() {
+    let x = lower("fun f(n: i64): i64 let k = n + 1 let g = fun (q) q + k end g(2) end").unwrap();
+    assert_eq!(x, [
+      "fun f$fun0 [I64, I64] [I64]",
+      "env_unpack 0 [I64]",
+      "I64Add 1 2",
+      "ret 3",
+      "fun f [I64] [I64]",
+      "i64 1",
+      "I64Add 0 1",
+      "fun_addr 0",
+      "env_pack 2",
+      "i64 2",
+      "call_indirect 3 4 5",
+      "ret 6",
+    ]);
+  }
+
+  #[test]
+  fn function_value() {
+    let x = lower("fun f(n: i64): i64 n end fun g(): i64 let h = f h(1) end").unwrap();
+    assert_eq!(x, [
+      "fun f [I64] [I64]",
+      "ret 0",
+      "fun g [] [I64]",
+      "fun_addr 2",
+      "i64 0",
+      "i64 1",
+      "call_indirect 0 1 2",
+      "ret 3",
+      "fun f$ref [I64, I64] [I64]",
+      "call 0 1",
+      "ret 2",
+    ]);
+  }
+
+  #[test]
+  fn unsupported() {
+    let e = lower("fun f(a: [i64; 70000], i: i64): i64 a[i] end").unwrap_err();
+    assert!(matches!(e.as_slice(), [LowerError::Unsupported(x, _)] if x == "`f` has too many variables or blocks"));
+
+    let e = lower("fun malloc(n: i64): i64 n end").unwrap_err();
+    assert!(matches!(e.as_slice(), [LowerError::Unsupported(x, _)] if x == "`malloc` is reserved"));
+    assert_eq!(e[0].span(), Span { file: 0, start: 4, stop: 10 });
+  }
+}
@@ -0,0 +1,795 @@
+use crate::prelude::*;
+
+// MACRO EXPANSION
+//
+// A macro is a quasiquoted template of statements, and a call `m(a, b)` of a
+// macro `m` is replaced by an instance of the template. Inside the template,
+// `$x` unquotes the argument for the parameter `x`, and in a list, that is, in
+// an array, a tuple, the arguments of a call, a `break` or `return`, or a
+// block, `$each(i, lo, hi, e)` splices in a copy of `e` for each `i` from `lo`
+// up to but not including `hi`, with `$i` unquoting the number. The bounds are
+// number literals, or unquotes of numbers, so a macro can generate a table:
+//
+//   macro squares(n)
+//     [$each(i, 0, $n, $i * $i)]
+//   end
+//
+// Templates are instantiated as s-expressions. The template is the sexp of
+// the macro's statements, and instantiating it rewrites that sexp, which is
+// then read back as statements. An unquoted argument stays an unquote in the
+// sexp, and is replaced by a copy of the argument's tree once it is read
+// back.
+//
+// A call that is a statement by itself splices in all of the template's
+// statements, and any other call needs a template that is a single
+// expression.
+//
+// An argument is evaluated as many times as the template's code evaluates it,
+// so `unless(p, e)` need not evaluate `e`. But an argument that the template
+// unquotes in more than one place, or in a splice, is evaluated once, into a
+// variable that is bound before the template's code, unless it is a literal
+// or a variable, which can be copied instead. A call that is an
+// expression and binds an argument expands to a `match` with a single arm,
+// which serves as a block.
+//
+// Expansion is hygienic. The variables that a template binds are renamed for
+// every expansion, e.g. `x@0`, which can't collide with a symbol from the
+// source, so they neither capture nor shadow the variables of the code at the
+// call site. Symbols that a template doesn't bind refer to items, and it is
+// an error if a local variable at the call site has the same name. Every
+// expansion gets new nodes.
+//
+// Macros are visible in the file that declares them, including its inline
+// modules, and they are expanded before names are resolved. A sexp has no
+// spans, so the code from the template gets the span of the call, and the code
+// from the arguments keeps its own. Errors in a call point at the call.
+
+#[derive(Debug)]
+pub enum MacroError {
+  Arity(String, Span),
+  Capture(String, Span),
+  Duplicate(String, Span),
+  NotANumber(Span),
+  NotAnExpr(String, Span),
+  Recursive(String, Span),
+  Splice(Span),
+  Unquote(String, Span),
+}
+
+impl MacroError {
+  pub fn span(&self) -> Span {
+    match self {
+      MacroError::Arity(_, x) => *x,
+      MacroError::Capture(_, x) => *x,
+      MacroError::Duplicate(_, x) => *x,
+      MacroError::NotANumber(x) => *x,
+      MacroError::NotAnExpr(_, x) => *x,
+      MacroError::Recursive(_, x) => *x,
+      MacroError::Splice(x) => *x,
+      MacroError::Unquote(_, x) => *x,
+    }
+  }
+}
+
+// Expanding more nested calls than this is taken to be unbounded recursion.
+
+const MAX_DEPTH: usize = 64;
+
+pub fn expand_macros<'a>(items: &'a [AstItem<'a>], aa: &mut Allocator<'a>) -> Result<Vec<AstItem<'a>>, MacroError> {
+  let mut s = Expander { macros: Vec::new(), count: 0, depth: 0, site: Span { file: 0, start: 0, stop: 0 }, locals: Vec::new(), aa, error: None };
+  let out = s.items(items);
+  match s.error {
+    Some(e) => Err(e),
+    None => Ok(out),
+  }
+}
+
+fn atom(a: &str) -> Sexp {
+  Sexp::from_atom(a)
+}
+
+fn list(a: Vec<Sexp>) -> Sexp {
+  Sexp::List(a.into_boxed_slice())
+}
+
+// The arguments of `$each(i, lo, hi, e)`, if `x` is one.
+
+fn splice(x: &Sexp) -> Option<&[Sexp]> {
+  match x.as_form() {
+    Some(("call", [f, args @ ..])) if matches!(f.as_form(), Some(("unquote", [y])) if y.as_atom() == Some("each")) => Some(args),
+    _ => None,
+  }
+}
+
+// Counts the places where a template unquotes each parameter. One in a splice
+// counts as many.
+
+fn count_uses(x: &Sexp, params: &[AstSymbol], counts: &mut [usize], k: usize) {
+  if let Some([_, _, _, e]) = splice(x) {
+    return count_uses(e, params, counts, 2);
+  }
+  match x.as_form() {
+    Some(("unquote", [y])) => {
+      if let Some(i) = params.iter().position(|z| y.as_atom() == Some(z.0.as_str())) {
+        counts[i] += k;
+      }
+    }
+    _ => {
+      for y in x.as_list().unwrap_or(&[]).iter() { count_uses(y, params, counts, k) }
+    }
+  }
+}
+
+struct Expander<'s, 'a> {
+  macros: Vec<&'a AstMacroDef<'a>>,
+  count: usize,
+  depth: usize,
+  site: Span,
+  locals: Vec<Symbol>,
+  aa: &'s mut Allocator<'a>,
+  error: Option<MacroError>,
+}
+
+impl<'s, 'a> Expander<'s, 'a> {
+  fn fail(&mut self, e: MacroError) {
+    if self.error.is_none() { self.error = Some(e) }
+  }
+
+  fn items(&mut self, items: &'a [AstItem<'a>]) -> Vec<AstItem<'a>> {
+    let n = self.macros.len();
+
+    for &item in items.iter() {
+      if let AstItem::MacroDef(x) = item {
+        if self.macros[n ..].iter().any(|y| y.name.0 == x.name.0) {
+          self.fail(MacroError::Duplicate(x.name.0.to_string(), x.span));
+        }
+        self.macros.push(x);
+      }
+    }
+
+    let mut out = Vec::new();
+
+    for item in items.iter() {
+      if let AstItem::MacroDef(_) = item { continue; }
+      self.locals.clear();
+      out.push(self.fold_item(item));
+    }
+
+    self.macros.truncate(n);
+
+    out
+  }
+
+  // Later declarations shadow earlier ones, so inner modules can redefine a
+  // macro.
+
+  fn find(&self, x: AstExpr<'a>) -> Option<&'a AstMacroDef<'a>> {
+    match x {
+      AstExpr::Symbol(y) => self.macros.iter().rev().find(|z| z.name.0 == y.0).copied(),
+      _ => None,
+    }
+  }
+
+  // Instantiates the template for a call, after the `let`s that bind its
+  // arguments, and returns the statements and the number of `let`s.
+  // Unbounded recursion is reported at the call in the source that started
+  // it.
+
+  fn instantiate(&mut self, m: &'a AstMacroDef<'a>, args: &'a [AstExpr<'a>], span: Span) -> Result<(Vec<Sexp>, usize), MacroError> {
+    if args.len() != m.params.len() {
+      return Err(MacroError::Arity(m.name.0.to_string(), span));
+    }
+
+    if self.depth == 0 {
+      self.site = span;
+    }
+
+    if self.depth == MAX_DEPTH {
+      return Err(MacroError::Recursive(m.name.0.to_string(), self.site));
+    }
+
+    let body = m.body.iter().map(|x| x.to_sexp()).collect::<Vec<_>>();
+    let mut counts = vec![0; m.params.len()];
+    for x in body.iter() { count_uses(x, m.params, &mut counts, 1) }
+
+    let mut out = Vec::new();
+    let mut bound = Vec::new();
+    for (i, x) in m.params.iter().enumerate() {
+      if counts[i] < 2 || matches!(args[i], AstExpr::Bool(_) | AstExpr::Number(_) | AstExpr::Symbol(_)) {
+        bound.push(None);
+        continue;
+      }
+      let y = format!("{}${}", x.0, self.count);
+      out.push(list(vec![atom("let"), atom(&y), atom("="), list(vec![atom("unquote"), atom(x.0.as_str())])]));
+      bound.push(Some(y));
+    }
+    let lets = out.len();
+
+    let mut t =
+      Template {
+        params: m.params,
+        args,
+        bound,
+        metas: Vec::new(),
+        locals: &self.locals,
+        suffix: self.count,
+        renames: Vec::new(),
+        span,
+      };
+    self.count += 1;
+    t.stmts(&body, &mut out)?;
+
+    Ok((out, lets))
+  }
+
+  // Reads back an instance, with copies of the arguments in place of their
+  // unquotes. Instantiating a template only renames its symbols and replaces
+  // unquotes and splices with expressions, so it always reads back.
+
+  fn read_stmts(&mut self, m: &'a AstMacroDef<'a>, x: &[Sexp], args: &'a [AstExpr<'a>], span: Span) -> &'a [AstStmt<'a>] {
+    let mut a = Vec::new();
+    for y in x.iter() { a.push(AstStmt::from_sexp(y, self.aa).unwrap()) }
+    let a = self.aa.copy_slice(a.as_slice());
+    Instance { params: m.params, args, span, aa: self.aa }.fold_block(a)
+  }
+
+  fn read_expr(&mut self, m: &'a AstMacroDef<'a>, x: &Sexp, args: &'a [AstExpr<'a>], span: Span) -> AstExpr<'a> {
+    let x = AstExpr::from_sexp(x, self.aa).unwrap();
+    let x = self.aa.alloc().init(x);
+    Instance { params: m.params, args, span, aa: self.aa }.fold_expr(x)
+  }
+}
+
+// An instance is an expression if it is a single expression after its
+// `let`s. If there are any, it is `match true case _ then ... end`, which is a
+// block that doesn't change what a `break` in it refers to, as a loop would.
+
+fn as_expr(x: Vec<Sexp>, lets: usize) -> Option<Sexp> {
+  if x.len() != lets + 1 { return None; }
+  match x[lets].as_form() {
+    Some(("exprseq", [y])) if lets == 0 => Some(y.clone()),
+    Some(("exprseq", [_])) => {
+      let mut arm = vec![atom("case"), list(vec![atom("_")])];
+      arm.extend(x);
+      Some(list(vec![atom("match"), atom("true"), list(arm)]))
+    }
+    _ => None,
+  }
+}
+
+impl<'s, 'a> Folder<'a, 'a> for Expander<'s, 'a> {
+  fn allocator(&mut self) -> &mut Allocator<'a> {
+    self.aa
+  }
+
+  fn fold_module(&mut self, x: &'a AstModule<'a>) -> AstModule<'a> {
+    let y = self.items(x.items);
+    AstModule { name: x.name, items: self.aa.copy_slice(y.as_slice()), id: NodeId::fresh() }
+  }
+
+  fn fold_param(&mut self, x: &'a AstParam<'a>) -> AstParam<'a> {
+    let y = rebuild_param(self, x);
+    self.bind(y.pat);
+    y
+  }
+
+  // The expansion can contain more macro calls, either from the template or
+  // from the arguments.
+
+  fn fold_block(&mut self, x: &'a [AstStmt<'a>]) -> &'a [AstStmt<'a>] {
+    let n = self.locals.len();
+    let mut a = Vec::new();
+
+    for stmt in x.iter() {
+      if let AstStmt::ExprSeq(AstExprSeq([AstExpr::FunCall(y)], _)) = stmt {
+        if let Some(m) = self.find(y.0) {
+          let span = stmt.span();
+          match self.instantiate(m, y.1, span) {
+            Ok((z, _)) => {
+              let z = self.read_stmts(m, &z, y.1, span);
+              self.depth += 1;
+              a.extend_from_slice(self.fold_block(z));
+              self.depth -= 1;
+            }
+            Err(e) => self.fail(e),
+          }
+          continue;
+        }
+      }
+      a.push(self.fold_stmt(stmt));
+    }
+
+    self.locals.truncate(n);
+    self.aa.copy_slice(a.as_slice())
+  }
+
+  fn fold_let(&mut self, x: &'a AstLet<'a>) -> AstLet<'a> {
+    fold_scoped_let(self, x)
+  }
+
+  fn fold_lambda(&mut self, x: &'a AstLambda<'a>) -> AstLambda<'a> {
+    fold_scoped_lambda(self, x)
+  }
+
+  fn fold_expr(&mut self, x: &'a AstExpr<'a>) -> AstExpr<'a> {
+    match x {
+      AstExpr::FunCall(y) => {
+        if let Some(m) = self.find(y.0) {
+          let span = x.span();
+          match self.instantiate(m, y.1, span) {
+            Ok((z, lets)) => {
+              match as_expr(z, lets) {
+                Some(z) => {
+                  let z = self.read_expr(m, &z, y.1, span);
+                  let z = self.aa.alloc().init(z);
+                  self.depth += 1;
+                  let z = self.fold_expr(z);
+                  self.depth -= 1;
+                  return z;
+                }
+                None => self.fail(MacroError::NotAnExpr(m.name.0.to_string(), span)),
+              }
+            }
+            Err(e) => self.fail(e),
+          }
+        }
+        rebuild_expr(self, x)
+      }
+      AstExpr::Unquote(y) => {
+        self.fail(MacroError::Unquote(y.0.to_string(), y.1));
+        rebuild_expr(self, x)
+      }
+      _ => rebuild_expr(self, x),
+    }
+  }
+}
+
+impl<'s, 'a> ScopedFolder<'a, 'a> for Expander<'s, 'a> {
+  fn locals(&mut self) -> &mut Vec<Symbol> {
+    &mut self.locals
+  }
+}
+
+// Instantiates a template for one call. The methods follow the forms of the
+// sexps that `ToSexp` makes, so that they only rename symbols that are
+// variables, and leave field names, types, and patterns of `match` as they
+// are.
+
+struct Template<'s, 'a> {
+  params: &'a [AstSymbol],
+  args: &'a [AstExpr<'a>],
+  bound: Vec<Option<String>>,
+  metas: Vec<(String, i128)>,
+  locals: &'s [Symbol],
+  suffix: usize,
+  renames: Vec<(String, String)>,
+  span: Span,
+}
+
+impl<'s, 'a> Template<'s, 'a> {
+  // The statements of a block, which is a scope.
+
+  fn stmts(&mut self, x: &[Sexp], out: &mut Vec<Sexp>) -> Result<(), MacroError> {
+    let n = self.renames.len();
+
+    for y in x.iter() {
+      if let Some(("exprseq", [z])) = y.as_form() {
+        if let Some(args) = splice(z) {
+          self.each(args, |t, e| {
+            out.push(list(vec![atom("exprseq"), t.expr(e)?]));
+            Ok(())
+          })?;
+          continue;
+        }
+      }
+      out.push(self.stmt(y)?);
+    }
+
+    self.renames.truncate(n);
+    Ok(())
+  }
+
+  // A form whose head is kept, with the first `n` elements after it as they
+  // are, and the rest a block.
+
+  fn block(&mut self, x: &Sexp, n: usize) -> Result<Sexp, MacroError> {
+    let Some(y) = x.as_list() else { return Ok(x.clone()) };
+    let n = (n + 1).min(y.len());
+    let mut a = y[.. n].to_vec();
+    self.stmts(&y[n ..], &mut a)?;
+    Ok(list(a))
+  }
+
+  fn stmt(&mut self, x: &Sexp) -> Result<Sexp, MacroError> {
+    match x.as_form() {
+      Some(("let", y)) => {
+        let i = y.iter().position(|z| z.as_atom() == Some("=")).unwrap_or(y.len());
+        let exprs = self.exprs(y.get(i + 1 ..).unwrap_or(&[]))?;
+        let mut a = vec![atom("let")];
+        for z in y[.. i].iter() { a.push(self.pat(z)) }
+        a.push(atom("="));
+        a.extend(exprs);
+        Ok(list(a))
+      }
+      Some((head, y)) => {
+        let mut a = vec![atom(head)];
+        a.extend(self.exprs(y)?);
+        Ok(list(a))
+      }
+      None => {
+        Ok(x.clone())
+      }
+    }
+  }
+
+  // A list of expressions, in which `$each` splices.
+
+  fn exprs(&mut self, x: &[Sexp]) -> Result<Vec<Sexp>, MacroError> {
+    let mut a = Vec::new();
+
+    for y in x.iter() {
+      match splice(y) {
+        Some(args) => {
+          self.each(args, |t, e| {
+            a.push(t.expr(e)?);
+            Ok(())
+          })?
+        }
+        None => {
+          a.push(self.expr(y)?)
+        }
+      }
+    }
+
+    Ok(a)
+  }
+
+  fn expr(&mut self, x: &Sexp) -> Result<Sexp, MacroError> {
+    if let Some(a) = x.as_atom() {
+      return self.symbol(a);
+    }
+
+    if splice(x).is_some() {
+      return Err(MacroError::Splice(self.span));
+    }
+
+    let Some((head, y)) = x.as_form() else { return Ok(x.clone()) };
+    let y =
+      match (head, y) {
+        ("unquote", [z]) => {
+          return self.unquote(z);
+        }
+        ("array" | "tuple", z) => {
+          self.exprs(z)?
+        }
+        ("call", [f, z @ ..]) => {
+          let mut a = vec![self.expr(f)?];
+          a.extend(self.exprs(z)?);
+          a
+        }
+        (".", [z, f]) => {
+          vec![self.expr(z)?, f.clone()]
+        }
+        ("fun", z) => {
+          return self.lambda(z);
+        }
+        ("if", [p, z, w]) => {
+          vec![self.expr(p)?, self.block(z, 0)?, self.block(w, 0)?]
+        }
+        ("loop", z) => {
+          let mut a = Vec::new();
+          self.stmts(z, &mut a)?;
+          a
+        }
+        ("match", [z, arms @ ..]) => {
+          let mut a = vec![self.expr(z)?];
+          for arm in arms.iter() { a.push(self.block(arm, 1)?) }
+          a
+        }
+        ("method", [z, f, w @ ..]) => {
+          let mut a = vec![self.expr(z)?, self.expr(f)?];
+          a.extend(self.exprs(w)?);
+          a
+        }
+        ("record", z) => {
+          let mut a = Vec::new();
+          for w in z.iter() {
+            match w.as_form() {
+              Some(("=", [f, e])) => a.push(list(vec![atom("="), f.clone(), self.expr(e)?])),
+              _ => a.push(w.clone()),
+            }
+          }
+          a
+        }
+        ("repeat", [z, n]) => {
+          vec![self.expr(z)?, n.clone()]
+        }
+        (_, z) => {
+          z.iter().map(|w| self.expr(w)).collect::<Result<_, _>>()?
+        }
+      };
+
+    let mut a = vec![atom(head)];
+    a.extend(y);
+    Ok(list(a))
+  }
+
+  // `(fun (params) (: ty) body...)`, whose parameters are in scope in its
+  // body.
+
+  fn lambda(&mut self, x: &[Sexp]) -> Result<Sexp, MacroError> {
+    let n = self.renames.len();
+    let mut a = vec![atom("fun")];
+    let Some((params, mut rest)) = x.split_first() else { return Ok(list(a)) };
+
+    let mut b = Vec::new();
+    for y in params.as_list().unwrap_or(&[]).iter() {
+      match y.as_form() {
+        Some((":", [pat, ty])) => b.push(list(vec![atom(":"), self.pat(pat), ty.clone()])),
+        _ => b.push(self.pat(y)),
+      }
+    }
+    a.push(list(b));
+
+    if let Some((ty, body)) = rest.split_first() {
+      if matches!(ty.as_form(), Some((":", [_]))) {
+        a.push(ty.clone());
+        rest = body;
+      }
+    }
+    self.stmts(rest, &mut a)?;
+
+    self.renames.truncate(n);
+    Ok(list(a))
+  }
+
+  // A pattern binds its variables under new names.
+
+  fn pat(&mut self, x: &Sexp) -> Sexp {
+    match (x.as_atom(), x.as_form()) {
+      (Some(a), _) => {
+        let b = format!("{}@{}", a, self.suffix);
+        self.renames.push((a.to_string(), b.clone()));
+        atom(&b)
+      }
+      (_, Some(("tuple", y))) => {
+        let mut a = vec![atom("tuple")];
+        for z in y.iter() { a.push(self.pat(z)) }
+        list(a)
+      }
+      _ => {
+        x.clone()
+      }
+    }
+  }
+
+  // A symbol that the template doesn't bind refers to an item, so a local
+  // variable at the call site must not capture it.
+
+  fn symbol(&mut self, a: &str) -> Result<Sexp, MacroError> {
+    if a == "false" || a == "true" || a.starts_with(|c: char| c.is_ascii_digit()) {
+      return Ok(atom(a));
+    }
+    if let Some(y) = self.renames.iter().rev().find(|y| y.0 == a) {
+      return Ok(atom(&y.1));
+    }
+    if self.locals.iter().any(|y| y.as_str() == a) {
+      return Err(MacroError::Capture(a.to_string(), self.span));
+    }
+    Ok(atom(a))
+  }
+
+  // The number of a splice, the variable that binds an argument, or the
+  // unquote itself, which is replaced by the argument after reading back.
+
+  fn unquote(&mut self, x: &Sexp) -> Result<Sexp, MacroError> {
+    let a = x.as_atom().unwrap_or("");
+    if let Some(y) = self.metas.iter().rev().find(|y| y.0 == a) {
+      return Ok(atom(&y.1.to_string()));
+    }
+    match self.params.iter().position(|y| y.0.as_str() == a) {
+      Some(i) => {
+        match &self.bound[i] {
+          Some(y) => Ok(atom(y)),
+          None => Ok(list(vec![atom("unquote"), x.clone()])),
+        }
+      }
+      None => {
+        Err(MacroError::Unquote(a.to_string(), self.span))
+      }
+    }
+  }
+
+  // The value of a bound of a splice. If it isn't a number, the error points
+  // at the argument for an unquoted parameter, and otherwise at the call.
+
+  fn number(&self, x: &Sexp) -> Result<i128, MacroError> {
+    let y =
+      match (x.as_atom(), x.as_form()) {
+        (Some(a), _) => {
+          AstNumber(a, self.span, NodeId::fresh())
+        }
+        (_, Some(("unquote", [y]))) => {
+          let a = y.as_atom().unwrap_or("");
+          if let Some(z) = self.metas.iter().rev().find(|z| z.0 == a) { return Ok(z.1); }
+          match self.params.iter().position(|z| z.0.as_str() == a) {
+            Some(i) => {
+              match self.args[i] {
+                AstExpr::Number(z) => *z,
+                z => return Err(MacroError::NotANumber(z.span())),
+              }
+            }
+            None => return Err(MacroError::Unquote(a.to_string(), self.span)),
+          }
+        }
+        _ => {
+          return Err(MacroError::NotANumber(self.span));
+        }
+      };
+    y.value().ok_or(MacroError::NotANumber(y.1))
+  }
+
+  // Calls `f` on the body of `$each(i, lo, hi, e)` once for each `i`.
+
+  fn each(&mut self, args: &[Sexp], mut f: impl FnMut(&mut Self, &Sexp) -> Result<(), MacroError>) -> Result<(), MacroError> {
+    let [i, lo, hi, e] = args else { return Err(MacroError::Splice(self.span)) };
+    let Some(i) = i.as_atom() else { return Err(MacroError::Splice(self.span)) };
+    let bounds = self.number(lo)? .. self.number(hi)?;
+    for k in bounds {
+      self.metas.push((i.to_string(), k));
+      f(self, e)?;
+      let _ = self.metas.pop();
+    }
+    Ok(())
+  }
+}
+
+// Reads back the arguments into an instance. The code from the template gets
+// the span of the call, and the arguments keep their own.
+
+struct Instance<'s, 'a> {
+  params: &'a [AstSymbol],
+  args: &'a [AstExpr<'a>],
+  span: Span,
+  aa: &'s mut Allocator<'a>,
+}
+
+impl<'s, 'a> Folder<'a, 'a> for Instance<'s, 'a> {
+  fn allocator(&mut self) -> &mut Allocator<'a> {
+    self.aa
+  }
+
+  fn fold_expr(&mut self, x: &'a AstExpr<'a>) -> AstExpr<'a> {
+    match x {
+      AstExpr::Unquote(y) => {
+        match self.params.iter().position(|z| z.0 == y.0) {
+          Some(i) => copy_expr(&self.args[i], self.aa),
+          None => rebuild_expr(self, x),
+        }
+      }
+      _ => rebuild_expr(self, x),
+    }
+  }
+
+  fn fold_bool(&mut self, x: &'a AstBool) -> AstBool {
+    AstBool(x.0, self.span, NodeId::fresh())
+  }
+
+  fn fold_match(&mut self, x: &'a AstMatch<'a>) -> AstMatch<'a> {
+    AstMatch { span: self.span, ..rebuild_match(self, x) }
+  }
+
+  fn fold_match_arm(&mut self, x: &'a AstMatchArm<'a>) -> AstMatchArm<'a> {
+    AstMatchArm { span: self.span, ..rebuild_match_arm(self, x) }
+  }
+
+  fn fold_number(&mut self, x: &'a AstNumber<'a>) -> AstNumber<'a> {
+    AstNumber(self.aa.copy_str(x.0), self.span, NodeId::fresh())
+  }
+
+  fn fold_symbol(&mut self, x: &'a AstSymbol) -> AstSymbol {
+    AstSymbol(x.0, self.span, NodeId::fresh())
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn expand(src: &str) -> Result<Vec<String>, MacroError> {
+    let mut arena = Arena::new();
+    let aa = arena.allocator_mut();
+    let items = Parser::new(src.as_bytes()).parse_file(aa).unwrap();
+    let items = expand_macros(items, aa)?;
+    Ok(items.iter().map(|x| x.to_sexp().to_string()).collect())
+  }
+
+  // The span of the last occurrence of `s`, and where it starts, which is
+  // where the span of a call that is `s` starts.
+
+  fn span_of(src: &str, s: &str) -> Span {
+    let i = src.rfind(s).unwrap();
+    Span { file: 0, start: i as u32, stop: (i + s.len()) as u32 }
+  }
+
+  fn start_of(src: &str, s: &str) -> u32 {
+    span_of(src, s).start
+  }
+
+  #[test]
+  fn hygiene() {
+    let src = "macro m(a) let x = $a let f = fun (x) x + h end f(x) end fun g(x) m(x) x end";
+    assert_eq!(expand(src).unwrap(), [
+      "(fundef g (x) (let x@0 = x) (let f@0 = (fun (x@0) (exprseq (+ x@0 h)))) (exprseq (call f@0 x@0)) (exprseq x))",
+    ]);
+
+    let src = "macro m() f(1) end fun g(f) m() end";
+    assert!(matches!(expand(src), Err(MacroError::Capture(x, y)) if x == "f" && y.start == start_of(src, "m()")));
+  }
+
+  #[test]
+  fn single_evaluation() {
+    let src = "macro dbl(v) $v + $v end fun f(a) dbl(a) + dbl(g(a)) end";
+    assert_eq!(expand(src).unwrap(), [
+      "(fundef f (a) (exprseq (+ (+ a a) (match true (case (_) (let v$1 = (call g a)) (exprseq (+ v$1 v$1)))))))",
+    ]);
+
+    let src = "macro unless(p, e) if $p then 0 else $e end end fun f(a) unless(a, g(a)) end";
+    assert_eq!(expand(src).unwrap(), [
+      "(fundef f (a) (exprseq (if a (then (exprseq 0)) (else (exprseq (call g a))))))",
+    ]);
+  }
+
+  #[test]
+  fn each() {
+    let src = "macro squares(n) [$each(i, 0, $n, $i * $i)] end fun f() squares(3) end";
+    assert_eq!(expand(src).unwrap(), ["(fundef f () (exprseq (array (* 0 0) (* 1 1) (* 2 2))))"]);
+
+    let src = "macro calls(n) $each(i, 1, $n, g($i)) end fun f() calls(3) end";
+    assert_eq!(expand(src).unwrap(), ["(fundef f () (exprseq (call g 1)) (exprseq (call g 2)))"]);
+
+    let src = "macro squares(n) [$each(i, 0, $n, $i * $i)] end fun f(k) squares(k + 1) end";
+    assert!(matches!(expand(src), Err(MacroError::NotANumber(x)) if x == span_of(src, "k + 1")));
+
+    let src = "macro m(n) $each(i, 0, $n, $i) + 1 end fun f() m(2) end";
+    assert!(matches!(expand(src), Err(MacroError::Splice(_))));
+  }
+
+  #[test]
+  fn recursion() {
+    let src = "macro r(x) r($x) end fun f() r(1) end";
+    assert!(matches!(expand(src), Err(MacroError::Recursive(x, y)) if x == "r" && y.start == start_of(src, "r(1)")));
+  }
+
+  #[test]
+  fn errors() {
+    let src = "macro m(x) $x end fun f() m(1, 2) end";
+    assert!(matches!(expand(src), Err(MacroError::Arity(x, _)) if x == "m"));
+
+    let src = "macro m(x) $y end fun f() m(1) end";
+    assert!(matches!(expand(src), Err(MacroError::Unquote(x, _)) if x == "y"));
+
+    let src = "macro m(x) let y = $x end fun f() m(1) + 1 end";
+    assert!(matches!(expand(src), Err(MacroError::NotAnExpr(x, _)) if x == "m"));
+
+    let src = "macro m(x) $x end macro m(x) $x end";
+    assert!(matches!(expand(src), Err(MacroError::Duplicate(x, _)) if x == "m"));
+  }
+
+  // The code from the template points at the call, and the arguments at
+  // themselves.
+
+  #[test]
+  fn spans() {
+    let src = "macro inc(v) $v + one end fun f(a) inc(a) end";
+    let mut arena = Arena::new();
+    let aa = arena.allocator_mut();
+    let items = Parser::new(src.as_bytes()).parse_file(aa).unwrap();
+    let items = expand_macros(items, aa).unwrap();
+    let AstItem::FunDef(f) = items[0] else { panic!() };
+    let [AstStmt::ExprSeq(AstExprSeq([AstExpr::OpCall2(x)], _))] = f.body else { panic!() };
+    let [AstExpr::Symbol(a), AstExpr::Symbol(one)] = x.1 else { panic!() };
+    assert_eq!(a.1, span_of(src, "a"));
+    assert_eq!(one.1.start, start_of(src, "inc(a)"));
+  }
+}
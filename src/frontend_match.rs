@@ -0,0 +1,262 @@
+use crate::prelude::*;
+
+// MATCH CHECKING
+//
+// Every pattern denotes an interval of values of the scrutinee's type, with
+// bools treated as 0 and 1 and i6s as unsigned. Checking a `match` walks the
+// arms in order while keeping track of the values that no earlier arm
+// covers. That finds unreachable arms and a witness for a non-exhaustive
+// match, and what it leaves behind is a table of disjoint intervals that
+// together cover the type, each with the arm that handles it.
+
+#[derive(Debug)]
+pub enum MatchError {
+  BadPattern(Span),
+  EmptyRange(Span),
+  NonExhaustive(Span, i128),
+  Unreachable(Span),
+}
+
+impl MatchError {
+  pub fn span(&self) -> Span {
+    match self {
+      Self::BadPattern(x) | Self::EmptyRange(x) | Self::NonExhaustive(x, _) | Self::Unreachable(x) => *x,
+    }
+  }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct MatchCase {
+  pub lo: i128,
+  pub hi: i128,
+  pub arm: usize,
+}
+
+// Sorted by `lo`, with adjacent cases always going to different arms.
+
+pub struct MatchTable {
+  pub cases: Box<[MatchCase]>,
+}
+
+// A multiway branch: the arm for each listed value, and a default arm for
+// every other value.
+
+pub struct MatchSwitch {
+  pub entries: Box<[(i128, usize)]>,
+  pub default: usize,
+}
+
+pub fn domain(ty: Ty) -> (i128, i128) {
+  match ty {
+    Ty::Bool => (0, 1),
+    Ty::I128 => (i128::MIN, i128::MAX),
+    Ty::I6 => (0, 63),
+    Ty::I64 => (i64::MIN as i128, i64::MAX as i128),
+  }
+}
+
+fn pat_interval(ty: Ty, pat: AstMatchPat<'_>, span: Span) -> Result<(i128, i128), MatchError> {
+  let (lo, hi) = domain(ty);
+  let in_domain = |x: AstNumber<'_>| {
+    match x.value() {
+      Some(n) if lo <= n && n <= hi => Ok(n),
+      _ => Err(MatchError::BadPattern(span)),
+    }
+  };
+
+  match pat {
+    AstMatchPat::Bool(x) => {
+      if ty != Ty::Bool { return Err(MatchError::BadPattern(span)); }
+      let n = x.0 as i128;
+      Ok((n, n))
+    }
+    AstMatchPat::Number(&x) => {
+      if ty == Ty::Bool { return Err(MatchError::BadPattern(span)); }
+      let n = in_domain(x)?;
+      Ok((n, n))
+    }
+    AstMatchPat::Range(&AstRange(x, y)) => {
+      if ty == Ty::Bool { return Err(MatchError::BadPattern(span)); }
+      let a = in_domain(x)?;
+      let b = in_domain(y)?;
+      if a > b { return Err(MatchError::EmptyRange(span)); }
+      Ok((a, b))
+    }
+    AstMatchPat::Wildcard => {
+      Ok((lo, hi))
+    }
+  }
+}
+
+pub fn check_match(x: &AstMatch<'_>, ty: Ty) -> Result<MatchTable, MatchError> {
+  let mut uncovered = Vec::new();
+  let mut cases = Vec::new();
+
+  uncovered.push(domain(ty));
+
+  for (i, arm) in x.arms.iter().enumerate() {
+    for &pat in arm.pats.iter() {
+      let (a, b) = pat_interval(ty, pat, arm.span)?;
+      let mut is_useful = false;
+      let mut rest = Vec::new();
+
+      for &(u, v) in uncovered.iter() {
+        if v < a || b < u {
+          rest.push((u, v));
+          continue;
+        }
+        let p = i128::max(u, a);
+        let q = i128::min(v, b);
+        cases.push(MatchCase { lo: p, hi: q, arm: i });
+        is_useful = true;
+        if u < p { rest.push((u, p - 1)); }
+        if q < v { rest.push((q + 1, v)); }
+      }
+
+      if ! is_useful { return Err(MatchError::Unreachable(arm.span)); }
+
+      uncovered = rest;
+    }
+  }
+
+  if let Some(&(u, _)) = uncovered.first() {
+    return Err(MatchError::NonExhaustive(x.span, u));
+  }
+
+  cases.sort_by_key(|c| c.lo);
+
+  let mut merged: Vec<MatchCase> = Vec::new();
+
+  for &c in cases.iter() {
+    match merged.last_mut() {
+      Some(d) if d.arm == c.arm => { d.hi = c.hi; }
+      _ => { merged.push(c); }
+    }
+  }
+
+  Ok(MatchTable { cases: merged.into_boxed_slice() })
+}
+
+impl MatchTable {
+  // Lowering to a multiway branch is possible when the arm covering the most
+  // values is made the default and there are at most `limit` values left
+  // over. Otherwise the sorted cases are suitable for a binary search.
+
+  pub fn to_switch(&self, limit: usize) -> Option<MatchSwitch> {
+    let num_arms = self.cases.iter().map(|c| c.arm + 1).max().unwrap_or(0);
+    let mut sizes = vec![0u128; num_arms];
+
+    for c in self.cases.iter() {
+      let n = (c.hi as u128).wrapping_sub(c.lo as u128).saturating_add(1);
+      sizes[c.arm] = sizes[c.arm].saturating_add(n);
+    }
+
+    let default = (0 .. num_arms).max_by_key(|&i| sizes[i])?;
+    let other = sizes.iter().enumerate().filter(|&(i, _)| i != default).map(|(_, &n)| n);
+
+    if other.fold(0u128, |x, y| x.saturating_add(y)) > limit as u128 {
+      return None;
+    }
+
+    let mut entries = Vec::new();
+
+    for c in self.cases.iter() {
+      if c.arm != default {
+        for v in c.lo ..= c.hi { entries.push((v, c.arm)); }
+      }
+    }
+
+    Some(MatchSwitch { entries: entries.into_boxed_slice(), default })
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn check(src: &str, ty: Ty) -> Result<MatchTable, MatchError> {
+    let mut arena = Arena::new();
+    let aa = arena.allocator_mut();
+    let AstExpr::Match(x) = Parser::new(src.as_bytes()).parse_expr(aa).unwrap() else { panic!("not a match") };
+    check_match(x, ty)
+  }
+
+  fn cases(x: &MatchTable) -> Vec<(i128, i128, usize)> {
+    x.cases.iter().map(|c| (c.lo, c.hi, c.arm)).collect()
+  }
+
+  #[test]
+  fn table() {
+    let x = check("match x case 0, 1 then a case 2..10 then b case _ then c end", Ty::I64).unwrap();
+    let min = i64::MIN as i128;
+    let max = i64::MAX as i128;
+    assert_eq!(cases(&x), [(min, -1, 2), (0, 1, 0), (2, 10, 1), (11, max, 2)]);
+
+    let x = check("match x case 5 then a case _ then a end", Ty::I6).unwrap();
+    assert_eq!(cases(&x), [(0, 4, 1), (5, 5, 0), (6, 63, 1)]);
+
+    let x = check("match x case false then a case true then b end", Ty::Bool).unwrap();
+    assert_eq!(cases(&x), [(0, 0, 0), (1, 1, 1)]);
+  }
+
+  #[test]
+  fn non_exhaustive() {
+    let e = check("match x case true then a end", Ty::Bool);
+    assert!(matches!(e, Err(MatchError::NonExhaustive(_, 0))));
+
+    let e = check("match x case 0..62 then a end", Ty::I6);
+    assert!(matches!(e, Err(MatchError::NonExhaustive(_, 63))));
+
+    let e = check("match x case 0..100 then a end", Ty::I128);
+    assert!(matches!(e, Err(MatchError::NonExhaustive(_, i128::MIN))));
+  }
+
+  #[test]
+  fn unreachable() {
+    let e = check("match x case 1..5 then a case 3 then b case _ then c end", Ty::I64);
+    assert!(matches!(e, Err(MatchError::Unreachable(_))));
+
+    let e = check("match x case _ then a case 0 then b end", Ty::I64);
+    assert!(matches!(e, Err(MatchError::Unreachable(_))));
+
+    // A pattern that overlaps earlier ones is fine while it covers something.
+
+    assert!(check("match x case 1..5 then a case 0..9 then b case _ then c end", Ty::I64).is_ok());
+  }
+
+  #[test]
+  fn bad_pattern() {
+    let e = check("match x case 64 then a case _ then b end", Ty::I6);
+    assert!(matches!(e, Err(MatchError::BadPattern(_))));
+
+    let e = check("match x case 1 then a case _ then b end", Ty::Bool);
+    assert!(matches!(e, Err(MatchError::BadPattern(_))));
+
+    let e = check("match x case true then a case _ then b end", Ty::I64);
+    assert!(matches!(e, Err(MatchError::BadPattern(_))));
+
+    let e = check("match x case 5..1 then a case _ then b end", Ty::I64);
+    assert!(matches!(e, Err(MatchError::EmptyRange(_))));
+  }
+
+  #[test]
+  fn switch_limit() {
+    let x = check("match x case 0, 1 then a case 2..10 then b case _ then c end", Ty::I64).unwrap();
+
+    let y = x.to_switch(11).unwrap();
+    assert_eq!(y.default, 2);
+    assert_eq!(y.entries.len(), 11);
+    assert_eq!(y.entries[0], (0, 0));
+    assert_eq!(y.entries[2], (2, 1));
+    assert_eq!(y.entries[10], (10, 1));
+
+    assert!(x.to_switch(10).is_none());
+
+    // The default is the arm with the most values, wherever it is.
+
+    let x = check("match x case 0..59 then a case 60 then b case _ then c end", Ty::I6).unwrap();
+    let y = x.to_switch(4).unwrap();
+    assert_eq!(y.default, 0);
+    assert_eq!(&*y.entries, [(60, 1), (61, 2), (62, 2), (63, 2)]);
+  }
+}
@@ -0,0 +1,491 @@
+use crate::prelude::*;
+
+// MODULES
+//
+// Every file is a module, and `module m ... end` declares a submodule inline.
+// Module `a.b` is the file `a/b.adelie` under the root directory, unless it is
+// declared inline in `a.adelie`. The file that compilation starts from is the
+// root module.
+//
+// Loading a program reads the root module and everything that it imports,
+// transitively, and flattens them into a single list of items. Each item is
+// renamed to its global name, which is its module path and its name joined by
+// dots, e.g. `math.gcd`, and references to it are rewritten to match. A symbol
+// can't contain a dot, so global names don't collide, and they are used as is
+// in the object file. The items of the root module keep their names.
+//
+// An item is private to its module unless it is declared `pub`.
+//
+// The files are read in waves. The files of a wave are parsed in parallel,
+// and the files that they import and that aren't loaded yet make up the next
+// wave. Imports are linked once everything is loaded.
+
+#[derive(Debug)]
+pub enum ModuleError {
+  Duplicate(String, Span),
+  Io(PathBuf, io::Error),
+  Macro(PathBuf, MacroError),
+  NotAValue(String, Span),
+  NotFound(String, Span),
+  Parse(PathBuf),
+  Private(String, Span),
+}
+
+impl ModuleError {
+  // Where the error is, unless it is about a file as a whole.
+
+  pub fn span(&self) -> Option<Span> {
+    match self {
+      Self::Duplicate(_, x) | Self::NotAValue(_, x) | Self::NotFound(_, x) | Self::Private(_, x) => Some(*x),
+      Self::Macro(_, e) => Some(e.span()),
+      Self::Io(_, _) | Self::Parse(_) => None,
+    }
+  }
+}
+
+struct Module<'a> {
+  path: Vec<Symbol>,
+  items: &'a [AstItem<'a>],
+  imports: Vec<usize>,
+}
+
+pub fn load_program<'a>(root: &Path, file: &Path, aa: &mut Allocator<'a>) -> Result<Vec<AstItem<'a>>, ModuleError> {
+  load_program_sources(root, file, &mut Vec::new(), aa)
+}
+
+// Like `load_program`, but also adds the files that were read to `units`,
+// which starts out empty, so that the spans of the items and of any error are
+// in them. They are added even if loading fails.
+
+pub fn load_program_sources<'a>(root: &Path, file: &Path, units: &mut Vec<ParsedUnit>, aa: &mut Allocator<'a>) -> Result<Vec<AstItem<'a>>, ModuleError> {
+  let mut s = Loader { root: root.to_path_buf(), modules: Vec::new(), units };
+
+  let mut files = vec![(Vec::new(), file.to_path_buf())];
+
+  while ! files.is_empty() {
+    let n = s.modules.len();
+    for unit in read_units(s.units.len() as u32, &files) { s.add_unit(unit?, aa)? }
+    files = s.missing(n);
+  }
+
+  for i in 0 .. s.modules.len() {
+    s.link(i)?;
+  }
+
+  let mut out = Vec::new();
+
+  for i in 0 .. s.modules.len() {
+    let mut r = Resolver { modules: &s.modules, cur: i, locals: Vec::new(), aa, error: None };
+    r.module(&mut out)?;
+  }
+
+  Ok(out)
+}
+
+fn dotted(path: &[Symbol]) -> String {
+  path.iter().map(|x| x.as_str()).collect::<Vec<_>>().join(".")
+}
+
+struct Loader<'u, 'a> {
+  root: PathBuf,
+  modules: Vec<Module<'a>>,
+  units: &'u mut Vec<ParsedUnit>,
+}
+
+impl<'u, 'a> Loader<'u, 'a> {
+  // The tree of a unit is copied into the program's arena, where its macros
+  // are expanded. A module is declared at the start of its file.
+
+  fn add_unit(&mut self, unit: ParsedUnit, aa: &mut Allocator<'a>) -> Result<(), ModuleError> {
+    let file = self.units.len() as u32;
+    self.units.push(unit);
+    let unit = &self.units[file as usize];
+    let path = unit.module().to_vec();
+    let items = copy_items(unit.items(), aa);
+    let items = aa.copy_slice(items.as_slice());
+    let items =
+      match expand_macros(items, aa) {
+        Ok(items) => aa.copy_slice(items.as_slice()),
+        Err(e) => return Err(ModuleError::Macro(unit.path().to_path_buf(), e)),
+      };
+    self.add(path, items, Span { file, start: 0, stop: 0 })
+  }
+
+  fn find(&self, path: &[Symbol]) -> Option<usize> {
+    self.modules.iter().position(|m| m.path == path)
+  }
+
+  fn add(&mut self, path: Vec<Symbol>, items: &'a [AstItem<'a>], span: Span) -> Result<(), ModuleError> {
+    if self.find(&path).is_some() {
+      return Err(ModuleError::Duplicate(dotted(&path), span));
+    }
+
+    self.modules.push(Module { path: path.clone(), items, imports: Vec::new() });
+
+    for &item in items.iter() {
+      if let AstItem::Module(x) = item {
+        let mut p = path.clone();
+        p.push(x.name.0);
+        self.add(p, x.items, x.name.1)?;
+      }
+    }
+
+    Ok(())
+  }
+
+  // Module `a.b.c` is declared by one of `a/b/c.adelie`, `a/b.adelie`, or
+  // `a.adelie`, and the first of those that exists is the one that is loaded.
+  // These are the files to load for the imports of the modules from `i` on.
+
+  fn missing(&self, i: usize) -> Vec<(Vec<Symbol>, PathBuf)> {
+    let mut out: Vec<(Vec<Symbol>, PathBuf)> = Vec::new();
+
+    for m in self.modules[i ..].iter() {
+      for &item in m.items.iter() {
+        let AstItem::Import(x) = item else { continue };
+        let path: Vec<Symbol> = x.0.iter().map(|y| y.0).collect();
+        for k in (1 ..= path.len()).rev() {
+          let prefix = &path[.. k];
+          if self.find(prefix).is_some() || out.iter().any(|y| y.0 == prefix) { break; }
+          let mut file = self.root.clone();
+          for seg in prefix.iter() { file.push(seg.as_str()) }
+          let _ = file.set_extension("adelie");
+          if file.is_file() {
+            out.push((prefix.to_vec(), file));
+            break;
+          }
+        }
+      }
+    }
+
+    out
+  }
+
+  fn link(&mut self, i: usize) -> Result<(), ModuleError> {
+    for &item in self.modules[i].items.iter() {
+      if let AstItem::Import(x) = item {
+        let path: Vec<Symbol> = x.0.iter().map(|y| y.0).collect();
+        match self.find(&path) {
+          Some(j) => self.modules[i].imports.push(j),
+          None => return Err(ModuleError::NotFound(dotted(&path), x.0[0].1.join(x.0[x.0.len() - 1].1))),
+        }
+      }
+    }
+
+    Ok(())
+  }
+}
+
+// Rewrites a module's items to refer to items by their global names. The
+// first error is kept, and the rest of the module is still rebuilt.
+
+struct Resolver<'s, 't, 'a> {
+  modules: &'s [Module<'a>],
+  cur: usize,
+  locals: Vec<Symbol>,
+  aa: &'t mut Allocator<'a>,
+  error: Option<ModuleError>,
+}
+
+impl<'s, 't, 'a> Resolver<'s, 't, 'a> {
+  fn qualified_name(&self, m: usize, name: Symbol) -> String {
+    let mut a = self.modules[m].path.clone();
+    a.push(name);
+    dotted(&a)
+  }
+
+  fn global_name(&self, m: usize, name: AstSymbol) -> AstSymbol {
+    if self.modules[m].path.is_empty() {
+      name
+    } else {
+      AstSymbol(Symbol::intern(&self.qualified_name(m, name.0)), name.1, NodeId::fresh())
+    }
+  }
+
+  fn child(&self, m: usize, name: Symbol) -> Option<usize> {
+    let path = &self.modules[m].path;
+    self.modules.iter().position(|x| {
+      x.path.len() == path.len() + 1 && x.path.starts_with(path) && x.path[path.len()] == name
+    })
+  }
+
+  // The modules that are visible by name are the imports and submodules.
+
+  fn module_alias(&self, name: Symbol) -> Option<usize> {
+    for &j in self.modules[self.cur].imports.iter() {
+      if self.modules[j].path.last() == Some(&name) { return Some(j); }
+    }
+    self.child(self.cur, name)
+  }
+
+  fn module_of(&self, expr: AstExpr<'a>) -> Option<usize> {
+    match expr {
+      AstExpr::Symbol(x) if ! self.locals.contains(&x.0) => self.module_alias(x.0),
+      AstExpr::Field(x) => self.module_of(x.0).and_then(|m| self.child(m, x.1.0)),
+      _ => None,
+    }
+  }
+
+  fn lookup(&self, m: usize, name: AstSymbol) -> Result<Option<AstSymbol>, ModuleError> {
+    for &item in self.modules[m].items.iter() {
+      let (is_pub, x) =
+        match item {
+          AstItem::ConstDef(x) => (x.is_pub, x.name),
+          AstItem::FunDef(x) => (x.is_pub, x.name),
+          AstItem::StructDef(x) => (x.is_pub, x.name),
+          AstItem::Import(_) | AstItem::MacroDef(_) | AstItem::Module(_) | AstItem::StaticAssert(_) => continue,
+        };
+      if x.0 == name.0 {
+        if m != self.cur && ! is_pub {
+          return Err(ModuleError::Private(self.qualified_name(m, name.0), name.1));
+        }
+        return Ok(Some(self.global_name(m, name)));
+      }
+    }
+    Ok(None)
+  }
+
+  fn fail(&mut self, e: ModuleError) {
+    if self.error.is_none() { self.error = Some(e) }
+  }
+
+  fn module(&mut self, out: &mut Vec<AstItem<'a>>) -> Result<(), ModuleError> {
+    let items = self.modules[self.cur].items;
+
+    for item in items.iter() {
+      match item {
+        AstItem::ConstDef(_) | AstItem::FunDef(_) | AstItem::StaticAssert(_) | AstItem::StructDef(_) => {
+          self.locals.clear();
+          out.push(self.fold_item(item));
+        }
+        AstItem::Import(_) | AstItem::MacroDef(_) | AstItem::Module(_) => {
+        }
+      }
+    }
+
+    match self.error.take() {
+      Some(e) => Err(e),
+      None => Ok(()),
+    }
+  }
+}
+
+impl<'s, 't, 'a> Folder<'a, 'a> for Resolver<'s, 't, 'a> {
+  fn allocator(&mut self) -> &mut Allocator<'a> {
+    self.aa
+  }
+
+  fn fold_const_def(&mut self, x: &'a AstConstDef<'a>) -> AstConstDef<'a> {
+    AstConstDef { name: self.global_name(self.cur, x.name), ..rebuild_const_def(self, x) }
+  }
+
+  fn fold_fun_def(&mut self, x: &'a AstFunDef<'a>) -> AstFunDef<'a> {
+    AstFunDef { name: self.global_name(self.cur, x.name), ..rebuild_fun_def(self, x) }
+  }
+
+  fn fold_struct_def(&mut self, x: &'a AstStructDef<'a>) -> AstStructDef<'a> {
+    AstStructDef { name: self.global_name(self.cur, x.name), ..rebuild_struct_def(self, x) }
+  }
+
+  fn fold_param(&mut self, x: &'a AstParam<'a>) -> AstParam<'a> {
+    let y = rebuild_param(self, x);
+    self.bind(y.pat);
+    y
+  }
+
+  fn fold_block(&mut self, x: &'a [AstStmt<'a>]) -> &'a [AstStmt<'a>] {
+    fold_scoped_block(self, x)
+  }
+
+  fn fold_let(&mut self, x: &'a AstLet<'a>) -> AstLet<'a> {
+    fold_scoped_let(self, x)
+  }
+
+  fn fold_expr(&mut self, x: &'a AstExpr<'a>) -> AstExpr<'a> {
+    match x {
+      AstExpr::Field(y) => {
+        // `m.f` where `m` names a module refers to the item `f` in it.
+
+        if let Some(m) = self.module_of(y.0) {
+          match self.lookup(m, y.1) {
+            Ok(Some(z)) => return AstExpr::Symbol(self.aa.alloc().init(z)),
+            Ok(None) if self.child(m, y.1.0).is_some() => self.fail(ModuleError::NotAValue(self.qualified_name(m, y.1.0), x.span())),
+            Ok(None) => self.fail(ModuleError::NotFound(self.qualified_name(m, y.1.0), x.span())),
+            Err(e) => self.fail(e),
+          }
+        }
+        rebuild_expr(self, x)
+      }
+      AstExpr::MethodCall(y) => {
+        // `m.f(y)` where `m` names a module is a call of the item `f` in it,
+        // and otherwise a method call is desugared.
+
+        match self.module_of(y.0) {
+          Some(m) => {
+            match self.lookup(m, y.1) {
+              Ok(Some(f)) => {
+                let f = AstExpr::Symbol(self.aa.alloc().init(f));
+                let z = fold_exprs(self, y.2);
+                return AstExpr::FunCall(self.aa.alloc().init(AstFunCall(f, z, NodeId::fresh())));
+              }
+              Ok(None) => self.fail(ModuleError::NotFound(self.qualified_name(m, y.1.0), y.1.1)),
+              Err(e) => self.fail(e),
+            }
+            rebuild_expr(self, x)
+          }
+          None => {
+            let z = y.desugar(self.aa);
+            let z = self.aa.alloc().init(z);
+            let z = self.fold_fun_call(z);
+            AstExpr::FunCall(self.aa.alloc().init(z))
+          }
+        }
+      }
+      AstExpr::Lambda(y) => {
+        let z = fold_scoped_lambda(self, y);
+        AstExpr::Lambda(self.aa.alloc().init(z))
+      }
+      AstExpr::Pipeline(y) => {
+        let z = y.desugar(self.aa);
+        let z = self.aa.alloc().init(z);
+        self.fold_expr(z)
+      }
+      AstExpr::Symbol(y) if ! self.locals.contains(&y.0) => {
+        match self.lookup(self.cur, **y) {
+          Ok(Some(z)) => return AstExpr::Symbol(self.aa.alloc().init(z)),
+          Ok(None) => {}
+          Err(e) => self.fail(e),
+        }
+        rebuild_expr(self, x)
+      }
+      _ => rebuild_expr(self, x),
+    }
+  }
+
+  fn fold_type(&mut self, x: &'a AstType<'a>) -> AstType<'a> {
+    let AstType::Symbol(y) = x else { return rebuild_type(self, x) };
+    if Ty::from_name(y.0.as_str()).is_some() { return rebuild_type(self, x); }
+
+    let path: Vec<Symbol> = y.0.as_str().split('.').map(Symbol::intern).collect();
+    let (&name, init) = path.split_last().unwrap();
+    let mut m = self.cur;
+    for (k, seg) in init.iter().enumerate() {
+      let n = if k == 0 { self.module_alias(*seg) } else { self.child(m, *seg) };
+      match n {
+        Some(n) => m = n,
+        None => {
+          self.fail(ModuleError::NotFound(y.0.to_string(), y.1));
+          return rebuild_type(self, x);
+        }
+      }
+    }
+    match self.lookup(m, AstSymbol(name, y.1, NodeId::fresh())) {
+      Ok(Some(z)) => return AstType::Symbol(self.aa.alloc().init(z)),
+      Ok(None) if init.is_empty() => {}
+      Ok(None) => self.fail(ModuleError::NotFound(y.0.to_string(), y.1)),
+      Err(e) => self.fail(e),
+    }
+    rebuild_type(self, x)
+  }
+}
+
+impl<'s, 't, 'a> ScopedFolder<'a, 'a> for Resolver<'s, 't, 'a> {
+  fn locals(&mut self) -> &mut Vec<Symbol> {
+    &mut self.locals
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use std::fs;
+
+  // Writes the files under a fresh root directory and loads the program whose
+  // root module is the first of them, showing each item as an s-expression.
+
+  fn load(name: &str, files: &[(&str, &str)]) -> Result<Vec<String>, ModuleError> {
+    let root = std::env::temp_dir().join(format!("adelie-{}-{}", name, std::process::id()));
+    for (path, src) in files.iter() {
+      let path = root.join(path);
+      fs::create_dir_all(path.parent().unwrap()).unwrap();
+      fs::write(path, src).unwrap();
+    }
+    let mut arena = Arena::new();
+    let aa = arena.allocator_mut();
+    let x = load_program(&root, &root.join(files[0].0), aa);
+    fs::remove_dir_all(&root).unwrap();
+    Ok(x?.iter().map(|y| y.to_sexp().to_string()).collect())
+  }
+
+  fn span(start: u32, stop: u32) -> Span {
+    Span { file: 0, start, stop }
+  }
+
+  #[test]
+  fn qualified_names() {
+    let x = load("qualified", &[
+      ("main.adelie", "import math\nimport util.bits\nfun main(x: math.Vec): i64 math.gcd(x.a, bits.pop(1)) end"),
+      ("math.adelie", "pub struct Vec { a: i64, b: i64 }\npub fun gcd(a: i64, b: i64): i64 if b == 0 then a else gcd(b, a - b) end end"),
+      ("util/bits.adelie", "pub const K = 1\npub fun pop(x: i64): i64 x + K end"),
+    ]).unwrap();
+    assert_eq!(x, [
+      "(fundef main ((: x math.Vec)) (: i64) (exprseq (call math.gcd (. x a) (call util.bits.pop 1))))",
+      "(pub (struct math.Vec (: a i64) (: b i64)))",
+      "(pub (fundef math.gcd ((: a i64) (: b i64)) (: i64) (exprseq (if (== b 0) (then (exprseq a)) (else (exprseq (call math.gcd b (- a b))))))))",
+      "(pub (const util.bits.K 1))",
+      "(pub (fundef util.bits.pop ((: x i64)) (: i64) (exprseq (+ x util.bits.K))))",
+    ]);
+  }
+
+  #[test]
+  fn locals_shadow_modules() {
+    let x = load("shadow", &[
+      ("main.adelie", "import math\nfun main(math: i64) let gcd = 1 math + gcd end"),
+      ("math.adelie", "pub fun gcd() 1 end"),
+    ]).unwrap();
+    assert_eq!(x[0], "(fundef main ((: math i64)) (let gcd = 1) (exprseq (+ math gcd)))");
+  }
+
+  #[test]
+  fn privacy() {
+    let e = load("private", &[
+      ("main.adelie", "import math\nfun main() math.private() end"),
+      ("math.adelie", "fun private() 1 end\npub fun public() private() end"),
+    ]).unwrap_err();
+    assert!(matches!(e, ModuleError::Private(ref x, y) if x == "math.private" && y == span(28, 35)));
+
+    let x = load("public", &[
+      ("main.adelie", "import math\nfun main() math.public() end"),
+      ("math.adelie", "fun private() 1 end\npub fun public() private() end"),
+    ]).unwrap();
+    assert_eq!(x[0], "(fundef main () (exprseq (call math.public)))");
+    assert_eq!(x[2], "(pub (fundef math.public () (exprseq (call math.private))))");
+  }
+
+  #[test]
+  fn inline_modules() {
+    let x = load("inline", &[
+      ("main.adelie", "module m\n  pub fun f() n.g() end\n  module n\n    pub fun g() 1 end\n  end\nend\nfun h() m.f() + m.n.g() end"),
+    ]).unwrap();
+    assert_eq!(x, [
+      "(fundef h () (exprseq (+ (call m.f) (call m.n.g))))",
+      "(pub (fundef m.f () (exprseq (call m.n.g))))",
+      "(pub (fundef m.n.g () (exprseq 1)))",
+    ]);
+  }
+
+  #[test]
+  fn errors() {
+    let e = load("not-found", &[("main.adelie", "module m\n  pub fun f() 1 end\nend\nfun h() m.q() end")]).unwrap_err();
+    assert!(matches!(e, ModuleError::NotFound(ref x, y) if x == "m.q" && y == span(43, 44)));
+
+    let e = load("no-file", &[("main.adelie", "import nowhere\nfun h() 1 end")]).unwrap_err();
+    assert!(matches!(e, ModuleError::NotFound(ref x, y) if x == "nowhere" && y == span(7, 14)));
+
+    let e = load("not-a-value", &[("main.adelie", "module m\n  module n\n  end\nend\nfun h() m.n end")]).unwrap_err();
+    assert!(matches!(e, ModuleError::NotAValue(ref x, y) if x == "m.n" && y == span(38, 41)));
+
+    let e = load("duplicate", &[("main.adelie", "module m\nend\nmodule m\nend\n")]).unwrap_err();
+    assert!(matches!(e, ModuleError::Duplicate(ref x, y) if x == "m" && y == span(20, 21)));
+  }
+}
@@ -0,0 +1,181 @@
+use crate::prelude::*;
+
+use std::sync::atomic::AtomicU32;
+use std::sync::atomic::Ordering;
+
+// NODE IDS
+//
+// Every item, statement, and expression gets an ID when it is made, by the
+// parser or by a pass that builds a new tree, and keeps it however the tree is
+// shared. IDs are handed out densely, so an analysis can keep what it finds
+// out about nodes in a `NodeMap` instead of in the tree.
+//
+// A `NodeTable` lists the nodes of a tree in preorder and records the parent
+// of each.
+
+#[derive(Clone, Copy, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
+pub struct NodeId(pub u32);
+
+static NEXT_ID: AtomicU32 = AtomicU32::new(0);
+
+impl NodeId {
+  pub fn fresh() -> Self {
+    NodeId(NEXT_ID.fetch_add(1, Ordering::Relaxed))
+  }
+}
+
+#[derive(Clone, Copy)]
+pub enum AstNode<'a> {
+  Expr(&'a AstExpr<'a>),
+  Item(&'a AstItem<'a>),
+  Stmt(&'a AstStmt<'a>),
+}
+
+impl<'a> AstNode<'a> {
+  pub fn id(&self) -> NodeId {
+    match self {
+      AstNode::Expr(x) => x.id(),
+      AstNode::Item(x) => x.id(),
+      AstNode::Stmt(x) => x.id(),
+    }
+  }
+}
+
+pub struct NodeTable<'a> {
+  order: Vec<NodeId>,
+  nodes: NodeMap<AstNode<'a>>,
+  parents: NodeMap<NodeId>,
+}
+
+impl<'a> NodeTable<'a> {
+  pub fn new(items: &'a [AstItem<'a>]) -> Self {
+    let mut s =
+      Numbering {
+        table: NodeTable { order: Vec::new(), nodes: NodeMap::new(), parents: NodeMap::new() },
+        parent: None,
+      };
+    for item in items.iter() { s.visit_item(item) }
+    s.table
+  }
+
+  pub fn is_empty(&self) -> bool {
+    self.order.is_empty()
+  }
+
+  pub fn len(&self) -> usize {
+    self.order.len()
+  }
+
+  // The IDs of the nodes of the tree, in preorder.
+
+  pub fn ids(&self) -> &[NodeId] {
+    &self.order
+  }
+
+  pub fn node(&self, x: NodeId) -> AstNode<'a> {
+    *self.nodes.get(x).unwrap()
+  }
+
+  // The innermost item, statement, or expression that contains a node, if
+  // any.
+
+  pub fn parent(&self, x: NodeId) -> Option<NodeId> {
+    self.parents.get(x).copied()
+  }
+}
+
+struct Numbering<'a> {
+  table: NodeTable<'a>,
+  parent: Option<NodeId>,
+}
+
+impl<'a> Numbering<'a> {
+  fn enter(&mut self, x: AstNode<'a>) -> Option<NodeId> {
+    let id = x.id();
+    self.table.order.push(id);
+    let _ = self.table.nodes.insert(id, x);
+    if let Some(p) = self.parent { let _ = self.table.parents.insert(id, p); }
+    self.parent.replace(id)
+  }
+}
+
+impl<'a> Visitor<'a> for Numbering<'a> {
+  fn visit_expr(&mut self, x: &'a AstExpr<'a>) {
+    let parent = self.enter(AstNode::Expr(x));
+    walk_expr(self, x);
+    self.parent = parent;
+  }
+
+  fn visit_item(&mut self, x: &'a AstItem<'a>) {
+    let parent = self.enter(AstNode::Item(x));
+    walk_item(self, x);
+    self.parent = parent;
+  }
+
+  fn visit_stmt(&mut self, x: &'a AstStmt<'a>) {
+    let parent = self.enter(AstNode::Stmt(x));
+    walk_stmt(self, x);
+    self.parent = parent;
+  }
+}
+
+// COPIES
+//
+// A subtree that has to appear in more than one place, such as a macro
+// argument that the template uses twice, is copied, so that each place has
+// nodes of its own. So is a tree that has to move to another arena.
+
+struct Copier<'x, 'b>(&'x mut Allocator<'b>);
+
+impl<'x, 'a, 'b> Folder<'a, 'b> for Copier<'x, 'b> {
+  fn allocator(&mut self) -> &mut Allocator<'b> {
+    self.0
+  }
+}
+
+pub fn copy_items<'a, 'b>(items: &'a [AstItem<'a>], aa: &mut Allocator<'b>) -> Vec<AstItem<'b>> {
+  let mut f = Copier(aa);
+  items.iter().map(|x| f.fold_item(x)).collect()
+}
+
+pub fn copy_expr<'a, 'b>(x: &'a AstExpr<'a>, aa: &mut Allocator<'b>) -> AstExpr<'b> {
+  Copier(aa).fold_expr(x)
+}
+
+// SIDE TABLES
+//
+// A map from node IDs to values, which grows as values are put in it.
+
+pub struct NodeMap<T> {
+  values: Vec<Option<T>>,
+}
+
+impl<T> Default for NodeMap<T> {
+  fn default() -> Self {
+    Self::new()
+  }
+}
+
+impl<T> NodeMap<T> {
+  pub fn new() -> Self {
+    Self { values: Vec::new() }
+  }
+
+  pub fn get(&self, x: NodeId) -> Option<&T> {
+    self.values.get(x.0 as usize)?.as_ref()
+  }
+
+  pub fn get_mut(&mut self, x: NodeId) -> Option<&mut T> {
+    self.values.get_mut(x.0 as usize)?.as_mut()
+  }
+
+  pub fn insert(&mut self, x: NodeId, value: T) -> Option<T> {
+    let i = x.0 as usize;
+    if self.values.len() <= i { self.values.resize_with(i + 1, || None) }
+    self.values[i].replace(value)
+  }
+
+  pub fn iter(&self) -> impl Iterator<Item = (NodeId, &T)> {
+    self.values.iter().enumerate().filter_map(|(i, x)| x.as_ref().map(|y| (NodeId(i as u32), y)))
+  }
+}
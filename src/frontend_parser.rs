@@ -3,26 +3,53 @@ use crate::prelude::*;
 pub struct Parser<'a> {
   lexer: Lexer<'a>,
   token: Token,
+  last: u32,
+  marks: Vec<Mark>,
 }
 
 #[derive(Debug)]
 pub struct ParseError;
 
+// Where each item and statement starts and stops, and where each block of them
+// ends, in the order that they were parsed. The AST doesn't keep this, but the
+// formatter needs it to put comments back in place.
+
+#[derive(Clone, Copy, Debug)]
+pub enum Mark {
+  Start(u32),
+  Stop(u32),
+  Else,
+  End(u32),
+}
+
 fn is_block_terminator(token: Token) -> bool {
-  match token {
-    Token::End | Token::Elif | Token::Else => true,
-    _ => false
-  }
+  matches!(token, Token::End | Token::Elif | Token::Else | Token::Case)
 }
 
 impl<'a> Parser<'a> {
   pub fn new(buf: &'a [u8]) -> Self {
     let mut lexer = Lexer::new(buf);
     let token = lexer.next();
-    Self { lexer, token, }
+    Self { lexer, token, last: 0, marks: Vec::new(), }
+  }
+
+  // A parser for the file with the given index among those that a program is
+  // read from, which its spans record.
+
+  pub fn with_file(buf: &'a [u8], file: u32) -> Self {
+    let mut p = Self::new(buf);
+    p.lexer.set_file(file);
+    p
+  }
+
+  pub fn marks(&self) -> &[Mark] {
+    &self.marks
   }
 
   fn advance(&mut self) {
+    if self.token != Token::Space {
+      self.last = self.lexer.loc().stop;
+    }
     self.token = self.lexer.next()
   }
 
@@ -49,62 +76,365 @@ impl<'a> Parser<'a> {
     self.lexer.span()
   }
 
-  pub fn parse_symbol<'b>(&mut self, aa: &mut Allocator<'b>) -> Result<AstSymbol<'b>, ParseError> {
+  fn loc(&self) -> Span {
+    self.lexer.loc()
+  }
+
+  pub fn parse_symbol(&mut self) -> Result<AstSymbol, ParseError> {
     self.expect(Token::Symbol)?;
-    let x = AstSymbol(aa.copy_str(str::from_utf8(self.span()).unwrap()));
+    let x = AstSymbol(self.lexer.symbol(), self.loc(), NodeId::fresh());
     self.advance();
     self.advance_over_space();
     Ok(x)
   }
 
+  pub fn parse_path<'b>(&mut self, aa: &mut Allocator<'b>) -> Result<&'b [AstSymbol], ParseError> {
+    let mut a = Vec::new();
+    self.expect(Token::Symbol)?;
+    a.push(AstSymbol(self.lexer.symbol(), self.loc(), NodeId::fresh()));
+    self.advance();
+    while self.token == Token::Dot {
+      self.advance();
+      self.expect(Token::Symbol)?;
+      a.push(AstSymbol(self.lexer.symbol(), self.loc(), NodeId::fresh()));
+      self.advance();
+    }
+    self.advance_over_space();
+    Ok(aa.copy_slice(a.as_slice()))
+  }
+
+  pub fn parse_file<'b>(&mut self, aa: &mut Allocator<'b>) -> Result<&'b [AstItem<'b>], ParseError> {
+    self.advance_over_space();
+    let mut a = Vec::new();
+    while self.token != Token::EOF {
+      let x = self.parse_item(aa)?;
+      a.push(x);
+    }
+    Ok(aa.copy_slice(a.as_slice()))
+  }
+
   pub fn parse_item<'b>(&mut self, aa: &mut Allocator<'b>) -> Result<AstItem<'b>, ParseError> {
+    self.marks.push(Mark::Start(self.loc().start));
+    let x = self.parse_item_inner(aa)?;
+    self.marks.push(Mark::Stop(self.last));
+    Ok(x)
+  }
+
+  fn parse_item_inner<'b>(&mut self, aa: &mut Allocator<'b>) -> Result<AstItem<'b>, ParseError> {
     match self.token {
+      Token::Const => {
+        let x = self.parse_constdef(aa)?;
+        Ok(AstItem::ConstDef(aa.alloc().init(x)))
+      }
       Token::Fun => {
         let x = self.parse_fundef(aa)?;
         Ok(AstItem::FunDef(aa.alloc().init(x)))
       }
+      Token::Import => {
+        self.advance();
+        self.advance_over_space();
+        let x = self.parse_path(aa)?;
+        Ok(AstItem::Import(aa.alloc().init(AstImport(x, NodeId::fresh()))))
+      }
+      Token::Macro => {
+        let x = self.parse_macrodef(aa)?;
+        Ok(AstItem::MacroDef(aa.alloc().init(x)))
+      }
+      Token::Module => {
+        self.advance();
+        self.advance_over_space();
+        let name = self.parse_symbol()?;
+        let mut a = Vec::new();
+        while self.token != Token::End {
+          let x = self.parse_item(aa)?;
+          a.push(x);
+        }
+        self.marks.push(Mark::End(self.loc().start));
+        self.advance();
+        self.advance_over_space();
+        let items = aa.copy_slice(a.as_slice());
+        Ok(AstItem::Module(aa.alloc().init(AstModule { name, items, id: NodeId::fresh() })))
+      }
+      Token::Pub => {
+        self.advance();
+        self.advance_over_space();
+        match self.token {
+          Token::Const => {
+            let x = self.parse_constdef(aa)?;
+            Ok(AstItem::ConstDef(aa.alloc().init(AstConstDef { is_pub: true, ..x })))
+          }
+          Token::Fun => {
+            let x = self.parse_fundef(aa)?;
+            Ok(AstItem::FunDef(aa.alloc().init(AstFunDef { is_pub: true, ..x })))
+          }
+          Token::Struct => {
+            let x = self.parse_structdef(aa)?;
+            Ok(AstItem::StructDef(aa.alloc().init(AstStructDef { is_pub: true, ..x })))
+          }
+          _ => {
+            self.fail()
+          }
+        }
+      }
+      Token::StaticAssert => {
+        self.advance();
+        self.expect(Token::LParen)?;
+        self.advance();
+        self.advance_over_space();
+        let x = self.parse_expr(aa)?;
+        self.expect(Token::RParen)?;
+        self.advance();
+        self.advance_over_space();
+        Ok(AstItem::StaticAssert(aa.alloc().init(AstStaticAssert(x, NodeId::fresh()))))
+      }
+      Token::Struct => {
+        let x = self.parse_structdef(aa)?;
+        Ok(AstItem::StructDef(aa.alloc().init(x)))
+      }
       _ => {
         self.fail()
       }
     }
   }
 
+  pub fn parse_macrodef<'b>(&mut self, aa: &mut Allocator<'b>) -> Result<AstMacroDef<'b>, ParseError> {
+    let start = self.loc();
+    self.expect(Token::Macro)?;
+    self.advance();
+    self.advance_over_space();
+    let name = self.parse_symbol()?;
+    self.expect(Token::LParen)?;
+    self.advance();
+    self.advance_over_space();
+    let mut a = Vec::new();
+    if self.token != Token::RParen {
+      a.push(self.parse_symbol()?);
+      while self.token != Token::RParen {
+        self.expect(Token::Comma)?;
+        self.advance();
+        self.advance_over_space();
+        a.push(self.parse_symbol()?);
+      }
+    }
+    self.advance();
+    self.advance_over_space();
+    let params = aa.copy_slice(a.as_slice());
+    let body = self.parse_stmt_seq(aa)?;
+    self.expect(Token::End)?;
+    let span = start.join(self.loc());
+    self.advance();
+    self.advance_over_space();
+    Ok(AstMacroDef { name, params, body, span, id: NodeId::fresh() })
+  }
+
+  pub fn parse_constdef<'b>(&mut self, aa: &mut Allocator<'b>) -> Result<AstConstDef<'b>, ParseError> {
+    self.expect(Token::Const)?;
+    self.advance();
+    self.advance_over_space();
+    let name = self.parse_symbol()?;
+    let ty =
+      if self.token == Token::Colon {
+        self.advance();
+        self.advance_over_space();
+        Some(self.parse_type(aa)?)
+      } else {
+        None
+      };
+    self.expect(Token::Assign)?;
+    self.advance();
+    self.advance_over_space();
+    let value = self.parse_expr(aa)?;
+    Ok(AstConstDef { is_pub: false, name, ty, value, id: NodeId::fresh() })
+  }
+
   pub fn parse_fundef<'b>(&mut self, aa: &mut Allocator<'b>) -> Result<AstFunDef<'b>, ParseError> {
     self.expect(Token::Fun)?;
     self.advance();
     self.advance_over_space();
-    let name = self.parse_symbol(aa)?;
+    let name = self.parse_symbol()?;
+    let (params, result) = self.parse_params_and_result(aa)?;
+    let body = self.parse_stmt_seq(aa)?;
+    self.expect(Token::End)?;
+    self.advance();
+    self.advance_over_space();
+    Ok(AstFunDef { is_pub: false, name, params, result, body, id: NodeId::fresh() })
+  }
+
+  pub fn parse_structdef<'b>(&mut self, aa: &mut Allocator<'b>) -> Result<AstStructDef<'b>, ParseError> {
+    self.expect(Token::Struct)?;
+    self.advance();
+    self.advance_over_space();
+    let name = self.parse_symbol()?;
+    self.expect(Token::LBrace)?;
+    self.advance();
+    self.advance_over_space();
+    let mut fields = Vec::new();
+    while self.token != Token::RBrace {
+      let x = self.parse_symbol()?;
+      self.expect(Token::Colon)?;
+      self.advance();
+      self.advance_over_space();
+      let y = self.parse_type(aa)?;
+      fields.push(AstStructField { name: x, ty: y });
+      if self.token != Token::RBrace {
+        self.expect(Token::Comma)?;
+        self.advance();
+        self.advance_over_space();
+      }
+    }
+    let fields = aa.copy_slice(fields.as_slice());
+    self.advance();
+    self.advance_over_space();
+    Ok(AstStructDef { is_pub: false, name, fields, id: NodeId::fresh() })
+  }
+
+  pub fn parse_params_and_result<'b>(&mut self, aa: &mut Allocator<'b>) -> Result<(&'b [AstParam<'b>], Option<AstType<'b>>), ParseError> {
     self.expect(Token::LParen)?;
     self.advance();
     self.advance_over_space();
     let mut params = Vec::new();
     if self.token != Token::RParen {
-      let param = self.parse_symbol(aa)?;
+      let param = self.parse_param(aa)?;
       params.push(param);
       while self.token != Token::RParen {
         self.expect(Token::Comma)?;
         self.advance();
         self.advance_over_space();
-        let param = self.parse_symbol(aa)?;
+        let param = self.parse_param(aa)?;
         params.push(param);
       }
     }
     let params = aa.copy_slice(params.as_slice());
     self.advance();
     self.advance_over_space();
-    let body = self.parse_stmt_seq(aa)?;
-    self.expect(Token::End)?;
+    let result =
+      if self.token == Token::Colon {
+        self.advance();
+        self.advance_over_space();
+        Some(self.parse_type(aa)?)
+      } else {
+        None
+      };
+    Ok((params, result))
+  }
+
+  pub fn parse_param<'b>(&mut self, aa: &mut Allocator<'b>) -> Result<AstParam<'b>, ParseError> {
+    let pat = self.parse_pat(aa)?;
+    let ty =
+      if self.token == Token::Colon {
+        self.advance();
+        self.advance_over_space();
+        Some(self.parse_type(aa)?)
+      } else {
+        None
+      };
+    Ok(AstParam { pat, ty })
+  }
+
+  pub fn parse_pat<'b>(&mut self, aa: &mut Allocator<'b>) -> Result<AstPat<'b>, ParseError> {
+    match self.token {
+      Token::LParen => {
+        // As in expressions, `(x)` is just `x`, and `(x,)` is a 1-tuple.
+
+        self.advance();
+        self.advance_over_space();
+        let mut a = Vec::new();
+        let mut comma = false;
+        while self.token != Token::RParen {
+          let x = self.parse_pat(aa)?;
+          a.push(x);
+          if self.token != Token::RParen {
+            self.expect(Token::Comma)?;
+            self.advance();
+            self.advance_over_space();
+            comma = true;
+          }
+        }
+        self.advance();
+        self.advance_over_space();
+        if a.len() == 1 && ! comma { return Ok(a[0]); }
+        let x = AstTuplePat(aa.copy_slice(a.as_slice()));
+        Ok(AstPat::Tuple(aa.alloc().init(x)))
+      }
+      _ => {
+        let x = self.parse_symbol()?;
+        Ok(AstPat::Symbol(aa.alloc().init(x)))
+      }
+    }
+  }
+
+  pub fn parse_array_len<'b>(&mut self, aa: &mut Allocator<'b>) -> Result<AstNumber<'b>, ParseError> {
+    self.expect(Token::Number)?;
+    let x = AstNumber(aa.copy_str(str::from_utf8(self.span()).unwrap()), self.loc(), NodeId::fresh());
+    if ! matches!(x.value(), Some(n) if n >= 0) { return self.fail(); }
     self.advance();
     self.advance_over_space();
-    Ok(AstFunDef { name, params, body })
+    Ok(x)
+  }
+
+  pub fn parse_type<'b>(&mut self, aa: &mut Allocator<'b>) -> Result<AstType<'b>, ParseError> {
+    match self.token {
+      Token::LBracket => {
+        self.advance();
+        self.advance_over_space();
+        let x = self.parse_type(aa)?;
+        self.expect(Token::Semi)?;
+        self.advance();
+        self.advance_over_space();
+        let n = self.parse_array_len(aa)?;
+        self.expect(Token::RBracket)?;
+        self.advance();
+        self.advance_over_space();
+        Ok(AstType::Array(aa.alloc().init(AstArrayType(x, n))))
+      }
+      Token::LParen => {
+        // As in expressions, `(x)` is just `x`, and `(x,)` is a 1-tuple.
+
+        self.advance();
+        self.advance_over_space();
+        let mut a = Vec::new();
+        let mut comma = false;
+        while self.token != Token::RParen {
+          let x = self.parse_type(aa)?;
+          a.push(x);
+          if self.token != Token::RParen {
+            self.expect(Token::Comma)?;
+            self.advance();
+            self.advance_over_space();
+            comma = true;
+          }
+        }
+        self.advance();
+        self.advance_over_space();
+        if a.len() == 1 && ! comma { return Ok(a[0]); }
+        let x = AstTupleType(aa.copy_slice(a.as_slice()));
+        Ok(AstType::Tuple(aa.alloc().init(x)))
+      }
+      _ => {
+        // A qualified name `a.b.c` is kept as a single symbol.
+
+        let x = self.parse_path(aa)?;
+        let x =
+          if x.len() == 1 {
+            x[0]
+          } else {
+            let a: Vec<&str> = x.iter().map(|y| y.0.as_str()).collect();
+            AstSymbol(Symbol::intern(&a.join(".")), x[0].1.join(x[x.len() - 1].1), NodeId::fresh())
+          };
+        Ok(AstType::Symbol(aa.alloc().init(x)))
+      }
+    }
   }
 
   pub fn parse_stmt_seq<'b>(&mut self, aa: &mut Allocator<'b>) -> Result<&'b [AstStmt<'b>], ParseError> {
     let mut a = Vec::new();
     while ! is_block_terminator(self.token) {
+      self.marks.push(Mark::Start(self.loc().start));
       let x = self.parse_stmt(aa)?;
+      self.marks.push(Mark::Stop(self.last));
       a.push(x);
     }
+    self.marks.push(Mark::End(self.loc().start));
     Ok(aa.copy_slice(a.as_slice()))
   }
 
@@ -121,14 +451,14 @@ impl<'a> Parser<'a> {
     Ok(aa.copy_slice(a.as_slice()))
   }
 
-  pub fn parse_symbol_nonempty_seq<'b>(&mut self, aa: &mut Allocator<'b>) -> Result<&'b [AstSymbol<'b>], ParseError> {
+  pub fn parse_pat_nonempty_seq<'b>(&mut self, aa: &mut Allocator<'b>) -> Result<&'b [AstPat<'b>], ParseError> {
     let mut a = Vec::new();
-    let x = self.parse_symbol(aa)?;
+    let x = self.parse_pat(aa)?;
     a.push(x);
     while self.token == Token::Comma {
       self.advance();
       self.advance_over_space();
-      let x = self.parse_symbol(aa)?;
+      let x = self.parse_pat(aa)?;
       a.push(x);
     }
     Ok(aa.copy_slice(a.as_slice()))
@@ -145,17 +475,17 @@ impl<'a> Parser<'a> {
           } else {
             self.parse_expr_nonempty_seq(aa)?
           };
-        Ok(AstStmt::Break(aa.alloc().init(AstBreak(x))))
+        Ok(AstStmt::Break(aa.alloc().init(AstBreak(x, NodeId::fresh()))))
       }
       Token::Let => {
         self.advance();
         self.advance_over_space();
-        let x = self.parse_symbol_nonempty_seq(aa)?;
+        let x = self.parse_pat_nonempty_seq(aa)?;
         self.expect(Token::Assign)?;
         self.advance();
         self.advance_over_space();
         let y = self.parse_expr_nonempty_seq(aa)?;
-        Ok(AstStmt::Let(aa.alloc().init(AstLet(x, y))))
+        Ok(AstStmt::Let(aa.alloc().init(AstLet(x, y, NodeId::fresh()))))
       }
       Token::Return => {
         self.advance();
@@ -166,17 +496,89 @@ impl<'a> Parser<'a> {
           } else {
             self.parse_expr_nonempty_seq(aa)?
           };
-        Ok(AstStmt::Return(aa.alloc().init(AstReturn(x))))
+        Ok(AstStmt::Return(aa.alloc().init(AstReturn(x, NodeId::fresh()))))
       }
       _ => {
         let x = self.parse_expr_nonempty_seq(aa)?;
-        Ok(AstStmt::ExprSeq(aa.alloc().init(AstExprSeq(x))))
+        Ok(AstStmt::ExprSeq(aa.alloc().init(AstExprSeq(x, NodeId::fresh()))))
+      }
+    }
+  }
+
+  pub fn parse_match_arm<'b>(&mut self, aa: &mut Allocator<'b>) -> Result<AstMatchArm<'b>, ParseError> {
+    self.expect(Token::Case)?;
+    let start = self.loc();
+    self.advance();
+    self.advance_over_space();
+    let mut pats = Vec::new();
+    let x = self.parse_match_pat(aa)?;
+    pats.push(x);
+    while self.token == Token::Comma {
+      self.advance();
+      self.advance_over_space();
+      let x = self.parse_match_pat(aa)?;
+      pats.push(x);
+    }
+    let pats = aa.copy_slice(pats.as_slice());
+    self.expect(Token::Then)?;
+    let span = start.join(self.loc());
+    self.advance();
+    self.advance_over_space();
+    let body = self.parse_stmt_seq(aa)?;
+    Ok(AstMatchArm { pats, body, span })
+  }
+
+  pub fn parse_match_pat<'b>(&mut self, aa: &mut Allocator<'b>) -> Result<AstMatchPat<'b>, ParseError> {
+    match self.token {
+      Token::False | Token::True => {
+        let x = AstBool(self.token == Token::True, self.loc(), NodeId::fresh());
+        self.advance();
+        self.advance_over_space();
+        Ok(AstMatchPat::Bool(aa.alloc().init(x)))
+      }
+      Token::Number => {
+        let x = AstNumber(aa.copy_str(str::from_utf8(self.span()).unwrap()), self.loc(), NodeId::fresh());
+        self.advance();
+        self.advance_over_space();
+        if self.token != Token::DotDot {
+          return Ok(AstMatchPat::Number(aa.alloc().init(x)));
+        }
+        self.advance();
+        self.advance_over_space();
+        self.expect(Token::Number)?;
+        let y = AstNumber(aa.copy_str(str::from_utf8(self.span()).unwrap()), self.loc(), NodeId::fresh());
+        self.advance();
+        self.advance_over_space();
+        Ok(AstMatchPat::Range(aa.alloc().init(AstRange(x, y))))
+      }
+      Token::Symbol if self.span() == b"_" => {
+        self.advance();
+        self.advance_over_space();
+        Ok(AstMatchPat::Wildcard)
+      }
+      _ => {
+        self.fail()
       }
     }
   }
 
   pub fn parse_expr<'b>(&mut self, aa: &mut Allocator<'b>) -> Result<AstExpr<'b>, ParseError> {
-    self.parse_expr_c(aa)
+    self.parse_expr_l(aa)
+  }
+
+  // pipe"l"ine
+
+  pub fn parse_expr_l<'b>(&mut self, aa: &mut Allocator<'b>) -> Result<AstExpr<'b>, ParseError> {
+    let mut e = self.parse_expr_c(aa)?;
+
+    while self.token == Token::Pipeline {
+      self.advance();
+      self.advance_over_space();
+      let x = self.parse_expr_t(aa)?;
+      e = AstExpr::Pipeline(aa.alloc().init(AstPipeline(e, x, NodeId::fresh())));
+    }
+
+    Ok(e)
   }
 
   // "c"omparison
@@ -198,7 +600,7 @@ impl<'a> Parser<'a> {
       self.advance();
       self.advance_over_space();
       let x = self.parse_expr_a(aa)?;
-      e = AstExpr::OpCall2(aa.alloc().init(AstOpCall(op, [e, x])));
+      e = AstExpr::OpCall2(aa.alloc().init(AstOpCall(op, [e, x], NodeId::fresh())));
     }
 
     Ok(e)
@@ -219,7 +621,7 @@ impl<'a> Parser<'a> {
       self.advance();
       self.advance_over_space();
       let x = self.parse_expr_m(aa)?;
-      e = AstExpr::OpCall2(aa.alloc().init(AstOpCall(op, [e, x])));
+      e = AstExpr::OpCall2(aa.alloc().init(AstOpCall(op, [e, x], NodeId::fresh())));
     }
 
     Ok(e)
@@ -240,7 +642,7 @@ impl<'a> Parser<'a> {
       self.advance();
       self.advance_over_space();
       let x = self.parse_expr_p(aa)?;
-      e = AstExpr::OpCall2(aa.alloc().init(AstOpCall(op, [e, x])));
+      e = AstExpr::OpCall2(aa.alloc().init(AstOpCall(op, [e, x], NodeId::fresh())));
     }
 
     Ok(e)
@@ -258,32 +660,115 @@ impl<'a> Parser<'a> {
     self.advance();
     self.advance_over_space();
     let x = self.parse_expr_p(aa)?;
-    Ok(AstExpr::OpCall1(aa.alloc().init(AstOpCall(op, [x]))))
+    Ok(AstExpr::OpCall1(aa.alloc().init(AstOpCall(op, [x], NodeId::fresh()))))
   }
 
   // "t"erminal (and funcalls)
 
   pub fn parse_expr_t<'b>(&mut self, aa: &mut Allocator<'b>) -> Result<AstExpr<'b>, ParseError> {
-    let mut e =
+    let e =
       match self.token {
         Token::LParen => {
+          // `(x)` is just `x`, while `()`, `(x,)`, and `(x, y)` are tuples.
+
           self.advance();
           self.advance_over_space();
-          let x = self.parse_expr(aa)?;
-          self.expect(Token::RParen)?;
+          if self.token == Token::RParen {
+            self.advance();
+            AstExpr::Tuple(aa.alloc().init(AstTuple(&[], NodeId::fresh())))
+          } else {
+            let x = self.parse_expr(aa)?;
+            if self.token == Token::Comma {
+              let mut a = Vec::new();
+              a.push(x);
+              while self.token == Token::Comma {
+                self.advance();
+                self.advance_over_space();
+                if self.token == Token::RParen { break; }
+                let x = self.parse_expr(aa)?;
+                a.push(x);
+              }
+              self.expect(Token::RParen)?;
+              self.advance();
+              AstExpr::Tuple(aa.alloc().init(AstTuple(aa.copy_slice(a.as_slice()), NodeId::fresh())))
+            } else {
+              self.expect(Token::RParen)?;
+              self.advance();
+              x
+            }
+          }
+        }
+        Token::LBracket => {
+          // `[x, y, z]` or `[x; n]`
+
           self.advance();
-          x
+          self.advance_over_space();
+          let mut a = Vec::new();
+          if self.token != Token::RBracket {
+            let x = self.parse_expr(aa)?;
+            if self.token == Token::Semi {
+              self.advance();
+              self.advance_over_space();
+              let n = self.parse_array_len(aa)?;
+              self.expect(Token::RBracket)?;
+              self.advance();
+              return self.parse_expr_postfix(AstExpr::ArrayRepeat(aa.alloc().init(AstArrayRepeat(x, n, NodeId::fresh()))), aa);
+            }
+            a.push(x);
+            while self.token == Token::Comma {
+              self.advance();
+              self.advance_over_space();
+              if self.token == Token::RBracket { break; }
+              let x = self.parse_expr(aa)?;
+              a.push(x);
+            }
+          }
+          self.expect(Token::RBracket)?;
+          self.advance();
+          AstExpr::Array(aa.alloc().init(AstArray(aa.copy_slice(a.as_slice()), NodeId::fresh())))
+        }
+        Token::LBrace => {
+          self.advance();
+          self.advance_over_space();
+          let mut a = Vec::new();
+          while self.token != Token::RBrace {
+            let x = self.parse_symbol()?;
+            self.expect(Token::Assign)?;
+            self.advance();
+            self.advance_over_space();
+            let y = self.parse_expr(aa)?;
+            a.push((x, y));
+            if self.token != Token::RBrace {
+              self.expect(Token::Comma)?;
+              self.advance();
+              self.advance_over_space();
+            }
+          }
+          self.advance();
+          AstExpr::Record(aa.alloc().init(AstRecord(aa.copy_slice(a.as_slice()), NodeId::fresh())))
+        }
+        Token::False | Token::True => {
+          let x = AstBool(self.token == Token::True, self.loc(), NodeId::fresh());
+          self.advance();
+          AstExpr::Bool(aa.alloc().init(x))
         }
         Token::Number => {
-          let x = AstNumber(aa.copy_str(str::from_utf8(self.span()).unwrap()));
+          let x = AstNumber(aa.copy_str(str::from_utf8(self.span()).unwrap()), self.loc(), NodeId::fresh());
           self.advance();
           AstExpr::Number(aa.alloc().init(x))
         }
         Token::Symbol => {
-          let x = AstSymbol(aa.copy_str(str::from_utf8(self.span()).unwrap()));
+          let x = AstSymbol(self.lexer.symbol(), self.loc(), NodeId::fresh());
           self.advance();
           AstExpr::Symbol(aa.alloc().init(x))
         }
+        Token::Dollar => {
+          self.advance();
+          self.expect(Token::Symbol)?;
+          let x = AstSymbol(self.lexer.symbol(), self.loc(), NodeId::fresh());
+          self.advance();
+          AstExpr::Unquote(aa.alloc().init(x))
+        }
         Token::If => {
           self.advance();
           self.advance_over_space();
@@ -295,6 +780,7 @@ impl<'a> Parser<'a> {
           let z =
             match self.token {
               Token::Else => {
+                self.marks.push(Mark::Else);
                 self.advance();
                 self.advance_over_space();
                 self.parse_stmt_seq(aa)?
@@ -305,7 +791,32 @@ impl<'a> Parser<'a> {
             };
           self.expect(Token::End)?;
           self.advance();
-          AstExpr::If(aa.alloc().init(AstIf(x, y, z)))
+          AstExpr::If(aa.alloc().init(AstIf(x, y, z, NodeId::fresh())))
+        }
+        Token::Fun => {
+          self.advance();
+          self.advance_over_space();
+          let (params, result) = self.parse_params_and_result(aa)?;
+          let body = self.parse_stmt_seq(aa)?;
+          self.expect(Token::End)?;
+          self.advance();
+          AstExpr::Lambda(aa.alloc().init(AstLambda { params, result, body, id: NodeId::fresh() }))
+        }
+        Token::Match => {
+          let start = self.loc();
+          self.advance();
+          self.advance_over_space();
+          let x = self.parse_expr(aa)?;
+          let mut arms = Vec::new();
+          while self.token == Token::Case {
+            let arm = self.parse_match_arm(aa)?;
+            arms.push(arm);
+          }
+          let arms = aa.copy_slice(arms.as_slice());
+          self.expect(Token::End)?;
+          let span = start.join(self.loc());
+          self.advance();
+          AstExpr::Match(aa.alloc().init(AstMatch { expr: x, arms, span, id: NodeId::fresh() }))
         }
         Token::Loop => {
           self.advance();
@@ -313,38 +824,80 @@ impl<'a> Parser<'a> {
           let x = self.parse_stmt_seq(aa)?;
           self.expect(Token::End)?;
           self.advance();
-          AstExpr::Loop(aa.alloc().init(AstLoop(x)))
+          AstExpr::Loop(aa.alloc().init(AstLoop(x, NodeId::fresh())))
         }
         _ => {
           return self.fail();
         }
       };
 
-    // Leave unconsumed space, because there can't be space between a function
-    // and its arguments.
+    self.parse_expr_postfix(e, aa)
+  }
 
-    while self.token == Token::LParen {
-      self.advance();
-      self.advance_over_space();
+  fn parse_args<'b>(&mut self, aa: &mut Allocator<'b>) -> Result<&'b [AstExpr<'b>], ParseError> {
+    self.expect(Token::LParen)?;
+    self.advance();
+    self.advance_over_space();
+
+    let mut a = Vec::new();
 
-      let mut a = Vec::new();
+    if self.token != Token::RParen {
+      let x = self.parse_expr(aa)?;
+      a.push(x);
 
-      if self.token != Token::RParen {
+      while self.token != Token::RParen {
+        self.expect(Token::Comma)?;
+        self.advance();
+        self.advance_over_space();
         let x = self.parse_expr(aa)?;
         a.push(x);
+      }
+    }
 
-        while self.token != Token::RParen {
-          self.expect(Token::Comma)?;
+    self.advance();
+
+    Ok(aa.copy_slice(a.as_slice()))
+  }
+
+  // Leave unconsumed space before calling this, because there can't be space
+  // between a function and its arguments, an array and its index, or a record
+  // and its field.
+
+  fn parse_expr_postfix<'b>(&mut self, e: AstExpr<'b>, aa: &mut Allocator<'b>) -> Result<AstExpr<'b>, ParseError> {
+    let mut e = e;
+
+    loop {
+      match self.token {
+        Token::LParen => {
+          let x = self.parse_args(aa)?;
+          e = AstExpr::FunCall(aa.alloc().init(AstFunCall(e, x, NodeId::fresh())))
+        }
+        Token::LBracket => {
           self.advance();
           self.advance_over_space();
           let x = self.parse_expr(aa)?;
-          a.push(x);
-        }
-      }
+          self.expect(Token::RBracket)?;
+          self.advance();
 
-      self.advance();
+          e = AstExpr::Index(aa.alloc().init(AstIndex(e, x, NodeId::fresh())))
+        }
+        Token::Dot => {
+          self.advance();
+          self.expect(Token::Symbol)?;
+          let x = AstSymbol(self.lexer.symbol(), self.loc(), NodeId::fresh());
+          self.advance();
 
-      e = AstExpr::FunCall(aa.alloc().init(AstFunCall(e, aa.copy_slice(a.as_slice()))))
+          if self.token == Token::LParen {
+            let y = self.parse_args(aa)?;
+            e = AstExpr::MethodCall(aa.alloc().init(AstMethodCall(e, x, y, NodeId::fresh())))
+          } else {
+            e = AstExpr::Field(aa.alloc().init(AstField(e, x, NodeId::fresh())))
+          }
+        }
+        _ => {
+          break;
+        }
+      }
     }
 
     self.advance_over_space();
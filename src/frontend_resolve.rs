@@ -0,0 +1,334 @@
+use crate::prelude::*;
+
+// NAME RESOLUTION
+//
+// Gives every definition -- a function or constant item, a parameter, or a
+// variable bound by `let` -- a `BindingId`, and records the binding that each
+// use of a name refers to. This runs on the items that `load_program`
+// produces, so names of functions in other modules are already qualified.
+//
+// A `let` binds its names after its right-hand side, so `let x = x + 1` refers
+// to an outer `x`, and a later binding of a name shadows an earlier one until
+// the end of the enclosing block.
+
+#[derive(Clone, Copy, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
+pub struct BindingId(pub u32);
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum BindingKind {
+  Const,
+  Fun,
+  Let,
+  Param,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct Binding {
+  pub name: AstSymbol,
+  pub kind: BindingKind,
+}
+
+#[derive(Debug)]
+pub enum ResolveError {
+  Duplicate(AstSymbol),
+  DuplicateParam(AstSymbol),
+  Unbound(AstSymbol),
+  UnknownFun(AstSymbol),
+}
+
+impl ResolveError {
+  pub fn span(&self) -> Span {
+    match self {
+      Self::Duplicate(x) | Self::DuplicateParam(x) | Self::Unbound(x) | Self::UnknownFun(x) => x.1,
+    }
+  }
+}
+
+pub struct Resolution {
+  pub bindings: Vec<Binding>,
+  // Keyed by the ID of the symbol node, since equal names at different places
+  // can refer to different bindings.
+  uses: NodeMap<BindingId>,
+}
+
+impl Resolution {
+  pub fn binding(&self, x: BindingId) -> &Binding {
+    &self.bindings[x.0 as usize]
+  }
+
+  // The binding that a use or definition of a name refers to.
+
+  pub fn binding_of(&self, x: &AstSymbol) -> Option<BindingId> {
+    self.uses.get(x.2).copied()
+  }
+}
+
+pub fn resolve_names<'a>(items: &'a [AstItem<'a>]) -> Result<Resolution, Vec<ResolveError>> {
+  let mut r =
+    Resolver {
+      bindings: Vec::new(),
+      uses: NodeMap::new(),
+      globals: Vec::new(),
+      locals: Vec::new(),
+      errors: Vec::new(),
+    };
+
+  // Function and constant items are visible everywhere, including before
+  // their definition.
+
+  r.declare_globals(items);
+
+  for item in items.iter() { r.visit_item(item) }
+
+  if ! r.errors.is_empty() { return Err(r.errors); }
+
+  Ok(Resolution { bindings: r.bindings, uses: r.uses })
+}
+
+struct Resolver {
+  bindings: Vec<Binding>,
+  uses: NodeMap<BindingId>,
+  globals: Vec<(Symbol, BindingId)>,
+  locals: Vec<(Symbol, BindingId)>,
+  errors: Vec<ResolveError>,
+}
+
+impl<'a> Resolver {
+  fn define(&mut self, x: &'a AstSymbol, kind: BindingKind) -> BindingId {
+    let id = BindingId(self.bindings.len() as u32);
+    self.bindings.push(Binding { name: *x, kind });
+    let _ = self.uses.insert(x.2, id);
+    id
+  }
+
+  fn declare_globals(&mut self, items: &'a [AstItem<'a>]) {
+    for item in items.iter() {
+      match item {
+        AstItem::ConstDef(x) => {
+          self.declare_global(&x.name, BindingKind::Const);
+        }
+        AstItem::FunDef(x) => {
+          self.declare_global(&x.name, BindingKind::Fun);
+        }
+        AstItem::Module(x) => {
+          self.declare_globals(x.items);
+        }
+        AstItem::Import(_) | AstItem::MacroDef(_) | AstItem::StaticAssert(_) | AstItem::StructDef(_) => {
+        }
+      }
+    }
+  }
+
+  // Names are already global, so two items of the same name are in the same
+  // module.
+
+  fn declare_global(&mut self, x: &'a AstSymbol, kind: BindingKind) {
+    if self.globals.iter().any(|y| y.0 == x.0) {
+      self.errors.push(ResolveError::Duplicate(*x));
+    }
+    let id = self.define(x, kind);
+    self.globals.push((x.0, id));
+  }
+
+  fn bind(&mut self, pat: &'a AstPat<'a>, kind: BindingKind) {
+    match pat {
+      AstPat::Symbol(x) => {
+        let id = self.define(x, kind);
+        self.locals.push((x.0, id));
+      }
+      AstPat::Tuple(x) => {
+        for y in x.0.iter() { self.bind(y, kind) }
+      }
+    }
+  }
+
+  fn params(&mut self, params: &'a [AstParam<'a>]) {
+    let mut names = Vec::new();
+    for param in params.iter() { param.pat.flatten(&mut names) }
+    for (i, x) in names.iter().enumerate() {
+      if x.0.as_str() != "_" && names[.. i].iter().any(|y| y.0 == x.0) {
+        self.errors.push(ResolveError::DuplicateParam(*x));
+      }
+    }
+    for param in params.iter() { self.bind(&param.pat, BindingKind::Param) }
+  }
+
+  fn lookup(&self, name: Symbol) -> Option<BindingId> {
+    let local = self.locals.iter().rev().find(|y| y.0 == name);
+    let global = || self.globals.iter().find(|y| y.0 == name);
+    local.or_else(global).map(|y| y.1)
+  }
+
+  // A name in call position that isn't bound at all is an intrinsic, if there
+  // is one of that name, and otherwise it is reported as an unknown function
+  // rather than as an unbound variable.
+
+  fn callee(&mut self, x: &'a AstSymbol) {
+    match self.lookup(x.0) {
+      Some(id) => { let _ = self.uses.insert(x.2, id); }
+      None if intrinsic(x.0).is_some() || is_len(x.0) => {}
+      None => self.errors.push(ResolveError::UnknownFun(*x)),
+    }
+  }
+}
+
+impl<'a> Visitor<'a> for Resolver {
+  fn visit_block(&mut self, x: &'a [AstStmt<'a>]) {
+    let n = self.locals.len();
+    walk_block(self, x);
+    self.locals.truncate(n);
+  }
+
+  fn visit_const_def(&mut self, x: &'a AstConstDef<'a>) {
+    self.visit_expr(&x.value);
+  }
+
+  fn visit_fun_def(&mut self, x: &'a AstFunDef<'a>) {
+    self.params(x.params);
+    self.visit_block(x.body);
+    self.locals.clear();
+  }
+
+  fn visit_macro_def(&mut self, _: &'a AstMacroDef<'a>) {
+    // Macros are expanded before this runs.
+  }
+
+  fn visit_struct_def(&mut self, _: &'a AstStructDef<'a>) {
+  }
+
+  fn visit_import(&mut self, _: &'a AstImport<'a>) {
+  }
+
+  fn visit_let(&mut self, x: &'a AstLet<'a>) {
+    for y in x.1.iter() { self.visit_expr(y) }
+    for y in x.0.iter() { self.bind(y, BindingKind::Let) }
+  }
+
+  fn visit_closure(&mut self, x: &'a AstClosure<'a>) {
+    self.callee(&x.0);
+    for y in x.1.iter() { self.visit_expr(y) }
+  }
+
+  fn visit_field(&mut self, x: &'a AstField<'a>) {
+    self.visit_expr(&x.0);
+  }
+
+  fn visit_fun_call(&mut self, x: &'a AstFunCall<'a>) {
+    match &x.0 {
+      AstExpr::Symbol(f) => self.callee(f),
+      f => self.visit_expr(f),
+    }
+    for y in x.1.iter() { self.visit_expr(y) }
+  }
+
+  fn visit_lambda(&mut self, x: &'a AstLambda<'a>) {
+    let n = self.locals.len();
+    self.params(x.params);
+    self.visit_block(x.body);
+    self.locals.truncate(n);
+  }
+
+  fn visit_module(&mut self, x: &'a AstModule<'a>) {
+    for y in x.items.iter() { self.visit_item(y) }
+  }
+
+  fn visit_method_call(&mut self, x: &'a AstMethodCall<'a>) {
+    self.visit_expr(&x.0);
+    self.callee(&x.1);
+    for y in x.2.iter() { self.visit_expr(y) }
+  }
+
+  fn visit_pipeline(&mut self, x: &'a AstPipeline<'a>) {
+    self.visit_expr(&x.0);
+    match &x.1 {
+      AstExpr::Symbol(f) => self.callee(f),
+      f => self.visit_expr(f),
+    }
+  }
+
+  fn visit_record(&mut self, x: &'a AstRecord<'a>) {
+    for (_, y) in x.0.iter() { self.visit_expr(y) }
+  }
+
+  fn visit_symbol(&mut self, x: &'a AstSymbol) {
+    // Only reached for names in expressions. Definitions, field names and
+    // types are handled above or not visited.
+    match self.lookup(x.0) {
+      Some(id) => { let _ = self.uses.insert(x.2, id); }
+      None => self.errors.push(ResolveError::Unbound(*x)),
+    }
+  }
+
+  fn visit_type(&mut self, _: &'a AstType<'a>) {
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  struct Uses<'r>(&'r Resolution, Vec<String>);
+
+  impl<'r, 'a> Visitor<'a> for Uses<'r> {
+    fn visit_expr(&mut self, x: &'a AstExpr<'a>) {
+      if let AstExpr::Symbol(y) = x {
+        match self.0.binding_of(y) {
+          Some(id) => self.1.push(format!("{} {}", y.0, id.0)),
+          None => self.1.push(format!("{} ?", y.0)),
+        }
+      }
+      walk_expr(self, x);
+    }
+  }
+
+  // Lists the binding that each name in an expression refers to, in the order
+  // written.
+
+  fn resolve(src: &str) -> Result<Vec<String>, Vec<ResolveError>> {
+    let mut arena = Arena::new();
+    let aa = arena.allocator_mut();
+    let items = Parser::new(src.as_bytes()).parse_file(aa).unwrap();
+    let names = resolve_names(items)?;
+    let mut uses = Uses(&names, Vec::new());
+    for item in items.iter() { uses.visit_item(item) }
+    Ok(uses.1)
+  }
+
+  #[test]
+  fn shadowing() {
+    // f is 0, the parameter x is 1, y is 2, the second x is 3, the lambda's x
+    // is 4, and g is 5.
+    let src = "fun f(x) let y = x let x = x + 1 let g = fun (x) x end x + y end";
+    assert_eq!(resolve(src).unwrap(), ["x 1", "x 1", "x 4", "x 3", "y 2"]);
+
+    let src = "fun f(x) if x then let x = 1 x else x end end";
+    assert_eq!(resolve(src).unwrap(), ["x 1", "x 2", "x 1"]);
+  }
+
+  #[test]
+  fn items() {
+    let src = "fun f(a) g(a) + k + len(a) end fun g(a) a end const k = 1";
+    assert_eq!(resolve(src).unwrap(), ["g 1", "a 3", "k 2", "len ?", "a 3", "a 4"]);
+  }
+
+  #[test]
+  fn errors() {
+    let name = |x: &ResolveError| match x { ResolveError::Duplicate(y) | ResolveError::DuplicateParam(y) | ResolveError::Unbound(y) | ResolveError::UnknownFun(y) => y.0.to_string() };
+
+    let src = "fun f(c) if c then let x = 1 x else 0 end x end";
+    let e = resolve(src).unwrap_err();
+    assert!(matches!(&e[..], [ResolveError::Unbound(x)] if x.1.start as usize == src.rfind('x').unwrap()));
+
+    let src = "fun f(a, b, a, _, _) a end";
+    let e = resolve(src).unwrap_err();
+    assert!(matches!(&e[..], [ResolveError::DuplicateParam(x)] if x.1.start as usize == src.find("a, _").unwrap()));
+
+    let e = resolve("fun f(a) h(a) + (a |> k) end").unwrap_err();
+    assert_eq!(e.iter().map(name).collect::<Vec<_>>(), ["h", "k"]);
+    assert!(e.iter().all(|x| matches!(x, ResolveError::UnknownFun(_))));
+
+    let src = "fun f() 1 end module m fun g() 2 end end fun f() 3 end";
+    let e = resolve(src).unwrap_err();
+    assert!(matches!(&e[..], [ResolveError::Duplicate(x)] if x.1.start as usize == src.rfind('f').unwrap()));
+  }
+}
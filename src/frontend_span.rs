@@ -0,0 +1,37 @@
+use crate::prelude::*;
+
+// A range of byte offsets into a source buffer, and the index of that buffer
+// among the files that the program was read from. A file that is parsed on
+// its own is file 0.
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Span {
+  pub file: u32,
+  pub start: u32,
+  pub stop: u32,
+}
+
+impl Span {
+  pub fn join(self, other: Self) -> Self {
+    Self {
+      file: self.file,
+      start: u32::min(self.start, other.start),
+      stop: u32::max(self.stop, other.stop),
+    }
+  }
+
+  // One-based line and column of the start of the span.
+
+  pub fn line_col(self, buf: &[u8]) -> (usize, usize) {
+    let i = usize::min(self.start as usize, buf.len());
+    let line = 1 + buf[.. i].iter().filter(|&&c| c == b'\n').count();
+    let col = 1 + buf[.. i].iter().rev().take_while(|&&c| c != b'\n').count();
+    (line, col)
+  }
+}
+
+impl fmt::Display for Span {
+  fn fmt(&self, out: &mut fmt::Formatter<'_>) -> fmt::Result {
+    write!(out, "{}..{}", self.start, self.stop)
+  }
+}
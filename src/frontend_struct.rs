@@ -0,0 +1,134 @@
+use crate::prelude::*;
+
+// STRUCTS
+//
+// Structs are scalarized. A value of a struct type is the concatenation of
+// the flattened values of its fields in declaration order, just as if it
+// were a tuple, so a field is a contiguous run of those scalars.
+
+#[derive(Debug)]
+pub enum StructError {
+  Duplicate(AstSymbol),
+  DuplicateField(AstSymbol),
+  Recursive(AstSymbol),
+  UnknownType(AstSymbol),
+}
+
+impl StructError {
+  pub fn span(&self) -> Span {
+    match self {
+      Self::Duplicate(x) | Self::DuplicateField(x) | Self::Recursive(x) | Self::UnknownType(x) => x.1,
+    }
+  }
+}
+
+pub struct StructTable<'a> {
+  defs: Vec<&'a AstStructDef<'a>>,
+}
+
+impl<'a> StructTable<'a> {
+  pub fn new(items: &[AstItem<'a>]) -> Result<Self, StructError> {
+    let mut defs: Vec<&'a AstStructDef<'a>> = Vec::new();
+
+    for &item in items.iter() {
+      if let AstItem::StructDef(x) = item {
+        if defs.iter().any(|y| y.name.0 == x.name.0) {
+          return Err(StructError::Duplicate(x.name));
+        }
+        for (i, field) in x.fields.iter().enumerate() {
+          if x.fields[.. i].iter().any(|y| y.name.0 == field.name.0) {
+            return Err(StructError::DuplicateField(field.name));
+          }
+        }
+        defs.push(x);
+      }
+    }
+
+    let table = Self { defs };
+
+    for &x in table.defs.iter() {
+      table.check(x, &mut Vec::new())?;
+    }
+
+    Ok(table)
+  }
+
+  // Every field type must be known, and no struct may contain itself, so that
+  // flattening terminates.
+
+  fn check(&self, x: &'a AstStructDef<'a>, path: &mut Vec<Symbol>) -> Result<(), StructError> {
+    if path.contains(&x.name.0) {
+      return Err(StructError::Recursive(x.name));
+    }
+    path.push(x.name.0);
+    for field in x.fields.iter() {
+      self.check_type(field.ty, path)?;
+    }
+    let _ = path.pop();
+    Ok(())
+  }
+
+  fn check_type(&self, ty: AstType<'a>, path: &mut Vec<Symbol>) -> Result<(), StructError> {
+    match ty {
+      AstType::Array(x) => {
+        self.check_type(x.0, path)
+      }
+      AstType::Symbol(x) => {
+        if Ty::from_name(x.0.as_str()).is_some() {
+          Ok(())
+        } else {
+          match self.get(x.0) {
+            Some(y) => self.check(y, path),
+            None => Err(StructError::UnknownType(*x)),
+          }
+        }
+      }
+      AstType::Tuple(x) => {
+        for &t in x.0.iter() { self.check_type(t, path)? }
+        Ok(())
+      }
+    }
+  }
+
+  pub fn get(&self, name: Symbol) -> Option<&'a AstStructDef<'a>> {
+    self.defs.iter().copied().find(|x| x.name.0 == name)
+  }
+
+  // The struct for a record literal, which is the one and only struct whose
+  // fields are exactly the given ones in any order, each given once.
+
+  pub fn find_by_fields(&self, names: &[AstSymbol]) -> Option<&'a AstStructDef<'a>> {
+    let mut found = None;
+
+    for &x in self.defs.iter() {
+      if x.fields.len() == names.len()
+        && x.fields.iter().all(|z| names.iter().filter(|y| y.0 == z.name.0).count() == 1)
+      {
+        if found.is_some() { return None; }
+        found = Some(x);
+      }
+    }
+
+    found
+  }
+
+  pub fn width(&self, ty: AstType<'a>) -> usize {
+    let mut a = Vec::new();
+    ty.flatten(self, &mut a).unwrap();
+    a.len()
+  }
+
+  // The scalars of a struct value that hold one of its fields.
+
+  pub fn field_slots(&self, x: &AstStructDef<'a>, name: Symbol) -> Option<Range<usize>> {
+    let mut start = 0;
+
+    for field in x.fields.iter() {
+      let n = self.width(field.ty);
+      if field.name.0 == name { return Some(start .. start + n); }
+      start += n;
+    }
+
+    None
+  }
+}
@@ -0,0 +1,145 @@
+use crate::prelude::*;
+
+use std::cmp::Ordering;
+use std::sync::OnceLock;
+use std::sync::RwLock;
+
+// SYMBOLS
+//
+// Names are interned, so that each distinct name is stored only once however
+// often it occurs, and a `Symbol` is its index in the table of names.
+// Comparing or hashing two symbols compares or hashes integers, and getting
+// the string back looks it up in the table.
+//
+// There is one table for the whole process, behind a lock, so threads that
+// parse different files agree on their symbols. Interning a new name takes
+// the lock for writing and everything else takes it for reading, and the
+// lexer keeps a cache of its own so that it interns each distinct name in a
+// file once. Interned strings are never freed.
+//
+// The keywords are interned first, in the order of `KEYWORDS`, so a symbol
+// is a keyword exactly when its index is in that table, and the lexer tells
+// keywords from names with an integer compare.
+
+#[derive(Clone, Copy, Eq, Hash, PartialEq)]
+pub struct Symbol(u32);
+
+// Sorted by spelling, which keeps them distinct, since the symbol of each
+// keyword is its index here.
+
+static KEYWORDS: [(&str, Token); 26] = [
+  ("and", Token::And),
+  ("break", Token::Break),
+  ("case", Token::Case),
+  ("const", Token::Const),
+  ("do", Token::Do),
+  ("elif", Token::Elif),
+  ("else", Token::Else),
+  ("end", Token::End),
+  ("false", Token::False),
+  ("for", Token::For),
+  ("fun", Token::Fun),
+  ("if", Token::If),
+  ("import", Token::Import),
+  ("let", Token::Let),
+  ("loop", Token::Loop),
+  ("macro", Token::Macro),
+  ("match", Token::Match),
+  ("module", Token::Module),
+  ("or", Token::Or),
+  ("pub", Token::Pub),
+  ("return", Token::Return),
+  ("static_assert", Token::StaticAssert),
+  ("struct", Token::Struct),
+  ("then", Token::Then),
+  ("true", Token::True),
+  ("while", Token::While),
+];
+
+struct Interner {
+  names: Vec<&'static str>,
+  symbols: HashMap<&'static str, Symbol>,
+}
+
+fn interner() -> &'static RwLock<Interner> {
+  static INTERNER: OnceLock<RwLock<Interner>> = OnceLock::new();
+
+  INTERNER.get_or_init(|| {
+    let names: Vec<_> = KEYWORDS.iter().map(|x| x.0).collect();
+    let symbols = names.iter().enumerate().map(|(i, &x)| (x, Symbol(i as u32))).collect();
+    RwLock::new(Interner { names, symbols })
+  })
+}
+
+impl Symbol {
+  pub fn intern(s: &str) -> Self {
+    if let Some(&x) = interner().read().unwrap().symbols.get(s) {
+      return x;
+    }
+    // Another thread may have interned it between the two locks.
+    let mut t = interner().write().unwrap();
+    if let Some(&x) = t.symbols.get(s) {
+      return x;
+    }
+    let s: &'static str = Box::leak(s.into());
+    let x = Symbol(t.names.len() as u32);
+    t.names.push(s);
+    let _ = t.symbols.insert(s, x);
+    x
+  }
+
+  pub fn as_str(self) -> &'static str {
+    interner().read().unwrap().names[self.0 as usize]
+  }
+
+  // The keyword that this symbol spells, if it is one.
+
+  pub fn keyword(self) -> Option<Token> {
+    KEYWORDS.get(self.0 as usize).map(|x| x.1)
+  }
+}
+
+// Symbols are ordered by their strings, which doesn't depend on the order in
+// which threads interned them.
+
+impl PartialOrd for Symbol {
+  fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+    Some(self.cmp(other))
+  }
+}
+
+impl Ord for Symbol {
+  fn cmp(&self, other: &Self) -> Ordering {
+    self.as_str().cmp(other.as_str())
+  }
+}
+
+impl fmt::Debug for Symbol {
+  fn fmt(&self, out: &mut fmt::Formatter<'_>) -> fmt::Result {
+    write!(out, "{:?}", self.as_str())
+  }
+}
+
+impl fmt::Display for Symbol {
+  fn fmt(&self, out: &mut fmt::Formatter<'_>) -> fmt::Result {
+    write!(out, "{}", self.as_str())
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn keywords() {
+    assert!(KEYWORDS.windows(2).all(|x| x[0].0 < x[1].0));
+    for (i, &(s, t)) in KEYWORDS.iter().enumerate() {
+      let x = Symbol::intern(s);
+      assert_eq!(x, Symbol(i as u32));
+      assert_eq!(x.keyword(), Some(t));
+      assert_eq!(x.as_str(), s);
+    }
+    assert_eq!(Symbol::intern("ends").keyword(), None);
+    assert_eq!(Symbol::intern("ends"), Symbol::intern("ends"));
+  }
+}
@@ -46,6 +46,7 @@ pub enum Token {
   Minus,
   Percent,
   Pipe,
+  Pipeline,
   Plus,
   Query,
   Slash,
@@ -56,17 +57,28 @@ pub enum Token {
 
   And,
   Break,
+  Case,
+  Const,
   Do,
   Elif,
   Else,
   End,
+  False,
   For,
   Fun,
   If,
+  Import,
   Let,
   Loop,
+  Macro,
+  Match,
+  Module,
   Or,
+  Pub,
   Return,
+  StaticAssert,
+  Struct,
   Then,
+  True,
   While,
 }
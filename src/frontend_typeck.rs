@@ -0,0 +1,982 @@
+use crate::prelude::*;
+
+// TYPE CHECKING
+//
+// Infers a type for every expression and binding by unification. Functions
+// are monomorphic, so a function without annotations gets its types from its
+// body and from its calls anywhere in the program.
+//
+// An integer literal has some integer type that is decided by how it is used,
+// and it is i64 if nothing decides it. Any other type that nothing decides,
+// such as that of an unused parameter, is ambiguous, and that is an error.
+//
+// The operators are overloaded by type, so which `Op11` or `Op21` an operator
+// stands for is chosen once the types of its operands are known. Likewise,
+// matches are checked for exhaustiveness once the types of their scrutinees
+// are known.
+//
+// This runs after name resolution, on the items that `load_program` produces,
+// so macros are expanded and method calls and pipelines are desugared. The
+// field access and indexing of a value require its type to be known from
+// what comes before it.
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum Type {
+  Array(Box<Type>, u64),
+  Fun(Box<[Type]>, Box<Type>),
+  Scalar(Ty),
+  Struct(Symbol),
+  Tuple(Box<[Type]>),
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum TypedOp {
+  Op11(Op11),
+  Op21(Op21),
+}
+
+#[derive(Debug)]
+pub enum TypeError {
+  // A definition whose type isn't fully determined, with `_` for the parts
+  // that aren't.
+  Ambiguous(String, Span),
+  // The number of arguments that an intrinsic or `len` takes and the number
+  // given.
+  Arity(usize, usize, Span),
+  BreakOutsideLoop(Span),
+  CannotInfer(Span),
+  DuplicateField(String, Span),
+  LiteralRange(String, Span),
+  Match(MatchError),
+  // The expected type and the type that was found.
+  Mismatch(String, String, Span),
+  NoField(String, Span),
+  NoOperator(String, Span),
+  UnknownRecord(Span),
+  UnknownType(String, Span),
+}
+
+impl TypeError {
+  pub fn span(&self) -> Span {
+    match self {
+      Self::Ambiguous(_, x) | Self::DuplicateField(_, x) | Self::LiteralRange(_, x) | Self::NoField(_, x) | Self::NoOperator(_, x) | Self::UnknownType(_, x) => *x,
+      Self::Arity(_, _, x) | Self::Mismatch(_, _, x) => *x,
+      Self::BreakOutsideLoop(x) | Self::CannotInfer(x) | Self::UnknownRecord(x) => *x,
+      Self::Match(x) => x.span(),
+    }
+  }
+}
+
+// What is found out about an expression is keyed by its ID, and about a
+// `match` by the ID of the `match`.
+
+pub struct Typing {
+  bindings: Box<[Type]>,
+  exprs: NodeMap<Type>,
+  ops: NodeMap<TypedOp>,
+  lens: NodeMap<u64>,
+  matches: NodeMap<MatchTable>,
+}
+
+impl Typing {
+  pub fn binding_type(&self, x: BindingId) -> &Type {
+    &self.bindings[x.0 as usize]
+  }
+
+  pub fn type_of(&self, x: &AstExpr<'_>) -> Option<&Type> {
+    self.exprs.get(x.id())
+  }
+
+  // The operation that an operator call performs.
+
+  pub fn op_of(&self, x: &AstExpr<'_>) -> Option<TypedOp> {
+    self.ops.get(x.id()).copied()
+  }
+
+  // The value of a call of `len`.
+
+  pub fn len_of(&self, x: &AstExpr<'_>) -> Option<u64> {
+    self.lens.get(x.id()).copied()
+  }
+
+  pub fn match_table(&self, x: &AstMatch<'_>) -> Option<&MatchTable> {
+    self.matches.get(x.id)
+  }
+}
+
+impl fmt::Display for Type {
+  fn fmt(&self, out: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      Self::Array(x, n) => write!(out, "[{}; {}]", x, n),
+      Self::Fun(x, y) => write!(out, "fun({}) -> {}", comma_separated(x), y),
+      Self::Scalar(x) => write!(out, "{}", x.to_name()),
+      Self::Struct(x) => write!(out, "{}", x),
+      Self::Tuple(x) if x.len() == 1 => write!(out, "({},)", x[0]),
+      Self::Tuple(x) => write!(out, "({})", comma_separated(x)),
+    }
+  }
+}
+
+impl TypedOp {
+  // The types of the operands and of the result.
+
+  pub fn types(self) -> (&'static [Ty], Ty) {
+    match self {
+      Self::Op11(x) => { let (a, [b]) = &bytecode::TYPE_OP_11[x as usize]; (a, *b) }
+      Self::Op21(x) => { let (a, [b]) = &bytecode::TYPE_OP_21[x as usize]; (a, *b) }
+    }
+  }
+}
+
+fn comma_separated<T: ToString>(xs: &[T]) -> String {
+  xs.iter().map(|x| x.to_string()).collect::<Vec<_>>().join(", ")
+}
+
+pub fn check_types<'a>(items: &'a [AstItem<'a>], names: &Resolution, structs: &StructTable<'a>) -> Result<Typing, Vec<TypeError>> {
+  let mut s =
+    Checker {
+      names,
+      structs,
+      terms: Vec::new(),
+      bindings: Vec::new(),
+      exprs: Vec::new(),
+      ops: Vec::new(),
+      intrinsics: Vec::new(),
+      lens: Vec::new(),
+      numbers: Vec::new(),
+      matches: Vec::new(),
+      loops: Vec::new(),
+      result: Var(0),
+      errors: Vec::new(),
+    };
+
+  for _ in names.bindings.iter() {
+    let v = s.fresh(Term::Any);
+    s.bindings.push(v);
+  }
+
+  // Every function's signature is known before any body is checked, so
+  // calls can come before definitions.
+
+  s.declare(items);
+  s.items(items);
+  s.solve()
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+struct Var(u32);
+
+#[derive(Clone)]
+enum Term {
+  Any,
+  Int,
+  Link(Var),
+  Array(Var, u64),
+  Fun(Box<[Var]>, Var),
+  Scalar(Ty),
+  Struct(Symbol),
+  Tuple(Box<[Var]>),
+}
+
+struct Checker<'s, 'a> {
+  names: &'s Resolution,
+  structs: &'s StructTable<'a>,
+  terms: Vec<Term>,
+  bindings: Vec<Var>,
+  exprs: Vec<(&'a AstExpr<'a>, Var)>,
+  ops: Vec<(&'a AstExpr<'a>, AstOp, usize, Var)>,
+  intrinsics: Vec<(&'a AstExpr<'a>, TypedOp)>,
+  lens: Vec<(&'a AstExpr<'a>, u64)>,
+  numbers: Vec<(&'a AstNumber<'a>, Var)>,
+  matches: Vec<(&'a AstMatch<'a>, Var)>,
+  loops: Vec<Var>,
+  result: Var,
+  errors: Vec<TypeError>,
+}
+
+// UNIFICATION
+
+impl<'s, 'a> Checker<'s, 'a> {
+  fn fresh(&mut self, t: Term) -> Var {
+    let v = Var(self.terms.len() as u32);
+    self.terms.push(t);
+    v
+  }
+
+  fn unit(&mut self) -> Var {
+    self.fresh(Term::Tuple(Box::new([])))
+  }
+
+  fn scalar(&mut self, t: Ty) -> Var {
+    self.fresh(Term::Scalar(t))
+  }
+
+  fn term(&self, v: Var) -> &Term {
+    &self.terms[v.0 as usize]
+  }
+
+  fn find(&self, mut v: Var) -> Var {
+    while let Term::Link(w) = *self.term(v) { v = w }
+    v
+  }
+
+  fn link(&mut self, v: Var, w: Var) {
+    self.terms[v.0 as usize] = Term::Link(w);
+  }
+
+  fn occurs(&self, v: Var, w: Var) -> bool {
+    let w = self.find(w);
+    if v == w { return true; }
+    match self.term(w) {
+      Term::Array(x, _) => self.occurs(v, *x),
+      Term::Fun(xs, y) => xs.iter().any(|&x| self.occurs(v, x)) || self.occurs(v, *y),
+      Term::Tuple(xs) => xs.iter().any(|&x| self.occurs(v, x)),
+      _ => false,
+    }
+  }
+
+  fn unify(&mut self, a: Var, b: Var) -> bool {
+    let a = self.find(a);
+    let b = self.find(b);
+
+    if a == b { return true; }
+
+    match (self.term(a).clone(), self.term(b).clone()) {
+      (Term::Any, _) => {
+        if self.occurs(a, b) { return false; }
+        self.link(a, b);
+        true
+      }
+      (_, Term::Any) => {
+        if self.occurs(b, a) { return false; }
+        self.link(b, a);
+        true
+      }
+      (Term::Int, Term::Int) => {
+        self.link(a, b);
+        true
+      }
+      (Term::Int, Term::Scalar(t)) if t != Ty::Bool => {
+        self.link(a, b);
+        true
+      }
+      (Term::Scalar(t), Term::Int) if t != Ty::Bool => {
+        self.link(b, a);
+        true
+      }
+      (Term::Scalar(x), Term::Scalar(y)) => {
+        x == y
+      }
+      (Term::Struct(x), Term::Struct(y)) => {
+        x == y
+      }
+      (Term::Array(x, n), Term::Array(y, m)) => {
+        n == m && self.unify(x, y)
+      }
+      (Term::Tuple(xs), Term::Tuple(ys)) => {
+        xs.len() == ys.len() && xs.iter().zip(ys.iter()).all(|(&x, &y)| self.unify(x, y))
+      }
+      (Term::Fun(xs, r), Term::Fun(ys, s)) => {
+        xs.len() == ys.len() && xs.iter().zip(ys.iter()).all(|(&x, &y)| self.unify(x, y)) && self.unify(r, s)
+      }
+      _ => {
+        false
+      }
+    }
+  }
+
+  // Requires the type `found` of something at `span` to be `expected`.
+
+  fn expect(&mut self, expected: Var, found: Var, span: Span) {
+    if ! self.unify(expected, found) {
+      let e = TypeError::Mismatch(self.show(expected), self.show(found), span);
+      self.errors.push(e);
+    }
+  }
+
+  fn show(&self, v: Var) -> String {
+    let v = self.find(v);
+    let shows = |xs: &[Var]| xs.iter().map(|&x| self.show(x)).collect::<Vec<_>>().join(", ");
+    match self.term(v) {
+      Term::Any | Term::Link(_) => "_".to_string(),
+      Term::Int => "{integer}".to_string(),
+      Term::Array(x, n) => format!("[{}; {}]", self.show(*x), n),
+      Term::Fun(xs, y) => format!("fun({}) -> {}", shows(xs), self.show(*y)),
+      Term::Scalar(t) => t.to_name().to_string(),
+      Term::Struct(x) => x.to_string(),
+      Term::Tuple(xs) => format!("({})", shows(xs)),
+    }
+  }
+
+  fn is_determined(&self, v: Var) -> bool {
+    let v = self.find(v);
+    match self.term(v) {
+      Term::Any | Term::Link(_) => false,
+      Term::Int | Term::Scalar(_) | Term::Struct(_) => true,
+      Term::Array(x, _) => self.is_determined(*x),
+      Term::Fun(xs, y) => xs.iter().all(|&x| self.is_determined(x)) && self.is_determined(*y),
+      Term::Tuple(xs) => xs.iter().all(|&x| self.is_determined(x)),
+    }
+  }
+
+  fn zonk(&self, v: Var) -> Type {
+    let v = self.find(v);
+    let zonks = |xs: &[Var]| xs.iter().map(|&x| self.zonk(x)).collect::<Box<[_]>>();
+    match self.term(v) {
+      Term::Any | Term::Link(_) => Type::Tuple(Box::new([])),
+      Term::Int => Type::Scalar(Ty::I64),
+      Term::Array(x, n) => Type::Array(Box::new(self.zonk(*x)), *n),
+      Term::Fun(xs, y) => Type::Fun(zonks(xs), Box::new(self.zonk(*y))),
+      Term::Scalar(t) => Type::Scalar(*t),
+      Term::Struct(x) => Type::Struct(*x),
+      Term::Tuple(xs) => Type::Tuple(zonks(xs)),
+    }
+  }
+
+  // The type of a sequence of values, which is a tuple unless there is
+  // exactly one.
+
+  fn seq(&mut self, xs: Vec<Var>) -> Var {
+    if xs.len() == 1 { return xs[0]; }
+    self.fresh(Term::Tuple(xs.into_boxed_slice()))
+  }
+}
+
+// INFERENCE
+
+fn exprs_span(xs: &[AstExpr<'_>]) -> Span {
+  let mut a = xs.iter().map(|x| x.span());
+  let first = a.next().unwrap_or(Span { file: 0, start: 0, stop: 0 });
+  a.fold(first, Span::join)
+}
+
+impl<'s, 'a> Checker<'s, 'a> {
+  fn binding(&mut self, x: &'a AstSymbol) -> Var {
+    match self.names.binding_of(x) {
+      Some(id) => self.bindings[id.0 as usize],
+      None => self.fresh(Term::Any),
+    }
+  }
+
+  fn ty(&mut self, ty: &'a AstType<'a>) -> Var {
+    match ty {
+      AstType::Array(x) => {
+        let y = self.ty(&x.0);
+        let n = x.1.value().unwrap_or(0) as u64;
+        self.fresh(Term::Array(y, n))
+      }
+      AstType::Symbol(x) => {
+        if let Some(t) = Ty::from_name(x.0.as_str()) {
+          self.scalar(t)
+        } else if let Some(y) = self.structs.get(x.0) {
+          self.fresh(Term::Struct(y.name.0))
+        } else {
+          self.errors.push(TypeError::UnknownType(x.0.to_string(), x.1));
+          self.fresh(Term::Any)
+        }
+      }
+      AstType::Tuple(x) => {
+        let a = x.0.iter().map(|y| self.ty(y)).collect();
+        self.seq_of(a)
+      }
+    }
+  }
+
+  // A tuple type, even of one element.
+
+  fn seq_of(&mut self, xs: Vec<Var>) -> Var {
+    self.fresh(Term::Tuple(xs.into_boxed_slice()))
+  }
+
+  fn pat(&mut self, pat: &'a AstPat<'a>) -> Var {
+    match pat {
+      AstPat::Symbol(x) => {
+        self.binding(x)
+      }
+      AstPat::Tuple(x) => {
+        let a = x.0.iter().map(|y| self.pat(y)).collect();
+        self.seq_of(a)
+      }
+    }
+  }
+
+  fn params(&mut self, params: &'a [AstParam<'a>]) -> Box<[Var]> {
+    let mut a = Vec::new();
+    for param in params.iter() {
+      let v = self.pat(&param.pat);
+      if let Some(ty) = &param.ty {
+        let t = self.ty(ty);
+        let span = param.pat.span();
+        self.expect(t, v, span);
+      }
+      a.push(v);
+    }
+    a.into_boxed_slice()
+  }
+
+  fn result(&mut self, result: &'a Option<AstType<'a>>) -> Var {
+    match result {
+      Some(ty) => self.ty(ty),
+      None => self.fresh(Term::Any),
+    }
+  }
+
+  fn declare(&mut self, items: &'a [AstItem<'a>]) {
+    for item in items.iter() {
+      match item {
+        AstItem::ConstDef(x) => {
+          if let Some(ty) = &x.ty {
+            let t = self.ty(ty);
+            let v = self.binding(&x.name);
+            self.expect(t, v, x.name.1);
+          }
+        }
+        AstItem::FunDef(x) => {
+          let params = self.params(x.params);
+          let result = self.result(&x.result);
+          let f = self.fresh(Term::Fun(params, result));
+          let v = self.binding(&x.name);
+          self.expect(v, f, x.name.1);
+        }
+        AstItem::Module(x) => {
+          self.declare(x.items);
+        }
+        AstItem::Import(_) | AstItem::MacroDef(_) | AstItem::StaticAssert(_) | AstItem::StructDef(_) => {
+        }
+      }
+    }
+  }
+
+  // A `return` in the value of a constant or in a static assertion gives it
+  // that value, as it would a function.
+
+  fn items(&mut self, items: &'a [AstItem<'a>]) {
+    for item in items.iter() {
+      match item {
+        AstItem::ConstDef(x) => {
+          let v = self.binding(&x.name);
+          self.result = v;
+          let t = self.expr(&x.value, true);
+          self.expect(v, t, x.value.span());
+        }
+        AstItem::FunDef(x) => {
+          let v = self.binding(&x.name);
+          let v = self.find(v);
+          let Term::Fun(_, result) = *self.term(v) else { continue };
+          self.result = result;
+          let t = self.stmt_seq(x.body);
+          let span = x.body.last().map(|y| y.span()).unwrap_or(x.name.1);
+          self.expect(result, t, span);
+        }
+        AstItem::Module(x) => {
+          self.items(x.items);
+        }
+        AstItem::StaticAssert(x) => {
+          let b = self.scalar(Ty::Bool);
+          self.result = b;
+          let t = self.expr(&x.0, true);
+          self.expect(b, t, x.0.span());
+        }
+        AstItem::Import(_) | AstItem::MacroDef(_) | AstItem::StructDef(_) => {
+        }
+      }
+    }
+  }
+
+  // The value of a block is that of its last statement if that is an
+  // expression, and otherwise the empty tuple. A block that ends by jumping
+  // elsewhere can have any type.
+
+  fn stmt_seq(&mut self, body: &'a [AstStmt<'a>]) -> Var {
+    let mut t = self.unit();
+    for (i, stmt) in body.iter().enumerate() {
+      let is_last = i + 1 == body.len();
+      t =
+        match stmt {
+          AstStmt::Break(x) => {
+            let a = self.exprs(x.0);
+            let v = self.seq(a);
+            match self.loops.last() {
+              Some(&l) => self.expect(l, v, exprs_span(x.0)),
+              None => self.errors.push(TypeError::BreakOutsideLoop(exprs_span(x.0))),
+            }
+            self.fresh(Term::Any)
+          }
+          AstStmt::ExprSeq(x) => {
+            let mut a = Vec::new();
+            for y in x.0.iter() { a.push(self.expr(y, is_last)) }
+            if is_last { self.seq(a) } else { self.unit() }
+          }
+          AstStmt::Let(x) => {
+            let a = self.exprs(x.1);
+            let v = self.seq(a);
+            let a = x.0.iter().map(|y| self.pat(y)).collect();
+            let p = self.seq(a);
+            self.expect(p, v, exprs_span(x.1));
+            self.unit()
+          }
+          AstStmt::Return(x) => {
+            let a = self.exprs(x.0);
+            let v = self.seq(a);
+            self.expect(self.result, v, exprs_span(x.0));
+            self.fresh(Term::Any)
+          }
+        };
+    }
+    t
+  }
+
+  fn exprs(&mut self, xs: &'a [AstExpr<'a>]) -> Vec<Var> {
+    xs.iter().map(|x| self.expr(x, true)).collect()
+  }
+
+  // Arithmetic and ordering are only on integers, which helps to infer the
+  // types of operands that are otherwise unconstrained.
+
+  fn op(&mut self, x: &'a AstExpr<'a>, op: AstOp, arity: usize, a: Var) {
+    let is_int =
+      match op {
+        AstOp::Add | AstOp::Div | AstOp::Mul | AstOp::Neg | AstOp::Sub
+          | AstOp::GT | AstOp::GE | AstOp::LT | AstOp::LE => true,
+        AstOp::And | AstOp::Not | AstOp::Or | AstOp::Xor | AstOp::EQ | AstOp::NE => false,
+      };
+    let n = self.fresh(Term::Int);
+    if is_int && ! self.unify(n, a) {
+      let e = TypeError::NoOperator(format!("{} on {}", op.to_name(), self.show(a)), x.span());
+      self.errors.push(e);
+    } else {
+      self.ops.push((x, op, arity, a));
+    }
+  }
+
+  // A callee that is a name with no binding is an intrinsic, as otherwise
+  // resolution would have failed.
+
+  fn intrinsic_of(&self, f: &'a AstExpr<'a>) -> Option<TypedOp> {
+    match f {
+      AstExpr::Symbol(x) if self.names.binding_of(x).is_none() => intrinsic(x.0),
+      _ => None,
+    }
+  }
+
+  fn intrinsic(&mut self, x: &'a AstExpr<'a>, op: TypedOp, args: &'a [AstExpr<'a>]) -> Var {
+    let (inputs, output) = op.types();
+    if args.len() != inputs.len() {
+      self.errors.push(TypeError::Arity(inputs.len(), args.len(), x.span()));
+    }
+    for (y, &t) in args.iter().zip(inputs.iter()) {
+      let a = self.expr(y, true);
+      let b = self.scalar(t);
+      self.expect(b, a, y.span());
+    }
+    self.intrinsics.push((x, op));
+    self.scalar(output)
+  }
+
+  fn is_len(&self, f: &'a AstExpr<'a>) -> bool {
+    matches!(f, AstExpr::Symbol(x) if self.names.binding_of(x).is_none() && is_len(x.0))
+  }
+
+  // As with indexing, the type of the array has to be known from what comes
+  // before.
+
+  fn len(&mut self, x: &'a AstExpr<'a>, args: &'a [AstExpr<'a>]) -> Var {
+    if args.len() != 1 {
+      self.errors.push(TypeError::Arity(1, args.len(), x.span()));
+    }
+    for y in args.iter() {
+      let a = self.expr(y, true);
+      let a = self.find(a);
+      match *self.term(a) {
+        Term::Array(_, n) => self.lens.push((x, n)),
+        _ => self.errors.push(TypeError::CannotInfer(y.span())),
+      }
+    }
+    self.scalar(Ty::I64)
+  }
+
+  // The branches of an `if` or `match` whose value is unused don't need to
+  // agree on a type.
+
+  fn expr(&mut self, x: &'a AstExpr<'a>, is_used: bool) -> Var {
+    let v =
+      match x {
+        AstExpr::Array(y) => {
+          let e = self.fresh(Term::Any);
+          for z in y.0.iter() {
+            let t = self.expr(z, true);
+            self.expect(e, t, z.span());
+          }
+          self.fresh(Term::Array(e, y.0.len() as u64))
+        }
+        AstExpr::ArrayRepeat(y) => {
+          let e = self.expr(&y.0, true);
+          let n = y.1.value().unwrap_or(0) as u64;
+          self.fresh(Term::Array(e, n))
+        }
+        AstExpr::Bool(_) => {
+          self.scalar(Ty::Bool)
+        }
+        AstExpr::Closure(y) => {
+          // The lifted function takes the captured values first, so the
+          // closure takes the rest of its parameters.
+          let f = self.binding(&y.0);
+          let f = self.find(f);
+          let Term::Fun(params, result) = self.term(f).clone() else { unreachable!() };
+          let (captures, params) = params.split_at(y.1.len());
+          for (&v, z) in captures.iter().zip(y.1.iter()) {
+            let t = self.expr(z, true);
+            self.expect(v, t, z.span());
+          }
+          self.fresh(Term::Fun(params.into(), result))
+        }
+        AstExpr::MethodCall(_) | AstExpr::Pipeline(_) | AstExpr::Unquote(_) => {
+          // Removed by module loading, which comes earlier.
+          unreachable!()
+        }
+        AstExpr::Field(y) => {
+          let t = self.expr(&y.0, true);
+          let t = self.find(t);
+          match *self.term(t) {
+            Term::Struct(name) => {
+              let def = self.structs.get(name).unwrap();
+              match def.fields.iter().find(|z| z.name.0 == y.1.0) {
+                Some(z) => self.ty(&z.ty),
+                None => {
+                  self.errors.push(TypeError::NoField(y.1.0.to_string(), y.1.1));
+                  self.fresh(Term::Any)
+                }
+              }
+            }
+            _ => {
+              self.errors.push(TypeError::CannotInfer(x.span()));
+              self.fresh(Term::Any)
+            }
+          }
+        }
+        AstExpr::FunCall(y) => {
+          match self.intrinsic_of(&y.0) {
+            Some(op) => {
+              self.intrinsic(x, op, y.1)
+            }
+            None if self.is_len(&y.0) => {
+              self.len(x, y.1)
+            }
+            None => {
+              let f = self.expr(&y.0, true);
+              let args = self.exprs(y.1).into_boxed_slice();
+              let r = self.fresh(Term::Any);
+              let g = self.fresh(Term::Fun(args, r));
+              self.expect(f, g, x.span());
+              r
+            }
+          }
+        }
+        AstExpr::If(y) => {
+          let c = self.expr(&y.0, true);
+          let b = self.scalar(Ty::Bool);
+          self.expect(b, c, y.0.span());
+          let t = self.stmt_seq(y.1);
+          let e = self.stmt_seq(y.2);
+          if is_used {
+            self.expect(t, e, x.span());
+            t
+          } else {
+            self.unit()
+          }
+        }
+        AstExpr::Index(y) => {
+          let a = self.expr(&y.0, true);
+          let i = self.expr(&y.1, true);
+          let n = self.scalar(Ty::I64);
+          self.expect(n, i, y.1.span());
+          let a = self.find(a);
+          match *self.term(a) {
+            Term::Array(e, _) => e,
+            _ => {
+              self.errors.push(TypeError::CannotInfer(y.0.span()));
+              self.fresh(Term::Any)
+            }
+          }
+        }
+        AstExpr::Lambda(y) => {
+          let params = self.params(y.params);
+          let result = self.result(&y.result);
+          let outer = (self.result, mem::take(&mut self.loops));
+          self.result = result;
+          let t = self.stmt_seq(y.body);
+          self.expect(result, t, x.span());
+          (self.result, self.loops) = outer;
+          self.fresh(Term::Fun(params, result))
+        }
+        AstExpr::Loop(y) => {
+          let l = self.fresh(Term::Any);
+          self.loops.push(l);
+          let _ = self.stmt_seq(y.0);
+          let _ = self.loops.pop();
+          l
+        }
+        AstExpr::Match(y) => {
+          let s = self.expr(&y.expr, true);
+          for arm in y.arms.iter() {
+            for pat in arm.pats.iter() {
+              let t =
+                match pat {
+                  AstMatchPat::Bool(_) => self.scalar(Ty::Bool),
+                  AstMatchPat::Number(_) | AstMatchPat::Range(_) => self.fresh(Term::Int),
+                  AstMatchPat::Wildcard => continue,
+                };
+              self.expect(t, s, arm.span);
+            }
+          }
+          self.matches.push((y, s));
+          let r = self.fresh(Term::Any);
+          for arm in y.arms.iter() {
+            let t = self.stmt_seq(arm.body);
+            if is_used { self.expect(r, t, arm.span) }
+          }
+          if is_used { r } else { self.unit() }
+        }
+        AstExpr::Number(y) => {
+          let v = self.fresh(Term::Int);
+          self.numbers.push((y, v));
+          v
+        }
+        AstExpr::OpCall1(y) => {
+          let a = self.expr(&y.1[0], true);
+          self.op(x, y.0, 1, a);
+          a
+        }
+        AstExpr::OpCall2(y) => {
+          let a = self.expr(&y.1[0], true);
+          let b = self.expr(&y.1[1], true);
+          self.expect(a, b, y.1[1].span());
+          self.op(x, y.0, 2, a);
+          match y.0 {
+            AstOp::EQ | AstOp::NE | AstOp::GT | AstOp::GE | AstOp::LT | AstOp::LE => self.scalar(Ty::Bool),
+            _ => a,
+          }
+        }
+        AstExpr::Record(y) => {
+          let names: Vec<AstSymbol> = y.0.iter().map(|z| z.0).collect();
+          let mut duplicate = false;
+          for (i, z) in names.iter().enumerate() {
+            if names[.. i].iter().any(|w| w.0 == z.0) {
+              self.errors.push(TypeError::DuplicateField(z.0.to_string(), z.1));
+              duplicate = true;
+            }
+          }
+          match self.structs.find_by_fields(&names) {
+            Some(def) if ! duplicate => {
+              for (name, z) in y.0.iter() {
+                let field = def.fields.iter().find(|f| f.name.0 == name.0).unwrap();
+                let f = self.ty(&field.ty);
+                let t = self.expr(z, true);
+                self.expect(f, t, z.span());
+              }
+              self.fresh(Term::Struct(def.name.0))
+            }
+            _ => {
+              for (_, z) in y.0.iter() { let _ = self.expr(z, true); }
+              if ! duplicate { self.errors.push(TypeError::UnknownRecord(x.span())); }
+              self.fresh(Term::Any)
+            }
+          }
+        }
+        AstExpr::Symbol(y) => {
+          self.binding(y)
+        }
+        AstExpr::Tuple(y) => {
+          let a = self.exprs(y.0);
+          self.seq_of(a)
+        }
+      };
+    self.exprs.push((x, v));
+    v
+  }
+}
+
+// SOLVING
+
+fn candidates_11(op: AstOp) -> &'static [Op11] {
+  match op {
+    AstOp::Neg => &[Op11::I64Neg],
+    AstOp::Not => &[Op11::BoolNot, Op11::I64BitNot],
+    _ => &[],
+  }
+}
+
+fn candidates_21(op: AstOp) -> &'static [Op21] {
+  match op {
+    AstOp::Add => &[Op21::I64Add, Op21::I128Add],
+    AstOp::And => &[Op21::BoolAnd, Op21::I64BitAnd],
+    AstOp::Div => &[Op21::I64DivS],
+    AstOp::Mul => &[Op21::I64Mul, Op21::I128Mul],
+    AstOp::Neg | AstOp::Not => &[],
+    AstOp::Or => &[Op21::BoolOr, Op21::I64BitOr],
+    AstOp::Sub => &[Op21::I64Sub, Op21::I128Sub],
+    AstOp::Xor => &[Op21::BoolNeq, Op21::I64BitXor],
+    AstOp::EQ => &[Op21::BoolEq, Op21::I64IsEq, Op21::I128IsEq, Op21::I6IsEq],
+    AstOp::NE => &[Op21::BoolNeq, Op21::I64IsNeq, Op21::I128IsNeq, Op21::I6IsNeq],
+    AstOp::GT => &[Op21::I64IsGtS, Op21::I128IsGtS, Op21::I6IsGt],
+    AstOp::GE => &[Op21::I64IsGeS, Op21::I128IsGeS, Op21::I6IsGe],
+    AstOp::LT => &[Op21::I64IsLtS, Op21::I128IsLtS, Op21::I6IsLt],
+    AstOp::LE => &[Op21::I64IsLeS, Op21::I128IsLeS, Op21::I6IsLe],
+  }
+}
+
+// The operation for an operator whose operands all have type `t`.
+
+fn select_op(op: AstOp, arity: usize, t: &Type) -> Option<TypedOp> {
+  let &Type::Scalar(t) = t else { return None };
+  if arity == 1 {
+    candidates_11(op).iter().find(|x| x.types().0 == [t]).map(|&x| TypedOp::Op11(x))
+  } else {
+    candidates_21(op).iter().find(|x| x.types().0 == [t, t]).map(|&x| TypedOp::Op21(x))
+  }
+}
+
+impl<'s, 'a> Checker<'s, 'a> {
+  fn solve(mut self) -> Result<Typing, Vec<TypeError>> {
+    for i in 0 .. self.terms.len() {
+      if let Term::Int = self.terms[i] { self.terms[i] = Term::Scalar(Ty::I64) }
+    }
+
+    for &(x, v) in self.numbers.iter() {
+      let Type::Scalar(t) = self.zonk(v) else { continue };
+      let (lo, hi) = domain(t);
+      if ! matches!(x.value(), Some(n) if lo <= n && n <= hi) {
+        self.errors.push(TypeError::LiteralRange(t.to_name().to_string(), x.1));
+      }
+    }
+
+    let mut ops = NodeMap::new();
+
+    for &(x, op, arity, v) in self.ops.iter() {
+      let t = self.zonk(v);
+      match select_op(op, arity, &t) {
+        Some(y) => { let _ = ops.insert(x.id(), y); }
+        None => self.errors.push(TypeError::NoOperator(format!("{} on {}", op.to_name(), t), x.span())),
+      }
+    }
+
+    for &(x, op) in self.intrinsics.iter() {
+      let _ = ops.insert(x.id(), op);
+    }
+
+    let mut lens = NodeMap::new();
+
+    for &(x, n) in self.lens.iter() {
+      let _ = lens.insert(x.id(), n);
+    }
+
+    let mut matches = NodeMap::new();
+
+    for &(x, v) in self.matches.iter() {
+      match self.zonk(v) {
+        Type::Scalar(t) => {
+          match check_match(x, t) {
+            Ok(y) => { let _ = matches.insert(x.id, y); }
+            Err(e) => self.errors.push(TypeError::Match(e)),
+          }
+        }
+        t => {
+          self.errors.push(TypeError::Mismatch("a scalar".to_string(), t.to_string(), x.span));
+        }
+      }
+    }
+
+    // A type that is still undetermined here is ambiguous. This is only
+    // checked when there are no other errors, since recovering from those
+    // leaves types undetermined.
+
+    if self.errors.is_empty() {
+      for (i, &v) in self.bindings.iter().enumerate() {
+        if ! self.is_determined(v) {
+          let e = TypeError::Ambiguous(self.show(v), self.names.bindings[i].name.1);
+          self.errors.push(e);
+        }
+      }
+    }
+
+    if ! self.errors.is_empty() { return Err(self.errors); }
+
+    let bindings = self.bindings.iter().map(|&v| self.zonk(v)).collect();
+    let mut exprs = NodeMap::new();
+
+    for &(x, v) in self.exprs.iter() {
+      let _ = exprs.insert(x.id(), self.zonk(v));
+    }
+
+    Ok(Typing { bindings, exprs, ops, lens, matches })
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  struct Ops<'t>(&'t Typing, Vec<TypedOp>);
+
+  impl<'t, 'a> Visitor<'a> for Ops<'t> {
+    fn visit_expr(&mut self, x: &'a AstExpr<'a>) {
+      if let Some(op) = self.0.op_of(x) { self.1.push(op) }
+      walk_expr(self, x);
+    }
+  }
+
+  // Lists the type of each binding, and the operation of each operator in
+  // the order written.
+
+  fn check(src: &str) -> Result<(Vec<String>, Vec<TypedOp>), Vec<TypeError>> {
+    let mut arena = Arena::new();
+    let aa = arena.allocator_mut();
+    let items = Parser::new(src.as_bytes()).parse_file(aa).unwrap();
+    let structs = StructTable::new(items).unwrap();
+    let names = resolve_names(items).unwrap();
+    let typing = check_types(items, &names, &structs)?;
+    let types = (0 .. names.bindings.len()).map(|i| format!("{}: {}", names.bindings[i].name.0, typing.binding_type(BindingId(i as u32)))).collect();
+    let mut ops = Ops(&typing, Vec::new());
+    for item in items.iter() { ops.visit_item(item) }
+    Ok((types, ops.1))
+  }
+
+  #[test]
+  fn literals_default_to_i64() {
+    let (types, ops) = check("fun f(): i128 let a = 1 let b = 2 b end fun g(x: i6): bool let k = 3 x < k end").unwrap();
+    assert_eq!(types, ["f: fun() -> i128", "g: fun(i6) -> bool", "a: i64", "b: i128", "x: i6", "k: i6"]);
+    assert_eq!(ops, [TypedOp::Op21(Op21::I6IsLt)]);
+  }
+
+  #[test]
+  fn operators_follow_operand_types() {
+    let (types, ops) = check("fun f(a: i64, b: i64): i64 a + b end fun g(a: i128): i128 a + 1 end").unwrap();
+    assert_eq!(types, ["f: fun(i64, i64) -> i64", "g: fun(i128) -> i128", "a: i64", "b: i64", "a: i128"]);
+    assert_eq!(ops, [TypedOp::Op21(Op21::I64Add), TypedOp::Op21(Op21::I128Add)]);
+  }
+
+  #[test]
+  fn control_flow() {
+    let src = "fun f(c: bool) let x = if c then 1 else 2 end let y = loop break c end let z = if c then return end end";
+    let (types, _) = check(src).unwrap();
+    assert_eq!(types, ["f: fun(bool) -> ()", "c: bool", "x: i64", "y: bool", "z: ()"]);
+
+    let e = check("fun f() break end").unwrap_err();
+    assert!(matches!(e.as_slice(), [TypeError::BreakOutsideLoop(_)]));
+  }
+
+  #[test]
+  fn mismatch() {
+    let e = check("fun f(c: bool): i64 c end").unwrap_err();
+    assert!(matches!(e.as_slice(), [TypeError::Mismatch(a, b, _)] if a == "i64" && b == "bool"));
+    assert_eq!(e[0].span(), Span { file: 0, start: 20, stop: 21 });
+  }
+
+  #[test]
+  fn ambiguous() {
+    let e = check("fun f(x) 1 end").unwrap_err();
+    assert!(matches!(e.as_slice(), [TypeError::Ambiguous(a, _), TypeError::Ambiguous(b, _)] if a == "fun(_) -> i64" && b == "_"));
+    assert_eq!(e[0].span(), Span { file: 0, start: 4, stop: 5 });
+    assert_eq!(e[1].span(), Span { file: 0, start: 6, stop: 7 });
+  }
+}
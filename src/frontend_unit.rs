@@ -0,0 +1,106 @@
+use crate::prelude::*;
+
+use std::thread;
+
+// PARSED UNITS
+//
+// A `ParsedUnit` owns the text of a source file, the arena that its tree is
+// allocated in, and the tree, so that a parsed file can be returned, kept
+// across passes, or sent to another thread. It also records the path of the
+// module that the file declares. A module that is declared inline is in the
+// file of its parent.
+//
+// The loader parses the files of a program in parallel, each on a thread of
+// its own, and keeps the units so that later passes can get at the text.
+//
+// The tree borrows from the arena, so it is stored with its lifetime erased
+// and handed out only for as long as the unit is borrowed. The arena is boxed
+// and nothing is allocated in it after parsing, so the tree stays where it is
+// for as long as the unit lives. The parser copies everything that the tree
+// holds onto out of the source, so the tree doesn't borrow that.
+
+pub struct ParsedUnit {
+  module: Vec<Symbol>,
+  path: PathBuf,
+  source: Box<[u8]>,
+  items: &'static [AstItem<'static>],
+  _arena: Box<Arena>,
+}
+
+// SAFETY: The tree is immutable and only points into the arena, which is only
+// reachable through the unit.
+
+unsafe impl Send for ParsedUnit {}
+
+impl ParsedUnit {
+  pub fn parse(file: u32, module: Vec<Symbol>, path: PathBuf, source: Vec<u8>) -> Result<Self, ParseError> {
+    let mut arena = Box::new(Arena::new());
+    let items = Parser::with_file(&source, file).parse_file(arena.allocator_mut())?;
+
+    // SAFETY: See above. `items` lives as long as the arena does, and it is
+    // never handed out with a longer lifetime than a borrow of the unit.
+
+    let items = unsafe { mem::transmute::<&[AstItem<'_>], &'static [AstItem<'static>]>(items) };
+
+    Ok(Self { module, path, source: source.into_boxed_slice(), items, _arena: arena })
+  }
+
+  pub fn read(file: u32, module: Vec<Symbol>, path: &Path) -> Result<Self, ModuleError> {
+    let source =
+      match std::fs::read(path) {
+        Ok(source) => source,
+        Err(e) => return Err(ModuleError::Io(path.to_path_buf(), e)),
+      };
+
+    match Self::parse(file, module, path.to_path_buf(), source) {
+      Ok(unit) => Ok(unit),
+      Err(_) => Err(ModuleError::Parse(path.to_path_buf())),
+    }
+  }
+
+  pub fn module(&self) -> &[Symbol] {
+    &self.module
+  }
+
+  pub fn path(&self) -> &Path {
+    &self.path
+  }
+
+  pub fn source(&self) -> &[u8] {
+    &self.source
+  }
+
+  pub fn items<'a>(&'a self) -> &'a [AstItem<'a>] {
+    self.items
+  }
+}
+
+// Reads and parses files in parallel, numbering them from `first`. The results
+// are in the order of the files.
+
+pub fn read_units(first: u32, files: &[(Vec<Symbol>, PathBuf)]) -> Vec<Result<ParsedUnit, ModuleError>> {
+  thread::scope(|s| {
+    let a: Vec<_> =
+      files
+        .iter()
+        .enumerate()
+        .map(|(i, (module, path))| s.spawn(move || ParsedUnit::read(first + i as u32, module.clone(), path)))
+        .collect();
+    a.into_iter().map(|x| x.join().unwrap()).collect()
+  })
+}
+
+// Where a span starts, as `path:line:col`, given the files that the program
+// was read from.
+
+pub fn locate(units: &[ParsedUnit], span: Span) -> String {
+  match units.get(span.file as usize) {
+    Some(x) => {
+      let (line, col) = span.line_col(x.source());
+      format!("{}:{}:{}", x.path().display(), line, col)
+    }
+    None => {
+      span.to_string()
+    }
+  }
+}
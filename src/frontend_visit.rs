@@ -0,0 +1,637 @@
+use crate::prelude::*;
+
+// A read-only traversal of the AST. Each method's default visits the node's
+// children in source order, by calling the `walk_` function of the same name,
+// so an implementation only overrides the nodes it cares about and calls the
+// `walk_` function itself to keep going. Every statement list goes through
+// `visit_block`, which is where a pass opens and closes a scope.
+
+pub trait Visitor<'a> {
+  fn visit_item(&mut self, x: &'a AstItem<'a>) { walk_item(self, x) }
+  fn visit_const_def(&mut self, x: &'a AstConstDef<'a>) { walk_const_def(self, x) }
+  fn visit_fun_def(&mut self, x: &'a AstFunDef<'a>) { walk_fun_def(self, x) }
+  fn visit_import(&mut self, x: &'a AstImport<'a>) { walk_import(self, x) }
+  fn visit_macro_def(&mut self, x: &'a AstMacroDef<'a>) { walk_macro_def(self, x) }
+  fn visit_module(&mut self, x: &'a AstModule<'a>) { walk_module(self, x) }
+  fn visit_static_assert(&mut self, x: &'a AstStaticAssert<'a>) { walk_static_assert(self, x) }
+  fn visit_struct_def(&mut self, x: &'a AstStructDef<'a>) { walk_struct_def(self, x) }
+  fn visit_struct_field(&mut self, x: &'a AstStructField<'a>) { walk_struct_field(self, x) }
+  fn visit_param(&mut self, x: &'a AstParam<'a>) { walk_param(self, x) }
+
+  fn visit_block(&mut self, x: &'a [AstStmt<'a>]) { walk_block(self, x) }
+  fn visit_stmt(&mut self, x: &'a AstStmt<'a>) { walk_stmt(self, x) }
+  fn visit_break(&mut self, x: &'a AstBreak<'a>) { walk_break(self, x) }
+  fn visit_expr_seq(&mut self, x: &'a AstExprSeq<'a>) { walk_expr_seq(self, x) }
+  fn visit_let(&mut self, x: &'a AstLet<'a>) { walk_let(self, x) }
+  fn visit_return(&mut self, x: &'a AstReturn<'a>) { walk_return(self, x) }
+
+  fn visit_expr(&mut self, x: &'a AstExpr<'a>) { walk_expr(self, x) }
+  fn visit_array(&mut self, x: &'a AstArray<'a>) { walk_array(self, x) }
+  fn visit_array_repeat(&mut self, x: &'a AstArrayRepeat<'a>) { walk_array_repeat(self, x) }
+  fn visit_bool(&mut self, _: &'a AstBool) {}
+  fn visit_closure(&mut self, x: &'a AstClosure<'a>) { walk_closure(self, x) }
+  fn visit_field(&mut self, x: &'a AstField<'a>) { walk_field(self, x) }
+  fn visit_fun_call(&mut self, x: &'a AstFunCall<'a>) { walk_fun_call(self, x) }
+  fn visit_if(&mut self, x: &'a AstIf<'a>) { walk_if(self, x) }
+  fn visit_index(&mut self, x: &'a AstIndex<'a>) { walk_index(self, x) }
+  fn visit_lambda(&mut self, x: &'a AstLambda<'a>) { walk_lambda(self, x) }
+  fn visit_loop(&mut self, x: &'a AstLoop<'a>) { walk_loop(self, x) }
+  fn visit_match(&mut self, x: &'a AstMatch<'a>) { walk_match(self, x) }
+  fn visit_match_arm(&mut self, x: &'a AstMatchArm<'a>) { walk_match_arm(self, x) }
+  fn visit_method_call(&mut self, x: &'a AstMethodCall<'a>) { walk_method_call(self, x) }
+  fn visit_number(&mut self, _: &'a AstNumber<'a>) {}
+  fn visit_op_call1(&mut self, x: &'a AstOpCall<'a, 1>) { walk_op_call(self, x) }
+  fn visit_op_call2(&mut self, x: &'a AstOpCall<'a, 2>) { walk_op_call(self, x) }
+  fn visit_pipeline(&mut self, x: &'a AstPipeline<'a>) { walk_pipeline(self, x) }
+  fn visit_record(&mut self, x: &'a AstRecord<'a>) { walk_record(self, x) }
+  fn visit_symbol(&mut self, _: &'a AstSymbol) {}
+  fn visit_tuple(&mut self, x: &'a AstTuple<'a>) { walk_tuple(self, x) }
+  fn visit_unquote(&mut self, x: &'a AstSymbol) { self.visit_symbol(x) }
+
+  fn visit_pat(&mut self, x: &'a AstPat<'a>) { walk_pat(self, x) }
+  fn visit_tuple_pat(&mut self, x: &'a AstTuplePat<'a>) { walk_tuple_pat(self, x) }
+
+  fn visit_type(&mut self, x: &'a AstType<'a>) { walk_type(self, x) }
+  fn visit_array_type(&mut self, x: &'a AstArrayType<'a>) { walk_array_type(self, x) }
+  fn visit_tuple_type(&mut self, x: &'a AstTupleType<'a>) { walk_tuple_type(self, x) }
+
+  fn visit_match_pat(&mut self, x: &'a AstMatchPat<'a>) { walk_match_pat(self, x) }
+  fn visit_range(&mut self, x: &'a AstRange<'a>) { walk_range(self, x) }
+}
+
+pub fn walk_item<'a, V: Visitor<'a> + ?Sized>(v: &mut V, x: &'a AstItem<'a>) {
+  match x {
+    AstItem::ConstDef(x) => v.visit_const_def(x),
+    AstItem::FunDef(x) => v.visit_fun_def(x),
+    AstItem::Import(x) => v.visit_import(x),
+    AstItem::MacroDef(x) => v.visit_macro_def(x),
+    AstItem::Module(x) => v.visit_module(x),
+    AstItem::StaticAssert(x) => v.visit_static_assert(x),
+    AstItem::StructDef(x) => v.visit_struct_def(x),
+  }
+}
+
+pub fn walk_const_def<'a, V: Visitor<'a> + ?Sized>(v: &mut V, x: &'a AstConstDef<'a>) {
+  v.visit_symbol(&x.name);
+  if let Some(ty) = &x.ty { v.visit_type(ty) }
+  v.visit_expr(&x.value);
+}
+
+pub fn walk_fun_def<'a, V: Visitor<'a> + ?Sized>(v: &mut V, x: &'a AstFunDef<'a>) {
+  v.visit_symbol(&x.name);
+  for y in x.params.iter() { v.visit_param(y) }
+  if let Some(ty) = &x.result { v.visit_type(ty) }
+  v.visit_block(x.body);
+}
+
+pub fn walk_import<'a, V: Visitor<'a> + ?Sized>(v: &mut V, x: &'a AstImport<'a>) {
+  for y in x.0.iter() { v.visit_symbol(y) }
+}
+
+pub fn walk_macro_def<'a, V: Visitor<'a> + ?Sized>(v: &mut V, x: &'a AstMacroDef<'a>) {
+  v.visit_symbol(&x.name);
+  for y in x.params.iter() { v.visit_symbol(y) }
+  v.visit_block(x.body);
+}
+
+pub fn walk_module<'a, V: Visitor<'a> + ?Sized>(v: &mut V, x: &'a AstModule<'a>) {
+  v.visit_symbol(&x.name);
+  for y in x.items.iter() { v.visit_item(y) }
+}
+
+pub fn walk_static_assert<'a, V: Visitor<'a> + ?Sized>(v: &mut V, x: &'a AstStaticAssert<'a>) {
+  v.visit_expr(&x.0);
+}
+
+pub fn walk_struct_def<'a, V: Visitor<'a> + ?Sized>(v: &mut V, x: &'a AstStructDef<'a>) {
+  v.visit_symbol(&x.name);
+  for y in x.fields.iter() { v.visit_struct_field(y) }
+}
+
+pub fn walk_struct_field<'a, V: Visitor<'a> + ?Sized>(v: &mut V, x: &'a AstStructField<'a>) {
+  v.visit_symbol(&x.name);
+  v.visit_type(&x.ty);
+}
+
+pub fn walk_param<'a, V: Visitor<'a> + ?Sized>(v: &mut V, x: &'a AstParam<'a>) {
+  v.visit_pat(&x.pat);
+  if let Some(ty) = &x.ty { v.visit_type(ty) }
+}
+
+pub fn walk_block<'a, V: Visitor<'a> + ?Sized>(v: &mut V, x: &'a [AstStmt<'a>]) {
+  for y in x.iter() { v.visit_stmt(y) }
+}
+
+pub fn walk_stmt<'a, V: Visitor<'a> + ?Sized>(v: &mut V, x: &'a AstStmt<'a>) {
+  match x {
+    AstStmt::Break(x) => v.visit_break(x),
+    AstStmt::ExprSeq(x) => v.visit_expr_seq(x),
+    AstStmt::Let(x) => v.visit_let(x),
+    AstStmt::Return(x) => v.visit_return(x),
+  }
+}
+
+pub fn walk_break<'a, V: Visitor<'a> + ?Sized>(v: &mut V, x: &'a AstBreak<'a>) {
+  for y in x.0.iter() { v.visit_expr(y) }
+}
+
+pub fn walk_expr_seq<'a, V: Visitor<'a> + ?Sized>(v: &mut V, x: &'a AstExprSeq<'a>) {
+  for y in x.0.iter() { v.visit_expr(y) }
+}
+
+pub fn walk_let<'a, V: Visitor<'a> + ?Sized>(v: &mut V, x: &'a AstLet<'a>) {
+  for y in x.0.iter() { v.visit_pat(y) }
+  for y in x.1.iter() { v.visit_expr(y) }
+}
+
+pub fn walk_return<'a, V: Visitor<'a> + ?Sized>(v: &mut V, x: &'a AstReturn<'a>) {
+  for y in x.0.iter() { v.visit_expr(y) }
+}
+
+pub fn walk_expr<'a, V: Visitor<'a> + ?Sized>(v: &mut V, x: &'a AstExpr<'a>) {
+  match x {
+    AstExpr::Array(x) => v.visit_array(x),
+    AstExpr::ArrayRepeat(x) => v.visit_array_repeat(x),
+    AstExpr::Bool(x) => v.visit_bool(x),
+    AstExpr::Closure(x) => v.visit_closure(x),
+    AstExpr::Field(x) => v.visit_field(x),
+    AstExpr::FunCall(x) => v.visit_fun_call(x),
+    AstExpr::If(x) => v.visit_if(x),
+    AstExpr::Index(x) => v.visit_index(x),
+    AstExpr::Lambda(x) => v.visit_lambda(x),
+    AstExpr::Loop(x) => v.visit_loop(x),
+    AstExpr::Match(x) => v.visit_match(x),
+    AstExpr::MethodCall(x) => v.visit_method_call(x),
+    AstExpr::Number(x) => v.visit_number(x),
+    AstExpr::OpCall1(x) => v.visit_op_call1(x),
+    AstExpr::OpCall2(x) => v.visit_op_call2(x),
+    AstExpr::Pipeline(x) => v.visit_pipeline(x),
+    AstExpr::Record(x) => v.visit_record(x),
+    AstExpr::Symbol(x) => v.visit_symbol(x),
+    AstExpr::Tuple(x) => v.visit_tuple(x),
+    AstExpr::Unquote(x) => v.visit_unquote(x),
+  }
+}
+
+pub fn walk_array<'a, V: Visitor<'a> + ?Sized>(v: &mut V, x: &'a AstArray<'a>) {
+  for y in x.0.iter() { v.visit_expr(y) }
+}
+
+pub fn walk_array_repeat<'a, V: Visitor<'a> + ?Sized>(v: &mut V, x: &'a AstArrayRepeat<'a>) {
+  v.visit_expr(&x.0);
+  v.visit_number(&x.1);
+}
+
+pub fn walk_closure<'a, V: Visitor<'a> + ?Sized>(v: &mut V, x: &'a AstClosure<'a>) {
+  v.visit_symbol(&x.0);
+  for y in x.1.iter() { v.visit_expr(y) }
+}
+
+pub fn walk_field<'a, V: Visitor<'a> + ?Sized>(v: &mut V, x: &'a AstField<'a>) {
+  v.visit_expr(&x.0);
+  v.visit_symbol(&x.1);
+}
+
+pub fn walk_fun_call<'a, V: Visitor<'a> + ?Sized>(v: &mut V, x: &'a AstFunCall<'a>) {
+  v.visit_expr(&x.0);
+  for y in x.1.iter() { v.visit_expr(y) }
+}
+
+pub fn walk_if<'a, V: Visitor<'a> + ?Sized>(v: &mut V, x: &'a AstIf<'a>) {
+  v.visit_expr(&x.0);
+  v.visit_block(x.1);
+  v.visit_block(x.2);
+}
+
+pub fn walk_index<'a, V: Visitor<'a> + ?Sized>(v: &mut V, x: &'a AstIndex<'a>) {
+  v.visit_expr(&x.0);
+  v.visit_expr(&x.1);
+}
+
+pub fn walk_lambda<'a, V: Visitor<'a> + ?Sized>(v: &mut V, x: &'a AstLambda<'a>) {
+  for y in x.params.iter() { v.visit_param(y) }
+  if let Some(ty) = &x.result { v.visit_type(ty) }
+  v.visit_block(x.body);
+}
+
+pub fn walk_loop<'a, V: Visitor<'a> + ?Sized>(v: &mut V, x: &'a AstLoop<'a>) {
+  v.visit_block(x.0);
+}
+
+pub fn walk_match<'a, V: Visitor<'a> + ?Sized>(v: &mut V, x: &'a AstMatch<'a>) {
+  v.visit_expr(&x.expr);
+  for y in x.arms.iter() { v.visit_match_arm(y) }
+}
+
+pub fn walk_match_arm<'a, V: Visitor<'a> + ?Sized>(v: &mut V, x: &'a AstMatchArm<'a>) {
+  for y in x.pats.iter() { v.visit_match_pat(y) }
+  v.visit_block(x.body);
+}
+
+pub fn walk_method_call<'a, V: Visitor<'a> + ?Sized>(v: &mut V, x: &'a AstMethodCall<'a>) {
+  v.visit_expr(&x.0);
+  v.visit_symbol(&x.1);
+  for y in x.2.iter() { v.visit_expr(y) }
+}
+
+pub fn walk_op_call<'a, V: Visitor<'a> + ?Sized, const N: usize>(v: &mut V, x: &'a AstOpCall<'a, N>) {
+  for y in x.1.iter() { v.visit_expr(y) }
+}
+
+pub fn walk_pipeline<'a, V: Visitor<'a> + ?Sized>(v: &mut V, x: &'a AstPipeline<'a>) {
+  v.visit_expr(&x.0);
+  v.visit_expr(&x.1);
+}
+
+pub fn walk_record<'a, V: Visitor<'a> + ?Sized>(v: &mut V, x: &'a AstRecord<'a>) {
+  for (y, z) in x.0.iter() {
+    v.visit_symbol(y);
+    v.visit_expr(z);
+  }
+}
+
+pub fn walk_tuple<'a, V: Visitor<'a> + ?Sized>(v: &mut V, x: &'a AstTuple<'a>) {
+  for y in x.0.iter() { v.visit_expr(y) }
+}
+
+pub fn walk_pat<'a, V: Visitor<'a> + ?Sized>(v: &mut V, x: &'a AstPat<'a>) {
+  match x {
+    AstPat::Symbol(x) => v.visit_symbol(x),
+    AstPat::Tuple(x) => v.visit_tuple_pat(x),
+  }
+}
+
+pub fn walk_tuple_pat<'a, V: Visitor<'a> + ?Sized>(v: &mut V, x: &'a AstTuplePat<'a>) {
+  for y in x.0.iter() { v.visit_pat(y) }
+}
+
+pub fn walk_type<'a, V: Visitor<'a> + ?Sized>(v: &mut V, x: &'a AstType<'a>) {
+  match x {
+    AstType::Array(x) => v.visit_array_type(x),
+    AstType::Symbol(x) => v.visit_symbol(x),
+    AstType::Tuple(x) => v.visit_tuple_type(x),
+  }
+}
+
+pub fn walk_array_type<'a, V: Visitor<'a> + ?Sized>(v: &mut V, x: &'a AstArrayType<'a>) {
+  v.visit_type(&x.0);
+  v.visit_number(&x.1);
+}
+
+pub fn walk_tuple_type<'a, V: Visitor<'a> + ?Sized>(v: &mut V, x: &'a AstTupleType<'a>) {
+  for y in x.0.iter() { v.visit_type(y) }
+}
+
+pub fn walk_match_pat<'a, V: Visitor<'a> + ?Sized>(v: &mut V, x: &'a AstMatchPat<'a>) {
+  match x {
+    AstMatchPat::Bool(x) => v.visit_bool(x),
+    AstMatchPat::Number(x) => v.visit_number(x),
+    AstMatchPat::Range(x) => v.visit_range(x),
+    AstMatchPat::Wildcard => {}
+  }
+}
+
+pub fn walk_range<'a, V: Visitor<'a> + ?Sized>(v: &mut V, x: &'a AstRange<'a>) {
+  v.visit_number(&x.0);
+  v.visit_number(&x.1);
+}
+
+// A traversal that rebuilds the AST in the folder's arena, which may be a
+// different one than the input lives in. Each method's default rebuilds the
+// node from its folded children by calling the `rebuild_` function of the
+// same name, so a desugaring overrides only the nodes that it rewrites. Each
+// node that it builds gets a fresh ID. Statement and expression lists go
+// through `fold_block` and `fold_expr_list`, so a pass can open a scope there
+// or splice in more than one node for an element.
+
+pub trait Folder<'a, 'b> {
+  fn allocator(&mut self) -> &mut Allocator<'b>;
+
+  fn fold_item(&mut self, x: &'a AstItem<'a>) -> AstItem<'b> { rebuild_item(self, x) }
+  fn fold_const_def(&mut self, x: &'a AstConstDef<'a>) -> AstConstDef<'b> { rebuild_const_def(self, x) }
+  fn fold_fun_def(&mut self, x: &'a AstFunDef<'a>) -> AstFunDef<'b> { rebuild_fun_def(self, x) }
+  fn fold_import(&mut self, x: &'a AstImport<'a>) -> AstImport<'b> { rebuild_import(self, x) }
+  fn fold_macro_def(&mut self, x: &'a AstMacroDef<'a>) -> AstMacroDef<'b> { rebuild_macro_def(self, x) }
+  fn fold_module(&mut self, x: &'a AstModule<'a>) -> AstModule<'b> { rebuild_module(self, x) }
+  fn fold_static_assert(&mut self, x: &'a AstStaticAssert<'a>) -> AstStaticAssert<'b> { AstStaticAssert(self.fold_expr(&x.0), NodeId::fresh()) }
+  fn fold_struct_def(&mut self, x: &'a AstStructDef<'a>) -> AstStructDef<'b> { rebuild_struct_def(self, x) }
+  fn fold_struct_field(&mut self, x: &'a AstStructField<'a>) -> AstStructField<'b> { rebuild_struct_field(self, x) }
+  fn fold_param(&mut self, x: &'a AstParam<'a>) -> AstParam<'b> { rebuild_param(self, x) }
+
+  fn fold_block(&mut self, x: &'a [AstStmt<'a>]) -> &'b [AstStmt<'b>] { fold_slice(self, x, Self::fold_stmt) }
+  fn fold_stmt(&mut self, x: &'a AstStmt<'a>) -> AstStmt<'b> { rebuild_stmt(self, x) }
+  fn fold_break(&mut self, x: &'a AstBreak<'a>) -> AstBreak<'b> { AstBreak(fold_exprs(self, x.0), NodeId::fresh()) }
+  fn fold_expr_seq(&mut self, x: &'a AstExprSeq<'a>) -> AstExprSeq<'b> { AstExprSeq(fold_exprs(self, x.0), NodeId::fresh()) }
+  fn fold_let(&mut self, x: &'a AstLet<'a>) -> AstLet<'b> { rebuild_let(self, x) }
+  fn fold_return(&mut self, x: &'a AstReturn<'a>) -> AstReturn<'b> { AstReturn(fold_exprs(self, x.0), NodeId::fresh()) }
+
+  fn fold_expr_list(&mut self, x: &'a [AstExpr<'a>]) -> &'b [AstExpr<'b>] { fold_slice(self, x, Self::fold_expr) }
+  fn fold_expr(&mut self, x: &'a AstExpr<'a>) -> AstExpr<'b> { rebuild_expr(self, x) }
+  fn fold_array(&mut self, x: &'a AstArray<'a>) -> AstArray<'b> { AstArray(fold_exprs(self, x.0), NodeId::fresh()) }
+  fn fold_array_repeat(&mut self, x: &'a AstArrayRepeat<'a>) -> AstArrayRepeat<'b> { rebuild_array_repeat(self, x) }
+  fn fold_bool(&mut self, x: &'a AstBool) -> AstBool { AstBool(x.0, x.1, NodeId::fresh()) }
+  fn fold_closure(&mut self, x: &'a AstClosure<'a>) -> AstClosure<'b> { rebuild_closure(self, x) }
+  fn fold_field(&mut self, x: &'a AstField<'a>) -> AstField<'b> { rebuild_field(self, x) }
+  fn fold_fun_call(&mut self, x: &'a AstFunCall<'a>) -> AstFunCall<'b> { rebuild_fun_call(self, x) }
+  fn fold_if(&mut self, x: &'a AstIf<'a>) -> AstIf<'b> { rebuild_if(self, x) }
+  fn fold_index(&mut self, x: &'a AstIndex<'a>) -> AstIndex<'b> { rebuild_index(self, x) }
+  fn fold_lambda(&mut self, x: &'a AstLambda<'a>) -> AstLambda<'b> { rebuild_lambda(self, x) }
+  fn fold_loop(&mut self, x: &'a AstLoop<'a>) -> AstLoop<'b> { AstLoop(fold_stmts(self, x.0), NodeId::fresh()) }
+  fn fold_match(&mut self, x: &'a AstMatch<'a>) -> AstMatch<'b> { rebuild_match(self, x) }
+  fn fold_match_arm(&mut self, x: &'a AstMatchArm<'a>) -> AstMatchArm<'b> { rebuild_match_arm(self, x) }
+  fn fold_method_call(&mut self, x: &'a AstMethodCall<'a>) -> AstMethodCall<'b> { rebuild_method_call(self, x) }
+  fn fold_number(&mut self, x: &'a AstNumber<'a>) -> AstNumber<'b> { AstNumber(self.allocator().copy_str(x.0), x.1, NodeId::fresh()) }
+  fn fold_op_call1(&mut self, x: &'a AstOpCall<'a, 1>) -> AstOpCall<'b, 1> { rebuild_op_call(self, x) }
+  fn fold_op_call2(&mut self, x: &'a AstOpCall<'a, 2>) -> AstOpCall<'b, 2> { rebuild_op_call(self, x) }
+  fn fold_pipeline(&mut self, x: &'a AstPipeline<'a>) -> AstPipeline<'b> { rebuild_pipeline(self, x) }
+  fn fold_record(&mut self, x: &'a AstRecord<'a>) -> AstRecord<'b> { rebuild_record(self, x) }
+  fn fold_symbol(&mut self, x: &'a AstSymbol) -> AstSymbol { AstSymbol(x.0, x.1, NodeId::fresh()) }
+  fn fold_tuple(&mut self, x: &'a AstTuple<'a>) -> AstTuple<'b> { AstTuple(fold_exprs(self, x.0), NodeId::fresh()) }
+  fn fold_unquote(&mut self, x: &'a AstSymbol) -> AstSymbol { self.fold_symbol(x) }
+
+  fn fold_pat(&mut self, x: &'a AstPat<'a>) -> AstPat<'b> { rebuild_pat(self, x) }
+  fn fold_tuple_pat(&mut self, x: &'a AstTuplePat<'a>) -> AstTuplePat<'b> { AstTuplePat(fold_slice(self, x.0, Self::fold_pat)) }
+
+  fn fold_type(&mut self, x: &'a AstType<'a>) -> AstType<'b> { rebuild_type(self, x) }
+  fn fold_array_type(&mut self, x: &'a AstArrayType<'a>) -> AstArrayType<'b> { rebuild_array_type(self, x) }
+  fn fold_tuple_type(&mut self, x: &'a AstTupleType<'a>) -> AstTupleType<'b> { AstTupleType(fold_slice(self, x.0, Self::fold_type)) }
+
+  fn fold_match_pat(&mut self, x: &'a AstMatchPat<'a>) -> AstMatchPat<'b> { rebuild_match_pat(self, x) }
+  fn fold_range(&mut self, x: &'a AstRange<'a>) -> AstRange<'b> { AstRange(self.fold_number(&x.0), self.fold_number(&x.1)) }
+}
+
+// A folder that keeps track of which local names are in scope, for a pass
+// that treats a local differently from an item of the same name. The names
+// that a `let` binds go out of scope at the end of its block. Such a folder
+// forwards `fold_block`, `fold_let`, and `fold_lambda` to the `fold_scoped_`
+// functions of the same names, and calls `bind` for any other binding that
+// it cares about.
+
+pub trait ScopedFolder<'a, 'b>: Folder<'a, 'b> {
+  fn locals(&mut self) -> &mut Vec<Symbol>;
+
+  fn bind(&mut self, pat: AstPat<'_>) {
+    let mut a = Vec::new();
+    pat.flatten(&mut a);
+    for x in a.iter() { self.locals().push(x.0) }
+  }
+}
+
+pub fn fold_scoped_block<'a, 'b, F: ScopedFolder<'a, 'b> + ?Sized>(f: &mut F, x: &'a [AstStmt<'a>]) -> &'b [AstStmt<'b>] {
+  let n = f.locals().len();
+  let y = fold_slice(f, x, F::fold_stmt);
+  f.locals().truncate(n);
+  y
+}
+
+// The names that a `let` binds aren't in scope in its own expressions.
+
+pub fn fold_scoped_let<'a, 'b, F: ScopedFolder<'a, 'b> + ?Sized>(f: &mut F, x: &'a AstLet<'a>) -> AstLet<'b> {
+  let y = fold_exprs(f, x.1);
+  let pats = fold_slice(f, x.0, F::fold_pat);
+  for &pat in pats.iter() { f.bind(pat) }
+  AstLet(pats, y, NodeId::fresh())
+}
+
+// A lambda's parameters are in scope only in its body.
+
+pub fn fold_scoped_lambda<'a, 'b, F: ScopedFolder<'a, 'b> + ?Sized>(f: &mut F, x: &'a AstLambda<'a>) -> AstLambda<'b> {
+  let n = f.locals().len();
+  let y = rebuild_lambda(f, x);
+  f.locals().truncate(n);
+  y
+}
+
+// Folds each element of a slice into a new slice in the folder's arena.
+
+pub fn fold_slice<'a, 'b, F, T, U>(f: &mut F, xs: &'a [T], g: impl Fn(&mut F, &'a T) -> U) -> &'b [U]
+where
+  F: Folder<'a, 'b> + ?Sized,
+  U: Copy,
+{
+  let mut a = Vec::new();
+  for x in xs.iter() { a.push(g(f, x)) }
+  f.allocator().copy_slice(a.as_slice())
+}
+
+pub fn fold_stmts<'a, 'b, F: Folder<'a, 'b> + ?Sized>(f: &mut F, xs: &'a [AstStmt<'a>]) -> &'b [AstStmt<'b>] {
+  f.fold_block(xs)
+}
+
+pub fn fold_exprs<'a, 'b, F: Folder<'a, 'b> + ?Sized>(f: &mut F, xs: &'a [AstExpr<'a>]) -> &'b [AstExpr<'b>] {
+  f.fold_expr_list(xs)
+}
+
+fn fold_result<'a, 'b, F: Folder<'a, 'b> + ?Sized>(f: &mut F, x: &'a Option<AstType<'a>>) -> Option<AstType<'b>> {
+  x.as_ref().map(|ty| f.fold_type(ty))
+}
+
+pub fn rebuild_item<'a, 'b, F: Folder<'a, 'b> + ?Sized>(f: &mut F, x: &'a AstItem<'a>) -> AstItem<'b> {
+  match x {
+    AstItem::ConstDef(x) => { let y = f.fold_const_def(x); AstItem::ConstDef(f.allocator().alloc().init(y)) }
+    AstItem::FunDef(x) => { let y = f.fold_fun_def(x); AstItem::FunDef(f.allocator().alloc().init(y)) }
+    AstItem::Import(x) => { let y = f.fold_import(x); AstItem::Import(f.allocator().alloc().init(y)) }
+    AstItem::MacroDef(x) => { let y = f.fold_macro_def(x); AstItem::MacroDef(f.allocator().alloc().init(y)) }
+    AstItem::Module(x) => { let y = f.fold_module(x); AstItem::Module(f.allocator().alloc().init(y)) }
+    AstItem::StaticAssert(x) => { let y = f.fold_static_assert(x); AstItem::StaticAssert(f.allocator().alloc().init(y)) }
+    AstItem::StructDef(x) => { let y = f.fold_struct_def(x); AstItem::StructDef(f.allocator().alloc().init(y)) }
+  }
+}
+
+pub fn rebuild_const_def<'a, 'b, F: Folder<'a, 'b> + ?Sized>(f: &mut F, x: &'a AstConstDef<'a>) -> AstConstDef<'b> {
+  AstConstDef {
+    is_pub: x.is_pub,
+    name: f.fold_symbol(&x.name),
+    ty: fold_result(f, &x.ty),
+    value: f.fold_expr(&x.value),
+    id: NodeId::fresh(),
+  }
+}
+
+pub fn rebuild_fun_def<'a, 'b, F: Folder<'a, 'b> + ?Sized>(f: &mut F, x: &'a AstFunDef<'a>) -> AstFunDef<'b> {
+  AstFunDef {
+    is_pub: x.is_pub,
+    name: f.fold_symbol(&x.name),
+    params: fold_slice(f, x.params, F::fold_param),
+    result: fold_result(f, &x.result),
+    body: fold_stmts(f, x.body),
+    id: NodeId::fresh(),
+  }
+}
+
+pub fn rebuild_import<'a, 'b, F: Folder<'a, 'b> + ?Sized>(f: &mut F, x: &'a AstImport<'a>) -> AstImport<'b> {
+  AstImport(fold_slice(f, x.0, F::fold_symbol), NodeId::fresh())
+}
+
+pub fn rebuild_macro_def<'a, 'b, F: Folder<'a, 'b> + ?Sized>(f: &mut F, x: &'a AstMacroDef<'a>) -> AstMacroDef<'b> {
+  AstMacroDef {
+    name: f.fold_symbol(&x.name),
+    params: fold_slice(f, x.params, F::fold_symbol),
+    body: fold_stmts(f, x.body),
+    span: x.span,
+    id: NodeId::fresh(),
+  }
+}
+
+pub fn rebuild_module<'a, 'b, F: Folder<'a, 'b> + ?Sized>(f: &mut F, x: &'a AstModule<'a>) -> AstModule<'b> {
+  AstModule {
+    name: f.fold_symbol(&x.name),
+    items: fold_slice(f, x.items, F::fold_item),
+    id: NodeId::fresh(),
+  }
+}
+
+pub fn rebuild_struct_def<'a, 'b, F: Folder<'a, 'b> + ?Sized>(f: &mut F, x: &'a AstStructDef<'a>) -> AstStructDef<'b> {
+  AstStructDef {
+    is_pub: x.is_pub,
+    name: f.fold_symbol(&x.name),
+    fields: fold_slice(f, x.fields, F::fold_struct_field),
+    id: NodeId::fresh(),
+  }
+}
+
+pub fn rebuild_struct_field<'a, 'b, F: Folder<'a, 'b> + ?Sized>(f: &mut F, x: &'a AstStructField<'a>) -> AstStructField<'b> {
+  AstStructField {
+    name: f.fold_symbol(&x.name),
+    ty: f.fold_type(&x.ty),
+  }
+}
+
+pub fn rebuild_param<'a, 'b, F: Folder<'a, 'b> + ?Sized>(f: &mut F, x: &'a AstParam<'a>) -> AstParam<'b> {
+  AstParam {
+    pat: f.fold_pat(&x.pat),
+    ty: fold_result(f, &x.ty),
+  }
+}
+
+pub fn rebuild_stmt<'a, 'b, F: Folder<'a, 'b> + ?Sized>(f: &mut F, x: &'a AstStmt<'a>) -> AstStmt<'b> {
+  match x {
+    AstStmt::Break(x) => { let y = f.fold_break(x); AstStmt::Break(f.allocator().alloc().init(y)) }
+    AstStmt::ExprSeq(x) => { let y = f.fold_expr_seq(x); AstStmt::ExprSeq(f.allocator().alloc().init(y)) }
+    AstStmt::Let(x) => { let y = f.fold_let(x); AstStmt::Let(f.allocator().alloc().init(y)) }
+    AstStmt::Return(x) => { let y = f.fold_return(x); AstStmt::Return(f.allocator().alloc().init(y)) }
+  }
+}
+
+pub fn rebuild_let<'a, 'b, F: Folder<'a, 'b> + ?Sized>(f: &mut F, x: &'a AstLet<'a>) -> AstLet<'b> {
+  AstLet(fold_slice(f, x.0, F::fold_pat), fold_exprs(f, x.1), NodeId::fresh())
+}
+
+pub fn rebuild_expr<'a, 'b, F: Folder<'a, 'b> + ?Sized>(f: &mut F, x: &'a AstExpr<'a>) -> AstExpr<'b> {
+  match x {
+    AstExpr::Array(x) => { let y = f.fold_array(x); AstExpr::Array(f.allocator().alloc().init(y)) }
+    AstExpr::ArrayRepeat(x) => { let y = f.fold_array_repeat(x); AstExpr::ArrayRepeat(f.allocator().alloc().init(y)) }
+    AstExpr::Bool(x) => { let y = f.fold_bool(x); AstExpr::Bool(f.allocator().alloc().init(y)) }
+    AstExpr::Closure(x) => { let y = f.fold_closure(x); AstExpr::Closure(f.allocator().alloc().init(y)) }
+    AstExpr::Field(x) => { let y = f.fold_field(x); AstExpr::Field(f.allocator().alloc().init(y)) }
+    AstExpr::FunCall(x) => { let y = f.fold_fun_call(x); AstExpr::FunCall(f.allocator().alloc().init(y)) }
+    AstExpr::If(x) => { let y = f.fold_if(x); AstExpr::If(f.allocator().alloc().init(y)) }
+    AstExpr::Index(x) => { let y = f.fold_index(x); AstExpr::Index(f.allocator().alloc().init(y)) }
+    AstExpr::Lambda(x) => { let y = f.fold_lambda(x); AstExpr::Lambda(f.allocator().alloc().init(y)) }
+    AstExpr::Loop(x) => { let y = f.fold_loop(x); AstExpr::Loop(f.allocator().alloc().init(y)) }
+    AstExpr::Match(x) => { let y = f.fold_match(x); AstExpr::Match(f.allocator().alloc().init(y)) }
+    AstExpr::MethodCall(x) => { let y = f.fold_method_call(x); AstExpr::MethodCall(f.allocator().alloc().init(y)) }
+    AstExpr::Number(x) => { let y = f.fold_number(x); AstExpr::Number(f.allocator().alloc().init(y)) }
+    AstExpr::OpCall1(x) => { let y = f.fold_op_call1(x); AstExpr::OpCall1(f.allocator().alloc().init(y)) }
+    AstExpr::OpCall2(x) => { let y = f.fold_op_call2(x); AstExpr::OpCall2(f.allocator().alloc().init(y)) }
+    AstExpr::Pipeline(x) => { let y = f.fold_pipeline(x); AstExpr::Pipeline(f.allocator().alloc().init(y)) }
+    AstExpr::Record(x) => { let y = f.fold_record(x); AstExpr::Record(f.allocator().alloc().init(y)) }
+    AstExpr::Symbol(x) => { let y = f.fold_symbol(x); AstExpr::Symbol(f.allocator().alloc().init(y)) }
+    AstExpr::Tuple(x) => { let y = f.fold_tuple(x); AstExpr::Tuple(f.allocator().alloc().init(y)) }
+    AstExpr::Unquote(x) => { let y = f.fold_unquote(x); AstExpr::Unquote(f.allocator().alloc().init(y)) }
+  }
+}
+
+pub fn rebuild_array_repeat<'a, 'b, F: Folder<'a, 'b> + ?Sized>(f: &mut F, x: &'a AstArrayRepeat<'a>) -> AstArrayRepeat<'b> {
+  AstArrayRepeat(f.fold_expr(&x.0), f.fold_number(&x.1), NodeId::fresh())
+}
+
+pub fn rebuild_closure<'a, 'b, F: Folder<'a, 'b> + ?Sized>(f: &mut F, x: &'a AstClosure<'a>) -> AstClosure<'b> {
+  AstClosure(f.fold_symbol(&x.0), fold_exprs(f, x.1), NodeId::fresh())
+}
+
+pub fn rebuild_field<'a, 'b, F: Folder<'a, 'b> + ?Sized>(f: &mut F, x: &'a AstField<'a>) -> AstField<'b> {
+  AstField(f.fold_expr(&x.0), f.fold_symbol(&x.1), NodeId::fresh())
+}
+
+pub fn rebuild_fun_call<'a, 'b, F: Folder<'a, 'b> + ?Sized>(f: &mut F, x: &'a AstFunCall<'a>) -> AstFunCall<'b> {
+  AstFunCall(f.fold_expr(&x.0), fold_exprs(f, x.1), NodeId::fresh())
+}
+
+pub fn rebuild_if<'a, 'b, F: Folder<'a, 'b> + ?Sized>(f: &mut F, x: &'a AstIf<'a>) -> AstIf<'b> {
+  AstIf(f.fold_expr(&x.0), fold_stmts(f, x.1), fold_stmts(f, x.2), NodeId::fresh())
+}
+
+pub fn rebuild_index<'a, 'b, F: Folder<'a, 'b> + ?Sized>(f: &mut F, x: &'a AstIndex<'a>) -> AstIndex<'b> {
+  AstIndex(f.fold_expr(&x.0), f.fold_expr(&x.1), NodeId::fresh())
+}
+
+pub fn rebuild_lambda<'a, 'b, F: Folder<'a, 'b> + ?Sized>(f: &mut F, x: &'a AstLambda<'a>) -> AstLambda<'b> {
+  AstLambda {
+    params: fold_slice(f, x.params, F::fold_param),
+    result: fold_result(f, &x.result),
+    body: fold_stmts(f, x.body),
+    id: NodeId::fresh(),
+  }
+}
+
+pub fn rebuild_match<'a, 'b, F: Folder<'a, 'b> + ?Sized>(f: &mut F, x: &'a AstMatch<'a>) -> AstMatch<'b> {
+  AstMatch {
+    expr: f.fold_expr(&x.expr),
+    arms: fold_slice(f, x.arms, F::fold_match_arm),
+    span: x.span,
+    id: NodeId::fresh(),
+  }
+}
+
+pub fn rebuild_match_arm<'a, 'b, F: Folder<'a, 'b> + ?Sized>(f: &mut F, x: &'a AstMatchArm<'a>) -> AstMatchArm<'b> {
+  AstMatchArm {
+    pats: fold_slice(f, x.pats, F::fold_match_pat),
+    body: fold_stmts(f, x.body),
+    span: x.span,
+  }
+}
+
+pub fn rebuild_method_call<'a, 'b, F: Folder<'a, 'b> + ?Sized>(f: &mut F, x: &'a AstMethodCall<'a>) -> AstMethodCall<'b> {
+  AstMethodCall(f.fold_expr(&x.0), f.fold_symbol(&x.1), fold_exprs(f, x.2), NodeId::fresh())
+}
+
+pub fn rebuild_op_call<'a, 'b, F: Folder<'a, 'b> + ?Sized, const N: usize>(f: &mut F, x: &'a AstOpCall<'a, N>) -> AstOpCall<'b, N> {
+  AstOpCall(x.0, array::from_fn(|i| f.fold_expr(&x.1[i])), NodeId::fresh())
+}
+
+pub fn rebuild_pipeline<'a, 'b, F: Folder<'a, 'b> + ?Sized>(f: &mut F, x: &'a AstPipeline<'a>) -> AstPipeline<'b> {
+  AstPipeline(f.fold_expr(&x.0), f.fold_expr(&x.1), NodeId::fresh())
+}
+
+pub fn rebuild_record<'a, 'b, F: Folder<'a, 'b> + ?Sized>(f: &mut F, x: &'a AstRecord<'a>) -> AstRecord<'b> {
+  AstRecord(fold_slice(f, x.0, |f, (y, z)| (f.fold_symbol(y), f.fold_expr(z))), NodeId::fresh())
+}
+
+pub fn rebuild_pat<'a, 'b, F: Folder<'a, 'b> + ?Sized>(f: &mut F, x: &'a AstPat<'a>) -> AstPat<'b> {
+  match x {
+    AstPat::Symbol(x) => { let y = f.fold_symbol(x); AstPat::Symbol(f.allocator().alloc().init(y)) }
+    AstPat::Tuple(x) => { let y = f.fold_tuple_pat(x); AstPat::Tuple(f.allocator().alloc().init(y)) }
+  }
+}
+
+pub fn rebuild_type<'a, 'b, F: Folder<'a, 'b> + ?Sized>(f: &mut F, x: &'a AstType<'a>) -> AstType<'b> {
+  match x {
+    AstType::Array(x) => { let y = f.fold_array_type(x); AstType::Array(f.allocator().alloc().init(y)) }
+    AstType::Symbol(x) => { let y = f.fold_symbol(x); AstType::Symbol(f.allocator().alloc().init(y)) }
+    AstType::Tuple(x) => { let y = f.fold_tuple_type(x); AstType::Tuple(f.allocator().alloc().init(y)) }
+  }
+}
+
+pub fn rebuild_array_type<'a, 'b, F: Folder<'a, 'b> + ?Sized>(f: &mut F, x: &'a AstArrayType<'a>) -> AstArrayType<'b> {
+  AstArrayType(f.fold_type(&x.0), f.fold_number(&x.1))
+}
+
+pub fn rebuild_match_pat<'a, 'b, F: Folder<'a, 'b> + ?Sized>(f: &mut F, x: &'a AstMatchPat<'a>) -> AstMatchPat<'b> {
+  match x {
+    AstMatchPat::Bool(x) => { let y = f.fold_bool(x); AstMatchPat::Bool(f.allocator().alloc().init(y)) }
+    AstMatchPat::Number(x) => { let y = f.fold_number(x); AstMatchPat::Number(f.allocator().alloc().init(y)) }
+    AstMatchPat::Range(x) => { let y = f.fold_range(x); AstMatchPat::Range(f.allocator().alloc().init(y)) }
+    AstMatchPat::Wildcard => AstMatchPat::Wildcard,
+  }
+}
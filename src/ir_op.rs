@@ -25,10 +25,20 @@ pub enum Op21 {
   BoolEq,
   BoolNeq,
   BoolOr,
+  I128Add,
+  I128IsEq,
+  I128IsGeS,
+  I128IsGtS,
+  I128IsLeS,
+  I128IsLtS,
+  I128IsNeq,
+  I128Mul,
+  I128Sub,
   I64Add,
   I64BitAnd,
   I64BitOr,
   I64BitXor,
+  I64DivS,
   I64IsEq,
   I64IsGeS,
   I64IsGeU,
@@ -54,6 +64,12 @@ pub enum Op21 {
   I64ShrS,
   I64ShrU,
   I64Sub,
+  I6IsEq,
+  I6IsGe,
+  I6IsGt,
+  I6IsLe,
+  I6IsLt,
+  I6IsNeq,
 }
 
 impl fmt::Display for Op11 {
@@ -67,3 +83,100 @@ impl fmt::Display for Op21 {
     write!(out, "{:?}", self)
   }
 }
+
+// EVALUATION
+//
+// A constant is an i128 that holds a value of its type: 0 or 1 for a bool,
+// 0 to 63 for an i6, and the value itself for an i64 or i128. Arithmetic
+// wraps around, as it does in generated code, except for division, which
+// traps on a zero divisor and on overflow. The value of an operation that
+// traps is unspecified, so check `traps` first.
+
+impl Op11 {
+  pub fn eval(self, x: i128) -> i128 {
+    let a = x as i64;
+    match self {
+      Self::BoolNot => x ^ 1,
+      Self::I128HiI64 => (x >> 64) as i64 as i128,
+      Self::I128ToI64 => a as i128,
+      Self::I64Abs => a.wrapping_abs() as i128,
+      Self::I64BitNot => (! a) as i128,
+      Self::I64Clz => a.leading_zeros() as i128,
+      Self::I64Ctz => a.trailing_zeros() as i128,
+      Self::I64IsNonZero => (a != 0) as i128,
+      Self::I64Neg => a.wrapping_neg() as i128,
+      Self::I64Popcount => a.count_ones() as i128,
+      Self::I64RevBits => a.reverse_bits() as i128,
+      Self::I64RevBytes => a.swap_bytes() as i128,
+      Self::I64ToI6 => x & 0x3f,
+    }
+  }
+}
+
+impl Op21 {
+  pub fn eval(self, x: i128, y: i128) -> i128 {
+    let a = x as i64;
+    let b = y as i64;
+    let u = a as u64;
+    let v = b as u64;
+    let k = (y & 0x3f) as u32;
+    match self {
+      Self::BoolAnd => x & y,
+      Self::BoolEq => (x == y) as i128,
+      Self::BoolNeq => (x != y) as i128,
+      Self::BoolOr => x | y,
+      Self::I128Add => x.wrapping_add(y),
+      Self::I128IsEq => (x == y) as i128,
+      Self::I128IsGeS => (x >= y) as i128,
+      Self::I128IsGtS => (x > y) as i128,
+      Self::I128IsLeS => (x <= y) as i128,
+      Self::I128IsLtS => (x < y) as i128,
+      Self::I128IsNeq => (x != y) as i128,
+      Self::I128Mul => x.wrapping_mul(y),
+      Self::I128Sub => x.wrapping_sub(y),
+      Self::I64Add => a.wrapping_add(b) as i128,
+      Self::I64BitAnd => (a & b) as i128,
+      Self::I64BitOr => (a | b) as i128,
+      Self::I64BitXor => (a ^ b) as i128,
+      Self::I64DivS => a.checked_div(b).unwrap_or(0) as i128,
+      Self::I64IsEq => (a == b) as i128,
+      Self::I64IsGeS => (a >= b) as i128,
+      Self::I64IsGeU => (u >= v) as i128,
+      Self::I64IsGtS => (a > b) as i128,
+      Self::I64IsGtU => (u > v) as i128,
+      Self::I64IsLeS => (a <= b) as i128,
+      Self::I64IsLeU => (u <= v) as i128,
+      Self::I64IsLtS => (a < b) as i128,
+      Self::I64IsLtU => (u < v) as i128,
+      Self::I64IsNeq => (a != b) as i128,
+      Self::I64MaxS => a.max(b) as i128,
+      Self::I64MaxU => u.max(v) as i64 as i128,
+      Self::I64MinS => a.min(b) as i128,
+      Self::I64MinU => u.min(v) as i64 as i128,
+      Self::I64Mul => a.wrapping_mul(b) as i128,
+      Self::I64MulFullS => a as i128 * b as i128,
+      Self::I64MulFullU => (u as u128 * v as u128) as i128,
+      Self::I64MulHiS => ((a as i128 * b as i128) >> 64) as i64 as i128,
+      Self::I64MulHiU => ((u as u128 * v as u128) >> 64) as u64 as i64 as i128,
+      Self::I64Rol => a.rotate_left(k) as i128,
+      Self::I64Ror => a.rotate_right(k) as i128,
+      Self::I64Shl => (a << k) as i128,
+      Self::I64ShrS => (a >> k) as i128,
+      Self::I64ShrU => (u >> k) as i64 as i128,
+      Self::I64Sub => a.wrapping_sub(b) as i128,
+      Self::I6IsEq => (x == y) as i128,
+      Self::I6IsGe => (x >= y) as i128,
+      Self::I6IsGt => (x > y) as i128,
+      Self::I6IsLe => (x <= y) as i128,
+      Self::I6IsLt => (x < y) as i128,
+      Self::I6IsNeq => (x != y) as i128,
+    }
+  }
+
+  pub fn traps(self, x: i128, y: i128) -> bool {
+    match self {
+      Self::I64DivS => (x as i64).checked_div(y as i64).is_none(),
+      _ => false,
+    }
+  }
+}
@@ -0,0 +1,838 @@
+use crate::prelude::*;
+
+use bytecode::BlockId;
+use bytecode::FunId;
+use bytecode::Inst;
+use bytecode::VarId;
+
+// SSA CONSTRUCTION
+//
+// Builds the code of a `bytecode::Function` from instructions that read and
+// write variables, following Braun et al., "Simple and Efficient Construction
+// of Static Single Assignment Form" (CC 2013).
+//
+// Jumps don't take arguments. Instead, a use of a variable that isn't defined
+// earlier in its block looks for its definitions in the block's predecessors,
+// and where those can differ, the block gets a parameter that each
+// predecessor's `Goto` passes the right value to. A parameter is trivial if
+// every argument is the same value or the parameter itself, and then it is
+// replaced by that value, so the parameters that remain are minimal for
+// reducible control flow.
+//
+// A block must be sealed once every jump to it has been emitted. A use in a
+// block that isn't sealed yet, such as a loop header whose back edge comes
+// later, makes a provisional parameter that gets its arguments on sealing.
+//
+// In the bytecode, `If` and `Switch` pass no arguments, so their edges to a
+// block that ends up with parameters go through an extra block that does.
+//
+// A call, an index, or the unpacking of an environment is a node with a value
+// for each of its outputs, which are the values just after it.
+
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub struct SsaBlock(u32);
+
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub struct SsaValue(u32);
+
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub struct SsaVar(u32);
+
+pub struct SsaBuilder {
+  blocks: Vec<BlockData>,
+  values: Vec<ValueData>,
+  vars: Vec<Ty>,
+  defs: HashMap<(SsaVar, SsaBlock), SsaValue>,
+  current: SsaBlock,
+}
+
+struct BlockData {
+  preds: Vec<SsaBlock>,
+  params: Vec<SsaValue>,
+  code: Vec<SsaValue>,
+  exit: Option<Exit>,
+  is_sealed: bool,
+  incomplete: Vec<(SsaVar, SsaValue)>,
+}
+
+enum ValueData {
+  Input,
+  // The arguments are in the order of the block's predecessors. A parameter
+  // that was found to be trivial links to its replacement.
+  Param(SsaBlock, Ty, Vec<SsaValue>),
+  Link(SsaValue),
+  Node(Node),
+  // An output of the node before it.
+  Output,
+}
+
+enum Node {
+  EnvPack(Box<[SsaValue]>),
+  EnvUnpack(SsaValue, Box<[Ty]>),
+  FunAddr(FunId),
+  // The callee, the arguments, and the number of outputs.
+  FunCall(FunId, Box<[SsaValue]>, usize),
+  // The code pointer, the inputs and outputs of its signature, and the
+  // arguments.
+  FunCallIndirect(SsaValue, Box<[Ty]>, Box<[Ty]>, Box<[SsaValue]>),
+  ImmBool(bool),
+  ImmI6(u6),
+  ImmI64(u64),
+  Index(SsaValue, Box<[SsaValue]>, Box<[Ty]>),
+  Op11(Op11, SsaValue),
+  Op21(Op21, SsaValue, SsaValue),
+}
+
+enum Exit {
+  Goto(SsaBlock),
+  If(SsaValue, SsaBlock, SsaBlock),
+  Ret(Box<[SsaValue]>),
+  Switch(SsaValue, Box<[(u64, SsaBlock)]>, SsaBlock),
+  Trap,
+}
+
+impl Node {
+  // The number of outputs of a node that has a value for each, or `None` for
+  // a node that is its only value.
+
+  fn outputs(&self) -> Option<usize> {
+    match self {
+      Self::EnvUnpack(_, tys) => Some(tys.len()),
+      Self::FunCall(_, _, n) => Some(*n),
+      Self::FunCallIndirect(_, _, tys, _) => Some(tys.len()),
+      Self::Index(_, _, tys) => Some(tys.len()),
+      _ => None,
+    }
+  }
+}
+
+impl BlockData {
+  fn new() -> Self {
+    Self {
+      preds: Vec::new(),
+      params: Vec::new(),
+      code: Vec::new(),
+      exit: None,
+      is_sealed: false,
+      incomplete: Vec::new(),
+    }
+  }
+}
+
+// BUILDING
+
+impl SsaBuilder {
+  // Starts a function whose entry block, which is current, receives the
+  // inputs. The entry block can't be jumped to.
+
+  pub fn new(inputs: &[Ty]) -> Self {
+    let mut entry = BlockData::new();
+    entry.is_sealed = true;
+    Self {
+      blocks: vec![entry],
+      values: inputs.iter().map(|_| ValueData::Input).collect(),
+      vars: Vec::new(),
+      defs: HashMap::new(),
+      current: SsaBlock(0),
+    }
+  }
+
+  pub fn input(&self, i: usize) -> SsaValue {
+    debug_assert!(matches!(self.values[i], ValueData::Input));
+    SsaValue(i as u32)
+  }
+
+  pub fn create_block(&mut self) -> SsaBlock {
+    let b = SsaBlock(self.blocks.len() as u32);
+    self.blocks.push(BlockData::new());
+    b
+  }
+
+  pub fn switch_to_block(&mut self, b: SsaBlock) {
+    debug_assert!(self.is_filled());
+    debug_assert!(self.block(b).exit.is_none());
+    self.current = b;
+  }
+
+  // Whether the current block has ended with a jump.
+
+  pub fn is_filled(&self) -> bool {
+    self.block(self.current).exit.is_some()
+  }
+
+  pub fn seal_block(&mut self, b: SsaBlock) {
+    debug_assert!(! self.block(b).is_sealed);
+    self.blocks[b.0 as usize].is_sealed = true;
+    let incomplete = mem::take(&mut self.blocks[b.0 as usize].incomplete);
+    for (x, v) in incomplete { let _ = self.add_param_args(x, v); }
+  }
+
+  pub fn declare_var(&mut self, ty: Ty) -> SsaVar {
+    let x = SsaVar(self.vars.len() as u32);
+    self.vars.push(ty);
+    x
+  }
+
+  pub fn def_var(&mut self, x: SsaVar, v: SsaValue) {
+    let _ = self.defs.insert((x, self.current), v);
+  }
+
+  pub fn use_var(&mut self, x: SsaVar) -> SsaValue {
+    self.read(x, self.current)
+  }
+
+  pub fn imm_bool(&mut self, imm: bool) -> SsaValue {
+    self.node(Node::ImmBool(imm))
+  }
+
+  pub fn imm_i6(&mut self, imm: u6) -> SsaValue {
+    self.node(Node::ImmI6(imm))
+  }
+
+  pub fn imm_i64(&mut self, imm: u64) -> SsaValue {
+    self.node(Node::ImmI64(imm))
+  }
+
+  pub fn index(&mut self, i: SsaValue, xs: &[SsaValue], tys: &[Ty]) -> Vec<SsaValue> {
+    let v = self.node(Node::Index(i, xs.into(), tys.into()));
+    self.outputs(v, tys.len())
+  }
+
+  pub fn op11(&mut self, op: Op11, x: SsaValue) -> SsaValue {
+    self.node(Node::Op11(op, x))
+  }
+
+  pub fn op21(&mut self, op: Op21, x: SsaValue, y: SsaValue) -> SsaValue {
+    self.node(Node::Op21(op, x, y))
+  }
+
+  pub fn env_pack(&mut self, xs: &[SsaValue]) -> SsaValue {
+    self.node(Node::EnvPack(xs.into()))
+  }
+
+  pub fn env_unpack(&mut self, x: SsaValue, tys: &[Ty]) -> Vec<SsaValue> {
+    let v = self.node(Node::EnvUnpack(x, tys.into()));
+    self.outputs(v, tys.len())
+  }
+
+  pub fn fun_addr(&mut self, f: FunId) -> SsaValue {
+    self.node(Node::FunAddr(f))
+  }
+
+  pub fn fun_call(&mut self, f: FunId, xs: &[SsaValue], outputs: &[Ty]) -> Vec<SsaValue> {
+    let v = self.node(Node::FunCall(f, xs.into(), outputs.len()));
+    self.outputs(v, outputs.len())
+  }
+
+  pub fn fun_call_indirect(&mut self, f: SsaValue, inputs: &[Ty], outputs: &[Ty], xs: &[SsaValue]) -> Vec<SsaValue> {
+    let v = self.node(Node::FunCallIndirect(f, inputs.into(), outputs.into(), xs.into()));
+    self.outputs(v, outputs.len())
+  }
+
+  pub fn goto(&mut self, a: SsaBlock) {
+    self.exit(Exit::Goto(a), &[a]);
+  }
+
+  pub fn if_(&mut self, x: SsaValue, a: SsaBlock, b: SsaBlock) {
+    self.exit(Exit::If(x, a, b), &[a, b]);
+  }
+
+  pub fn ret(&mut self, xs: &[SsaValue]) {
+    self.exit(Exit::Ret(xs.into()), &[]);
+  }
+
+  pub fn switch(&mut self, x: SsaValue, cases: &[(u64, SsaBlock)], a: SsaBlock) {
+    let mut targets: Vec<_> = cases.iter().map(|c| c.1).collect();
+    targets.push(a);
+    self.exit(Exit::Switch(x, cases.into(), a), &targets);
+  }
+
+  pub fn trap(&mut self) {
+    self.exit(Exit::Trap, &[]);
+  }
+
+  fn block(&self, b: SsaBlock) -> &BlockData {
+    &self.blocks[b.0 as usize]
+  }
+
+  // Code after a jump is unreachable, but it still gets a block of its own.
+
+  fn open(&mut self) {
+    if self.is_filled() {
+      let b = self.create_block();
+      self.seal_block(b);
+      self.current = b;
+    }
+  }
+
+  fn node(&mut self, node: Node) -> SsaValue {
+    self.open();
+    let v = SsaValue(self.values.len() as u32);
+    self.values.push(ValueData::Node(node));
+    self.blocks[self.current.0 as usize].code.push(v);
+    v
+  }
+
+  fn outputs(&mut self, v: SsaValue, n: usize) -> Vec<SsaValue> {
+    (1 ..= n).map(|k| {
+      self.values.push(ValueData::Output);
+      SsaValue(v.0 + k as u32)
+    }).collect()
+  }
+
+  fn exit(&mut self, exit: Exit, targets: &[SsaBlock]) {
+    self.open();
+    for &a in targets.iter() {
+      debug_assert!(a.0 != 0 && ! self.block(a).is_sealed);
+      self.blocks[a.0 as usize].preds.push(self.current);
+    }
+    self.blocks[self.current.0 as usize].exit = Some(exit);
+  }
+}
+
+// LOOKUP
+
+impl SsaBuilder {
+  fn find(&self, mut v: SsaValue) -> SsaValue {
+    while let ValueData::Link(w) = self.values[v.0 as usize] { v = w }
+    v
+  }
+
+  fn read(&mut self, x: SsaVar, b: SsaBlock) -> SsaValue {
+    if let Some(&v) = self.defs.get(&(x, b)) { return self.find(v); }
+
+    let block = self.block(b);
+    let v =
+      if ! block.is_sealed {
+        let v = self.param(x, b);
+        self.blocks[b.0 as usize].incomplete.push((x, v));
+        v
+      } else if block.preds.len() == 1 {
+        let a = block.preds[0];
+        self.read(x, a)
+      } else {
+        // Defining the parameter before looking in the predecessors ends the
+        // search at loops.
+        assert!(b.0 != 0, "variable used before it is defined");
+        let v = self.param(x, b);
+        let _ = self.defs.insert((x, b), v);
+        self.add_param_args(x, v)
+      };
+
+    let _ = self.defs.insert((x, b), v);
+    v
+  }
+
+  fn param(&mut self, x: SsaVar, b: SsaBlock) -> SsaValue {
+    let v = SsaValue(self.values.len() as u32);
+    self.values.push(ValueData::Param(b, self.vars[x.0 as usize], Vec::new()));
+    self.blocks[b.0 as usize].params.push(v);
+    v
+  }
+
+  fn add_param_args(&mut self, x: SsaVar, v: SsaValue) -> SsaValue {
+    let ValueData::Param(b, _, _) = self.values[v.0 as usize] else { unreachable!() };
+    let preds = self.block(b).preds.clone();
+    let args: Vec<_> = preds.iter().map(|&a| self.read(x, a)).collect();
+    let ValueData::Param(_, _, xs) = &mut self.values[v.0 as usize] else { unreachable!() };
+    *xs = args;
+    self.remove_trivial(v)
+  }
+
+  // A block with no predecessors keeps its parameters, since they have no
+  // value to be replaced by, but such a block is unreachable.
+
+  fn remove_trivial(&mut self, v: SsaValue) -> SsaValue {
+    let ValueData::Param(_, _, xs) = &self.values[v.0 as usize] else { return v };
+    let mut same = None;
+
+    for &y in xs.iter() {
+      let y = self.find(y);
+      if Some(y) == same || y == v { continue; }
+      if same.is_some() { return v; }
+      same = Some(y);
+    }
+
+    let Some(same) = same else { return v };
+
+    let users: Vec<_> =
+      (0 .. self.values.len() as u32).map(SsaValue).filter(|&w| {
+        match &self.values[w.0 as usize] {
+          ValueData::Param(_, _, ys) => w != v && ys.iter().any(|&y| self.find(y) == v),
+          _ => false,
+        }
+      }).collect();
+
+    self.values[v.0 as usize] = ValueData::Link(same);
+
+    // Parameters that used this one may have become trivial too.
+
+    for w in users { let _ = self.remove_trivial(w); }
+
+    self.find(same)
+  }
+}
+
+// EMITTING BYTECODE
+//
+// Variables are numbered in the order that the code defines them, so a block
+// must come after the blocks that dominate it, whose values it can use. The
+// blocks are emitted in reverse postorder, which does that, with the entry
+// block first and implicit, then the blocks that are unreachable, then the
+// extra blocks for `If` and `Switch` edges.
+//
+// A `VarId` or `BlockId` is 16 bits, so a function with more variables or
+// blocks than that can number can't be emitted. Arrays count an SSA value per
+// scalar of each element, so a large one can get there.
+
+impl SsaBuilder {
+  fn params(&self, b: SsaBlock) -> impl '_ + Iterator<Item = SsaValue> {
+    self.block(b).params.iter().copied().filter(|&v| matches!(self.values[v.0 as usize], ValueData::Param(..)))
+  }
+
+  // The arguments that a jump from `a` passes to `b`.
+
+  fn args(&self, a: SsaBlock, b: SsaBlock) -> Vec<SsaValue> {
+    let k = self.block(b).preds.iter().position(|&p| p == a).unwrap();
+    self.params(b).map(|v| {
+      let ValueData::Param(_, _, xs) = &self.values[v.0 as usize] else { unreachable!() };
+      self.find(xs[k])
+    }).collect()
+  }
+
+  fn succs(&self, b: SsaBlock) -> Vec<SsaBlock> {
+    match self.block(b).exit.as_ref().expect("block is not filled") {
+      &Exit::Goto(a) => vec![a],
+      &Exit::If(_, c, d) => vec![c, d],
+      Exit::Ret(_) | Exit::Trap => vec![],
+      Exit::Switch(_, cases, d) => cases.iter().map(|x| x.1).chain([*d]).collect(),
+    }
+  }
+
+  // The blocks in reverse postorder from the entry block, followed by the
+  // rest in the order they were created. Successors are visited last to
+  // first, so that the then branch of an `If` comes before the else branch.
+
+  fn layout(&self) -> Vec<SsaBlock> {
+    let mut seen = vec![false; self.blocks.len()];
+    let mut order = Vec::new();
+    let mut stack = vec![(SsaBlock(0), self.succs(SsaBlock(0)))];
+    seen[0] = true;
+
+    while let Some((b, succs)) = stack.last_mut() {
+      match succs.pop() {
+        Some(c) => {
+          if ! seen[c.0 as usize] {
+            seen[c.0 as usize] = true;
+            let succs = self.succs(c);
+            stack.push((c, succs));
+          }
+        }
+        None => {
+          order.push(*b);
+          let _ = stack.pop();
+        }
+      }
+    }
+
+    order.reverse();
+    for (i, &x) in seen.iter().enumerate() {
+      if ! x { order.push(SsaBlock(i as u32)) }
+    }
+    order
+  }
+
+  // The number of variables and the number of blocks after the entry block
+  // that the bytecode will have.
+
+  fn counts(&self) -> (usize, usize) {
+    let mut num_vars = 0;
+    let mut num_edges = 0;
+
+    for x in self.values.iter() {
+      match x {
+        ValueData::Input => num_vars += 1,
+        ValueData::Node(node) => num_vars += node.outputs().unwrap_or(1),
+        ValueData::Link(_) | ValueData::Output | ValueData::Param(..) => {}
+      }
+    }
+
+    for (i, block) in self.blocks.iter().enumerate() {
+      num_vars += self.params(SsaBlock(i as u32)).count();
+      let targets =
+        match block.exit.as_ref().expect("block is not filled") {
+          &Exit::If(_, c, d) => vec![c, d],
+          Exit::Switch(_, cases, d) => cases.iter().map(|x| x.1).chain([*d]).collect(),
+          Exit::Goto(_) | Exit::Ret(_) | Exit::Trap => vec![],
+        };
+      num_edges += targets.iter().filter(|&&b| self.params(b).next().is_some()).count();
+    }
+
+    (num_vars, self.blocks.len() - 1 + num_edges)
+  }
+
+  // Returns `None` if there are more variables or blocks than a `VarId` or a
+  // `BlockId` can number.
+
+  pub fn finish<'a>(self, aa: &mut Allocator<'a>) -> Option<&'a [Inst<'a>]> {
+    let (num_vars, num_blocks) = self.counts();
+    if num_vars > 1 << 16 || num_blocks > 1 << 16 { return None; }
+
+    let order = self.layout();
+    let mut pos = vec![0; self.blocks.len()];
+    for (k, b) in order.iter().enumerate() { pos[b.0 as usize] = k }
+    let target = |b: SsaBlock| BlockId(pos[b.0 as usize] as u16 - 1);
+
+    let mut ids = vec![VarId(u16::MAX); self.values.len()];
+    let mut n = 0u32;
+
+    for (i, x) in self.values.iter().enumerate() {
+      if let ValueData::Input = x { ids[i] = VarId(n as u16); n += 1; }
+    }
+
+    for &b in order.iter() {
+      assert!(self.block(b).is_sealed, "block is not sealed");
+      for v in self.params(b) {
+        ids[v.0 as usize] = VarId(n as u16);
+        n += 1;
+      }
+      for &v in self.block(b).code.iter() {
+        let outputs =
+          match &self.values[v.0 as usize] {
+            ValueData::Node(node) => {
+              match node.outputs() {
+                Some(k) => v.0 + 1 .. v.0 + 1 + k as u32,
+                None => v.0 .. v.0 + 1,
+              }
+            }
+            _ => unreachable!(),
+          };
+        for w in outputs {
+          ids[w as usize] = VarId(n as u16);
+          n += 1;
+        }
+      }
+    }
+
+    let id = |v: SsaValue| ids[self.find(v).0 as usize];
+    let ids_of = |aa: &mut Allocator<'a>, xs: &[SsaValue]| {
+      let xs: Vec<_> = xs.iter().map(|&x| id(x)).collect();
+      aa.copy_slice(xs.as_slice())
+    };
+
+    let mut code = Vec::new();
+    let mut edges = Vec::new();
+
+    // The target of an `If` or `Switch` edge, which is an extra block if the
+    // edge must pass arguments.
+
+    let mut edge = |a: SsaBlock, b: SsaBlock| {
+      if self.params(b).next().is_none() { return target(b); }
+      edges.push((a, b));
+      BlockId((self.blocks.len() - 2 + edges.len()) as u16)
+    };
+
+    for &b in order.iter() {
+      let block = self.block(b);
+
+      if b.0 != 0 {
+        let tys: Vec<_> = self.params(b).map(|v| {
+          let ValueData::Param(_, ty, _) = self.values[v.0 as usize] else { unreachable!() };
+          ty
+        }).collect();
+        code.push(Inst::Block(aa.copy_slice(tys.as_slice())));
+      }
+
+      for &v in block.code.iter() {
+        let ValueData::Node(node) = &self.values[v.0 as usize] else { unreachable!() };
+        code.push(
+          match *node {
+            Node::EnvPack(ref xs) => Inst::EnvPack(ids_of(aa, xs)),
+            Node::EnvUnpack(x, ref tys) => Inst::EnvUnpack(id(x), aa.copy_slice(tys)),
+            Node::FunAddr(f) => Inst::FunAddr(f),
+            Node::FunCall(f, ref xs, _) => Inst::FunCall(f, ids_of(aa, xs)),
+            Node::FunCallIndirect(f, ref inputs, ref outputs, ref xs) => {
+              let signature = bytecode::Signature { inputs: aa.copy_slice(inputs), outputs: aa.copy_slice(outputs) };
+              Inst::FunCallIndirect(id(f), signature, ids_of(aa, xs))
+            }
+            Node::ImmBool(imm) => Inst::ImmBool(imm),
+            Node::ImmI6(imm) => Inst::ImmI6(imm),
+            Node::ImmI64(imm) => Inst::ImmI64(imm),
+            Node::Index(i, ref xs, ref tys) => Inst::Index(id(i), ids_of(aa, xs), aa.copy_slice(tys)),
+            Node::Op11(op, x) => Inst::Op11(op, id(x)),
+            Node::Op21(op, x, y) => Inst::Op21(op, id(x), id(y)),
+          }
+        );
+      }
+
+      code.push(
+        match block.exit.as_ref().expect("block is not filled") {
+          &Exit::Goto(a) => {
+            let xs = ids_of(aa, &self.args(b, a));
+            Inst::Goto(target(a), xs)
+          }
+          &Exit::If(x, c, d) => {
+            Inst::If(id(x), edge(b, c), edge(b, d))
+          }
+          Exit::Ret(xs) => {
+            Inst::Ret(ids_of(aa, xs))
+          }
+          Exit::Switch(x, cases, d) => {
+            let cases: Vec<_> = cases.iter().map(|&(k, c)| (k, edge(b, c))).collect();
+            Inst::Switch(id(*x), aa.copy_slice(cases.as_slice()), edge(b, *d))
+          }
+          Exit::Trap => {
+            Inst::Trap
+          }
+        }
+      );
+    }
+
+    for &(a, b) in edges.iter() {
+      code.push(Inst::Block(&[]));
+      let xs = ids_of(aa, &self.args(a, b));
+      code.push(Inst::Goto(target(b), xs));
+    }
+
+    Some(aa.copy_slice(code.as_slice()))
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  // Checks that each variable is defined before it is used and each jump
+  // goes to a block, and returns the parameter types of the blocks after the
+  // entry block.
+
+  fn check(code: &[Inst<'_>], inputs: usize) -> Vec<Vec<Ty>> {
+    let blocks = code.iter().filter(|x| matches!(x, Inst::Block(_))).count();
+    let mut n = inputs;
+    let mut params = Vec::new();
+    let var = |x: VarId, n: usize| assert!((x.0 as usize) < n, "var {} used before it is defined", x.0);
+    let block = |a: BlockId| assert!((a.0 as usize) < blocks, "block {} out of range", a.0);
+
+    for inst in code.iter() {
+      match *inst {
+        Inst::Block(tys) => {
+          params.push(tys.to_vec());
+          n += tys.len();
+        }
+        Inst::Goto(a, xs) => {
+          block(a);
+          for &x in xs.iter() { var(x, n) }
+        }
+        Inst::If(x, a, b) => {
+          var(x, n);
+          block(a);
+          block(b);
+        }
+        Inst::ImmBool(_) | Inst::ImmI6(_) | Inst::ImmI64(_) => {
+          n += 1;
+        }
+        Inst::Op11(_, x) => {
+          var(x, n);
+          n += 1;
+        }
+        Inst::Op21(_, x, y) => {
+          var(x, n);
+          var(y, n);
+          n += 1;
+        }
+        Inst::Ret(xs) => {
+          for &x in xs.iter() { var(x, n) }
+        }
+        Inst::Switch(x, cases, a) => {
+          var(x, n);
+          for &(_, b) in cases.iter() { block(b) }
+          block(a);
+        }
+        Inst::Trap => {
+        }
+        _ => unreachable!(),
+      }
+    }
+
+    params
+  }
+
+  #[test]
+  fn loop_params_are_minimal() {
+    let mut arena = Arena::new();
+    let aa = arena.allocator_mut();
+
+    // The sum of 0 .. n, plus a constant that the loop only reads.
+
+    let mut b = SsaBuilder::new(&[Ty::I64]);
+    let n = b.input(0);
+    let i = b.declare_var(Ty::I64);
+    let s = b.declare_var(Ty::I64);
+    let k = b.declare_var(Ty::I64);
+    let zero = b.imm_i64(0);
+    let seven = b.imm_i64(7);
+    b.def_var(i, zero);
+    b.def_var(s, zero);
+    b.def_var(k, seven);
+
+    let header = b.create_block();
+    let body = b.create_block();
+    let exit = b.create_block();
+    b.goto(header);
+
+    b.switch_to_block(header);
+    let x = b.use_var(i);
+    let c = b.op21(Op21::I64IsLtS, x, n);
+    b.if_(c, body, exit);
+    b.seal_block(body);
+    b.seal_block(exit);
+
+    b.switch_to_block(body);
+    let x = b.use_var(i);
+    let y = b.use_var(s);
+    let y = b.op21(Op21::I64Add, y, x);
+    b.def_var(s, y);
+    let one = b.imm_i64(1);
+    let x = b.op21(Op21::I64Add, x, one);
+    b.def_var(i, x);
+    let _ = b.use_var(k);
+    b.goto(header);
+    b.seal_block(header);
+
+    b.switch_to_block(exit);
+    let y = b.use_var(s);
+    let z = b.use_var(k);
+    let y = b.op21(Op21::I64Add, y, z);
+    b.ret(&[y]);
+
+    let code = b.finish(aa).unwrap();
+    let params = check(code, 1);
+
+    // Only the header has parameters, one each for `i` and `s`, and the exit
+    // edge of the `If` passes them on through an extra block.
+
+    assert_eq!(params[0], [Ty::I64, Ty::I64]);
+    assert!(params[1 ..].iter().all(|x| x.is_empty()));
+    assert!(params.len() >= 3);
+  }
+
+  #[test]
+  fn trivial_params_are_pruned() {
+    let mut arena = Arena::new();
+    let aa = arena.allocator_mut();
+
+    // A loop that only reads a variable, and a join that gets the same value
+    // from both sides.
+
+    let mut b = SsaBuilder::new(&[Ty::Bool]);
+    let c = b.input(0);
+    let x = b.declare_var(Ty::I64);
+    let one = b.imm_i64(1);
+    b.def_var(x, one);
+
+    let header = b.create_block();
+    let then = b.create_block();
+    let join = b.create_block();
+    b.goto(header);
+
+    b.switch_to_block(header);
+    let _ = b.use_var(x);
+    b.if_(c, then, join);
+    b.seal_block(then);
+
+    b.switch_to_block(then);
+    b.def_var(x, one);
+    b.goto(header);
+    b.seal_block(header);
+    b.seal_block(join);
+
+    b.switch_to_block(join);
+    let y = b.use_var(x);
+    b.ret(&[y]);
+
+    let code = b.finish(aa).unwrap();
+    let params = check(code, 1);
+    assert!(params.iter().all(|x| x.is_empty()));
+  }
+
+  #[test]
+  fn if_edges_pass_arguments_through_extra_blocks() {
+    let mut arena = Arena::new();
+    let aa = arena.allocator_mut();
+
+    let mut b = SsaBuilder::new(&[Ty::Bool]);
+    let c = b.input(0);
+    let x = b.declare_var(Ty::I64);
+    let one = b.imm_i64(1);
+    b.def_var(x, one);
+
+    let join = b.create_block();
+    let other = b.create_block();
+    b.if_(c, join, other);
+    b.seal_block(other);
+
+    b.switch_to_block(other);
+    let two = b.imm_i64(2);
+    b.def_var(x, two);
+    b.goto(join);
+    b.seal_block(join);
+
+    b.switch_to_block(join);
+    let y = b.use_var(x);
+    b.ret(&[y]);
+
+    let code = b.finish(aa).unwrap();
+    let params = check(code, 1);
+    assert_eq!(params.iter().filter(|x| ! x.is_empty()).count(), 1);
+
+    // The extra block comes last and passes the value from the entry block.
+
+    let n = code.len();
+    assert!(matches!(code[n - 2], Inst::Block([])));
+    assert!(matches!(code[n - 1], Inst::Goto(_, [VarId(1)])));
+  }
+
+  #[test]
+  fn blocks_are_laid_out_in_reverse_postorder() {
+    let mut arena = Arena::new();
+    let aa = arena.allocator_mut();
+
+    // The block created first is reached last, and uses a value defined by
+    // the block that jumps to it, so it has to be emitted after that one.
+
+    let mut b = SsaBuilder::new(&[Ty::I64]);
+    let n = b.input(0);
+    let x = b.declare_var(Ty::I64);
+
+    let last = b.create_block();
+    let first = b.create_block();
+    let unreachable = b.create_block();
+    b.goto(first);
+    b.seal_block(first);
+
+    b.switch_to_block(first);
+    let one = b.imm_i64(1);
+    let y = b.op21(Op21::I64Add, n, one);
+    b.def_var(x, y);
+    b.goto(last);
+    b.seal_block(last);
+
+    b.switch_to_block(last);
+    let y = b.use_var(x);
+    let y = b.op21(Op21::I64Add, y, y);
+    b.ret(&[y]);
+
+    b.seal_block(unreachable);
+    b.switch_to_block(unreachable);
+    b.trap();
+
+    let code = b.finish(aa).unwrap();
+    let _ = check(code, 1);
+
+    // The entry block jumps to the block emitted first, and the unreachable
+    // block comes after the reachable ones.
+
+    assert!(matches!(code[0], Inst::Goto(BlockId(0), [])));
+    assert!(matches!(code[code.len() - 1], Inst::Trap));
+  }
+}
@@ -14,3 +14,24 @@ impl fmt::Display for Ty {
     write!(out, "{:?}", self)
   }
 }
+
+impl Ty {
+  pub fn from_name(name: &str) -> Option<Self> {
+    match name {
+      "bool" => Some(Self::Bool),
+      "i128" => Some(Self::I128),
+      "i6" => Some(Self::I6),
+      "i64" => Some(Self::I64),
+      _ => None,
+    }
+  }
+
+  pub fn to_name(self) -> &'static str {
+    match self {
+      Self::Bool => "bool",
+      Self::I128 => "i128",
+      Self::I6 => "i6",
+      Self::I64 => "i64",
+    }
+  }
+}
@@ -14,11 +14,32 @@ pub mod bc;
 pub mod buf;
 pub mod bytecode;
 pub mod frontend_ast;
+pub mod frontend_closure;
+pub mod frontend_doc;
+pub mod frontend_eval;
+pub mod frontend_fold;
+pub mod frontend_format;
+pub mod frontend_highlight;
+pub mod frontend_intrinsic;
 pub mod frontend_lexer;
+pub mod frontend_lint;
+pub mod frontend_lower;
+pub mod frontend_macro;
+pub mod frontend_match;
+pub mod frontend_module;
+pub mod frontend_node;
 pub mod frontend_parser;
+pub mod frontend_resolve;
+pub mod frontend_span;
+pub mod frontend_struct;
+pub mod frontend_symbol;
 pub mod frontend_token;
+pub mod frontend_typeck;
+pub mod frontend_unit;
+pub mod frontend_visit;
 pub mod ir_bytecode;
 pub mod ir_op;
+pub mod ir_ssa;
 pub mod ir_ty;
 pub mod phantom;
 pub mod sexp;
@@ -27,68 +48,305 @@ pub mod u6;
 
 use crate::prelude::*;
 
-pub fn go() {
-  let source =
-      b"\
-fun foo(n)
-  n
-end";
+// `adelie fmt [--check] FILE...` formats each file in place. With `--check`,
+// it only reports the files that aren't formatted, and fails if there are any.
+
+pub fn fmt(args: &[String]) -> bool {
+  let is_check = args.iter().any(|x| x == "--check");
+  let mut is_ok = true;
+
+  for path in args.iter().filter(|x| *x != "--check") {
+    let buf =
+      match std::fs::read(path) {
+        Ok(buf) => buf,
+        Err(e) => {
+          eprintln!("{}: {}", path, e);
+          is_ok = false;
+          continue;
+        }
+      };
+
+    let out =
+      match format_source(&buf) {
+        Ok(out) => out,
+        Err(e) => {
+          eprintln!("{}: {:?}", path, e);
+          is_ok = false;
+          continue;
+        }
+      };
+
+    if out.as_bytes() == buf.as_slice() {
+      continue;
+    }
+
+    if is_check {
+      println!("{}", path);
+      is_ok = false;
+    } else if let Err(e) = std::fs::write(path, out) {
+      eprintln!("{}: {}", path, e);
+      is_ok = false;
+    }
+  }
+
+  is_ok
+}
+
+// `adelie build [-A|-W|-D LINT]... FILE` compiles the program whose root
+// module is FILE to an object file next to it, with the extension `.o`. The
+// other modules of the program are found relative to FILE's directory. The
+// flags allow a lint, make it warn, or make it an error.
+
+pub fn build(args: &[String]) -> bool {
+  let usage = "usage: adelie build [-A|-W|-D LINT]... FILE";
+  let mut levels = LintLevels::default();
+  let mut files = Vec::new();
+  let mut args = args.iter();
+
+  while let Some(x) = args.next() {
+    let level =
+      match x.as_str() {
+        "-A" => Level::Allow,
+        "-W" => Level::Warn,
+        "-D" => Level::Deny,
+        _ => {
+          files.push(x);
+          continue;
+        }
+      };
+    match args.next().and_then(|y| Lint::from_name(y)) {
+      Some(lint) => levels.set(lint, level),
+      None => {
+        eprintln!("{}", usage);
+        return false;
+      }
+    }
+  }
+
+  let [path] = files.as_slice() else {
+    eprintln!("{}", usage);
+    return false;
+  };
+
+  let file = Path::new(path);
+  let root = file.parent().unwrap_or(Path::new("."));
 
   let mut arena = Arena::new();
-  let allocator = arena.allocator_mut();
-  let mut parser = Parser::new(source);
+  let aa = arena.allocator_mut();
+  let mut units = Vec::new();
+
+  let Some(items) = load(root, file, &mut units, aa) else { return false };
+
+  // Lambdas become function items before anything resolves names.
+
+  let items = convert_closures(&items, aa);
+  let items = aa.copy_slice(items.as_slice());
 
+  let Some((structs, names, typing)) = check(&units, items) else { return false };
 
-  if let Ok(x) = parser.parse_item(allocator) {
-    println!("{}", x.to_sexp());
+  let mut is_ok = true;
+
+  for x in lint_program(items, &names, &levels).iter() {
+    let severity = if x.level == Level::Deny { "error" } else { "warning" };
+    eprintln!("{}: {}: {} [{}]", locate(&units, x.span), severity, x.message, x.lint.to_name());
+    if x.level == Level::Deny { is_ok = false }
   }
 
-  use bytecode::*;
+  if ! is_ok { return false; }
+
+  let consts =
+    match eval_constants(items, &names, &typing, &structs) {
+      Ok(consts) => consts,
+      Err(e) => {
+        for x in e.iter() { eprintln!("{}: {:?}", locate(&units, x.span()), x) }
+        return false;
+      }
+    };
+
+  // Folding makes a new tree, which is checked again.
+
+  let items =
+    match fold_constants(items, &names, &typing, &consts, aa) {
+      Ok(items) => items,
+      Err(e) => {
+        for x in e.iter() { eprintln!("{}: {:?}", locate(&units, x.span()), x) }
+        return false;
+      }
+    };
+
+  let items = aa.copy_slice(items.as_slice());
+
+  let Some((structs, names, typing)) = check(&units, items) else { return false };
 
   let program =
-    Program {
-      functions: &[
-        Function {
-          name: "foo",
-          signature: Signature {
-            inputs: &[
-              Ty::I64,
-              Ty::I64,
-            ],
-            outputs: &[
-              Ty::I64,
-              Ty::I64,
-              Ty::I64,
-              Ty::Bool,
-              Ty::I64,
-            ]
-          },
-          code: &[
-            Inst::ImmI64(13),
-            Inst::Goto(BlockId(0), &[VarId(1), VarId(0)]),
-            Inst::Block(&[Ty::I64, Ty::I64]),
-            Inst::Op11(Op11::I64Neg, VarId(3)),
-            Inst::Op21(Op21::I64Add, VarId(3), VarId(4)),
-            Inst::Op11(Op11::I64IsNonZero, VarId(0)),
-            Inst::Op11(Op11::I64ToI6, VarId(1)),
-            Inst::Op21(Op21::I64Ror, VarId(0), VarId(8)),
-            Inst::Ret(
-              &[
-                VarId(4),
-                VarId(5),
-                VarId(6),
-                VarId(7),
-                VarId(9),
-              ]
-            ),
-          ]
-        }
-      ]
+    match lower_program(items, &names, &typing, &structs, aa) {
+      Ok(program) => program,
+      Err(e) => {
+        for x in e.iter() { eprintln!("{}: {:?}", locate(&units, x.span()), x) }
+        return false;
+      }
     };
 
   let object_bytes = backend::compile(program);
 
-  let mut out = File::create("out.o").unwrap();
+  let out = file.with_extension("o");
+
+  if let Err(e) = std::fs::write(&out, object_bytes) {
+    eprintln!("{}: {}", out.display(), e);
+    return false;
+  }
+
+  true
+}
+
+// `adelie doc [--html] FILE` prints a reference for the exported functions of
+// the program whose root module is FILE, as Markdown or, with `--html`, as an
+// HTML page.
+
+pub fn doc(args: &[String]) -> bool {
+  let is_html = args.iter().any(|x| x == "--html");
+  let files: Vec<&String> = args.iter().filter(|x| *x != "--html").collect();
+
+  let [path] = files.as_slice() else {
+    eprintln!("usage: adelie doc [--html] FILE");
+    return false;
+  };
+
+  let file = Path::new(path);
+  let root = file.parent().unwrap_or(Path::new("."));
+  let title = file.file_stem().map(|x| x.to_string_lossy()).unwrap_or_default();
+
+  let mut arena = Arena::new();
+  let aa = arena.allocator_mut();
+  let mut units = Vec::new();
+
+  let Some(items) = load(root, file, &mut units, aa) else { return false };
+
+  let items = aa.copy_slice(items.as_slice());
+
+  let Some((_, names, typing)) = check(&units, items) else { return false };
+
+  let docs = document_program(items, &names, &typing, &units);
+
+  if is_html {
+    print!("{}", docs_to_html(&title, &docs));
+  } else {
+    print!("{}", docs_to_markdown(&title, &docs));
+  }
+
+  true
+}
+
+// `adelie dump FILE` prints the sexp of each item parsed from FILE, one per
+// form, broken to fit within `WIDTH` columns. The output is stable, so dumps
+// can be diffed.
+
+pub fn dump(args: &[String]) -> bool {
+  let [path] = args else {
+    eprintln!("usage: adelie dump FILE");
+    return false;
+  };
+
+  let buf =
+    match std::fs::read(path) {
+      Ok(buf) => buf,
+      Err(e) => {
+        eprintln!("{}: {}", path, e);
+        return false;
+      }
+    };
+
+  let mut arena = Arena::new();
+  let aa = arena.allocator_mut();
+  let mut parser = Parser::new(&buf);
+
+  let items =
+    match parser.parse_file(aa) {
+      Ok(items) => items,
+      Err(e) => {
+        eprintln!("{}: {:?}", path, e);
+        return false;
+      }
+    };
+
+  for x in items.iter() {
+    println!("{}", x.to_sexp().pretty(WIDTH));
+  }
+
+  true
+}
+
+// `adelie highlight [--html] FILE` prints FILE with its syntax highlighted for
+// a terminal or, with `--html`, as an HTML page.
+
+pub fn highlight(args: &[String]) -> bool {
+  let is_html = args.iter().any(|x| x == "--html");
+  let files: Vec<&String> = args.iter().filter(|x| *x != "--html").collect();
+
+  let [path] = files.as_slice() else {
+    eprintln!("usage: adelie highlight [--html] FILE");
+    return false;
+  };
+
+  let buf =
+    match std::fs::read(path) {
+      Ok(buf) => buf,
+      Err(e) => {
+        eprintln!("{}: {}", path, e);
+        return false;
+      }
+    };
+
+  if is_html {
+    let title = Path::new(path).file_name().map(|x| x.to_string_lossy()).unwrap_or_default();
+    print!("{}", highlight_to_html(&title, &buf));
+  } else {
+    print!("{}", highlight_to_ansi(&buf));
+  }
+
+  true
+}
+
+fn load<'a>(root: &Path, file: &Path, units: &mut Vec<ParsedUnit>, aa: &mut Allocator<'a>) -> Option<Vec<AstItem<'a>>> {
+  match load_program_sources(root, file, units, aa) {
+    Ok(items) => Some(items),
+    Err(e) => {
+      match e.span() {
+        Some(x) => eprintln!("{}: {:?}", locate(units, x), e),
+        None => eprintln!("{}: {:?}", file.display(), e),
+      }
+      None
+    }
+  }
+}
+
+fn check<'a>(units: &[ParsedUnit], items: &'a [AstItem<'a>]) -> Option<(StructTable<'a>, Resolution, Typing)> {
+  let structs =
+    match StructTable::new(items) {
+      Ok(structs) => structs,
+      Err(e) => {
+        eprintln!("{}: {:?}", locate(units, e.span()), e);
+        return None;
+      }
+    };
+
+  let names =
+    match resolve_names(items) {
+      Ok(names) => names,
+      Err(e) => {
+        for x in e.iter() { eprintln!("{}: {:?}", locate(units, x.span()), x) }
+        return None;
+      }
+    };
+
+  let typing =
+    match check_types(items, &names, &structs) {
+      Ok(typing) => typing,
+      Err(e) => {
+        for x in e.iter() { eprintln!("{}: {:?}", locate(units, x.span()), x) }
+        return None;
+      }
+    };
 
-  out.write_all(&object_bytes).unwrap()
+  Some((structs, names, typing))
 }
@@ -2,10 +2,31 @@ pub(crate) use crate::bc;
 pub(crate) use crate::buf::*;
 pub(crate) use crate::bytecode;
 pub(crate) use crate::frontend_ast::*;
+pub(crate) use crate::frontend_closure::*;
+pub(crate) use crate::frontend_doc::*;
+pub(crate) use crate::frontend_eval::*;
+pub(crate) use crate::frontend_fold::*;
+pub(crate) use crate::frontend_format::*;
+pub(crate) use crate::frontend_highlight::*;
+pub(crate) use crate::frontend_intrinsic::*;
 pub(crate) use crate::frontend_lexer::*;
+pub(crate) use crate::frontend_lint::*;
+pub(crate) use crate::frontend_lower::*;
+pub(crate) use crate::frontend_macro::*;
+pub(crate) use crate::frontend_match::*;
+pub(crate) use crate::frontend_module::*;
+pub(crate) use crate::frontend_node::*;
 pub(crate) use crate::frontend_parser::*;
+pub(crate) use crate::frontend_resolve::*;
+pub(crate) use crate::frontend_span::*;
+pub(crate) use crate::frontend_struct::*;
+pub(crate) use crate::frontend_symbol::*;
 pub(crate) use crate::frontend_token::*;
+pub(crate) use crate::frontend_typeck::*;
+pub(crate) use crate::frontend_unit::*;
+pub(crate) use crate::frontend_visit::*;
 pub(crate) use crate::ir_op::*;
+pub(crate) use crate::ir_ssa::*;
 pub(crate) use crate::ir_ty::*;
 pub(crate) use crate::phantom::*;
 pub(crate) use crate::sexp::*;
@@ -15,9 +36,14 @@ pub(crate) use crate::u6::*;
 pub(crate) use oxcart::Allocator;
 pub(crate) use oxcart::Arena;
 pub(crate) use std::array;
+pub(crate) use std::collections::HashMap;
 pub(crate) use std::fmt;
 pub(crate) use std::fs::File;
-pub(crate) use std::io::Write;
+pub(crate) use std::io;
+pub(crate) use std::mem;
+pub(crate) use std::ops::Range;
+pub(crate) use std::path::Path;
+pub(crate) use std::path::PathBuf;
 pub(crate) use std::str;
 pub(crate) use std::sync::Arc;
 pub(crate) use target_lexicon;
@@ -26,13 +52,17 @@ pub(crate) use variant_count::VariantCount;
 pub(crate) mod cranelift {
   // use cranelift_codegen::ir::Signature;
   // use cranelift_module::FuncId;
-  // pub(crate) use cranelift_codegen::ir::Signature;
   // pub(crate) use cranelift_codegen::ir::Value;
   // pub(crate) use cranelift_codegen::ir::Inst;
   pub(crate) use cranelift_codegen as codegen;
   pub(crate) use cranelift_codegen::Context;
   pub(crate) use cranelift_codegen::ir::AbiParam;
   pub(crate) use cranelift_codegen::ir::InstBuilder;
+  pub(crate) use cranelift_codegen::ir::MemFlags;
+  pub(crate) use cranelift_codegen::ir::Signature;
+  pub(crate) use cranelift_codegen::ir::StackSlotData;
+  pub(crate) use cranelift_codegen::ir::StackSlotKind;
+  pub(crate) use cranelift_codegen::ir::TrapCode;
   pub(crate) use cranelift_codegen::ir::Type;
   pub(crate) use cranelift_codegen::ir::condcodes::IntCC;
   pub(crate) use cranelift_codegen::ir::types::I128;
@@ -43,7 +73,9 @@ pub(crate) mod cranelift {
   pub(crate) use cranelift_codegen::settings::Configurable;
   pub(crate) use cranelift_frontend::FunctionBuilder;
   pub(crate) use cranelift_frontend::FunctionBuilderContext;
+  pub(crate) use cranelift_frontend::Switch;
   pub(crate) use cranelift_module as module;
+  pub(crate) use cranelift_module::DataContext;
   pub(crate) use cranelift_module::Linkage;
   pub(crate) use cranelift_module::Module;
   pub(crate) use cranelift_module::ModuleCompiledFunction;
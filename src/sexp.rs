@@ -1,27 +1,206 @@
 use crate::prelude::*;
 
-#[derive(Clone)]
+#[derive(Clone, Eq, PartialEq)]
 pub enum Sexp {
   Atom(String),
   List(Box<[Sexp]>),
 }
 
+#[derive(Debug)]
+pub enum SexpError {
+  // A syntax error at a byte offset.
+  Syntax(usize),
+  // A well-formed sexp that doesn't denote what was expected.
+  Form(String),
+}
+
 impl Sexp {
   pub fn from_atom(a: &str) -> Self {
     Self::Atom(a.to_string())
   }
+
+  pub fn as_atom(&self) -> Option<&str> {
+    match self {
+      Self::Atom(a) => Some(a),
+      Self::List(_) => None,
+    }
+  }
+
+  pub fn as_list(&self) -> Option<&[Sexp]> {
+    match self {
+      Self::Atom(_) => None,
+      Self::List(a) => Some(a),
+    }
+  }
+
+  // The head and the rest of a list whose first element is an atom.
+
+  pub fn as_form(&self) -> Option<(&str, &[Sexp])> {
+    match self {
+      Self::List(a) => {
+        match a.split_first() {
+          Some((Self::Atom(x), y)) => Some((x, y)),
+          _ => None,
+        }
+      }
+      Self::Atom(_) => None,
+    }
+  }
+
+  // Reads exactly one sexp.
+
+  pub fn read(s: &str) -> Result<Self, SexpError> {
+    let mut r = Reader { buf: s.as_bytes(), pos: 0 };
+    r.skip_space();
+    let x = r.read()?;
+    r.skip_space();
+    if r.pos != r.buf.len() { return Err(SexpError::Syntax(r.pos)); }
+    Ok(x)
+  }
+
+  // Reads a sequence of sexps separated by space.
+
+  pub fn read_seq(s: &str) -> Result<Vec<Self>, SexpError> {
+    let mut r = Reader { buf: s.as_bytes(), pos: 0 };
+    let mut a = Vec::new();
+    r.skip_space();
+    while r.pos != r.buf.len() {
+      a.push(r.read()?);
+      r.skip_space();
+    }
+    Ok(a)
+  }
 }
 
 pub trait ToSexp {
   fn to_sexp(&self) -> Sexp;
 }
 
+// The inverse of `ToSexp`, for types that live in an arena.
+
+pub trait FromSexp<'a>: Sized {
+  fn from_sexp(x: &Sexp, aa: &mut Allocator<'a>) -> Result<Self, SexpError>;
+}
+
+struct Reader<'a> {
+  buf: &'a [u8],
+  pos: usize,
+}
+
+fn is_delimiter(c: u8) -> bool {
+  c.is_ascii_whitespace() || c == b'(' || c == b')' || c == b'"'
+}
+
+impl<'a> Reader<'a> {
+  fn skip_space(&mut self) {
+    while self.pos != self.buf.len() && self.buf[self.pos].is_ascii_whitespace() {
+      self.pos += 1;
+    }
+  }
+
+  fn read(&mut self) -> Result<Sexp, SexpError> {
+    match self.buf.get(self.pos) {
+      None | Some(b')') => {
+        Err(SexpError::Syntax(self.pos))
+      }
+      Some(b'(') => {
+        self.pos += 1;
+        let mut a = Vec::new();
+        loop {
+          self.skip_space();
+          match self.buf.get(self.pos) {
+            None => return Err(SexpError::Syntax(self.pos)),
+            Some(b')') => break,
+            Some(_) => a.push(self.read()?),
+          }
+        }
+        self.pos += 1;
+        Ok(Sexp::List(a.into_boxed_slice()))
+      }
+      Some(b'"') => {
+        self.pos += 1;
+        let mut a = String::new();
+        loop {
+          let i = self.pos;
+          while self.pos != self.buf.len() && self.buf[self.pos] != b'"' && self.buf[self.pos] != b'\\' {
+            self.pos += 1;
+          }
+          // Quotes and backslashes are ascii, so this is on a char boundary.
+          a.push_str(str::from_utf8(&self.buf[i .. self.pos]).unwrap());
+          match self.buf.get(self.pos) {
+            None => return Err(SexpError::Syntax(self.pos)),
+            Some(b'"') => break,
+            Some(_) => a.push(self.read_escape()?),
+          }
+        }
+        self.pos += 1;
+        Ok(Sexp::Atom(a))
+      }
+      Some(_) => {
+        let i = self.pos;
+        while self.pos != self.buf.len() && ! is_delimiter(self.buf[self.pos]) {
+          self.pos += 1;
+        }
+        // Delimiters are ascii, so this is on a char boundary.
+        let a = str::from_utf8(&self.buf[i .. self.pos]).unwrap();
+        Ok(Sexp::from_atom(a))
+      }
+    }
+  }
+
+  fn read_escape(&mut self) -> Result<char, SexpError> {
+    let i = self.pos;
+    self.pos += 2;
+    match self.buf.get(i + 1) {
+      Some(b'"') => Ok('"'),
+      Some(b'\\') => Ok('\\'),
+      Some(b'n') => Ok('\n'),
+      Some(b'r') => Ok('\r'),
+      Some(b't') => Ok('\t'),
+      Some(b'u') if self.buf.get(self.pos) == Some(&b'{') => {
+        let j = self.pos + 1;
+        let mut k = j;
+        while k != self.buf.len() && self.buf[k].is_ascii_hexdigit() { k += 1; }
+        if self.buf.get(k) != Some(&b'}') { return Err(SexpError::Syntax(i)); }
+        self.pos = k + 1;
+        str::from_utf8(&self.buf[j .. k]).ok()
+          .and_then(|x| u32::from_str_radix(x, 16).ok())
+          .and_then(char::from_u32)
+          .ok_or(SexpError::Syntax(i))
+      }
+      _ => Err(SexpError::Syntax(i)),
+    }
+  }
+}
+
+// An atom needs quotes if reading it back unquoted would give something else.
+
+fn needs_quotes(a: &str) -> bool {
+  a.is_empty() || a.chars().any(|c| c.is_whitespace() || c.is_control() || matches!(c, '(' | ')' | '"' | '\\'))
+}
+
+fn write_atom(f: &mut impl fmt::Write, a: &str) -> fmt::Result {
+  if ! needs_quotes(a) { return f.write_str(a); }
+  f.write_char('"')?;
+  for c in a.chars() {
+    match c {
+      '"' => f.write_str("\\\"")?,
+      '\\' => f.write_str("\\\\")?,
+      '\n' => f.write_str("\\n")?,
+      '\r' => f.write_str("\\r")?,
+      '\t' => f.write_str("\\t")?,
+      c if c.is_control() => write!(f, "\\u{{{:x}}}", c as u32)?,
+      c => f.write_char(c)?,
+    }
+  }
+  f.write_char('"')
+}
+
 impl fmt::Display for Sexp {
   fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
     match self {
       Self::Atom(a) => {
-        // TODO: escape
-        write!(f, "{}", a)?;
+        write_atom(f, a)?;
       }
       Self::List(a) => {
         write!(f, "(")?;
@@ -36,3 +215,171 @@ impl fmt::Display for Sexp {
     Ok(())
   }
 }
+
+// PRETTY PRINTING
+//
+// A list is printed on one line if it fits, and otherwise with each element
+// after the head on its own line. This is Wadler's "prettier printer" with
+// only the combinators that sexps need.
+
+enum Doc {
+  Text(String),
+  Line,
+  Nest(usize, Box<Doc>),
+  Group(Box<Doc>),
+  Concat(Vec<Doc>),
+}
+
+#[derive(Clone, Copy, Eq, PartialEq)]
+enum Mode {
+  Flat,
+  Break,
+}
+
+impl Sexp {
+  // Prints with lines at most `width` columns wide where possible.
+
+  pub fn pretty(&self, width: usize) -> String {
+    let doc = self.to_doc();
+    let mut out = String::new();
+    let mut col = 0;
+    let mut stack = vec![(0, Mode::Break, &doc)];
+
+    while let Some((i, m, x)) = stack.pop() {
+      match x {
+        Doc::Text(s) => {
+          out.push_str(s);
+          col += s.chars().count();
+        }
+        Doc::Line => {
+          if m == Mode::Flat {
+            out.push(' ');
+            col += 1;
+          } else {
+            out.push('\n');
+            out.push_str(&" ".repeat(i));
+            col = i;
+          }
+        }
+        Doc::Nest(j, y) => {
+          stack.push((i + j, m, y));
+        }
+        Doc::Group(y) => {
+          let m = if m == Mode::Flat || fits(width as isize - col as isize, y, &stack) { Mode::Flat } else { Mode::Break };
+          stack.push((i, m, y));
+        }
+        Doc::Concat(a) => {
+          for y in a.iter().rev() { stack.push((i, m, y)); }
+        }
+      }
+    }
+
+    out
+  }
+
+  fn to_doc(&self) -> Doc {
+    match self {
+      Self::Atom(_) => {
+        Doc::Text(self.to_string())
+      }
+      Self::List(a) => {
+        let Some((x, y)) = a.split_first() else { return Doc::Text("()".to_string()) };
+        // Forms are indented under their head, other lists align their
+        // elements. Atoms right after the head stay on its line if they fit.
+        let indent = if let Self::Atom(_) = x { 2 } else { 1 };
+        let n = if indent == 2 { y.iter().take_while(|z| z.as_atom().is_some()).count() } else { 0 };
+        let mut head = vec![x.to_doc()];
+        for z in y[.. n].iter() {
+          head.push(Doc::Line);
+          head.push(z.to_doc());
+        }
+        let mut rest = vec![Doc::Group(Box::new(Doc::Concat(head)))];
+        for z in y[n ..].iter() {
+          rest.push(Doc::Line);
+          rest.push(z.to_doc());
+        }
+        Doc::Group(Box::new(Doc::Concat(vec![
+          Doc::Text("(".to_string()),
+          Doc::Nest(indent, Box::new(Doc::Concat(rest))),
+          Doc::Text(")".to_string()),
+        ])))
+      }
+    }
+  }
+}
+
+// Whether `x` printed flat, followed by the rest of the document up to its
+// next line break, fits in `width` columns.
+
+fn fits(mut width: isize, x: &Doc, rest: &[(usize, Mode, &Doc)]) -> bool {
+  let mut stack = vec![(Mode::Flat, x)];
+  let mut k = rest.len();
+
+  loop {
+    if width < 0 { return false; }
+
+    let (m, y) =
+      match stack.pop() {
+        Some(z) => z,
+        None => {
+          if k == 0 { return true; }
+          k -= 1;
+          (rest[k].1, rest[k].2)
+        }
+      };
+
+    match y {
+      Doc::Text(s) => {
+        width -= s.chars().count() as isize;
+      }
+      Doc::Line => {
+        if m == Mode::Break { return true; }
+        width -= 1;
+      }
+      Doc::Nest(_, z) | Doc::Group(z) => {
+        stack.push((m, z));
+      }
+      Doc::Concat(a) => {
+        for z in a.iter().rev() { stack.push((m, z)); }
+      }
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn quoting() {
+    for a in ["x", "", "a b", "(", ")", "\"", "\\", "\n\t\r", "\u{1}", "é"] {
+      let x = Sexp::from_atom(a);
+      let s = x.to_string();
+      assert_eq!(s.starts_with('"'), a != "x" && a != "é");
+      assert_eq!(Sexp::read(&s).unwrap().as_atom(), Some(a));
+    }
+  }
+
+  #[test]
+  fn read() {
+    let x = Sexp::read(" (a (b \"c d\") ()) ").unwrap();
+    assert_eq!(x.to_string(), "(a (b \"c d\") ())");
+    assert_eq!(Sexp::read_seq("a (b) c").unwrap().len(), 3);
+  }
+
+  #[test]
+  fn read_errors() {
+    assert!(matches!(Sexp::read("(a"), Err(SexpError::Syntax(2))));
+    assert!(matches!(Sexp::read(")"), Err(SexpError::Syntax(0))));
+    assert!(matches!(Sexp::read("a b"), Err(SexpError::Syntax(2))));
+    assert!(matches!(Sexp::read("\"\\q\""), Err(SexpError::Syntax(1))));
+  }
+
+  #[test]
+  fn pretty() {
+    let x = Sexp::read("(f (g a b) (h c d))").unwrap();
+    assert_eq!(x.pretty(80), "(f (g a b) (h c d))");
+    assert_eq!(x.pretty(12), "(f\n  (g a b)\n  (h c d))");
+    assert!(Sexp::read(&x.pretty(1)).unwrap() == x);
+  }
+}